@@ -0,0 +1,237 @@
+//! Parsing of FHIR request bodies into the request structs
+//!
+//! `warp::body::json` rejects a malformed body with a generic message that
+//! never reaches the client as FHIR. `fhir_json` instead rejects with
+//! `InvalidBody`, which `handle_invalid_body` turns into a 400
+//! OperationOutcome naming the offending element. In strict mode, elements
+//! the request struct doesn't know are rejected too rather than ignored.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::json;
+use warp::hyper::body::Bytes;
+use warp::{Filter, Reply};
+
+/// Rejection for a body that doesn't deserialize into the expected resource
+#[derive(Debug)]
+pub struct InvalidBody {
+    /// FHIR issue type, e.g. `required` for a missing element
+    code: &'static str,
+    diagnostics: String,
+    /// FHIRPath-style location, e.g. `Observation.valueQuantity.value`
+    expression: Option<String>,
+}
+
+impl warp::reject::Reject for InvalidBody {}
+
+/// Deserialize the body, as read by `body`, as the `resource_type` request struct `T`
+///
+/// `body` is `warp::body::bytes()` or a filter built on it. With `strict` set,
+/// any element of the body that `T` would drop is an error.
+pub fn fhir_json<T, B>(body: B, resource_type: &'static str, strict: bool)
+    -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone
+where
+    T: DeserializeOwned + Serialize + Send,
+    B: Filter<Extract = (Bytes,), Error = warp::Rejection> + Clone,
+{
+    body.and_then(move |body: Bytes| async move {
+        parse_resource::<T>(resource_type, &body, strict).map_err(warp::reject::custom)
+    })
+}
+
+fn parse_resource<T>(resource_type: &str, body: &[u8], strict: bool) -> Result<T, InvalidBody>
+where
+    T: DeserializeOwned + Serialize,
+{
+    let resource: T = serde_json::from_slice(body).map_err(|e| invalid_body(resource_type, body, &e))?;
+    if !strict {
+        return Ok(resource);
+    }
+
+    // Elements that don't survive a round trip through `T` were ignored by it
+    let submitted: serde_json::Value = serde_json::from_slice(body)
+        .map_err(|e| invalid_body(resource_type, body, &e))?;
+    let understood = serde_json::to_value(&resource)
+        .map_err(|e| invalid_body(resource_type, body, &e))?;
+    let mut unknown = Vec::new();
+    unknown_elements(&submitted, &understood, resource_type.to_string(), &mut unknown);
+    match unknown.first() {
+        None => Ok(resource),
+        Some(first) => Err(InvalidBody {
+            code: "structure",
+            diagnostics: format!("Unknown element(s): {}", unknown.join(", ")),
+            expression: Some(first.clone()),
+        }),
+    }
+}
+
+fn invalid_body(resource_type: &str, body: &[u8], error: &serde_json::Error) -> InvalidBody {
+    let message = error.to_string();
+    let location = json_path_at(body, error.line(), error.column());
+    let join = |path: &str, element: &str| match (path.is_empty(), element.is_empty()) {
+        (_, true) => path.to_string(),
+        (true, false) => element.to_string(),
+        (false, false) => format!("{}.{}", path, element),
+    };
+
+    // serde reports a missing field once the object lacking it has been read
+    let missing = message.strip_prefix("missing field `")
+        .and_then(|rest| rest.split_once('`'))
+        .map(|(field, _)| field);
+    let (code, path) = match missing {
+        Some(field) => ("required", join(&location, field)),
+        None if error.is_syntax() || error.is_eof() => ("structure", location),
+        None => ("value", location),
+    };
+    InvalidBody {
+        code,
+        diagnostics: message,
+        expression: Some(join(resource_type, &path)),
+    }
+}
+
+/// Path of the element being read at this 1-based line and column, e.g. `component[1].code`
+fn json_path_at(body: &[u8], line: usize, column: usize) -> String {
+    enum Frame {
+        Object { key: Option<String>, expecting_key: bool },
+        Array { index: usize },
+    }
+
+    let line_start: usize = body.split(|&b| b == b'\n')
+        .take(line.saturating_sub(1))
+        .map(|l| l.len() + 1)
+        .sum();
+    let end = (line_start + column).min(body.len());
+
+    let mut frames: Vec<Frame> = Vec::new();
+    let mut i = 0;
+    while i < end {
+        match body[i] {
+            b'{' => frames.push(Frame::Object { key: None, expecting_key: true }),
+            b'[' => frames.push(Frame::Array { index: 0 }),
+            b'}' | b']' => {
+                frames.pop();
+            }
+            b',' => match frames.last_mut() {
+                Some(Frame::Object { key, expecting_key }) => {
+                    *key = None;
+                    *expecting_key = true;
+                }
+                Some(Frame::Array { index }) => *index += 1,
+                None => {}
+            },
+            b':' => {
+                if let Some(Frame::Object { expecting_key, .. }) = frames.last_mut() {
+                    *expecting_key = false;
+                }
+            }
+            b'"' => {
+                let start = i + 1;
+                i = start;
+                while i < body.len() && body[i] != b'"' {
+                    i += if body[i] == b'\\' { 2 } else { 1 };
+                }
+                if let Some(Frame::Object { key, expecting_key: true }) = frames.last_mut() {
+                    *key = Some(String::from_utf8_lossy(&body[start..i.min(body.len())]).into_owned());
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    let mut path = String::new();
+    for frame in &frames {
+        match frame {
+            Frame::Object { key: Some(key), .. } => {
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(key);
+            }
+            Frame::Object { key: None, .. } => {}
+            Frame::Array { index } => path.push_str(&format!("[{}]", index)),
+        }
+    }
+    path
+}
+
+/// Collect the paths of elements in `submitted` with no counterpart in `understood`
+///
+/// Null elements are skipped, since optional fields left out of the output are null on input.
+fn unknown_elements(submitted: &serde_json::Value, understood: &serde_json::Value, path: String, unknown: &mut Vec<String>) {
+    match (submitted, understood) {
+        (serde_json::Value::Object(submitted), serde_json::Value::Object(understood)) => {
+            for (key, value) in submitted.iter().filter(|(_, value)| !value.is_null()) {
+                let element = format!("{}.{}", path, key);
+                match understood.get(key) {
+                    Some(known) => unknown_elements(value, known, element, unknown),
+                    None => unknown.push(element),
+                }
+            }
+        }
+        (serde_json::Value::Array(submitted), serde_json::Value::Array(understood)) => {
+            for (index, (value, known)) in submitted.iter().zip(understood).enumerate() {
+                unknown_elements(value, known, format!("{}[{}]", path, index), unknown);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// 400 OperationOutcome for an `InvalidBody` rejection; other rejections pass through
+pub async fn handle_invalid_body(rejection: warp::Rejection) -> Result<warp::reply::Response, warp::Rejection> {
+    let Some(invalid) = rejection.find::<InvalidBody>() else {
+        return Err(rejection);
+    };
+
+    let mut issue = json!({
+        "severity": "error",
+        "code": invalid.code,
+        "diagnostics": invalid.diagnostics,
+    });
+    if let Some(expression) = &invalid.expression {
+        issue["expression"] = json!([expression]);
+    }
+    let outcome = json!({ "resourceType": "OperationOutcome", "issue": [issue] });
+    Ok(warp::reply::with_status(warp::reply::json(&outcome), warp::http::StatusCode::BAD_REQUEST).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, serde::Deserialize, Serialize)]
+    struct Quantity {
+        value: f64,
+        unit: String,
+    }
+
+    #[derive(Debug, serde::Deserialize, Serialize)]
+    #[allow(non_snake_case)]
+    struct Resource {
+        status: String,
+        valueQuantity: Option<Quantity>,
+    }
+
+    fn expression(body: &str, strict: bool) -> Option<String> {
+        parse_resource::<Resource>("Observation", body.as_bytes(), strict).unwrap_err().expression
+    }
+
+    #[test]
+    fn test_invalid_body_names_the_element() {
+        assert_eq!(expression(r#"{"valueQuantity": null}"#, false).as_deref(), Some("Observation.status"));
+        assert_eq!(
+            expression("{\n  \"status\": \"final\",\n  \"valueQuantity\": {\"value\": \"high\", \"unit\": \"mg\"}\n}", false).as_deref(),
+            Some("Observation.valueQuantity.value"),
+        );
+        assert_eq!(
+            expression(r#"{"status": "final", "valueQuantity": {"value": 1.5}}"#, false).as_deref(),
+            Some("Observation.valueQuantity.unit"),
+        );
+
+        let extra = r#"{"status": "final", "valueQuantity": {"value": 1.5, "unit": "mg", "comparator": "<"}}"#;
+        assert!(parse_resource::<Resource>("Observation", extra.as_bytes(), false).is_ok());
+        assert_eq!(expression(extra, true).as_deref(), Some("Observation.valueQuantity.comparator"));
+    }
+}
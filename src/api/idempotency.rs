@@ -0,0 +1,258 @@
+//! Replay of responses to retried POSTs carrying an `Idempotency-Key` header
+//!
+//! A client that times out and retries a create would otherwise store the same
+//! records twice. `RestApi` remembers the response to each keyed create for a
+//! while and sends it back for a repeat key instead of running the handler again.
+//! A key is reserved before its first request runs, so a retry racing it is
+//! turned away rather than stored twice, and reusing a key with another body
+//! is refused rather than answered with the first body's response.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::http::{HeaderMap, HeaderValue, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::reply::Response;
+
+/// How long a key is remembered
+pub const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(3600);
+
+/// Keys remembered at once; the oldest is dropped to make room
+pub const DEFAULT_IDEMPOTENCY_CAPACITY: usize = 10_000;
+
+/// Header added to replayed responses so clients can tell them apart
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+#[derive(Debug)]
+struct StoredReply {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+#[derive(Debug)]
+struct Entry {
+    stored_at: Instant,
+    body_hash: Option<u64>,     // Set once the handler has read the request body
+    reply: Option<StoredReply>, // None while the first request is still running
+}
+
+/// Entries by key, plus the order they expire in
+#[derive(Debug, Default)]
+struct Entries {
+    by_key: HashMap<String, Entry>,
+    expiry: VecDeque<(Instant, String)>, // Oldest first; stale once its key was stored again or released
+}
+
+impl Entries {
+    fn live(&self, key: &str, ttl: Duration) -> Option<&Entry> {
+        self.by_key.get(key).filter(|entry| entry.stored_at.elapsed() < ttl)
+    }
+
+    fn is_current(&self, stored_at: Instant, key: &str) -> bool {
+        self.by_key.get(key).is_some_and(|entry| entry.stored_at == stored_at)
+    }
+
+    /// Drop expired entries, then the oldest ones until another fits
+    fn make_room(&mut self, ttl: Duration, capacity: usize) {
+        // Released keys leave stale expiry slots behind; sweep them once they pile up
+        if self.expiry.len() > capacity.saturating_mul(2) {
+            let by_key = &self.by_key;
+            self.expiry.retain(|(stored_at, key)| by_key.get(key).is_some_and(|entry| entry.stored_at == *stored_at));
+        }
+        while let Some((stored_at, key)) = self.expiry.front() {
+            let current = self.is_current(*stored_at, key);
+            if current && stored_at.elapsed() < ttl && self.by_key.len() < capacity {
+                break;
+            }
+            let (_, key) = self.expiry.pop_front().expect("front exists");
+            if current {
+                self.by_key.remove(&key);
+            }
+        }
+    }
+}
+
+/// What a repeated key finds in the store
+#[derive(Debug)]
+pub enum Lookup {
+    /// The response first sent for the key
+    Replay(Response),
+    /// The first request with the key hasn't finished yet
+    InFlight,
+    /// The key was first used with a different body
+    BodyMismatch,
+    /// The key isn't known, or has expired
+    Unknown,
+}
+
+/// Bounded map of idempotency keys to the response first sent for them
+#[derive(Debug)]
+pub struct IdempotencyStore {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl Default for IdempotencyStore {
+    fn default() -> Self {
+        IdempotencyStore::new(DEFAULT_IDEMPOTENCY_TTL, DEFAULT_IDEMPOTENCY_CAPACITY)
+    }
+}
+
+impl IdempotencyStore {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        IdempotencyStore {
+            ttl,
+            capacity,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Whether `key` is reserved or has a stored response
+    pub fn contains(&self, key: &str) -> bool {
+        self.entries.lock().unwrap().live(key, self.ttl).is_some()
+    }
+
+    /// How a request repeating `key` with `body` should be answered
+    pub fn lookup(&self, key: &str, body: &[u8]) -> Lookup {
+        let entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.live(key, self.ttl) else {
+            return Lookup::Unknown;
+        };
+        let Some(stored) = &entry.reply else {
+            return Lookup::InFlight;
+        };
+        if entry.body_hash.is_some_and(|hash| hash != body_hash(body)) {
+            return Lookup::BodyMismatch;
+        }
+
+        let mut response = Response::new(stored.body.clone().into());
+        *response.status_mut() = stored.status;
+        *response.headers_mut() = stored.headers.clone();
+        response.headers_mut().insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+        Lookup::Replay(response)
+    }
+
+    /// Claim `key` for a request about to run, or `None` if it is already taken
+    ///
+    /// Until the reservation completes, requests repeating the key find it in
+    /// flight. Dropping it uncompleted frees the key again.
+    pub fn reserve(self: &Arc<Self>, key: String) -> Option<Reservation> {
+        if self.capacity == 0 {
+            return Some(Reservation { store: Arc::clone(self), key, stored_at: None });
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.live(&key, self.ttl).is_some() {
+            return None;
+        }
+        entries.make_room(self.ttl, self.capacity);
+
+        let stored_at = Instant::now();
+        entries.by_key.insert(key.clone(), Entry { stored_at, body_hash: None, reply: None });
+        entries.expiry.push_back((stored_at, key.clone()));
+        Some(Reservation { store: Arc::clone(self), key, stored_at: Some(stored_at) })
+    }
+
+    /// Note the body of the request holding `key`, so a retry with another body is refused
+    pub fn note_body(&self, key: &str, body: &[u8]) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.by_key.get_mut(key).filter(|entry| entry.reply.is_none()) {
+            entry.body_hash = Some(body_hash(body));
+        }
+    }
+}
+
+/// A key claimed by `IdempotencyStore::reserve` for one running request
+#[derive(Debug)]
+pub struct Reservation {
+    store: Arc<IdempotencyStore>,
+    key: String,
+    stored_at: Option<Instant>, // None when the store keeps nothing
+}
+
+impl Reservation {
+    /// Remember the response sent for the key
+    pub fn complete(mut self, status: StatusCode, headers: HeaderMap, body: Bytes) {
+        let Some(stored_at) = self.stored_at.take() else {
+            return;
+        };
+        let mut entries = self.store.entries.lock().unwrap();
+        if let Some(entry) = entries.by_key.get_mut(&self.key).filter(|entry| entry.stored_at == stored_at) {
+            entry.reply = Some(StoredReply { status, headers, body });
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let Some(stored_at) = self.stored_at else {
+            return;
+        };
+        let mut entries = self.store.entries.lock().unwrap();
+        if entries.is_current(stored_at, &self.key) {
+            entries.by_key.remove(&self.key);
+        }
+    }
+}
+
+fn body_hash(body: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_reply(store: &Arc<IdempotencyStore>, key: &str, body: &'static str) {
+        let reservation = store.reserve(key.to_string()).unwrap();
+        store.note_body(key, b"request");
+        reservation.complete(StatusCode::CREATED, HeaderMap::new(), Bytes::from_static(body.as_bytes()));
+    }
+
+    #[test]
+    fn test_replays_until_expired_or_evicted() {
+        let store = Arc::new(IdempotencyStore::new(DEFAULT_IDEMPOTENCY_TTL, 2));
+        store_reply(&store, "a", "first");
+        let Lookup::Replay(replayed) = store.lookup("a", b"request") else {
+            panic!("nothing replayed");
+        };
+        assert_eq!(replayed.status(), StatusCode::CREATED);
+        assert_eq!(replayed.headers()[REPLAYED_HEADER], "true");
+
+        store_reply(&store, "b", "second");
+        store_reply(&store, "c", "third");
+        assert!(matches!(store.lookup("a", b"request"), Lookup::Unknown));
+        assert!(matches!(store.lookup("c", b"request"), Lookup::Replay(_)));
+
+        let expired = Arc::new(IdempotencyStore::new(Duration::ZERO, 2));
+        store_reply(&expired, "a", "first");
+        assert!(matches!(expired.lookup("a", b"request"), Lookup::Unknown));
+    }
+
+    #[test]
+    fn test_reserved_keys_are_in_flight_until_completed_or_dropped() {
+        let store = Arc::new(IdempotencyStore::new(DEFAULT_IDEMPOTENCY_TTL, 10));
+        let reservation = store.reserve("a".to_string()).unwrap();
+        assert!(store.reserve("a".to_string()).is_none());
+        assert!(matches!(store.lookup("a", b"request"), Lookup::InFlight));
+
+        drop(reservation);
+        assert!(!store.contains("a"));
+
+        store_reply(&store, "a", "first");
+        assert!(store.reserve("a".to_string()).is_none());
+        assert!(matches!(store.lookup("a", b"another request"), Lookup::BodyMismatch));
+
+        for i in 0..100 {
+            drop(store.reserve(format!("released-{}", i)));
+        }
+        assert!(store.entries.lock().unwrap().expiry.len() <= 21);
+        assert!(matches!(store.lookup("a", b"request"), Lookup::Replay(_)));
+    }
+}
@@ -1 +1 @@
-pub mod rest; 
\ No newline at end of file
+pub mod rest; pub mod idempotency; pub mod body;
@@ -1,14 +1,27 @@
-use std::sync::Arc;
-use warp::Filter;
+// Request structs mirror FHIR JSON field names (resourceType, valueQuantity, ...)
+#![allow(non_snake_case)]
+
+use std::sync::{Arc, Mutex};
+use warp::{Filter, Reply};
+use warp::filters::BoxedFilter;
 use warp::reply::{Json, with_header};
 use std::convert::Infallible;
 use serde::{Deserialize, Serialize};
-use crate::timeseries::query::QueryEngine;
+use crate::timeseries::query::{Aggregation, QueryEngine, QueryError, RecordFilter, TimeSeriesQuery, ValueFilter};
+use crate::timeseries::detection::DetectionConfig;
+use crate::timeseries::functions::{OutlierMethod, TimeSeriesStats};
 use crate::fhir::{FHIRObservation, ObservationComponent};
-use crate::fhir::{MedicationAdministration, DeviceObservation, VitalSigns, VitalType};
+use crate::fhir::{MedicationAdministration, DeviceObservation, VitalSigns, VitalType, Condition, Encounter,
+                  AllergyIntolerance};
+use crate::fhir::{FHIRError, ResourceType};
+use crate::fhir::resources::Patient;
 use crate::fhir::conversion::FHIRConverter;
 use crate::storage::Record;
+use crate::api::idempotency::{IdempotencyStore, Lookup, Reservation};
+use crate::api::body::{fhir_json, handle_invalid_body};
+use crate::config::parse_duration;
 use serde_json::json;
+use chrono_tz::Tz;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FHIRObservationComponentRequest {
@@ -28,6 +41,8 @@ pub struct SampledData {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FHIRObservationRequest {
     pub resourceType: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>, // Generated on create when absent
     pub status: String,
     pub code: CodeBlock,
     pub subject: Reference,
@@ -37,9 +52,18 @@ pub struct FHIRObservationRequest {
     pub valueQuantity: Option<ValueQuantity>,
     pub component: Option<Vec<FHIRObservationComponentRequest>>,
     pub valueSampledData: Option<SampledData>,
+    pub valueString: Option<String>,
+    pub valueBoolean: Option<bool>,
+    pub valueCodeableConcept: Option<CodeBlock>,
     
     // Optional device reference
     pub device: Option<Reference>,
+    
+    // Optional categories; the first coding's code is kept
+    pub category: Option<Vec<CodeBlock>>,
+    
+    // Optional performers; the first reference is kept
+    pub performer: Option<Vec<Reference>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -67,9 +91,79 @@ pub struct ValueQuantity {
     pub code: String,
 }
 
+/// Outcome reported in every `ApiResponse`, serialized as "success", "error" or "partial"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseStatus {
+    Success,
+    Error,
+    /// Some items of a batch were stored and some were rejected
+    Partial,
+}
+
+/// Machine-readable reason for an error response, e.g. `INVALID_TIMESTAMP`
+///
+/// Codes are stable; clients should match on them rather than on `message`,
+/// which is meant for people and may change wording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    MissingParameter,
+    InvalidParameter,
+    InvalidFilter,
+    InvalidTimestamp,
+    InvalidTimeRange,
+    InvalidResourceType,
+    /// The resource is well-formed JSON but not a usable FHIR resource
+    InvalidResource,
+    UnknownVitalCode,
+    NotFound,
+    Unauthorized,
+    NotImplemented,
+    PersistenceDisabled,
+    CorruptChunk,
+    Timeout,
+    /// Writes are refused until a flush catches up; retry later
+    Backpressure,
+    /// A request with the same `Idempotency-Key` is still running; retry later
+    IdempotencyKeyInUse,
+    /// The `Idempotency-Key` was first sent with a different body
+    IdempotencyKeyMismatch,
+    StorageFailure,
+    Internal,
+}
+
+impl From<&QueryError> for ErrorCode {
+    fn from(error: &QueryError) -> Self {
+        match error {
+            QueryError::StorageError(_) => ErrorCode::StorageFailure,
+            QueryError::InvalidTimeRange(_) => ErrorCode::InvalidTimeRange,
+            QueryError::MetricNotFound(_) => ErrorCode::NotFound,
+            QueryError::InvalidFilter(_) => ErrorCode::InvalidFilter,
+            QueryError::InvalidParameter(_) => ErrorCode::InvalidParameter,
+            QueryError::Timeout(_) => ErrorCode::Timeout,
+            QueryError::Backpressure(_) => ErrorCode::Backpressure,
+        }
+    }
+}
+
+impl From<&FHIRError> for ErrorCode {
+    fn from(error: &FHIRError) -> Self {
+        match error {
+            FHIRError::ConversionError(_) | FHIRError::ValidationError(_) => ErrorCode::InvalidResource,
+            FHIRError::NotFound(_) => ErrorCode::NotFound,
+        }
+    }
+}
+
+/// Body of every non-FHIR response
+///
+/// `error_code` is only present on errors.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ApiResponse {
-    pub status: String,
+    pub status: ResponseStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
     pub message: String,
     pub data: Option<serde_json::Value>,
 }
@@ -127,6 +221,122 @@ pub struct VitalSignsRequest {
     pub reliability: Option<String>, // reliability indicator
 }
 
+// Request for condition (diagnosis / problem list entry)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConditionRequest {
+    pub resourceType: String,        // Should be "Condition"
+    pub clinicalStatus: CodeBlock,   // active, resolved, etc.
+    pub code: CodeBlock,             // condition code
+    pub subject: Reference,          // patient reference
+    pub onsetDateTime: String,       // when the condition started
+    pub severity: Option<CodeBlock>, // mild, moderate, severe
+}
+
+// Request for encounter (admission / visit)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EncounterRequest {
+    pub resourceType: String, // Should be "Encounter"
+    pub status: String,       // planned, in-progress, finished, etc.
+    pub class: Coding,        // encounter class (IMP, AMB, EMER, ...)
+    pub subject: Reference,   // patient reference
+    pub period: Period,       // when the encounter took place
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Period {
+    pub start: String,
+    pub end: Option<String>, // absent while the encounter is ongoing
+}
+
+// Request for allergy / intolerance
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AllergyIntoleranceRequest {
+    pub resourceType: String,        // Should be "AllergyIntolerance"
+    pub code: CodeBlock,             // substance code
+    pub criticality: Option<String>, // low, high, unable-to-assess
+    #[serde(rename = "type")]
+    pub allergyType: Option<String>, // allergy or intolerance
+    pub patient: Reference,          // patient reference
+    pub recordedDate: String,        // when the allergy was recorded
+}
+
+// Request for patient demographics
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PatientRequest {
+    pub resourceType: String,        // Should be "Patient"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,          // assigned by the server when absent
+    #[serde(default)]
+    pub name: Vec<HumanName>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub birthDate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gender: Option<String>,      // male, female, other, unknown
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HumanName {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub family: Option<String>,
+    #[serde(default)]
+    pub given: Vec<String>,
+}
+
+impl From<&Patient> for PatientRequest {
+    fn from(patient: &Patient) -> Self {
+        let name = if patient.family_name.is_some() || !patient.given_names.is_empty() {
+            vec![HumanName {
+                family: patient.family_name.clone(),
+                given: patient.given_names.clone(),
+            }]
+        } else {
+            Vec::new()
+        };
+        
+        PatientRequest {
+            resourceType: "Patient".to_string(),
+            id: Some(patient.id.clone()),
+            name,
+            birthDate: patient.birth_date.clone(),
+            gender: patient.gender.clone(),
+        }
+    }
+}
+
+// Request for a correlation matrix between metrics
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CorrelationRequest {
+    pub metrics: Vec<String>,
+    pub start: i64,
+    pub end: i64,
+}
+
+// Request for several metrics over the same time range
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchQueryRequest {
+    pub metrics: Vec<String>,
+    pub start: i64,
+    pub end: i64,
+    pub aggregation: Option<Aggregation>,
+    pub interval: Option<u64>, // Aggregation bucket in seconds
+}
+
+// Request for several metrics aligned on one time grid
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MatrixRequest {
+    pub metrics: Vec<String>,
+    pub start: i64,
+    pub end: i64,
+    pub interval: serde_json::Value, // Seconds or a duration such as "5m"
+    pub aggregation: Option<Aggregation>, // Per grid cell, mean by default
+}
+
+// Request for a point-in-time snapshot of the store
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotRequest {
+    pub destination: String,
+}
+
 // Add this new request struct after the existing request structs
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FHIRBundle {
@@ -145,6 +355,8 @@ pub struct BundleEntry {
 pub struct BundleRequest {
     pub method: String,
     pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ifNoneExist: Option<String>, // Search criteria making the create conditional
 }
 
 // Add this request struct near the other request structs
@@ -157,11 +369,105 @@ pub struct DebugSettings {
 
 pub struct RestApi {
     query_engine: Arc<QueryEngine>,
+    auth_token: Option<Arc<str>>,
+    idempotency: Arc<IdempotencyStore>,
+    conditional_creates: Arc<Mutex<()>>, // Held from an If-None-Exist lookup until its create is stored
+    strict_body: bool,
+    query_window: QueryWindow,
+}
+
+/// Default and widest time span of a query, see `RestApi::with_query_window`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueryWindow {
+    pub default_span: Option<std::time::Duration>, // Span before `end` of a query giving no start; each endpoint's own default if unset
+    pub max_span: Option<std::time::Duration>,     // Wider queries need `allow_full_scan=true`; unbounded if unset
+}
+
+/// How far back `start` reaches when neither the query nor `QueryWindow` sets it
+const DEFAULT_START_SPAN_SECS: i64 = 86400;
+
+impl QueryWindow {
+    /// Start of a query without one: `default_span` before `end`, or `fallback` if that isn't configured
+    fn default_start(&self, end: i64, fallback: i64) -> i64 {
+        match self.default_span {
+            Some(span) => end.saturating_sub(i64::try_from(span.as_secs()).unwrap_or(i64::MAX)),
+            None => fallback,
+        }
+    }
+    
+    /// Reject `start..end` when it is wider than `max_span`, unless `allow_full_scan=true` was passed
+    fn check_span(&self, start: i64, end: i64, params: &std::collections::HashMap<String, String>) 
+        -> Result<(), QueryError> 
+    {
+        let Some(max_span) = self.max_span else {
+            return Ok(());
+        };
+        if params.get("allow_full_scan").is_some_and(|value| value == "true") {
+            return Ok(());
+        }
+        let span = i128::from(end) - i128::from(start);
+        if span > i128::from(max_span.as_secs()) {
+            return Err(QueryError::InvalidTimeRange(format!(
+                "Query spans {}s, more than the maximum of {}s; narrow it or pass allow_full_scan=true",
+                span, max_span.as_secs()
+            )));
+        }
+        Ok(())
+    }
 }
 
+/// Rejection for requests without the configured bearer token
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Rejection for a `start` or `end` query parameter that isn't a time
+#[derive(Debug)]
+struct InvalidTimeBound(String);
+
+impl warp::reject::Reject for InvalidTimeBound {}
+
+/// Rejection for a create whose `Idempotency-Key` another running request holds
+#[derive(Debug)]
+struct IdempotencyKeyInUse;
+
+impl warp::reject::Reject for IdempotencyKeyInUse {}
+
+/// Rejection for a query spanning more than `QueryWindow::max_span`
+#[derive(Debug)]
+struct QueryTooWide(String);
+
+impl warp::reject::Reject for QueryTooWide {}
+
 impl RestApi {
     pub fn new(query_engine: Arc<QueryEngine>) -> Self {
-        RestApi { query_engine }
+        RestApi {
+            query_engine,
+            auth_token: None,
+            idempotency: Arc::new(IdempotencyStore::default()),
+            conditional_creates: Arc::default(),
+            strict_body: false,
+            query_window: QueryWindow::default(),
+        }
+    }
+    
+    /// Require `Authorization: Bearer <token>` on every route; `None` leaves the API open
+    pub fn with_auth_token(mut self, token: Option<String>) -> Self {
+        self.auth_token = token.map(Arc::from);
+        self
+    }
+    
+    /// Reject FHIR resources carrying elements the server would ignore, instead of dropping them
+    pub fn with_strict_body(mut self, strict: bool) -> Self {
+        self.strict_body = strict;
+        self
+    }
+    
+    /// Default span of queries that don't bound their start, and the widest span allowed without `allow_full_scan=true`
+    pub fn with_query_window(mut self, window: QueryWindow) -> Self {
+        self.query_window = window;
+        self
     }
 
     pub fn routes(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
@@ -176,28 +482,87 @@ impl RestApi {
                         ),
                         "Access-Control-Allow-Methods", "GET, POST, OPTIONS"
                     ),
-                    "Access-Control-Allow-Headers", "Content-Type"
+                    "Access-Control-Allow-Headers", "Content-Type, Authorization, Idempotency-Key, If-None-Exist"
                 )
             });
         
-        // Basic CRUD endpoints
-        cors_options
-            .or(self.get_observation())
-            .or(self.post_observation())
-            .or(self.post_bundle())  // Add the new bundle endpoint
-            .or(self.get_patient())
+        // Creates replay their first response when retried with the same Idempotency-Key
+        let create_routes = self.post_observation()
+            .or(self.post_bundle())
+            .or(self.post_import())
+            .or(self.post_patient())
             .or(self.post_medication_administration())
             .or(self.post_device_observation())
             .or(self.post_vital_signs())
+            .or(self.post_condition())
+            .or(self.post_encounter())
+            .or(self.post_allergy_intolerance())
+            .or(self.post_internal_records())
+            .map(warp::Reply::into_response)
+            .boxed();
+        
+        // Basic CRUD endpoints; only CORS preflight skips auth
+        let fhir_routes = self.get_metadata()
+            .or(self.get_observation())
+            .or(self.stream_observations())
+            .or(self.get_latest_observations())
+            .or(self.get_sampled_observation())
+            .or(self.get_observation_stats())
+            .or(self.idempotent(create_routes))
+            .or(self.get_patient())
+            .or(self.get_patient_everything())
+            .or(self.get_device_observations())
+            .or(self.search_medication_administrations())
+            .or(self.count_resources_by_type())
             .or(self.get_resource_by_type())
-            .or(self.debug_metrics())
+            // Last, so the named routes above take precedence over ids
+            .or(self.read_resource())
+            .map(warp::Reply::into_response)
+            .boxed();
+        
+        let other_routes = self.debug_metrics()
+            .or(self.debug_metric_counts())
+            .or(self.debug_stats())
+            .or(self.debug_chunks())
+            .or(self.debug_chunk_summary())
+            .or(self.ws_subscribe())
             .or(self.get_time_chunked())
             // Time-series analysis endpoints
             .or(self.get_trend_analysis())
+            .or(self.get_range())
+            .or(self.get_count())
+            .or(self.post_batch_query())
+            .or(self.post_matrix())
             .or(self.get_stats())
+            .or(self.get_stats_bucketed())
             .or(self.get_outliers())
             .or(self.get_rate_of_change())
+            .or(self.get_rate_alerts())
+            .or(self.get_smooth())
+            .or(self.export_csv())
+            .or(self.get_autocorrelation())
+            .or(self.get_histogram())
+            .or(self.get_flatline())
+            .or(self.post_correlation())
+            .or(self.get_changepoints())
+            .or(self.post_detection_config())
+            .or(self.post_snapshot())
+            .or(self.get_verify())
+            .or(self.post_flush())
+            .or(self.post_compact())
             .or(self.debug_settings())
+            .map(warp::Reply::into_response)
+            .boxed();
+        
+        // Boxed in groups so the chain, wrapped in auth, stays within the type recursion limit
+        let api_routes = fhir_routes.or(other_routes).unify().boxed();
+        
+        cors_options
+            .or(self.authorized().and(api_routes))
+            .recover(handle_unauthorized)
+            .recover(handle_idempotency_key_in_use)
+            .recover(handle_invalid_body)
+            .recover(handle_invalid_time_bound)
             .map(|reply| {
                 // Add CORS headers to all responses
                 with_header(
@@ -208,479 +573,740 @@ impl RestApi {
                         ),
                         "Access-Control-Allow-Methods", "GET, POST, OPTIONS"
                     ),
-                    "Access-Control-Allow-Headers", "Content-Type"
+                    "Access-Control-Allow-Headers", "Content-Type, Authorization, Idempotency-Key, If-None-Exist"
                 )
             })
     }
+    
+    /// Serve `routes`, remembering each keyed response in the idempotency store
+    ///
+    /// A POST repeating a stored `Idempotency-Key` for the same path and body gets
+    /// the stored response back without reaching `routes`; with another body it
+    /// gets a 422. The key is reserved while its first request runs, so a retry
+    /// racing it gets a 409 instead of storing twice. Server errors are not
+    /// stored, so those retries run again.
+    fn idempotent(&self, routes: BoxedFilter<(warp::reply::Response,)>)
+        -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone
+    {
+        let known_store = Arc::clone(&self.idempotency);
+        let replay_store = Arc::clone(&self.idempotency);
+        // Only a known key reads the body here; otherwise `routes` still needs it
+        let replay = warp::post()
+            .and(warp::path::full())
+            .and(warp::header::optional::<String>("idempotency-key"))
+            .and_then(move |path: warp::path::FullPath, key: Option<String>| {
+                let store = Arc::clone(&known_store);
+                async move {
+                    key.map(|key| idempotency_scope(&path, &key))
+                        .filter(|scope| store.contains(scope))
+                        .ok_or_else(warp::reject::not_found)
+                }
+            })
+            .and(warp::body::bytes())
+            .map(move |scope: String, body: warp::hyper::body::Bytes| {
+                match replay_store.lookup(&scope, &body) {
+                    Lookup::Replay(response) => response,
+                    Lookup::BodyMismatch => idempotency_error_reply(
+                        ErrorCode::IdempotencyKeyMismatch,
+                        "Idempotency-Key was already used with a different request body",
+                    ),
+                    Lookup::InFlight | Lookup::Unknown => idempotency_key_in_use_reply(),
+                }
+            });
+        
+        let reserve_store = Arc::clone(&self.idempotency);
+        let record = warp::method()
+            .and(warp::path::full())
+            .and(warp::header::optional::<String>("idempotency-key"))
+            .and_then(move |method: warp::http::Method, path: warp::path::FullPath, key: Option<String>| {
+                let store = Arc::clone(&reserve_store);
+                async move {
+                    let Some(key) = key.filter(|_| method == warp::http::Method::POST) else {
+                        return Ok(None);
+                    };
+                    match store.reserve(idempotency_scope(&path, &key)) {
+                        Some(reservation) => Ok(Some(reservation)),
+                        None => Err(warp::reject::custom(IdempotencyKeyInUse)),
+                    }
+                }
+            })
+            .and(routes)
+            .and_then(|reservation: Option<Reservation>, response: warp::reply::Response| async move {
+                let Some(reservation) = reservation.filter(|_| !response.status().is_server_error()) else {
+                    return Ok::<_, warp::Rejection>(response);
+                };
+                let (parts, body) = response.into_parts();
+                let body = match warp::hyper::body::to_bytes(body).await {
+                    Ok(body) => body,
+                    Err(e) => {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::Internal),
+                            message: format!("Failed to buffer response: {}", e),
+                            data: None,
+                        };
+                        return Ok(warp::reply::with_status(
+                            warp::reply::json(&response),
+                            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                        ).into_response());
+                    }
+                };
+                reservation.complete(parts.status, parts.headers.clone(), body.clone());
+                Ok(warp::reply::Response::from_parts(parts, body.into()))
+            });
+        
+        replay.or(record).unify()
+    }
+    
+    /// Body of a create, noted against its `Idempotency-Key` for comparison with retries
+    fn create_body(&self) -> impl Filter<Extract = (warp::hyper::body::Bytes,), Error = warp::Rejection> + Clone {
+        let store = Arc::clone(&self.idempotency);
+        warp::path::full()
+            .and(warp::header::optional::<String>("idempotency-key"))
+            .and(warp::body::bytes())
+            .map(move |path: warp::path::FullPath, key: Option<String>, body: warp::hyper::body::Bytes| {
+                if let Some(key) = key {
+                    store.note_body(&idempotency_scope(&path, &key), &body);
+                }
+                body
+            })
+    }
+    
+    /// Passes when no token is configured or the request carries the right bearer token
+    fn authorized(&self) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+        let auth_token = self.auth_token.clone();
+        
+        warp::header::optional::<String>("authorization")
+            .and_then(move |header: Option<String>| {
+                let auth_token = auth_token.clone();
+                async move {
+                    let Some(expected) = auth_token else {
+                        return Ok(());
+                    };
+                    let provided = header.as_deref().and_then(|h| h.strip_prefix("Bearer "));
+                    if provided.is_some_and(|token| tokens_match(token.trim(), &expected)) {
+                        Ok(())
+                    } else {
+                        Err(warp::reject::custom(Unauthorized))
+                    }
+                }
+            })
+            .untuple_one()
+    }
 
     fn get_observation(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         let query_engine = Arc::clone(&self.query_engine);
+        let query_window = self.query_window;
         
         warp::path!("fhir" / "Observation")
             .and(warp::get())
-            .and(warp::query::<std::collections::HashMap<String, String>>())
-            .and_then(move |params: std::collections::HashMap<String, String>| {
+            .and(warp::query::<Vec<(String, String)>>())
+            .and_then(move |raw_params: Vec<(String, String)>| {
                 let query_engine = Arc::clone(&query_engine);
                 async move {
+                    // value-quantity may repeat, so the filter is read before collapsing the params
+                    let record_filter = match record_filter_from_params(&raw_params) {
+                        Ok(filter) => filter,
+                        Err(e) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: e.to_string(),
+                                data: None,
+                            };
+                            return Ok::<_, Infallible>(warp::reply::json(&response).into_response());
+                        }
+                    };
+                    let params: std::collections::HashMap<String, String> = raw_params.into_iter().collect();
+                    let render = match RenderOptions::from_params(&params) {
+                        Ok(render) => render,
+                        Err(e) => return Ok(bad_request_reply(e)),
+                    };
+                    
                     // Extract patient and code from query params if available
                     let patient = params.get("patient");
                     let code = params.get("code");
                     
-                    if let (Some(patient_id), Some(code_value)) = (patient, code) {
+                    let metric_pattern = match (patient, code) {
                         // Format metric name with a wildcard for the unit part
-                        let metric_pattern = format!("{}|{}|", patient_id, code_value);
-                        
+                        (Some(patient_id), Some(code_value)) => Some(format!("{}|{}|", patient_id, code_value)),
+                        // Only observations carry a category or performer, so searching by one may span every code
+                        (Some(patient_id), None) if record_filter.category.is_some() || !record_filter.context_filter.is_empty() => {
+                            Some(format!("{}|", patient_id))
+                        }
+                        _ => None,
+                    };
+                    
+                    if let Some(metric_pattern) = metric_pattern {
                         println!("Querying metric pattern: {}", metric_pattern);
                         
+                        if !record_filter.is_empty() {
+                            let (start_time, end_time) = time_bounds_from_params(&params, query_window);
+                            if let Err(e) = query_window.check_span(start_time, end_time, &params) {
+                                return Ok(bad_request_reply(e));
+                            }
+                            let response = match query_metrics_with_filter(
+                                &query_engine, query_engine.get_matching_metrics(&metric_pattern),
+                                start_time, end_time, &record_filter,
+                            ) {
+                                Ok(records) => ApiResponse {
+                                    status: ResponseStatus::Success,
+                                    error_code: None,
+                                    message: format!("Found {} matching observations", records.len()),
+                                    data: Some(render.records(&records)),
+                                },
+                                Err(e) => ApiResponse {
+                                    status: ResponseStatus::Error,
+                                    error_code: Some(ErrorCode::from(&e)),
+                                    message: format!("Error querying observations: {:?}", e),
+                                    data: None,
+                                },
+                            };
+                            return Ok(warp::reply::json(&response).into_response());
+                        }
+                        
                         // Query for records with this metric prefix
                         match query_engine.get_metrics_by_prefix(&metric_pattern) {
                             Ok(Some(record)) => {
+                                // A component comes back with the rest of its observation
+                                let data = match observation_components(&query_engine, &record) {
+                                    Ok(components) if !components.is_empty() => render.records(&components)
+                                        .as_array()
+                                        .and_then(|panels| panels.first().cloned())
+                                        .unwrap_or_else(|| render.record(&record)),
+                                    _ => render.record(&record),
+                                };
                                 let response = ApiResponse {
-                                    status: "success".to_string(),
+                                    status: ResponseStatus::Success,
+                                    error_code: None,
                                     message: "Observation found".to_string(),
-                                    data: Some(format_record_for_api(&record)),
+                                    data: Some(data),
                                 };
-                                Ok::<Json, Infallible>(warp::reply::json(&response))
+                                Ok::<_, Infallible>(warp::reply::json(&response).into_response())
                             },
                             Ok(None) => {
                                 let response = ApiResponse {
-                                    status: "error".to_string(),
+                                    status: ResponseStatus::Error,
+                                    error_code: Some(ErrorCode::NotFound),
                                     message: "No observations found".to_string(), 
                                     data: None,
                                 };
-                                Ok::<Json, Infallible>(warp::reply::json(&response))
+                                Ok::<_, Infallible>(warp::reply::json(&response).into_response())
                             },
                             Err(e) => {
                                 let response = ApiResponse {
-                                    status: "error".to_string(),
+                                    status: ResponseStatus::Error,
+                                    error_code: Some(ErrorCode::from(&e)),
                                     message: format!("Error querying observations: {:?}", e),
                                     data: None,
                                 };
-                                Ok::<Json, Infallible>(warp::reply::json(&response))
+                                Ok::<_, Infallible>(warp::reply::json(&response).into_response())
                             }
                         }
                     } else {
                         // Return all observations (not implemented yet)
                         let response = ApiResponse {
-                            status: "error".to_string(),
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::NotImplemented),
                             message: "Listing all observations not implemented yet".to_string(),
                             data: None,
                         };
-                        Ok::<Json, Infallible>(warp::reply::json(&response))
+                        Ok::<_, Infallible>(warp::reply::json(&response).into_response())
                     }
                 }
             })
     }
 
-    async fn handle_observation_request(
-        observation: FHIRObservationRequest, 
-        query_engine: Arc<QueryEngine>
-    ) -> Result<impl warp::Reply, Infallible> {
-        // Parse the timestamp
-        let timestamp = match parse_iso8601_to_unix(&observation.effectiveDateTime) {
-            Ok(ts) => ts,
-            Err(_) => {
-                let response = ApiResponse {
-                    status: "error".to_string(),
-                    message: "Invalid timestamp format".to_string(),
-                    data: None,
-                };
-                return Ok(warp::reply::json(&response));
-            }
-        };
-        
-        // Extract patient ID
-        let patient_id = observation.subject.reference.replace("Patient/", "");
-        
-        // Extract device ID if present
-        let device_id = observation.device.as_ref().map(|dev| dev.reference.replace("Device/", ""));
-        
-        // Get the main code
-        let coding = &observation.code.coding[0];
-        let code = coding.code.clone();
-        
-        // Create the appropriate FHIR Observation based on which value field is present
-        let fhir_observation = if let Some(value_quantity) = &observation.valueQuantity {
-            // Numeric observation
-            FHIRObservation::Numeric {
-                code,
-                value: value_quantity.value,
-                unit: value_quantity.unit.clone(),
-                timestamp,
-                patient_id: patient_id.clone(),
-                device_id: device_id.clone(),
-            }
-        } else if let Some(components) = &observation.component {
-            // Component observation
-            let mut observation_components = Vec::new();
-            
-            for component in components {
-                let comp_coding = &component.code.coding[0];
-                let comp_value = &component.valueQuantity;
-                
-                observation_components.push(ObservationComponent {
-                    code: comp_coding.code.clone(),
-                    value: comp_value.value,
-                    unit: comp_value.unit.clone(),
-                });
-            }
-            
-            FHIRObservation::Component {
-                code,
-                components: observation_components,
-                timestamp,
-                patient_id: patient_id.clone(),
-                device_id: device_id.clone(),
-            }
-        } else if let Some(sampled_data) = &observation.valueSampledData {
-            // Sampled data observation
-            // Parse the space-separated data values
-            let values: Vec<f64> = sampled_data.data
-                .split_whitespace()
-                .filter_map(|s| s.parse::<f64>().ok())
-                .collect();
-                
-            FHIRObservation::SampledData {
-                code,
-                period: sampled_data.period,
-                factor: sampled_data.factor.unwrap_or(1.0),
-                data: values,
-                start_time: timestamp,
-                patient_id: patient_id.clone(),
-                device_id: device_id.clone(),
-            }
-        } else {
-            // No known value type
-            let response = ApiResponse {
-                status: "error".to_string(),
-                message: "No valid observation value provided".to_string(),
-                data: None,
-            };
-            return Ok(warp::reply::json(&response));
-        };
-        
-        // Convert to records and store
-        let records = fhir_observation.to_records();
-        println!("Storing observation with metric names: {:?}", 
-                records.iter().map(|r| &r.metric_name).collect::<Vec<_>>());
-        
-        for record in records {
-            if let Err(err) = query_engine.store_record(record) {
-                let response = ApiResponse {
-                    status: "error".to_string(),
-                    message: format!("Failed to store observation: {:?}", err),
-                    data: None,
-                };
-                return Ok(warp::reply::json(&response));
-            }
-        }
-        
-        let response = ApiResponse {
-            status: "success".to_string(),
-            message: "Observation stored successfully".to_string(),
-            data: Some(serde_json::to_value(observation).unwrap()),
-        };
-        Ok(warp::reply::json(&response))
-    }
-
-    fn post_observation(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        let query_engine = Arc::clone(&self.query_engine);
-        
-        warp::path!("fhir" / "Observation")
-            .and(warp::post())
-            .and(warp::body::json())
-            .and_then(move |observation: FHIRObservationRequest| {
-                let query_engine = Arc::clone(&query_engine);
-                async move {
-                    Self::handle_observation_request(observation, query_engine).await
-                }
-            })
-    }
-
-    fn get_patient(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path!("fhir" / "Patient")
+    /// FHIR capability discovery; returns the CapabilityStatement itself, unwrapped
+    fn get_metadata(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("fhir" / "metadata")
             .and(warp::get())
             .map(|| {
-                let response = ApiResponse {
-                    status: "error".to_string(),
-                    message: "Patient resource not implemented yet".to_string(),
-                    data: None,
-                };
-                warp::reply::json(&response)
+                with_header(
+                    warp::reply::json(&crate::fhir::capability::capability_statement()),
+                    "Content-Type", "application/fhir+json",
+                )
             })
     }
 
-    // New method to query resources by type
-    fn get_resource_by_type(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    /// The last `n` observations of a metric, newest first
+    fn get_latest_observations(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         let query_engine = Arc::clone(&self.query_engine);
         
-        warp::path!("fhir" / "resources" / String)
+        warp::path!("fhir" / "Observation" / "latest")
             .and(warp::get())
             .and(warp::query::<std::collections::HashMap<String, String>>())
-            .and_then(move |resource_type: String, params: std::collections::HashMap<String, String>| {
+            .and_then(move |params: std::collections::HashMap<String, String>| {
                 let query_engine = Arc::clone(&query_engine);
                 async move {
-                    // Get time range from query params, with defaults
-                    let now = chrono::Utc::now().timestamp();
-                    let start_time = params.get("_since")
-                        .and_then(|s| s.parse::<i64>().ok())
-                        .unwrap_or(0); // Default to all records (timestamp 0)
-                    
-                    let end_time = params.get("_until")
-                        .and_then(|s| s.parse::<i64>().ok())
-                        .unwrap_or(now);
-                    
-                    // Query by resource type
-                    match query_engine.query_by_resource_type(&resource_type, start_time, end_time) {
-                        Ok(records) => {
+                    let metric = match params.get("metric") {
+                        Some(metric) => metric.clone(),
+                        None => {
                             let response = ApiResponse {
-                                status: "success".to_string(),
-                                message: format!("Found {} records for {}", records.len(), resource_type),
-                                data: Some(serde_json::to_value(format_records_for_api(&records)).unwrap()),
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::MissingParameter),
+                                message: "Missing required 'metric' parameter".to_string(),
+                                data: None,
                             };
-                            Ok::<Json, Infallible>(warp::reply::json(&response))
-                        },
-                        Err(_) => {
+                            return Ok::<_, Infallible>(warp::reply::json(&response).into_response());
+                        }
+                    };
+                    
+                    let render = match RenderOptions::from_params(&params) {
+                        Ok(render) => render,
+                        Err(e) => return Ok(bad_request_reply(e)),
+                    };
+                    
+                    let n = match params.get("n").map(|n| n.parse::<usize>()) {
+                        None => 1,
+                        Some(Ok(n)) if n > 0 => n,
+                        Some(_) => {
                             let response = ApiResponse {
-                                status: "error".to_string(),
-                                message: format!("No records found for {}", resource_type),
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::InvalidParameter),
+                                message: "'n' must be a positive integer".to_string(),
                                 data: None,
                             };
-                            Ok::<Json, Infallible>(warp::reply::json(&response))
+                            return Ok(warp::reply::json(&response).into_response());
                         }
-                    }
+                    };
+                    
+                    let response = match query_engine.query_latest_n(&metric, n) {
+                        Ok(records) => ApiResponse {
+                            status: ResponseStatus::Success,
+                            error_code: None,
+                            message: format!("Found {} latest observations", records.len()),
+                            data: Some(render.records(&records)),
+                        },
+                        Err(e) => ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::from(&e)),
+                            message: format!("Error querying latest observations: {:?}", e),
+                            data: None,
+                        },
+                    };
+                    
+                    Ok(warp::reply::json(&response).into_response())
                 }
             })
     }
 
-    // Debug endpoint to see all metrics and resource types
-    fn debug_metrics(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    /// Reassemble the samples of a SampledData observation stored between `start` and `end`
+    fn get_sampled_observation(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         let query_engine = Arc::clone(&self.query_engine);
         
-        warp::path!("debug" / "metrics")
+        warp::path!("fhir" / "Observation" / "sampled")
             .and(warp::get())
-            .and_then(move || {
+            .and(time_query(self.query_window))
+            .and_then(move |params: std::collections::HashMap<String, String>| {
                 let query_engine = Arc::clone(&query_engine);
                 async move {
-                    // Get internal data about metrics and resources
-                    let debug_info = query_engine.debug_metrics().unwrap_or_default();
+                    let (Some(patient_id), Some(code)) = (params.get("patient"), params.get("code")) else {
+                        return Ok::<_, Infallible>(bad_request_reply(QueryError::InvalidParameter(
+                            "Missing required parameters: patient and code".to_string()
+                        )));
+                    };
                     
-                    let response = ApiResponse {
-                        status: "success".to_string(),
-                        message: "Debug metrics info".to_string(),
-                        data: Some(serde_json::to_value(debug_info).unwrap()),
+                    let (start_time, end_time) = query_bounds(&params);
+                    
+                    let metric = format!("{}|{}|sampled", patient_id, code);
+                    let records = match query_engine.query_range_filtered(&metric, start_time, end_time, |_| true) {
+                        Ok(records) if !records.is_empty() => records,
+                        Ok(_) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::NotFound),
+                                message: format!("No sampled data for {} between {} and {}", metric, start_time, end_time),
+                                data: None,
+                            };
+                            return Ok(warp::reply::with_status(
+                                warp::reply::json(&response),
+                                warp::http::StatusCode::NOT_FOUND,
+                            ).into_response());
+                        }
+                        Err(e) => return Ok(bad_request_reply(e)),
                     };
-                    Ok::<Json, Infallible>(warp::reply::json(&response))
+                    
+                    let response = match FHIRObservation::from_records(&records) {
+                        Ok(FHIRObservation::SampledData { code, period, factor, data, start_time, patient_id, device_id, .. }) => {
+                            let mut observation = json!({
+                                "resourceType": "Observation",
+                                "status": "final",
+                                "code": { "coding": [{ "system": "http://loinc.org", "code": code }] },
+                                "subject": { "reference": format!("Patient/{}", patient_id) },
+                                "effectiveDateTime": chrono::DateTime::from_timestamp(start_time, 0)
+                                    .map(|dt| dt.to_rfc3339())
+                                    .unwrap_or_default(),
+                                "valueSampledData": {
+                                    "period": period,
+                                    "factor": factor,
+                                    "dimensions": 1,
+                                    "data": data,
+                                },
+                            });
+                            if let Some(device_id) = device_id {
+                                observation["device"] = json!({ "reference": format!("Device/{}", device_id) });
+                            }
+                            ApiResponse {
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Reassembled {} samples", records.len()),
+                                data: Some(observation),
+                            }
+                        }
+                        Ok(other) => ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidResource),
+                            message: format!("Records of {} are not sampled data: {:?}", metric, other),
+                            data: None,
+                        },
+                        Err(e) => ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::from(&e)),
+                            message: format!("Failed to reassemble sampled data: {:?}", e),
+                            data: None,
+                        },
+                    };
+                    Ok(warp::reply::json(&response).into_response())
                 }
             })
     }
 
-    // New endpoint for time-chunked queries
-    fn get_time_chunked(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    /// Observation $stats operation: selected statistics of a patient's code as a Parameters resource
+    ///
+    /// `statistic` takes a comma-separated list and defaults to `average,min,max`.
+    fn get_observation_stats(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         let query_engine = Arc::clone(&self.query_engine);
         
-        warp::path!("fhir" / "timeseries")
+        warp::path!("fhir" / "Observation" / "$stats")
             .and(warp::get())
-            .and(warp::query::<std::collections::HashMap<String, String>>())
+            .and(time_query(self.query_window))
             .and_then(move |params: std::collections::HashMap<String, String>| {
                 let query_engine = Arc::clone(&query_engine);
                 async move {
-                    // Extract parameters
-                    let resource_type = params.get("resource_type").map(|s| s.to_string()).unwrap_or("Observation".to_string());
-                    
-                    // Parse time parameters
-                    let now = chrono::Utc::now().timestamp();
-                    let start_time = params.get("start")
-                        .and_then(|s| s.parse::<i64>().ok())
-                        .unwrap_or(now - 86400); // Default to last 24 hours
-                    
-                    let end_time = params.get("end")
-                        .and_then(|s| s.parse::<i64>().ok())
-                        .unwrap_or(now);
+                    let (Some(patient_id), Some(code)) = (params.get("patient"), params.get("code")) else {
+                        return Ok::<_, Infallible>(bad_request_reply(QueryError::InvalidParameter(
+                            "Missing required parameters: patient and code".to_string()
+                        )));
+                    };
+                    let statistics: Vec<&str> = params.get("statistic")
+                        .map(|s| s.split(',').map(str::trim).filter(|s| !s.is_empty()).collect())
+                        .unwrap_or_else(|| vec!["average", "min", "max"]);
+                    if statistics.is_empty() {
+                        return Ok(bad_request_reply(QueryError::InvalidParameter(
+                            "statistic must name at least one statistic".to_string()
+                        )));
+                    }
                     
-                    // Parse chunk size (in seconds)
-                    let chunk_size = params.get("chunk_size")
-                        .and_then(|s| s.parse::<u64>().ok())
-                        .unwrap_or(3600); // Default to 1 hour
+                    let (start_time, end_time) = query_bounds(&params);
                     
-                    // Query with time chunking
-                    match query_engine.query_time_chunked(&resource_type, start_time, end_time, chunk_size) {
-                        Ok(chunks) => {
-                            // Transform each chunk to have better-formatted records
-                            let formatted_chunks: Vec<serde_json::Value> = chunks.iter().map(|chunk| {
-                                serde_json::json!({
-                                    "start_time": chunk.start_time,
-                                    "end_time": chunk.end_time,
-                                    "records": format_records_for_api(&chunk.records)
-                                })
-                            }).collect();
-                            
-                            let response = ApiResponse {
-                                status: "success".to_string(),
-                                message: format!("Found data in {} time chunks", chunks.len()),
-                                data: Some(serde_json::to_value(formatted_chunks).unwrap()),
-                            };
-                            Ok::<Json, Infallible>(warp::reply::json(&response))
-                        },
-                        Err(_e) => {
+                    // Like a read, the first unit stored for the code answers
+                    let metric = match query_engine.get_matching_metrics(&format!("{}|{}|", patient_id, code)) {
+                        Ok(metrics) => metrics.into_iter().next(),
+                        Err(e) => return Ok(bad_request_reply(e)),
+                    };
+                    let stats = match metric.map(|metric| query_engine.calculate_stats(&metric, start_time, end_time, timeout_from_params(&params))) {
+                        Some(Ok(stats)) if stats.count > 0 => stats,
+                        Some(Err(e)) => return Ok(bad_request_reply(e)),
+                        _ => {
                             let response = ApiResponse {
-                                status: "error".to_string(),
-                                message: "Error querying time chunks".to_string(),
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::NotFound),
+                                message: format!("No observations of {} for patient {} between {} and {}",
+                                                 code, patient_id, start_time, end_time),
                                 data: None,
                             };
-                            Ok::<Json, Infallible>(warp::reply::json(&response))
+                            return Ok(warp::reply::with_status(
+                                warp::reply::json(&response),
+                                warp::http::StatusCode::NOT_FOUND,
+                            ).into_response());
                         }
-                    }
+                    };
+                    
+                    let parameter = match statistics.iter()
+                        .map(|name| statistic_parameter(&stats, name))
+                        .collect::<Result<Vec<_>, _>>()
+                    {
+                        Ok(parameter) => parameter,
+                        Err(e) => return Ok(bad_request_reply(e)),
+                    };
+                    let response = ApiResponse {
+                        status: ResponseStatus::Success,
+                        error_code: None,
+                        message: format!("Statistics over {} observations of {} for patient {}", stats.count, code, patient_id),
+                        data: Some(json!({
+                            "resourceType": "Parameters",
+                            "parameter": parameter,
+                        })),
+                    };
+                    Ok(warp::reply::json(&response).into_response())
                 }
             })
     }
 
-    fn post_medication_administration(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    /// Stream matching observations as NDJSON, one chunk at a time
+    fn stream_observations(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         let query_engine = Arc::clone(&self.query_engine);
+        let query_window = self.query_window;
         
-        warp::path!("fhir" / "MedicationAdministration")
-            .and(warp::post())
-            .and(warp::body::json())
-            .and_then(move |request: MedicationAdministrationRequest| {
+        warp::path!("fhir" / "Observation" / "_stream")
+            .and(warp::get())
+            .and(warp::query::<std::collections::HashMap<String, String>>())
+            .and_then(move |params: std::collections::HashMap<String, String>| {
                 let query_engine = Arc::clone(&query_engine);
                 async move {
-                    // Validate resource type
-                    if request.resourceType != "MedicationAdministration" {
-                        let response = ApiResponse {
-                            status: "error".to_string(),
-                            message: "Invalid resource type".to_string(),
-                            data: None,
-                        };
-                        return Ok::<Json, Infallible>(warp::reply::json(&response));
-                    }
-                    
-                    // Parse timestamp
-                    let timestamp = match parse_iso8601_to_unix(&request.effectiveDateTime) {
-                        Ok(ts) => ts,
-                        Err(_) => {
+                    let metric_pattern = match (params.get("patient"), params.get("code")) {
+                        (Some(patient_id), Some(code_value)) => format!("{}|{}|", patient_id, code_value),
+                        _ => {
                             let response = ApiResponse {
-                                status: "error".to_string(),
-                                message: "Invalid timestamp format".to_string(),
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::MissingParameter),
+                                message: "Streaming requires both patient and code parameters".to_string(),
                                 data: None,
                             };
-                            return Ok(warp::reply::json(&response));
+                            return Ok::<_, Infallible>(warp::reply::json(&response).into_response());
                         }
                     };
                     
-                    // Extract patient ID
-                    let patient_id = request.subject.reference.replace("Patient/", "");
-                    
-                    // Extract practitioner ID if present
-                    let practitioner_id = request.performer.as_ref()
-                        .map(|performer| performer.reference.replace("Practitioner/", ""));
-                    
-                    // Extract medication information
-                    let coding = &request.medication.coding[0];
-                    
-                    // Create MedicationAdministration
-                    let med_administration = MedicationAdministration {
-                        medication_code: coding.code.clone(),
-                        medication_display: coding.display.clone(),
-                        dose_value: request.dosage.value,
-                        dose_unit: request.dosage.unit.clone(),
-                        route: request.route.display.clone(),
-                        timestamp,
-                        patient_id,
-                        practitioner_id,
-                        status: request.status.clone(),
-                    };
-                    
-                    // Convert to records and store
-                    let records = med_administration.to_records();
-                    println!("Storing medication administration with metric name: {:?}", 
-                            records.iter().map(|r| &r.metric_name).collect::<Vec<_>>());
+                    let (start_time, end_time) = time_bounds_from_params(&params, query_window);
+                    if let Err(e) = query_window.check_span(start_time, end_time, &params) {
+                        return Ok(bad_request_reply(e));
+                    }
+                    if start_time >= end_time {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidTimeRange),
+                            message: "Start time must be before end time".to_string(),
+                            data: None,
+                        };
+                        return Ok(warp::reply::json(&response).into_response());
+                    }
                     
-                    for record in records {
-                        if let Err(err) = query_engine.store_record(record) {
+                    let metrics = match query_engine.get_matching_metrics(&metric_pattern) {
+                        Ok(metrics) => metrics,
+                        Err(e) => {
                             let response = ApiResponse {
-                                status: "error".to_string(),
-                                message: format!("Failed to store medication administration: {:?}", err),
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Error querying observations: {:?}", e),
                                 data: None,
                             };
-                            return Ok(warp::reply::json(&response));
+                            return Ok(warp::reply::json(&response).into_response());
                         }
-                    }
+                    };
                     
-                    let response = ApiResponse {
-                        status: "success".to_string(),
-                        message: "Medication administration stored successfully".to_string(),
-                        data: Some(serde_json::to_value(request).unwrap()),
+                    let body = stream_records_body(query_engine, metrics, start_time, end_time, None, |record| {
+                        let mut line = serde_json::to_vec(&format_record_for_api(record)).unwrap_or_default();
+                        line.push(b'\n');
+                        line
+                    });
+                    
+                    let response = warp::http::Response::builder()
+                        .header("Content-Type", "application/x-ndjson")
+                        .body(body)
+                        .unwrap();
+                    Ok(response)
+                }
+            })
+    }
+
+    /// Store one Observation, answering 201 Created
+    ///
+    /// With `if_none_exist` search criteria, an Observation already matching
+    /// them is returned with 200 OK instead and nothing is stored. Conditional
+    /// creates run one at a time, so two of them can't both miss and store.
+    async fn handle_observation_request(
+        mut observation: FHIRObservationRequest, 
+        query_engine: Arc<QueryEngine>,
+        durable: bool,
+        if_none_exist: Option<String>,
+        conditional_creates: &Mutex<()>,
+    ) -> Result<warp::reply::Response, Infallible> {
+        if durable && !query_engine.persistence_active() {
+            return Ok(durability_unavailable_reply().into_response());
+        }
+        
+        // Parse the timestamp
+        let timestamp = match parse_iso8601_to_unix(&observation.effectiveDateTime) {
+            Ok(ts) => ts,
+            Err(_) => {
+                let response = ApiResponse {
+                    status: ResponseStatus::Error,
+                    error_code: Some(ErrorCode::InvalidTimestamp),
+                    message: "Invalid timestamp format".to_string(),
+                    data: None,
+                };
+                return Ok(warp::reply::json(&response).into_response());
+            }
+        };
+        
+        // Create the appropriate FHIR Observation based on which value field is present
+        let fhir_observation = match observation_from_request(&observation, timestamp) {
+            Some(obs) => obs,
+            None => {
+                let response = ApiResponse {
+                    status: ResponseStatus::Error,
+                    error_code: Some(ErrorCode::InvalidResource),
+                    message: "No valid observation value provided".to_string(),
+                    data: None,
+                };
+                return Ok(warp::reply::json(&response).into_response());
+            }
+        };
+        
+        let _conditional = if_none_exist.is_some().then(|| conditional_creates.lock().unwrap());
+        if let Some(criteria) = &if_none_exist {
+            match find_existing_observation(&query_engine, criteria) {
+                Ok(Some(existing)) => {
+                    let response = match resource_to_json("Observation", &existing) {
+                        Ok(resource) => ApiResponse {
+                            status: ResponseStatus::Success,
+                            error_code: None,
+                            message: "Observation already exists".to_string(),
+                            data: Some(resource),
+                        },
+                        Err(e) => ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::from(&e)),
+                            message: format!("Failed to convert existing observation: {:?}", e),
+                            data: None,
+                        },
                     };
-                    Ok(warp::reply::json(&response))
+                    return Ok(warp::reply::json(&response).into_response());
+                }
+                Ok(None) => {}
+                Err(e) => return Ok(bad_request_reply(e)),
+            }
+        }
+        
+        // Convert to records and store
+        let mut records = fhir_observation.to_records();
+        observation.id = Some(assign_resource_id(&mut records, observation.id.take()));
+        println!("Storing observation with metric names: {:?}", 
+                records.iter().map(|r| &r.metric_name).collect::<Vec<_>>());
+        
+        for record in records {
+            let stored = if durable {
+                query_engine.store_record_durable(record)
+            } else {
+                query_engine.store_record(record)
+            };
+            if let Err(err) = stored {
+                let response = ApiResponse {
+                    status: ResponseStatus::Error,
+                    error_code: Some(ErrorCode::from(&err)),
+                    message: format!("Failed to store observation: {:?}", err),
+                    data: None,
+                };
+                return Ok(warp::reply::json(&response).into_response());
+            }
+        }
+        
+        let response = ApiResponse {
+            status: ResponseStatus::Success,
+            error_code: None,
+            message: "Observation stored successfully".to_string(),
+            data: Some(serde_json::to_value(observation).unwrap()),
+        };
+        Ok(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::CREATED).into_response())
+    }
+
+    fn post_observation(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        let conditional_creates = Arc::clone(&self.conditional_creates);
+        let strict_body = self.strict_body;
+        
+        warp::path!("fhir" / "Observation")
+            .and(warp::post())
+            .and(fhir_json::<FHIRObservationRequest, _>(self.create_body(), "Observation", strict_body))
+            .and(durable_flag())
+            .and(warp::header::optional::<String>("if-none-exist"))
+            .and_then(move |observation: FHIRObservationRequest, durable: bool, if_none_exist: Option<String>| {
+                let query_engine = Arc::clone(&query_engine);
+                let conditional_creates = Arc::clone(&conditional_creates);
+                async move {
+                    Self::handle_observation_request(observation, query_engine, durable, if_none_exist, &conditional_creates).await
                 }
             })
     }
 
-    fn post_device_observation(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    fn post_patient(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         let query_engine = Arc::clone(&self.query_engine);
+        let strict_body = self.strict_body;
         
-        warp::path!("fhir" / "DeviceObservation")
+        warp::path!("fhir" / "Patient")
             .and(warp::post())
-            .and(warp::body::json())
-            .and_then(move |request: DeviceObservationRequest| {
+            .and(fhir_json::<PatientRequest, _>(self.create_body(), "Patient", strict_body))
+            .and_then(move |request: PatientRequest| {
                 let query_engine = Arc::clone(&query_engine);
                 async move {
                     // Validate resource type
-                    if request.resourceType != "DeviceObservation" {
+                    if request.resourceType != "Patient" {
                         let response = ApiResponse {
-                            status: "error".to_string(),
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidResourceType),
                             message: "Invalid resource type".to_string(),
                             data: None,
                         };
                         return Ok::<Json, Infallible>(warp::reply::json(&response));
                     }
                     
-                    // Parse timestamp
-                    let timestamp = match parse_iso8601_to_unix(&request.effectiveDateTime) {
-                        Ok(ts) => ts,
-                        Err(_) => {
+                    let now = chrono::Utc::now();
+                    let id = request.id.clone()
+                        .unwrap_or_else(|| format!("patient-{}", now.timestamp_nanos_opt().unwrap_or_default()));
+                    
+                    // The id becomes the first segment of every metric name for this patient
+                    if id.is_empty() || id.contains('|') || id.contains('/') {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidParameter),
+                            message: format!("Invalid patient id: {}", id),
+                            data: None,
+                        };
+                        return Ok(warp::reply::json(&response));
+                    }
+                    
+                    if let Some(birth_date) = &request.birthDate {
+                        if parse_iso8601_to_unix(birth_date).is_err() {
                             let response = ApiResponse {
-                                status: "error".to_string(),
-                                message: "Invalid timestamp format".to_string(),
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::InvalidTimestamp),
+                                message: "Invalid birthDate format".to_string(),
                                 data: None,
                             };
                             return Ok(warp::reply::json(&response));
                         }
-                    };
-                    
-                    // Extract device ID
-                    let device_id = request.device.reference.replace("Device/", "");
-                    
-                    // Extract patient ID if present
-                    let patient_id = request.subject.as_ref()
-                        .map(|subject| subject.reference.replace("Patient/", ""));
+                    }
                     
-                    // Extract code
-                    let coding = &request.code.coding[0];
+                    if let Some(gender) = &request.gender {
+                        if !["male", "female", "other", "unknown"].contains(&gender.as_str()) {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::InvalidParameter),
+                                message: format!("Invalid gender: {}", gender),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    }
                     
-                    // Create device observation
-                    let device_observation = DeviceObservation {
-                        device_id,
-                        device_type: request.deviceType.clone(),
-                        metric_type: request.metricType.clone(),
-                        code: coding.code.clone(),
-                        value: request.valueQuantity.value,
-                        unit: request.valueQuantity.unit.clone(),
-                        timestamp,
-                        patient_id,
-                        status: request.status.clone(),
+                    // Only the first name is kept
+                    let name = request.name.first();
+                    let patient = Patient {
+                        id,
+                        family_name: name.and_then(|n| n.family.clone()),
+                        given_names: name.map(|n| n.given.clone()).unwrap_or_default(),
+                        birth_date: request.birthDate.clone(),
+                        gender: request.gender.clone(),
+                        registered: now.timestamp(),
                     };
                     
-                    // Convert to records and store
-                    let records = device_observation.to_records();
-                    println!("Storing device observation with metric name: {:?}", 
-                            records.iter().map(|r| &r.metric_name).collect::<Vec<_>>());
-                    
-                    for record in records {
+                    for record in patient.to_records() {
                         if let Err(err) = query_engine.store_record(record) {
                             let response = ApiResponse {
-                                status: "error".to_string(),
-                                message: format!("Failed to store device observation: {:?}", err),
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&err)),
+                                message: format!("Failed to store patient: {:?}", err),
                                 data: None,
                             };
                             return Ok(warp::reply::json(&response));
@@ -688,614 +1314,3734 @@ impl RestApi {
                     }
                     
                     let response = ApiResponse {
-                        status: "success".to_string(),
-                        message: "Device observation stored successfully".to_string(),
-                        data: Some(serde_json::to_value(request).unwrap()),
+                        status: ResponseStatus::Success,
+                        error_code: None,
+                        message: "Patient stored successfully".to_string(),
+                        data: Some(serde_json::to_value(PatientRequest::from(&patient)).unwrap()),
                     };
                     Ok(warp::reply::json(&response))
                 }
             })
     }
 
-    fn post_vital_signs(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    fn get_patient(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         let query_engine = Arc::clone(&self.query_engine);
         
-        warp::path!("fhir" / "VitalSigns")
-            .and(warp::post())
-            .and(warp::body::json())
-            .and_then(move |request: VitalSignsRequest| {
+        warp::path!("fhir" / "Patient" / String)
+            .and(warp::get())
+            .and_then(move |patient_id: String| {
                 let query_engine = Arc::clone(&query_engine);
                 async move {
-                    // Validate resource type
-                    if request.resourceType != "VitalSigns" {
-                        let response = ApiResponse {
-                            status: "error".to_string(),
-                            message: "Invalid resource type".to_string(),
-                            data: None,
-                        };
-                        return Ok::<Json, Infallible>(warp::reply::json(&response));
-                    }
-                    
-                    // Parse timestamp
-                    let timestamp = match parse_iso8601_to_unix(&request.effectiveDateTime) {
-                        Ok(ts) => ts,
-                        Err(_) => {
+                    let record = match query_engine.query_latest(&Patient::demographics_metric(&patient_id)) {
+                        Ok(Some(record)) => record,
+                        Ok(None) => {
                             let response = ApiResponse {
-                                status: "error".to_string(),
-                                message: "Invalid timestamp format".to_string(),
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::NotFound),
+                                message: format!("Patient {} not found", patient_id),
                                 data: None,
                             };
-                            return Ok(warp::reply::json(&response));
+                            return Ok::<_, Infallible>(warp::reply::with_status(
+                                warp::reply::json(&response),
+                                warp::http::StatusCode::NOT_FOUND,
+                            ));
                         }
-                    };
-                    
-                    // Extract patient ID
-                    let patient_id = request.subject.reference.replace("Patient/", "");
-                    
-                    // Extract optional metadata
-                    let method = request.method.as_ref().map(|m| m.display.clone());
-                    let position = request.position.as_ref().map(|p| p.display.clone());
-                    let reliability = request.reliability.clone();
-                    
-                    // Get main code
-                    let coding = &request.code.coding[0];
-                    let code = coding.code.clone();
-                    
-                    // Determine vital type and create VitalSigns object
-                    let vital_signs = if let Some(value_quantity) = &request.valueQuantity {
-                        // Single vital sign
-                        let vital_type = match code.as_str() {
-                            "8867-4" => VitalType::HeartRate,
-                            "9279-1" => VitalType::RespiratoryRate,
-                            "59408-5" => VitalType::OxygenSaturation,
-                            "8310-5" => VitalType::Temperature,
-                            "29463-7" => VitalType::Weight,
-                            "8302-2" => VitalType::Height,
-                            _ => {
-                                let response = ApiResponse {
-                                    status: "error".to_string(),
-                                    message: format!("Unknown vital sign code: {}", code),
-                                    data: None,
-                                };
-                                return Ok(warp::reply::json(&response));
-                            }
-                        };
-                        
-                        // Create VitalSigns object
-                        VitalSigns {
-                            vital_type,
-                            value: value_quantity.value,
-                            unit: value_quantity.unit.clone(),
-                            timestamp,
-                            patient_id,
-                            method,
-                            position,
-                            reliability,
-                        }
-                    } else if let Some(components) = &request.component {
-                        // Check if this is blood pressure (has systolic and diastolic)
-                        if code == "85354-9" && components.len() == 2 { // 85354-9 is BP panel
-                            // Find systolic and diastolic components
-                            let mut systolic = None;
-                            let mut diastolic = None;
-                            
-                            for component in components {
-                                let comp_code = &component.code.coding[0].code;
-                                if comp_code == "8480-6" { // Systolic
-                                    systolic = Some(component.valueQuantity.value);
-                                } else if comp_code == "8462-4" { // Diastolic
-                                    diastolic = Some(component.valueQuantity.value);
-                                }
-                            }
-                            
-                            if let (Some(sys), Some(dia)) = (systolic, diastolic) {
-                                // Get unit from first component
-                                let unit = components[0].valueQuantity.unit.clone();
-                                
-                                VitalSigns {
-                                    vital_type: VitalType::BloodPressure {
-                                        systolic: sys,
-                                        diastolic: dia,
-                                    },
-                                    value: sys, // Store systolic as the main value for consistency
-                                    unit,
-                                    timestamp,
-                                    patient_id,
-                                    method,
-                                    position,
-                                    reliability,
-                                }
-                            } else {
-                                let response = ApiResponse {
-                                    status: "error".to_string(),
-                                    message: "Blood pressure must have both systolic and diastolic components".to_string(),
-                                    data: None,
-                                };
-                                return Ok(warp::reply::json(&response));
-                            }
-                        } else {
+                        Err(e) => {
                             let response = ApiResponse {
-                                status: "error".to_string(),
-                                message: "Invalid component-based vital sign".to_string(),
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Failed to query patient: {:?}", e),
                                 data: None,
                             };
-                            return Ok(warp::reply::json(&response));
+                            return Ok(warp::reply::with_status(
+                                warp::reply::json(&response),
+                                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                            ));
                         }
-                    } else {
-                        let response = ApiResponse {
-                            status: "error".to_string(),
-                            message: "No valid vital sign value provided".to_string(),
-                            data: None,
-                        };
-                        return Ok(warp::reply::json(&response));
                     };
                     
-                    // Convert to records and store
-                    let records = vital_signs.to_records();
-                    println!("Storing vital signs with metric names: {:?}", 
-                            records.iter().map(|r| &r.metric_name).collect::<Vec<_>>());
+                    let (status, response) = match Patient::from_records(&[record]) {
+                        Ok(patient) => (warp::http::StatusCode::OK, ApiResponse {
+                            status: ResponseStatus::Success,
+                            error_code: None,
+                            message: format!("Found patient {}", patient_id),
+                            data: Some(serde_json::to_value(PatientRequest::from(&patient)).unwrap()),
+                        }),
+                        Err(e) => (warp::http::StatusCode::INTERNAL_SERVER_ERROR, ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::from(&e)),
+                            message: format!("Failed to convert patient: {:?}", e),
+                            data: None,
+                        }),
+                    };
+                    Ok(warp::reply::with_status(warp::reply::json(&response), status))
+                }
+            })
+    }
+
+    /// Patient $everything operation: all of a patient's data as a Bundle
+    fn get_patient_everything(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        let query_window = self.query_window;
+        
+        warp::path!("fhir" / "Patient" / String / "$everything")
+            .and(warp::get())
+            .and(warp::query::<std::collections::HashMap<String, String>>())
+            .and_then(move |patient_id: String, params: std::collections::HashMap<String, String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Get time range from query params, with defaults
+                    let (start_time, end_time) = time_bounds_from_params(&params, query_window);
+                    if let Err(e) = query_window.check_span(start_time, end_time, &params) {
+                        return Ok(bad_request_reply(e));
+                    }
                     
-                    for record in records {
-                        if let Err(err) = query_engine.store_record(record) {
+                    let records = match query_engine.query_by_patient(&patient_id, start_time, end_time) {
+                        Ok(records) => records,
+                        Err(e) => {
                             let response = ApiResponse {
-                                status: "error".to_string(),
-                                message: format!("Failed to store vital signs: {:?}", err),
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Failed to query patient data: {:?}", e),
                                 data: None,
                             };
-                            return Ok(warp::reply::json(&response));
+                            return Ok::<_, Infallible>(warp::reply::json(&response).into_response());
+                        }
+                    };
+                    
+                    // Convert each group of records back into a FHIR resource
+                    let mut entries = Vec::new();
+                    let mut errors = Vec::new();
+                    for (resource_type, group) in group_records_into_resources(records) {
+                        match resource_to_json(&resource_type, &group) {
+                            Ok(resource) => entries.push(json!({ "resource": resource })),
+                            Err(e) => errors.push(format!("{}: {:?}", resource_type, e)),
                         }
                     }
                     
+                    let bundle = json!({
+                        "resourceType": "Bundle",
+                        "type": "searchset",
+                        "total": entries.len(),
+                        "entry": entries,
+                    });
+                    
                     let response = ApiResponse {
-                        status: "success".to_string(),
-                        message: "Vital signs stored successfully".to_string(),
-                        data: Some(serde_json::to_value(request).unwrap()),
+                        status: if errors.is_empty() { ResponseStatus::Success } else { ResponseStatus::Partial },
+                        error_code: None,
+                        message: format!("Found {} resources for patient {} with {} conversion errors",
+                                         bundle["total"], patient_id, errors.len()),
+                        data: Some(bundle),
                     };
-                    Ok(warp::reply::json(&response))
+                    Ok(warp::reply::json(&response).into_response())
                 }
             })
     }
 
-    /// Endpoint for trend analysis
-    fn get_trend_analysis(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    /// Read a resource by the id it was assigned on create
+    fn read_resource(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         let query_engine = Arc::clone(&self.query_engine);
         
-        warp::path!("timeseries" / "trend")
+        warp::path!("fhir" / String / String)
             .and(warp::get())
-            .and(warp::query::<std::collections::HashMap<String, String>>())
-            .and_then(move |params: std::collections::HashMap<String, String>| {
+            .and_then(move |resource_type: String, id: String| {
                 let query_engine = Arc::clone(&query_engine);
                 async move {
-                    // Parse parameters
-                    let resource_type = params.get("resource_type")
-                        .map(|s| s.to_string())
-                        .unwrap_or("Observation".to_string());
-                        
-                    let metric = params.get("metric")
-                        .map(|s| s.to_string())
-                        .unwrap_or("".to_string());
-                        
-                    let now = chrono::Utc::now().timestamp();
-                    let start_time = params.get("start")
-                        .and_then(|s| s.parse::<i64>().ok())
-                        .unwrap_or(now - 86400); // Default to last 24 hours
+                    if resource_type.parse::<ResourceType>().is_err() {
+                        return Ok::<_, Infallible>(unknown_resource_type_reply(&resource_type));
+                    }
                     
-                    let end_time = params.get("end")
-                        .and_then(|s| s.parse::<i64>().ok())
-                        .unwrap_or(now);
+                    let records: Vec<Record> = match query_engine.query_by_id(&id) {
+                        Ok(records) => records.into_iter().filter(|r| r.resource_type == resource_type).collect(),
+                        Err(e) => return Ok(bad_request_reply(e)),
+                    };
+                    if records.is_empty() {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::NotFound),
+                            message: format!("{} {} not found", resource_type, id),
+                            data: None,
+                        };
+                        return Ok(warp::reply::with_status(
+                            warp::reply::json(&response),
+                            warp::http::StatusCode::NOT_FOUND,
+                        ).into_response());
+                    }
                     
-                    if metric.is_empty() {
-                        // If no specific metric, do resource-wide analysis
-                        let pattern = params.get("pattern").map(|s| s.to_string()).unwrap_or("".to_string());
-                        
-                        match query_engine.calculate_trend_by_resource(&resource_type, &pattern, start_time, end_time) {
-                            Ok(trends) => {
-                                let response = ApiResponse {
-                                    status: "success".to_string(),
-                                    message: format!("Found trend analysis for {} metrics", trends.len()),
-                                    data: Some(serde_json::to_value(trends).unwrap()),
-                                };
-                                Ok::<Json, Infallible>(warp::reply::json(&response))
-                            },
-                            Err(e) => {
-                                let response = ApiResponse {
-                                    status: "error".to_string(),
-                                    message: format!("Failed to calculate trends: {:?}", e),
-                                    data: None,
-                                };
-                                Ok(warp::reply::json(&response))
-                            }
-                        }
-                    } else {
-                        // Specific metric trend analysis
-                        match query_engine.calculate_trend(&metric, start_time, end_time) {
-                            Ok(trend) => {
-                                let response = ApiResponse {
-                                    status: "success".to_string(),
-                                    message: format!("Trend analysis for metric: {}", metric),
-                                    data: Some(serde_json::to_value(trend).unwrap()),
-                                };
-                                Ok::<Json, Infallible>(warp::reply::json(&response))
-                            },
-                            Err(e) => {
-                                let response = ApiResponse {
-                                    status: "error".to_string(),
-                                    message: format!("Failed to calculate trend: {:?}", e),
-                                    data: None,
-                                };
-                                Ok(warp::reply::json(&response))
-                            }
+                    let (status, response) = match resource_to_json(&resource_type, &records) {
+                        Ok(resource) => (warp::http::StatusCode::OK, ApiResponse {
+                            status: ResponseStatus::Success,
+                            error_code: None,
+                            message: format!("Found {} {}", resource_type, id),
+                            data: Some(resource),
+                        }),
+                        Err(e) => (warp::http::StatusCode::INTERNAL_SERVER_ERROR, ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::from(&e)),
+                            message: format!("Failed to convert {} {}: {:?}", resource_type, id, e),
+                            data: None,
+                        }),
+                    };
+                    Ok(warp::reply::with_status(warp::reply::json(&response), status).into_response())
+                }
+            })
+    }
+
+    /// Everything a device reported, as DeviceObservation resources
+    fn get_device_observations(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("fhir" / "Device" / String / "observations")
+            .and(warp::get())
+            .and(time_query(self.query_window))
+            .and_then(move |device_id: String, params: std::collections::HashMap<String, String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    let (start_time, end_time) = query_bounds(&params);
+                    
+                    let records = match query_engine.query_by_device(&device_id, start_time, end_time) {
+                        Ok(records) => records,
+                        Err(e) => return Ok::<_, Infallible>(bad_request_reply(e)),
+                    };
+                    
+                    let mut entries = Vec::new();
+                    let mut errors = Vec::new();
+                    for record in records {
+                        match resource_to_json("DeviceObservation", std::slice::from_ref(&record)) {
+                            Ok(resource) => entries.push(json!({ "resource": resource })),
+                            Err(e) => errors.push(format!("{}: {:?}", record.metric_name, e)),
                         }
                     }
+                    
+                    let bundle = json!({
+                        "resourceType": "Bundle",
+                        "type": "searchset",
+                        "total": entries.len(),
+                        "entry": entries,
+                    });
+                    
+                    let response = ApiResponse {
+                        status: if errors.is_empty() { ResponseStatus::Success } else { ResponseStatus::Partial },
+                        error_code: None,
+                        message: format!("Found {} observations from device {} with {} conversion errors",
+                                         bundle["total"], device_id, errors.len()),
+                        data: Some(bundle),
+                    };
+                    Ok(warp::reply::json(&response).into_response())
                 }
             })
     }
-    
-    /// Endpoint for statistics
-    fn get_stats(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+
+    // New method to query resources by type
+    fn get_resource_by_type(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         let query_engine = Arc::clone(&self.query_engine);
+        let query_window = self.query_window;
         
-        warp::path!("timeseries" / "stats")
+        warp::path!("fhir" / "resources" / String)
             .and(warp::get())
-            .and(warp::query::<std::collections::HashMap<String, String>>())
-            .and_then(move |params: std::collections::HashMap<String, String>| {
+            .and(warp::query::<Vec<(String, String)>>())
+            .and_then(move |resource_type: String, raw_params: Vec<(String, String)>| {
                 let query_engine = Arc::clone(&query_engine);
                 async move {
-                    // Required parameter: metric
-                    let metric = match params.get("metric") {
-                        Some(m) => m.to_string(),
-                        None => {
+                    if resource_type.parse::<ResourceType>().is_err() {
+                        return Ok::<_, Infallible>(unknown_resource_type_reply(&resource_type));
+                    }
+                    let record_filter = match record_filter_from_params(&raw_params) {
+                        Ok(filter) => filter,
+                        Err(e) => {
                             let response = ApiResponse {
-                                status: "error".to_string(),
-                                message: "Missing required parameter: metric".to_string(),
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: e.to_string(),
                                 data: None,
                             };
-                            return Ok(warp::reply::json(&response));
+                            return Ok::<_, Infallible>(warp::reply::json(&response).into_response());
                         }
                     };
+                    let params: std::collections::HashMap<String, String> = raw_params.iter().cloned().collect();
+                    let render = match RenderOptions::from_params(&params) {
+                        Ok(render) => render,
+                        Err(e) => return Ok(bad_request_reply(e)),
+                    };
                     
-                    // Parse time parameters
-                    let now = chrono::Utc::now().timestamp();
-                    let start_time = params.get("start")
-                        .and_then(|s| s.parse::<i64>().ok())
-                        .unwrap_or(now - 86400); // Default to last 24 hours
+                    // Get time range from query params, with defaults, narrowed by any `date` params
+                    let (start_time, end_time) = match date_bounds_from_params(&raw_params, time_bounds_from_params(&params, query_window)) {
+                        Ok(bounds) => bounds,
+                        Err(e) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: e.to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response).into_response());
+                        }
+                    };
+                    if let Err(e) = query_window.check_span(start_time, end_time, &params) {
+                        return Ok(bad_request_reply(e));
+                    }
                     
-                    let end_time = params.get("end")
-                        .and_then(|s| s.parse::<i64>().ok())
-                        .unwrap_or(now);
+                    // `_summary=count` wants only the Bundle total
+                    if params.get("_summary").is_some_and(|summary| summary == "count") {
+                        let count = if record_filter.is_empty() {
+                            query_engine.count_by_resource_type(&resource_type, start_time, end_time)
+                        } else {
+                            // Filters look at values, so these records have to be read
+                            query_metrics_with_filter(
+                                &query_engine, query_engine.get_metrics_by_resource_type(&resource_type),
+                                start_time, end_time, &record_filter,
+                            ).map(|records| records.len())
+                        };
+                        let response = match count {
+                            Ok(count) => ApiResponse {
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Counted {} records for {}", count, resource_type),
+                                data: Some(json!({
+                                    "resourceType": "Bundle",
+                                    "type": "searchset",
+                                    "total": count,
+                                    "entry": [],
+                                })),
+                            },
+                            Err(e) => ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Error counting {}: {}", resource_type, e),
+                                data: None,
+                            },
+                        };
+                        return Ok(warp::reply::json(&response).into_response());
+                    }
                     
-                    // Calculate statistics
-                    match query_engine.calculate_stats(&metric, start_time, end_time) {
-                        Ok(stats) => {
+                    if !record_filter.is_empty() {
+                        let response = match query_metrics_with_filter(
+                            &query_engine, query_engine.get_metrics_by_resource_type(&resource_type),
+                            start_time, end_time, &record_filter,
+                        ) {
+                            Ok(records) => ApiResponse {
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Found {} records for {}", records.len(), resource_type),
+                                data: Some(render.records(&records)),
+                            },
+                            Err(e) => ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Error querying {}: {:?}", resource_type, e),
+                                data: None,
+                            },
+                        };
+                        return Ok(warp::reply::json(&response).into_response());
+                    }
+                    
+                    // Query by resource type
+                    match query_engine.query_by_resource_type(&resource_type, start_time, end_time) {
+                        Ok(records) => {
                             let response = ApiResponse {
-                                status: "success".to_string(),
-                                message: format!("Statistics for metric: {}", metric),
-                                data: Some(serde_json::to_value(stats).unwrap()),
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Found {} records for {}", records.len(), resource_type),
+                                data: Some(render.records(&records)),
                             };
-                            Ok::<Json, Infallible>(warp::reply::json(&response))
+                            Ok::<_, Infallible>(warp::reply::json(&response).into_response())
                         },
-                        Err(e) => {
+                        Err(_) => {
                             let response = ApiResponse {
-                                status: "error".to_string(),
-                                message: format!("Failed to calculate statistics: {:?}", e),
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::NotFound),
+                                message: format!("No records found for {}", resource_type),
                                 data: None,
                             };
-                            Ok(warp::reply::json(&response))
+                            Ok::<_, Infallible>(warp::reply::json(&response).into_response())
                         }
                     }
                 }
             })
     }
-    
-    /// Endpoint for outlier detection
-    fn get_outliers(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+
+    /// Number of records `get_resource_by_type` would match, without their bodies
+    fn count_resources_by_type(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         let query_engine = Arc::clone(&self.query_engine);
+        let query_window = self.query_window;
         
-        warp::path!("timeseries" / "outliers")
+        warp::path!("fhir" / "resources" / String / "_count")
             .and(warp::get())
-            .and(warp::query::<std::collections::HashMap<String, String>>())
-            .and_then(move |params: std::collections::HashMap<String, String>| {
+            .and(warp::query::<Vec<(String, String)>>())
+            .and_then(move |resource_type: String, raw_params: Vec<(String, String)>| {
                 let query_engine = Arc::clone(&query_engine);
                 async move {
-                    // Required parameter: metric
-                    let metric = match params.get("metric") {
-                        Some(m) => m.to_string(),
-                        None => {
-                            let response = ApiResponse {
-                                status: "error".to_string(),
-                                message: "Missing required parameter: metric".to_string(),
-                                data: None,
-                            };
-                            return Ok(warp::reply::json(&response));
+                    if resource_type.parse::<ResourceType>().is_err() {
+                        return Ok::<_, Infallible>(unknown_resource_type_reply(&resource_type));
+                    }
+                    let params: std::collections::HashMap<String, String> = raw_params.iter().cloned().collect();
+                    let bounds = date_bounds_from_params(&raw_params, time_bounds_from_params(&params, query_window));
+                    if let Ok((start_time, end_time)) = bounds {
+                        if let Err(e) = query_window.check_span(start_time, end_time, &params) {
+                            return Ok(bad_request_reply(e));
                         }
+                    }
+                    let result = bounds.and_then(|(start_time, end_time)| {
+                        query_engine.count_by_resource_type(&resource_type, start_time, end_time)
+                    });
+                    
+                    let response = match result {
+                        Ok(count) => ApiResponse {
+                            status: ResponseStatus::Success,
+                            error_code: None,
+                            message: format!("Counted {} records for {}", count, resource_type),
+                            data: Some(json!(count)),
+                        },
+                        Err(e) => ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::from(&e)),
+                            message: format!("Error counting {}: {}", resource_type, e),
+                            data: None,
+                        },
+                    };
+                    Ok::<_, Infallible>(warp::reply::json(&response).into_response())
+                }
+            })
+    }
+
+    // Debug endpoint to see all metrics and resource types
+    fn debug_metrics(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("debug" / "metrics")
+            .and(warp::get())
+            .and_then(move || {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Get internal data about metrics and resources
+                    let debug_info = query_engine.debug_metrics().unwrap_or_default();
+                    
+                    let response = ApiResponse {
+                        status: ResponseStatus::Success,
+                        error_code: None,
+                        message: "Debug metrics info".to_string(),
+                        data: Some(serde_json::to_value(debug_info).unwrap()),
+                    };
+                    Ok::<Json, Infallible>(warp::reply::json(&response))
+                }
+            })
+    }
+
+    /// Which metrics hold the most records, with the span each covers
+    fn debug_metric_counts(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("debug" / "metrics" / "counts")
+            .and(warp::get())
+            .map(move || {
+                let response = match query_engine.metric_counts() {
+                    Ok(counts) => ApiResponse {
+                        status: ResponseStatus::Success,
+                        error_code: None,
+                        message: format!("Record counts for {} metrics", counts.len()),
+                        data: Some(serde_json::to_value(counts).unwrap()),
+                    },
+                    Err(e) => ApiResponse {
+                        status: ResponseStatus::Error,
+                        error_code: Some(ErrorCode::from(&e)),
+                        message: format!("Failed to count metrics: {}", e),
+                        data: None,
+                    },
+                };
+                warp::reply::json(&response)
+            })
+    }
+
+    /// WebSocket pushing each newly inserted record whose metric starts with `metric`
+    fn ws_subscribe(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("ws" / "subscribe")
+            .and(warp::ws())
+            .and(warp::query::<std::collections::HashMap<String, String>>())
+            .map(move |ws: warp::ws::Ws, params: std::collections::HashMap<String, String>| {
+                let Some(metric) = params.get("metric").cloned() else {
+                    let response = ApiResponse {
+                        status: ResponseStatus::Error,
+                        error_code: Some(ErrorCode::MissingParameter),
+                        message: "Missing required parameter: metric".to_string(),
+                        data: None,
                     };
+                    return warp::reply::with_status(
+                        warp::reply::json(&response),
+                        warp::http::StatusCode::BAD_REQUEST,
+                    ).into_response();
+                };
+                
+                // Subscribe before the upgrade so nothing inserted after the handshake is missed
+                let updates = query_engine.subscribe();
+                ws.on_upgrade(move |socket| forward_updates(socket, updates, metric))
+                    .into_response()
+            })
+    }
+
+    fn debug_stats(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("debug" / "stats")
+            .and(warp::get())
+            .map(move || {
+                let response = ApiResponse {
+                    status: ResponseStatus::Success,
+                    error_code: None,
+                    message: "Ingest stats".to_string(),
+                    data: Some(serde_json::to_value(query_engine.ingest_stats()).unwrap()),
+                };
+                warp::reply::json(&response)
+            })
+    }
+
+    /// Resident chunks with their time range, record count, dirty flag and compression state
+    fn debug_chunks(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("debug" / "chunks")
+            .and(warp::get())
+            .map(move || {
+                let chunks = query_engine.chunk_infos();
+                let response = ApiResponse {
+                    status: ResponseStatus::Success,
+                    error_code: None,
+                    message: format!("{} resident chunks", chunks.len()),
+                    data: Some(serde_json::to_value(chunks).unwrap()),
+                };
+                warp::reply::json(&response)
+            })
+    }
+
+    /// Count/min/max/avg of `metric` within one chunk
+    fn debug_chunk_summary(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("debug" / "chunks" / i64 / "summary")
+            .and(warp::get())
+            .and(warp::query::<std::collections::HashMap<String, String>>())
+            .map(move |chunk_id: i64, params: std::collections::HashMap<String, String>| {
+                let Some(metric) = params.get("metric") else {
+                    return bad_request_reply(QueryError::InvalidParameter(
+                        "Missing required parameter: metric".to_string()
+                    ));
+                };
+                
+                let (status, response) = match query_engine.summarize_chunk(chunk_id, metric) {
+                    Ok(summary) => (warp::http::StatusCode::OK, ApiResponse {
+                        status: ResponseStatus::Success,
+                        error_code: None,
+                        message: format!("Summary of {} in chunk {}", metric, chunk_id),
+                        data: Some(serde_json::to_value(summary).unwrap()),
+                    }),
+                    Err(e) => {
+                        let status = match e {
+                            QueryError::MetricNotFound(_) => warp::http::StatusCode::NOT_FOUND,
+                            _ => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                        };
+                        (status, ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::from(&e)),
+                            message: format!("Failed to summarize chunk: {}", e),
+                            data: None,
+                        })
+                    }
+                };
+                warp::reply::with_status(warp::reply::json(&response), status).into_response()
+            })
+    }
+
+    // New endpoint for time-chunked queries
+    //
+    // With `Accept: application/x-ndjson` each chunk is written as its own line
+    // as soon as it's read, instead of building the whole response first. Given
+    // `metric=` and no chunk width, it returns that metric's raw records instead,
+    // exactly as /timeseries/range does.
+    fn get_time_chunked(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("fhir" / "timeseries")
+            .and(warp::get())
+            .and(time_query(self.query_window))
+            .and(warp::header::optional::<String>("accept"))
+            .and(warp::header::optional::<String>("if-none-match"))
+            .and_then(move |params: std::collections::HashMap<String, String>, accept: Option<String>,
+                            if_none_match: Option<String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    let chunk_width_given = ["interval", "chunk_size"].iter().any(|name| params.contains_key(*name));
+                    if params.contains_key("metric") && !chunk_width_given {
+                        return Ok::<_, Infallible>(metric_range_reply(&query_engine, &params, if_none_match.as_deref()));
+                    }
+                    
+                    // Extract parameters
+                    let resource_type = params.get("resource_type").map(|s| s.to_string()).unwrap_or("Observation".to_string());
                     
                     // Parse time parameters
-                    let now = chrono::Utc::now().timestamp();
-                    let start_time = params.get("start")
-                        .and_then(|s| s.parse::<i64>().ok())
-                        .unwrap_or(now - 86400); // Default to last 24 hours
+                    let (start_time, end_time) = query_bounds(&params);
                     
-                    let end_time = params.get("end")
-                        .and_then(|s| s.parse::<i64>().ok())
-                        .unwrap_or(now);
+                    // Width of each time chunk, defaults to 1 hour
+                    let chunk_size = match interval_param(&params, &["chunk_size"]) {
+                        Ok(chunk_size) => chunk_size.unwrap_or(3600),
+                        Err(e) => return Ok(bad_request_reply(e)),
+                    };
                     
-                    // Parse threshold
-                    let threshold = params.get("threshold")
-                        .and_then(|s| s.parse::<f64>().ok())
-                        .unwrap_or(2.0); // Default Z-score threshold of 2.0
+                    if accept.is_some_and(|accept| accept.contains("application/x-ndjson")) {
+                        if start_time >= end_time || chunk_size == 0 {
+                            return Ok::<_, Infallible>(bad_request_reply(QueryError::InvalidParameter(
+                                "start must be before end and interval positive".to_string(),
+                            )));
+                        }
+                        let body = stream_time_chunks_body(query_engine, resource_type, start_time, end_time, chunk_size);
+                        let response = warp::http::Response::builder()
+                            .header("Content-Type", "application/x-ndjson")
+                            .body(body)
+                            .unwrap();
+                        return Ok(response);
+                    }
                     
-                    // Detect outliers
-                    match query_engine.detect_outliers(&metric, start_time, end_time, threshold) {
-                        Ok(outliers) => {
-                            let response = ApiResponse {
-                                status: "success".to_string(),
-                                message: format!("Found {} outliers for metric: {}", outliers.outliers.len(), metric),
-                                data: Some(serde_json::to_value(outliers).unwrap()),
-                            };
-                            Ok::<Json, Infallible>(warp::reply::json(&response))
+                    // Query with time chunking
+                    let timeout = timeout_from_params(&params);
+                    match query_engine.query_time_chunked(&resource_type, start_time, end_time, chunk_size, timeout) {
+                        Ok(chunks) => {
+                            let records: Vec<Record> = chunks.iter().flat_map(|chunk| chunk.records.iter().cloned()).collect();
+                            let etag = records_etag(&records, &params);
+                            Ok(conditional_reply(&etag, if_none_match.as_deref(), || {
+                                // Transform each chunk to have better-formatted records
+                                let formatted_chunks: Vec<serde_json::Value> = chunks.iter().map(time_chunk_json).collect();
+                                
+                                let response = ApiResponse {
+                                    status: ResponseStatus::Success,
+                                    error_code: None,
+                                    message: format!("Found data in {} time chunks", chunks.len()),
+                                    data: Some(serde_json::to_value(formatted_chunks).unwrap()),
+                                };
+                                warp::reply::json(&response)
+                            }))
                         },
                         Err(e) => {
                             let response = ApiResponse {
-                                status: "error".to_string(),
-                                message: format!("Failed to detect outliers: {:?}", e),
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Error querying time chunks: {}", e),
                                 data: None,
                             };
-                            Ok(warp::reply::json(&response))
+                            Ok(warp::reply::json(&response).into_response())
                         }
                     }
                 }
             })
     }
-    
-    /// Endpoint for rate of change calculation
-    fn get_rate_of_change(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+
+    fn post_medication_administration(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         let query_engine = Arc::clone(&self.query_engine);
+        let strict_body = self.strict_body;
         
-        warp::path!("timeseries" / "rate")
-            .and(warp::get())
-            .and(warp::query::<std::collections::HashMap<String, String>>())
-            .and_then(move |params: std::collections::HashMap<String, String>| {
+        warp::path!("fhir" / "MedicationAdministration")
+            .and(warp::post())
+            .and(fhir_json::<MedicationAdministrationRequest, _>(self.create_body(), "MedicationAdministration", strict_body))
+            .and(durable_flag())
+            .and_then(move |request: MedicationAdministrationRequest, durable: bool| {
                 let query_engine = Arc::clone(&query_engine);
                 async move {
-                    // Required parameter: metric
-                    let metric = match params.get("metric") {
-                        Some(m) => m.to_string(),
-                        None => {
+                    if durable && !query_engine.persistence_active() {
+                        return Ok::<Json, Infallible>(durability_unavailable_reply());
+                    }
+                    
+                    // Validate resource type
+                    if request.resourceType != "MedicationAdministration" {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidResourceType),
+                            message: "Invalid resource type".to_string(),
+                            data: None,
+                        };
+                        return Ok::<Json, Infallible>(warp::reply::json(&response));
+                    }
+                    
+                    // Parse timestamp
+                    let timestamp = match parse_iso8601_to_unix(&request.effectiveDateTime) {
+                        Ok(ts) => ts,
+                        Err(_) => {
                             let response = ApiResponse {
-                                status: "error".to_string(),
-                                message: "Missing required parameter: metric".to_string(),
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::InvalidTimestamp),
+                                message: "Invalid timestamp format".to_string(),
                                 data: None,
                             };
                             return Ok(warp::reply::json(&response));
                         }
                     };
                     
-                    // Parse time parameters
-                    let now = chrono::Utc::now().timestamp();
-                    let start_time = params.get("start")
-                        .and_then(|s| s.parse::<i64>().ok())
-                        .unwrap_or(now - 86400); // Default to last 24 hours
+                    // Extract patient ID
+                    let patient_id = request.subject.reference.replace("Patient/", "");
                     
-                    let end_time = params.get("end")
-                        .and_then(|s| s.parse::<i64>().ok())
-                        .unwrap_or(now);
+                    // Extract practitioner ID if present
+                    let practitioner_id = request.performer.as_ref()
+                        .map(|performer| performer.reference.replace("Practitioner/", ""));
                     
-                    // Parse period
-                    let period = params.get("period")
-                        .and_then(|s| s.parse::<i64>().ok())
-                        .unwrap_or(3600); // Default to hourly rate
+                    // Extract medication information
+                    let coding = &request.medication.coding[0];
                     
-                    // Calculate rate of change
-                    match query_engine.calculate_rate_of_change(&metric, start_time, end_time, period) {
-                        Ok(rates) => {
-                            let response = ApiResponse {
-                                status: "success".to_string(),
-                                message: format!("Calculated {} rate points for metric: {}", rates.len(), metric),
-                                data: Some(serde_json::to_value(format_records_for_api(&rates)).unwrap()),
-                            };
-                            Ok::<Json, Infallible>(warp::reply::json(&response))
-                        },
-                        Err(e) => {
+                    // Create MedicationAdministration
+                    let med_administration = MedicationAdministration {
+                        medication_code: coding.code.clone(),
+                        medication_display: coding.display.clone(),
+                        dose_value: request.dosage.value,
+                        dose_unit: request.dosage.unit.clone(),
+                        route: request.route.display.clone(),
+                        timestamp,
+                        patient_id,
+                        practitioner_id,
+                        status: request.status.clone(),
+                    };
+                    
+                    // Convert to records and store
+                    let mut records = med_administration.to_records();
+                    let id = assign_resource_id(&mut records, None);
+                    println!("Storing medication administration with metric name: {:?}", 
+                            records.iter().map(|r| &r.metric_name).collect::<Vec<_>>());
+                    
+                    for record in records {
+                        let stored = if durable {
+                            query_engine.store_record_durable(record)
+                        } else {
+                            query_engine.store_record(record)
+                        };
+                        if let Err(err) = stored {
                             let response = ApiResponse {
-                                status: "error".to_string(),
-                                message: format!("Failed to calculate rate of change: {:?}", e),
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&err)),
+                                message: format!("Failed to store medication administration: {:?}", err),
                                 data: None,
                             };
-                            Ok(warp::reply::json(&response))
+                            return Ok(warp::reply::json(&response));
                         }
                     }
+                    
+                    let response = ApiResponse {
+                        status: ResponseStatus::Success,
+                        error_code: None,
+                        message: "Medication administration stored successfully".to_string(),
+                        data: Some(with_resource_id(serde_json::to_value(request).unwrap(), &id)),
+                    };
+                    Ok(warp::reply::json(&response))
                 }
             })
     }
 
-    fn post_bundle(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    /// A patient's medication administrations, optionally for one medication code
+    fn search_medication_administrations(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         let query_engine = Arc::clone(&self.query_engine);
         
-        warp::path!("fhir")
+        warp::path!("fhir" / "MedicationAdministration")
+            .and(warp::get())
+            // Defaulting to everything up to now
+            .and(time_query_from(self.query_window, |_| 0))
+            .and_then(move |params: std::collections::HashMap<String, String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    let Some(patient_id) = params.get("patient") else {
+                        return Ok::<_, Infallible>(bad_request_reply(QueryError::InvalidParameter(
+                            "Missing required parameter: patient".to_string()
+                        )));
+                    };
+                    let patient_id = patient_id.trim_start_matches("Patient/");
+                    let (start_time, end_time) = query_bounds(&params);
+                    
+                    let medication = params.get("medication").map(String::as_str);
+                    let records = match query_engine.query_medication_administrations(patient_id, medication, start_time, end_time) {
+                        Ok(records) => records,
+                        Err(e) => return Ok(bad_request_reply(e)),
+                    };
+                    
+                    let mut entries = Vec::new();
+                    let mut errors = Vec::new();
+                    for record in records {
+                        match resource_to_json("MedicationAdministration", std::slice::from_ref(&record)) {
+                            Ok(resource) => entries.push(json!({ "resource": resource })),
+                            Err(e) => errors.push(format!("{}: {:?}", record.metric_name, e)),
+                        }
+                    }
+                    
+                    let bundle = json!({
+                        "resourceType": "Bundle",
+                        "type": "searchset",
+                        "total": entries.len(),
+                        "entry": entries,
+                    });
+                    
+                    let response = ApiResponse {
+                        status: if errors.is_empty() { ResponseStatus::Success } else { ResponseStatus::Partial },
+                        error_code: None,
+                        message: format!("Found {} medication administrations for patient {} with {} conversion errors",
+                                         bundle["total"], patient_id, errors.len()),
+                        data: Some(bundle),
+                    };
+                    Ok(warp::reply::json(&response).into_response())
+                }
+            })
+    }
+
+    fn post_device_observation(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        let strict_body = self.strict_body;
+        
+        warp::path!("fhir" / "DeviceObservation")
             .and(warp::post())
-            .and(warp::body::json())
-            .and_then(move |bundle: FHIRBundle| {
+            .and(fhir_json::<DeviceObservationRequest, _>(self.create_body(), "DeviceObservation", strict_body))
+            .and_then(move |request: DeviceObservationRequest| {
                 let query_engine = Arc::clone(&query_engine);
                 async move {
-                    // Verify this is a Bundle
-                    if bundle.resourceType != "Bundle" {
+                    // Validate resource type
+                    if request.resourceType != "DeviceObservation" {
                         let response = ApiResponse {
-                            status: "error".to_string(),
-                            message: "Expected a FHIR Bundle".to_string(),
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidResourceType),
+                            message: "Invalid resource type".to_string(),
                             data: None,
                         };
                         return Ok::<Json, Infallible>(warp::reply::json(&response));
                     }
                     
-                    let mut processed_count = 0;
-                    let mut errors = Vec::new();
-                    let mut records_to_store: Vec<Record> = Vec::new();
-                    
-                    // Process each entry in the bundle
-                    for entry in bundle.entry {
-                        // Check if this is an Observation POST
-                        if let Some(resource_type) = entry.resource.get("resourceType").and_then(|v| v.as_str()) {
-                            if resource_type == "Observation" && entry.request.method == "POST" {
-                                // Parse the observation
-                                match serde_json::from_value::<FHIRObservationRequest>(entry.resource.clone()) {
-                                    Ok(observation) => {
-                                        // Parse the timestamp
-                                        match parse_iso8601_to_unix(&observation.effectiveDateTime) {
-                                            Ok(timestamp) => {
-                                                // Extract patient ID
-                                                let patient_id = observation.subject.reference.replace("Patient/", "");
-                                                
-                                                // Extract device ID if present
-                                                let device_id = observation.device.as_ref().map(|dev| dev.reference.replace("Device/", ""));
-                                                
-                                                // Get the main code
-                                                let coding = &observation.code.coding[0];
-                                                let code = coding.code.clone();
-                                                
-                                                // Create the appropriate FHIR Observation
-                                                let fhir_observation = if let Some(value_quantity) = &observation.valueQuantity {
-                                                    // Numeric observation
-                                                    Some(FHIRObservation::Numeric {
-                                                        code,
-                                                        value: value_quantity.value,
-                                                        unit: value_quantity.unit.clone(),
-                                                        timestamp,
-                                                        patient_id: patient_id.clone(),
-                                                        device_id: device_id.clone(),
-                                                    })
-                                                } else if let Some(components) = &observation.component {
-                                                    // Component observation
-                                                    let mut observation_components = Vec::new();
-                                                    
-                                                    for component in components {
-                                                        let comp_coding = &component.code.coding[0];
-                                                        let comp_value = &component.valueQuantity;
-                                                        
-                                                        observation_components.push(ObservationComponent {
-                                                            code: comp_coding.code.clone(),
-                                                            value: comp_value.value,
-                                                            unit: comp_value.unit.clone(),
-                                                        });
-                                                    }
-                                                    
-                                                    Some(FHIRObservation::Component {
-                                                        code,
-                                                        components: observation_components,
-                                                        timestamp,
-                                                        patient_id: patient_id.clone(),
-                                                        device_id: device_id.clone(),
-                                                    })
-                                                } else if let Some(sampled_data) = &observation.valueSampledData {
-                                                    // Sampled data observation
-                                                    // Parse the space-separated data values
-                                                    let values: Vec<f64> = sampled_data.data
-                                                        .split_whitespace()
-                                                        .filter_map(|s| s.parse::<f64>().ok())
-                                                        .collect();
-                                                        
-                                                    Some(FHIRObservation::SampledData {
-                                                        code,
-                                                        period: sampled_data.period,
-                                                        factor: sampled_data.factor.unwrap_or(1.0),
-                                                        data: values,
-                                                        start_time: timestamp,
-                                                        patient_id: patient_id.clone(),
-                                                        device_id: device_id.clone(),
-                                                    })
-                                                } else {
-                                                    None
-                                                };
-                                                
-                                                if let Some(obs) = fhir_observation {
-                                                    // Convert to records and store in batch
-                                                    let new_records = obs.to_records();
-                                                    records_to_store.extend(new_records);
-                                                    processed_count += 1;
-                                                } else {
-                                                    errors.push(format!("No valid observation value provided"));
-                                                }
-                                            },
-                                            Err(_) => {
-                                                errors.push(format!("Invalid timestamp format"));
-                                            }
-                                        }
-                                    },
-                                    Err(e) => {
-                                        errors.push(format!("Failed to parse observation: {}", e));
-                                    }
-                                }
-                            }
+                    // Parse timestamp
+                    let timestamp = match parse_iso8601_to_unix(&request.effectiveDateTime) {
+                        Ok(ts) => ts,
+                        Err(_) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::InvalidTimestamp),
+                                message: "Invalid timestamp format".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
                         }
-                    }
+                    };
                     
-                    // Store all records in a single batch operation
-                    if !records_to_store.is_empty() {
-                        if let Err(err) = query_engine.store_records(records_to_store) {
-                            errors.push(format!("Failed to store some records: {:?}", err));
+                    // Extract device ID
+                    let device_id = request.device.reference.replace("Device/", "");
+                    
+                    // Extract patient ID if present
+                    let patient_id = request.subject.as_ref()
+                        .map(|subject| subject.reference.replace("Patient/", ""));
+                    
+                    // Extract code
+                    let coding = &request.code.coding[0];
+                    
+                    // Create device observation
+                    let device_observation = DeviceObservation {
+                        device_id,
+                        device_type: request.deviceType.clone(),
+                        metric_type: request.metricType.clone(),
+                        code: coding.code.clone(),
+                        value: request.valueQuantity.value,
+                        unit: request.valueQuantity.unit.clone(),
+                        timestamp,
+                        patient_id,
+                        status: request.status.clone(),
+                    };
+                    
+                    // Convert to records and store
+                    let mut records = device_observation.to_records();
+                    let id = assign_resource_id(&mut records, None);
+                    println!("Storing device observation with metric name: {:?}", 
+                            records.iter().map(|r| &r.metric_name).collect::<Vec<_>>());
+                    
+                    for record in records {
+                        if let Err(err) = query_engine.store_record(record) {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&err)),
+                                message: format!("Failed to store device observation: {:?}", err),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
                         }
                     }
                     
                     let response = ApiResponse {
-                        status: if errors.is_empty() { "success".to_string() } else { "partial".to_string() },
-                        message: format!("Processed {} observations with {} errors", processed_count, errors.len()),
-                        data: if errors.is_empty() { 
-                            None 
-                        } else { 
-                            Some(serde_json::to_value(errors).unwrap()) 
-                        },
+                        status: ResponseStatus::Success,
+                        error_code: None,
+                        message: "Device observation stored successfully".to_string(),
+                        data: Some(with_resource_id(serde_json::to_value(request).unwrap(), &id)),
                     };
-                    
-                    Ok::<Json, Infallible>(warp::reply::json(&response))
+                    Ok(warp::reply::json(&response))
                 }
             })
     }
 
-    fn debug_settings(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    fn post_vital_signs(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         let query_engine = Arc::clone(&self.query_engine);
+        let strict_body = self.strict_body;
         
-        warp::path!("debug" / "settings")
+        warp::path!("fhir" / "VitalSigns")
             .and(warp::post())
-            .and(warp::body::json())
-            .map(move |settings: DebugSettings| {
-                // Apply settings to the query engine
-                if let Err(e) = query_engine.set_debug_settings(settings.memory_mode, settings.disable_wal, settings.batch_size) {
-                    return warp::reply::with_status(
-                        warp::reply::json(&json!({
-                            "status": "error",
-                            "message": format!("Failed to apply debug settings: {}", e)
-                        })),
-                        warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                    );
+            .and(fhir_json::<VitalSignsRequest, _>(self.create_body(), "VitalSigns", strict_body))
+            .and_then(move |request: VitalSignsRequest| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Validate resource type
+                    if request.resourceType != "VitalSigns" {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidResourceType),
+                            message: "Invalid resource type".to_string(),
+                            data: None,
+                        };
+                        return Ok::<Json, Infallible>(warp::reply::json(&response));
+                    }
+                    
+                    // Parse timestamp
+                    let timestamp = match parse_iso8601_to_unix(&request.effectiveDateTime) {
+                        Ok(ts) => ts,
+                        Err(_) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::InvalidTimestamp),
+                                message: "Invalid timestamp format".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    };
+                    
+                    // Extract patient ID
+                    let patient_id = request.subject.reference.replace("Patient/", "");
+                    
+                    // Extract optional metadata
+                    let method = request.method.as_ref().map(|m| m.display.clone());
+                    let position = request.position.as_ref().map(|p| p.display.clone());
+                    let reliability = request.reliability.clone();
+                    
+                    // Get main code
+                    let coding = &request.code.coding[0];
+                    let code = coding.code.clone();
+                    
+                    // Determine vital type and create VitalSigns object
+                    let vital_signs = if let Some(value_quantity) = &request.valueQuantity {
+                        // Single vital sign
+                        let vital_type = match code.as_str() {
+                            "8867-4" => VitalType::HeartRate,
+                            "9279-1" => VitalType::RespiratoryRate,
+                            "59408-5" => VitalType::OxygenSaturation,
+                            "8310-5" => VitalType::Temperature,
+                            "29463-7" => VitalType::Weight,
+                            "8302-2" => VitalType::Height,
+                            _ => {
+                                let response = ApiResponse {
+                                    status: ResponseStatus::Error,
+                                    error_code: Some(ErrorCode::UnknownVitalCode),
+                                    message: format!("Unknown vital sign code: {}", code),
+                                    data: None,
+                                };
+                                return Ok(warp::reply::json(&response));
+                            }
+                        };
+                        
+                        // Create VitalSigns object
+                        VitalSigns {
+                            vital_type,
+                            value: value_quantity.value,
+                            unit: value_quantity.unit.clone(),
+                            timestamp,
+                            patient_id,
+                            method,
+                            position,
+                            reliability,
+                        }
+                    } else if let Some(components) = &request.component {
+                        // Check if this is blood pressure (has systolic and diastolic)
+                        if code == "85354-9" && components.len() == 2 { // 85354-9 is BP panel
+                            // Find systolic and diastolic components
+                            let mut systolic = None;
+                            let mut diastolic = None;
+                            
+                            for component in components {
+                                let comp_code = &component.code.coding[0].code;
+                                if comp_code == "8480-6" { // Systolic
+                                    systolic = Some(component.valueQuantity.value);
+                                } else if comp_code == "8462-4" { // Diastolic
+                                    diastolic = Some(component.valueQuantity.value);
+                                }
+                            }
+                            
+                            if let (Some(sys), Some(dia)) = (systolic, diastolic) {
+                                // Get unit from first component
+                                let unit = components[0].valueQuantity.unit.clone();
+                                
+                                VitalSigns {
+                                    vital_type: VitalType::BloodPressure {
+                                        systolic: sys,
+                                        diastolic: dia,
+                                    },
+                                    value: sys, // Store systolic as the main value for consistency
+                                    unit,
+                                    timestamp,
+                                    patient_id,
+                                    method,
+                                    position,
+                                    reliability,
+                                }
+                            } else {
+                                let response = ApiResponse {
+                                    status: ResponseStatus::Error,
+                                    error_code: Some(ErrorCode::InvalidResource),
+                                    message: "Blood pressure must have both systolic and diastolic components".to_string(),
+                                    data: None,
+                                };
+                                return Ok(warp::reply::json(&response));
+                            }
+                        } else {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::InvalidResource),
+                                message: "Invalid component-based vital sign".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    } else {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidResource),
+                            message: "No valid vital sign value provided".to_string(),
+                            data: None,
+                        };
+                        return Ok(warp::reply::json(&response));
+                    };
+                    
+                    // Convert to records and store
+                    let mut records = vital_signs.to_records();
+                    let id = assign_resource_id(&mut records, None);
+                    println!("Storing vital signs with metric names: {:?}", 
+                            records.iter().map(|r| &r.metric_name).collect::<Vec<_>>());
+                    
+                    for record in records {
+                        if let Err(err) = query_engine.store_record(record) {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&err)),
+                                message: format!("Failed to store vital signs: {:?}", err),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    }
+                    
+                    let response = ApiResponse {
+                        status: ResponseStatus::Success,
+                        error_code: None,
+                        message: "Vital signs stored successfully".to_string(),
+                        data: Some(with_resource_id(serde_json::to_value(request).unwrap(), &id)),
+                    };
+                    Ok(warp::reply::json(&response))
                 }
-                
-                warp::reply::with_status(
-                    warp::reply::json(&json!({
-                        "status": "success",
-                        "message": "Debug settings applied"
-                    })),
-                    warp::http::StatusCode::OK,
-                )
             })
     }
-}
 
-// Helper function to parse ISO8601 timestamp to Unix timestamp
-fn parse_iso8601_to_unix(iso_time: &str) -> Result<i64, Box<dyn std::error::Error>> {
-    // This is a simplistic implementation
-    // In a real app, use a proper datetime crate
-    let timestamp = chrono::DateTime::parse_from_rfc3339(iso_time)?
-        .timestamp();
-    Ok(timestamp)
-}
-
-/// Helper function to transform a Record into an API-friendly response
-fn format_record_for_api(record: &Record) -> serde_json::Value {
-    // Extract components from metric name (format: "{patient_id}|{code}|{unit}")
-    let parts: Vec<&str> = record.metric_name.split('|').collect();
-    
-    // Extract patient ID, code, and unit
-    let patient_id = parts.get(0).unwrap_or(&"unknown");
+    fn post_condition(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        let strict_body = self.strict_body;
+        
+        warp::path!("fhir" / "Condition")
+            .and(warp::post())
+            .and(fhir_json::<ConditionRequest, _>(self.create_body(), "Condition", strict_body))
+            .and_then(move |request: ConditionRequest| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Validate resource type
+                    if request.resourceType != "Condition" {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidResourceType),
+                            message: "Invalid resource type".to_string(),
+                            data: None,
+                        };
+                        return Ok::<Json, Infallible>(warp::reply::json(&response));
+                    }
+                    
+                    // Parse onset timestamp
+                    let onset = match parse_iso8601_to_unix(&request.onsetDateTime) {
+                        Ok(ts) => ts,
+                        Err(_) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::InvalidTimestamp),
+                                message: "Invalid timestamp format".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    };
+                    
+                    // Extract patient ID
+                    let patient_id = request.subject.reference.replace("Patient/", "");
+                    
+                    // Extract condition code and status
+                    let (coding, status_coding) = match (request.code.coding.first(), request.clinicalStatus.coding.first()) {
+                        (Some(coding), Some(status_coding)) => (coding, status_coding),
+                        _ => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::InvalidResource),
+                                message: "Condition code and clinicalStatus must each have a coding".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    };
+                    
+                    let severity = request.severity.as_ref()
+                        .and_then(|s| s.coding.first())
+                        .map(|c| c.display.to_lowercase());
+                    
+                    // Create Condition
+                    let condition = Condition {
+                        code: coding.code.clone(),
+                        code_display: coding.display.clone(),
+                        clinical_status: status_coding.code.clone(),
+                        onset,
+                        patient_id,
+                        severity,
+                    };
+                    
+                    // Convert to records and store
+                    let mut records = condition.to_records();
+                    let id = assign_resource_id(&mut records, None);
+                    println!("Storing condition with metric name: {:?}", 
+                            records.iter().map(|r| &r.metric_name).collect::<Vec<_>>());
+                    
+                    for record in records {
+                        if let Err(err) = query_engine.store_record(record) {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&err)),
+                                message: format!("Failed to store condition: {:?}", err),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    }
+                    
+                    let response = ApiResponse {
+                        status: ResponseStatus::Success,
+                        error_code: None,
+                        message: "Condition stored successfully".to_string(),
+                        data: Some(with_resource_id(serde_json::to_value(request).unwrap(), &id)),
+                    };
+                    Ok(warp::reply::json(&response))
+                }
+            })
+    }
+
+    fn post_encounter(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        let strict_body = self.strict_body;
+        
+        warp::path!("fhir" / "Encounter")
+            .and(warp::post())
+            .and(fhir_json::<EncounterRequest, _>(self.create_body(), "Encounter", strict_body))
+            .and_then(move |request: EncounterRequest| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Validate resource type
+                    if request.resourceType != "Encounter" {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidResourceType),
+                            message: "Invalid resource type".to_string(),
+                            data: None,
+                        };
+                        return Ok::<Json, Infallible>(warp::reply::json(&response));
+                    }
+                    
+                    // Parse period bounds
+                    let period_start = parse_iso8601_to_unix(&request.period.start);
+                    let period_end = request.period.end.as_deref().map(parse_iso8601_to_unix).transpose();
+                    let (period_start, period_end) = match (period_start, period_end) {
+                        (Ok(start), Ok(end)) => (start, end),
+                        _ => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::InvalidTimestamp),
+                                message: "Invalid timestamp format".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    };
+                    
+                    if period_end.is_some_and(|end| end < period_start) {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidResource),
+                            message: "Encounter period end must not be before its start".to_string(),
+                            data: None,
+                        };
+                        return Ok(warp::reply::json(&response));
+                    }
+                    
+                    // Create Encounter
+                    let encounter = Encounter {
+                        class: request.class.code.clone(),
+                        status: request.status.clone(),
+                        period_start,
+                        period_end,
+                        patient_id: request.subject.reference.replace("Patient/", ""),
+                    };
+                    
+                    // Convert to records and store
+                    let mut records = encounter.to_records();
+                    let id = assign_resource_id(&mut records, None);
+                    println!("Storing encounter with metric name: {:?}", 
+                            records.iter().map(|r| &r.metric_name).collect::<Vec<_>>());
+                    
+                    for record in records {
+                        if let Err(err) = query_engine.store_record(record) {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&err)),
+                                message: format!("Failed to store encounter: {:?}", err),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    }
+                    
+                    let response = ApiResponse {
+                        status: ResponseStatus::Success,
+                        error_code: None,
+                        message: "Encounter stored successfully".to_string(),
+                        data: Some(with_resource_id(serde_json::to_value(request).unwrap(), &id)),
+                    };
+                    Ok(warp::reply::json(&response))
+                }
+            })
+    }
+
+    fn post_allergy_intolerance(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        let strict_body = self.strict_body;
+        
+        warp::path!("fhir" / "AllergyIntolerance")
+            .and(warp::post())
+            .and(fhir_json::<AllergyIntoleranceRequest, _>(self.create_body(), "AllergyIntolerance", strict_body))
+            .and_then(move |request: AllergyIntoleranceRequest| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Validate resource type
+                    if request.resourceType != "AllergyIntolerance" {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidResourceType),
+                            message: "Invalid resource type".to_string(),
+                            data: None,
+                        };
+                        return Ok::<Json, Infallible>(warp::reply::json(&response));
+                    }
+                    
+                    // Parse recorded timestamp
+                    let recorded = match parse_iso8601_to_unix(&request.recordedDate) {
+                        Ok(ts) => ts,
+                        Err(_) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::InvalidTimestamp),
+                                message: "Invalid timestamp format".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    };
+                    
+                    // Extract substance code
+                    let coding = match request.code.coding.first() {
+                        Some(coding) => coding,
+                        None => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::InvalidResource),
+                                message: "AllergyIntolerance code must have a coding".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    };
+                    
+                    // Create AllergyIntolerance
+                    let allergy = AllergyIntolerance {
+                        substance_code: coding.code.clone(),
+                        substance_display: coding.display.clone(),
+                        criticality: request.criticality.clone().unwrap_or_else(|| "unable-to-assess".to_string()),
+                        allergy_type: request.allergyType.clone().unwrap_or_else(|| "allergy".to_string()),
+                        recorded,
+                        patient_id: request.patient.reference.replace("Patient/", ""),
+                    };
+                    
+                    // Convert to records and store
+                    let mut records = allergy.to_records();
+                    let id = assign_resource_id(&mut records, None);
+                    println!("Storing allergy intolerance with metric name: {:?}", 
+                            records.iter().map(|r| &r.metric_name).collect::<Vec<_>>());
+                    
+                    for record in records {
+                        if let Err(err) = query_engine.store_record(record) {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&err)),
+                                message: format!("Failed to store allergy intolerance: {:?}", err),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    }
+                    
+                    let response = ApiResponse {
+                        status: ResponseStatus::Success,
+                        error_code: None,
+                        message: "Allergy intolerance stored successfully".to_string(),
+                        data: Some(with_resource_id(serde_json::to_value(request).unwrap(), &id)),
+                    };
+                    Ok(warp::reply::json(&response))
+                }
+            })
+    }
+
+    /// Endpoint for trend analysis
+    fn get_trend_analysis(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("timeseries" / "trend")
+            .and(warp::get())
+            .and(time_query(self.query_window))
+            .and_then(move |params: std::collections::HashMap<String, String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Parse parameters
+                    let resource_type = params.get("resource_type")
+                        .map(|s| s.to_string())
+                        .unwrap_or("Observation".to_string());
+                        
+                    let metric = params.get("metric")
+                        .map(|s| s.to_string())
+                        .unwrap_or("".to_string());
+                        
+                    let (start_time, end_time) = query_bounds(&params);
+                    
+                    if metric.is_empty() {
+                        // If no specific metric, do resource-wide analysis
+                        let pattern = params.get("pattern").map(|s| s.to_string()).unwrap_or("".to_string());
+                        
+                        match query_engine.calculate_trend_by_resource(&resource_type, &pattern, start_time, end_time) {
+                            Ok(trends) => {
+                                let response = ApiResponse {
+                                    status: ResponseStatus::Success,
+                                    error_code: None,
+                                    message: format!("Found trend analysis for {} metrics", trends.len()),
+                                    data: Some(serde_json::to_value(trends).unwrap()),
+                                };
+                                Ok::<Json, Infallible>(warp::reply::json(&response))
+                            },
+                            Err(e) => {
+                                let response = ApiResponse {
+                                    status: ResponseStatus::Error,
+                                    error_code: Some(ErrorCode::from(&e)),
+                                    message: format!("Failed to calculate trends: {:?}", e),
+                                    data: None,
+                                };
+                                Ok(warp::reply::json(&response))
+                            }
+                        }
+                    } else {
+                        // Specific metric trend analysis
+                        match query_engine.calculate_trend(&metric, start_time, end_time) {
+                            Ok(trend) => {
+                                let response = ApiResponse {
+                                    status: ResponseStatus::Success,
+                                    error_code: None,
+                                    message: format!("Trend analysis for metric: {}", metric),
+                                    data: Some(serde_json::to_value(trend).unwrap()),
+                                };
+                                Ok::<Json, Infallible>(warp::reply::json(&response))
+                            },
+                            Err(e) => {
+                                let response = ApiResponse {
+                                    status: ResponseStatus::Error,
+                                    error_code: Some(ErrorCode::from(&e)),
+                                    message: format!("Failed to calculate trend: {:?}", e),
+                                    data: None,
+                                };
+                                Ok(warp::reply::json(&response))
+                            }
+                        }
+                    }
+                }
+            })
+    }
+    
+    /// Number of records `metric_range_reply` would return, without their bodies
+    fn get_count(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("timeseries" / "count")
+            .and(warp::get())
+            .and(time_query(self.query_window))
+            .and_then(move |params: std::collections::HashMap<String, String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Required parameter: metric
+                    let metric = match params.get("metric") {
+                        Some(m) => m.to_string(),
+                        None => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::MissingParameter),
+                                message: "Missing required parameter: metric".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    };
+                    
+                    // Parse time parameters
+                    let (start_time, end_time) = query_bounds(&params);
+                    
+                    let response = match query_engine.count_range(&metric, start_time, end_time) {
+                        Ok(count) => ApiResponse {
+                            status: ResponseStatus::Success,
+                            error_code: None,
+                            message: format!("Counted {} records for metric: {}", count, metric),
+                            data: Some(json!(count)),
+                        },
+                        Err(e) => ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::from(&e)),
+                            message: format!("Error counting records: {}", e),
+                            data: None,
+                        },
+                    };
+                    Ok::<Json, Infallible>(warp::reply::json(&response))
+                }
+            })
+    }
+
+    /// `/fhir/timeseries?metric=` under its older path; both share `metric_range_reply`
+    fn get_range(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("timeseries" / "range")
+            .and(warp::get())
+            .and(time_query(self.query_window))
+            .and(warp::header::optional::<String>("if-none-match"))
+            .map(move |params: std::collections::HashMap<String, String>, if_none_match: Option<String>| {
+                metric_range_reply(&query_engine, &params, if_none_match.as_deref())
+            })
+    }
+
+    /// Several metrics aggregated onto a shared time grid, e.g. for feature extraction
+    fn post_matrix(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        let query_window = self.query_window;
+        
+        warp::path!("timeseries" / "matrix")
+            .and(warp::post())
+            .and(warp::query::<std::collections::HashMap<String, String>>())
+            .and(warp::body::json())
+            .and_then(move |params: std::collections::HashMap<String, String>, request: MatrixRequest| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    if let Err(e) = query_window.check_span(request.start, request.end, &params) {
+                        return Ok::<_, Infallible>(bad_request_reply(e));
+                    }
+                    let interval = match &request.interval {
+                        serde_json::Value::Number(n) => n.as_u64().ok_or_else(|| format!("Invalid interval '{}'", n)),
+                        serde_json::Value::String(s) => parse_interval(s),
+                        other => Err(format!("Invalid interval '{}'", other)),
+                    };
+                    let interval = match interval {
+                        Ok(interval) => std::time::Duration::from_secs(interval),
+                        Err(message) => return Ok(bad_request_reply(QueryError::InvalidParameter(message))),
+                    };
+                    
+                    let aggregation = request.aggregation.unwrap_or(Aggregation::Mean);
+                    match query_engine.aligned_matrix(&request.metrics, request.start, request.end, interval, &aggregation) {
+                        Ok(matrix) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Aligned {} metrics on {} timestamps",
+                                                 matrix.columns.len(), matrix.timestamps.len()),
+                                data: Some(serde_json::to_value(matrix).unwrap()),
+                            };
+                            Ok(warp::reply::json(&response).into_response())
+                        }
+                        Err(e) => Ok(bad_request_reply(e)),
+                    }
+                }
+            })
+    }
+
+    /// Endpoint for querying several metrics in one round-trip
+    fn post_batch_query(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        let query_window = self.query_window;
+        
+        warp::path!("timeseries" / "batch")
+            .and(warp::post())
+            .and(warp::query::<std::collections::HashMap<String, String>>())
+            .and(warp::body::json())
+            .and_then(move |params: std::collections::HashMap<String, String>, request: BatchQueryRequest| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    let invalid = if request.metrics.is_empty() {
+                        Some("At least one metric is required")
+                    } else if request.interval == Some(0) {
+                        Some("Interval must be at least one second")
+                    } else {
+                        None
+                    };
+                    if let Some(message) = invalid {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidParameter),
+                            message: message.to_string(),
+                            data: None,
+                        };
+                        return Ok::<_, Infallible>(warp::reply::json(&response).into_response());
+                    }
+                    if let Err(e) = query_window.check_span(request.start, request.end, &params) {
+                        return Ok(bad_request_reply(e));
+                    }
+                    
+                    let query = TimeSeriesQuery {
+                        start_time: request.start,
+                        end_time: request.end,
+                        metrics: request.metrics,
+                        aggregation: request.aggregation,
+                        interval: request.interval.map(std::time::Duration::from_secs),
+                        timeout: None,
+                    };
+                    
+                    let response = match query_engine.query_batch(&query) {
+                        Ok(results) => {
+                            let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+                            let per_metric: serde_json::Map<String, serde_json::Value> = results.into_iter()
+                                .map(|(metric, result)| {
+                                    let entry = match result {
+                                        Ok(records) => json!({
+                                            "status": "success",
+                                            "records": format_records_for_api(&records),
+                                        }),
+                                        Err(e) => json!({
+                                            "status": "error",
+                                            "message": e.to_string(),
+                                        }),
+                                    };
+                                    (metric, entry)
+                                })
+                                .collect();
+                            ApiResponse {
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Queried {} metrics, {} failed", per_metric.len(), failed),
+                                data: Some(serde_json::Value::Object(per_metric)),
+                            }
+                        },
+                        Err(e) => ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::from(&e)),
+                            message: format!("Failed to run batch query: {:?}", e),
+                            data: None,
+                        },
+                    };
+                    
+                    Ok(warp::reply::json(&response).into_response())
+                }
+            })
+    }
+
+    /// Endpoint for statistics
+    fn get_stats(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("timeseries" / "stats")
+            .and(warp::get())
+            .and(time_query(self.query_window))
+            .and_then(move |params: std::collections::HashMap<String, String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Required parameter: metric
+                    let metric = match params.get("metric") {
+                        Some(m) => m.to_string(),
+                        None => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::MissingParameter),
+                                message: "Missing required parameter: metric".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    };
+                    
+                    // Parse time parameters
+                    let (start_time, end_time) = query_bounds(&params);
+                    
+                    // Calculate statistics
+                    let timeout = timeout_from_params(&params);
+                    match query_engine.calculate_stats(&metric, start_time, end_time, timeout) {
+                        Ok(stats) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Statistics for metric: {}", metric),
+                                data: Some(serde_json::to_value(stats).unwrap()),
+                            };
+                            Ok::<Json, Infallible>(warp::reply::json(&response))
+                        },
+                        Err(e) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Failed to calculate statistics: {:?}", e),
+                                data: None,
+                            };
+                            Ok(warp::reply::json(&response))
+                        }
+                    }
+                }
+            })
+    }
+    
+    /// Endpoint for statistics per time bucket, e.g. hourly box plots
+    fn get_stats_bucketed(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("timeseries" / "stats" / "bucketed")
+            .and(warp::get())
+            .and(time_query(self.query_window))
+            .and_then(move |params: std::collections::HashMap<String, String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Required parameter: metric
+                    let metric = match params.get("metric") {
+                        Some(m) => m.to_string(),
+                        None => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::MissingParameter),
+                                message: "Missing required parameter: metric".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response).into_response());
+                        }
+                    };
+                    
+                    // Parse time parameters
+                    let (start_time, end_time) = query_bounds(&params);
+                    
+                    // Bucket width, defaults to hourly buckets
+                    let interval = match interval_param(&params, &[]) {
+                        Ok(interval) => interval.unwrap_or(3600),
+                        Err(e) => return Ok(bad_request_reply(e)),
+                    };
+                    
+                    match query_engine.calculate_stats_bucketed(&metric, start_time, end_time, std::time::Duration::from_secs(interval)) {
+                        Ok(buckets) => {
+                            let buckets: Vec<serde_json::Value> = buckets.into_iter()
+                                .map(|(bucket_start, stats)| json!({ "bucket_start": bucket_start, "stats": stats }))
+                                .collect();
+                            let response = ApiResponse {
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Statistics for {} buckets of metric: {}", buckets.len(), metric),
+                                data: Some(serde_json::to_value(buckets).unwrap()),
+                            };
+                            Ok::<_, Infallible>(warp::reply::json(&response).into_response())
+                        },
+                        Err(e) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Failed to calculate statistics: {:?}", e),
+                                data: None,
+                            };
+                            Ok(warp::reply::json(&response).into_response())
+                        }
+                    }
+                }
+            })
+    }
+    
+    /// Endpoint for outlier detection
+    fn get_outliers(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("timeseries" / "outliers")
+            .and(warp::get())
+            .and(time_query(self.query_window))
+            .and_then(move |params: std::collections::HashMap<String, String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Required parameter: metric
+                    let metric = match params.get("metric") {
+                        Some(m) => m.to_string(),
+                        None => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::MissingParameter),
+                                message: "Missing required parameter: metric".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    };
+                    
+                    // Parse time parameters
+                    let (start_time, end_time) = query_bounds(&params);
+                    
+                    // Parse threshold
+                    let threshold = params.get("threshold")
+                        .and_then(|s| s.parse::<f64>().ok())
+                        .unwrap_or(2.0); // Default Z-score threshold of 2.0
+                    
+                    // Scoring method, z-score unless `method=mad`
+                    let method = match params.get("method").map(|m| m.parse::<OutlierMethod>()) {
+                        None => OutlierMethod::default(),
+                        Some(Ok(method)) => method,
+                        Some(Err(e)) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::InvalidParameter),
+                                message: e,
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    };
+                    
+                    // Detect outliers
+                    let timeout = timeout_from_params(&params);
+                    match query_engine.detect_outliers(&metric, start_time, end_time, threshold, method, timeout) {
+                        Ok(outliers) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Found {} outliers for metric: {}", outliers.outliers.len(), metric),
+                                data: Some(serde_json::to_value(outliers).unwrap()),
+                            };
+                            Ok::<Json, Infallible>(warp::reply::json(&response))
+                        },
+                        Err(e) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Failed to detect outliers: {:?}", e),
+                                data: None,
+                            };
+                            Ok(warp::reply::json(&response))
+                        }
+                    }
+                }
+            })
+    }
+    
+    /// Endpoint for rate of change calculation
+    fn get_rate_of_change(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("timeseries" / "rate")
+            .and(warp::get())
+            .and(time_query(self.query_window))
+            .and_then(move |params: std::collections::HashMap<String, String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Required parameter: metric
+                    let metric = match params.get("metric") {
+                        Some(m) => m.to_string(),
+                        None => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::MissingParameter),
+                                message: "Missing required parameter: metric".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response).into_response());
+                        }
+                    };
+                    
+                    // Parse time parameters
+                    let (start_time, end_time) = query_bounds(&params);
+                    
+                    // Period rates are expressed per, defaults to hourly rates
+                    let period = match interval_param(&params, &["period"]) {
+                        Ok(period) => period.map_or(3600, interval_seconds_i64),
+                        Err(e) => return Ok(bad_request_reply(e)),
+                    };
+                    
+                    // Optional fixed grid, one rate per bucket
+                    let bucket = match params.get("bucket").map(|s| parse_interval(s)) {
+                        None => None,
+                        Some(Ok(bucket)) => Some(interval_seconds_i64(bucket)),
+                        Some(Err(e)) => return Ok(bad_request_reply(QueryError::InvalidParameter(e))),
+                    };
+                    
+                    // Calculate rate of change
+                    match query_engine.calculate_rate_of_change(&metric, start_time, end_time, period, bucket) {
+                        Ok(rates) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Calculated {} rate points for metric: {}", rates.len(), metric),
+                                data: Some(serde_json::to_value(format_records_for_api(&rates)).unwrap()),
+                            };
+                            Ok::<_, Infallible>(warp::reply::json(&response).into_response())
+                        },
+                        Err(e) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Failed to calculate rate of change: {:?}", e),
+                                data: None,
+                            };
+                            Ok(warp::reply::json(&response).into_response())
+                        }
+                    }
+                }
+            })
+    }
+
+    /// Savitzky-Golay smoothed copy of a metric
+    fn get_smooth(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("timeseries" / "smooth")
+            .and(warp::get())
+            .and(time_query(self.query_window))
+            .and_then(move |params: std::collections::HashMap<String, String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    let metric = match params.get("metric") {
+                        Some(m) => m.to_string(),
+                        None => {
+                            return Ok::<_, Infallible>(bad_request_reply(QueryError::InvalidParameter(
+                                "Missing required parameter: metric".to_string()
+                            )));
+                        }
+                    };
+                    
+                    let (start_time, end_time) = query_bounds(&params);
+                    
+                    let window = match params.get("window").map(|s| s.parse::<usize>()) {
+                        None => 5,
+                        Some(Ok(window)) => window,
+                        Some(Err(_)) => {
+                            return Ok(bad_request_reply(QueryError::InvalidParameter(
+                                format!("Invalid window: {}", params["window"])
+                            )));
+                        }
+                    };
+                    let order = match params.get("order").map(|s| s.parse::<usize>()) {
+                        None => 2,
+                        Some(Ok(order)) => order,
+                        Some(Err(_)) => {
+                            return Ok(bad_request_reply(QueryError::InvalidParameter(
+                                format!("Invalid order: {}", params["order"])
+                            )));
+                        }
+                    };
+                    
+                    match query_engine.smooth(&metric, start_time, end_time, window, order) {
+                        Ok(smoothed) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Smoothed {} points for metric: {}", smoothed.len(), metric),
+                                data: Some(serde_json::to_value(format_records_for_api(&smoothed)).unwrap()),
+                            };
+                            Ok(warp::reply::json(&response).into_response())
+                        },
+                        Err(e @ QueryError::InvalidParameter(_)) => Ok(bad_request_reply(e)),
+                        Err(e) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Failed to smooth metric: {:?}", e),
+                                data: None,
+                            };
+                            Ok(warp::reply::json(&response).into_response())
+                        }
+                    }
+                }
+            })
+    }
+
+    /// Points whose rate of change exceeds `max_rate`, with the records each rate came from
+    fn get_rate_alerts(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("timeseries" / "alerts" / "rate")
+            .and(warp::get())
+            .and(time_query(self.query_window))
+            .and_then(move |params: std::collections::HashMap<String, String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Required parameters: metric and max_rate
+                    let (metric, max_rate) = match (
+                        params.get("metric"),
+                        params.get("max_rate").and_then(|s| s.parse::<f64>().ok()),
+                    ) {
+                        (Some(metric), Some(max_rate)) => (metric.to_string(), max_rate),
+                        _ => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::MissingParameter),
+                                message: "Missing or invalid required parameters: metric, max_rate".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response).into_response());
+                        }
+                    };
+                    
+                    let (start_time, end_time) = query_bounds(&params);
+                    
+                    // Defaults to per-minute rates
+                    let period = match interval_param(&params, &["period"]) {
+                        Ok(period) => period.map_or(60, interval_seconds_i64),
+                        Err(e) => return Ok(bad_request_reply(e)),
+                    };
+                    
+                    match query_engine.rate_threshold_breaches(&metric, start_time, end_time, period, max_rate) {
+                        Ok(breaches) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Found {} rate breaches for metric: {}", breaches.len(), metric),
+                                data: Some(serde_json::to_value(breaches).unwrap()),
+                            };
+                            Ok::<_, Infallible>(warp::reply::json(&response).into_response())
+                        },
+                        Err(e) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Failed to check rate alerts: {}", e),
+                                data: None,
+                            };
+                            Ok(warp::reply::json(&response).into_response())
+                        }
+                    }
+                }
+            })
+    }
+
+    /// Endpoint for autocorrelation / period discovery
+    fn get_autocorrelation(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("timeseries" / "acf")
+            .and(warp::get())
+            .and(time_query(self.query_window))
+            .and_then(move |params: std::collections::HashMap<String, String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Required parameter: metric
+                    let metric = match params.get("metric") {
+                        Some(m) => m.to_string(),
+                        None => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::MissingParameter),
+                                message: "Missing required parameter: metric".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    };
+                    
+                    // Parse time parameters
+                    let (start_time, end_time) = query_bounds(&params);
+                    
+                    // Defaults to half the number of points
+                    let max_lag = params.get("max_lag")
+                        .and_then(|s| s.parse::<usize>().ok());
+                    
+                    match query_engine.autocorrelation(&metric, start_time, end_time, max_lag) {
+                        Ok(result) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Autocorrelation for metric: {}", metric),
+                                data: Some(serde_json::to_value(result).unwrap()),
+                            };
+                            Ok::<Json, Infallible>(warp::reply::json(&response))
+                        },
+                        Err(e) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Failed to calculate autocorrelation: {:?}", e),
+                                data: None,
+                            };
+                            Ok(warp::reply::json(&response))
+                        }
+                    }
+                }
+            })
+    }
+
+    /// Endpoint for a metric's value distribution
+    fn get_histogram(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("timeseries" / "histogram")
+            .and(warp::get())
+            .and(time_query(self.query_window))
+            .and_then(move |params: std::collections::HashMap<String, String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Required parameter: metric
+                    let metric = match params.get("metric") {
+                        Some(m) => m.to_string(),
+                        None => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::MissingParameter),
+                                message: "Missing required parameter: metric".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    };
+                    
+                    // Parse time parameters
+                    let (start_time, end_time) = query_bounds(&params);
+                    
+                    let bins = params.get("bins")
+                        .and_then(|s| s.parse::<usize>().ok())
+                        .filter(|&b| b > 0)
+                        .unwrap_or(10);
+                    
+                    match query_engine.histogram(&metric, start_time, end_time, bins) {
+                        Ok(buckets) => {
+                            let buckets: Vec<serde_json::Value> = buckets.iter()
+                                .map(|(low, high, count)| json!({ "low": low, "high": high, "count": count }))
+                                .collect();
+                            let response = ApiResponse {
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Histogram with {} buckets for metric: {}", buckets.len(), metric),
+                                data: Some(serde_json::to_value(buckets).unwrap()),
+                            };
+                            Ok::<Json, Infallible>(warp::reply::json(&response))
+                        },
+                        Err(e) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Failed to calculate histogram: {:?}", e),
+                                data: None,
+                            };
+                            Ok(warp::reply::json(&response))
+                        }
+                    }
+                }
+            })
+    }
+
+    /// Endpoint for stuck-sensor detection
+    fn get_flatline(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("timeseries" / "flatline")
+            .and(warp::get())
+            .and(time_query(self.query_window))
+            .and_then(move |params: std::collections::HashMap<String, String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Required parameter: metric
+                    let metric = match params.get("metric") {
+                        Some(m) => m.to_string(),
+                        None => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::MissingParameter),
+                                message: "Missing required parameter: metric".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    };
+                    
+                    // Parse time parameters
+                    let (start_time, end_time) = query_bounds(&params);
+                    
+                    // Seconds a value must stay unchanged, defaults to 10 minutes
+                    let min_duration = params.get("min_duration")
+                        .and_then(|s| s.parse::<i64>().ok())
+                        .filter(|&d| d >= 0)
+                        .unwrap_or(600);
+                    
+                    match query_engine.detect_flatline(&metric, start_time, end_time, min_duration) {
+                        Ok(runs) => {
+                            let runs: Vec<serde_json::Value> = runs.iter()
+                                .map(|(start, end, value)| json!({ "start": start, "end": end, "value": value }))
+                                .collect();
+                            let response = ApiResponse {
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Found {} flatline runs for metric: {}", runs.len(), metric),
+                                data: Some(serde_json::to_value(runs).unwrap()),
+                            };
+                            Ok::<Json, Infallible>(warp::reply::json(&response))
+                        },
+                        Err(e) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Failed to detect flatlines: {:?}", e),
+                                data: None,
+                            };
+                            Ok(warp::reply::json(&response))
+                        }
+                    }
+                }
+            })
+    }
+
+    /// Endpoint for a pairwise correlation matrix between metrics
+    fn post_correlation(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("timeseries" / "correlation")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and_then(move |request: CorrelationRequest| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    if request.metrics.is_empty() {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::MissingParameter),
+                            message: "At least one metric is required".to_string(),
+                            data: None,
+                        };
+                        return Ok::<Json, Infallible>(warp::reply::json(&response));
+                    }
+                    
+                    match query_engine.correlation_matrix(&request.metrics, request.start, request.end) {
+                        Ok(matrix) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Correlation matrix for {} metrics", request.metrics.len()),
+                                data: Some(json!({
+                                    "metrics": request.metrics,
+                                    "matrix": matrix,
+                                })),
+                            };
+                            Ok(warp::reply::json(&response))
+                        },
+                        Err(e) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Failed to calculate correlation matrix: {:?}", e),
+                                data: None,
+                            };
+                            Ok(warp::reply::json(&response))
+                        }
+                    }
+                }
+            })
+    }
+
+    /// Endpoint for changepoint detection with the active detection config
+    fn get_changepoints(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("timeseries" / "changepoints")
+            .and(warp::get())
+            .and(time_query(self.query_window))
+            .and_then(move |params: std::collections::HashMap<String, String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Required parameter: metric
+                    let metric = match params.get("metric") {
+                        Some(m) => m.to_string(),
+                        None => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::MissingParameter),
+                                message: "Missing required parameter: metric".to_string(),
+                                data: None,
+                            };
+                            return Ok(warp::reply::json(&response));
+                        }
+                    };
+                    
+                    // Parse time parameters
+                    let (start_time, end_time) = query_bounds(&params);
+                    
+                    match query_engine.detect_changepoints(&metric, start_time, end_time) {
+                        Ok(result) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Success,
+                                error_code: None,
+                                message: format!("Found {} changepoints for metric: {}", result.changepoints.len(), metric),
+                                data: Some(serde_json::to_value(result).unwrap()),
+                            };
+                            Ok::<Json, Infallible>(warp::reply::json(&response))
+                        },
+                        Err(e) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::from(&e)),
+                                message: format!("Failed to detect changepoints: {:?}", e),
+                                data: None,
+                            };
+                            Ok(warp::reply::json(&response))
+                        }
+                    }
+                }
+            })
+    }
+
+    /// Endpoint for swapping the pattern detection config at runtime
+    fn post_detection_config(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("config" / "detection")
+            .and(warp::post())
+            .and(warp::body::json())
+            .map(move |config: DetectionConfig| {
+                match query_engine.set_detection_config(config) {
+                    Ok(()) => {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Success,
+                            error_code: None,
+                            message: "Detection config updated".to_string(),
+                            data: None,
+                        };
+                        warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::OK)
+                    },
+                    Err(e) => {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidParameter),
+                            message: format!("Invalid detection config: {}", e),
+                            data: None,
+                        };
+                        warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::BAD_REQUEST)
+                    }
+                }
+            })
+    }
+
+    /// Endpoint for taking a point-in-time backup of the store
+    fn post_snapshot(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("admin" / "snapshot")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and_then(move |request: SnapshotRequest| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Copying chunk files is blocking disk work
+                    let destination = request.destination.clone();
+                    let result = tokio::task::spawn_blocking(move || {
+                        query_engine.snapshot(std::path::Path::new(&destination))
+                    }).await;
+                    
+                    let response = match result {
+                        Ok(Ok(())) => ApiResponse {
+                            status: ResponseStatus::Success,
+                            error_code: None,
+                            message: format!("Snapshot written to {}", request.destination),
+                            data: Some(json!({ "destination": request.destination })),
+                        },
+                        Ok(Err(e)) => ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::from(&e)),
+                            message: format!("Failed to take snapshot: {}", e),
+                            data: None,
+                        },
+                        Err(e) => ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::Internal),
+                            message: format!("Snapshot task failed: {}", e),
+                            data: None,
+                        },
+                    };
+                    Ok::<Json, Infallible>(warp::reply::json(&response))
+                }
+            })
+    }
+
+    /// Endpoint for checking every persisted chunk for corruption
+    fn get_verify(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("admin" / "verify")
+            .and(warp::get())
+            .and_then(move || {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Every chunk is read back from disk
+                    let result = tokio::task::spawn_blocking(move || query_engine.verify_integrity()).await;
+                    
+                    let response = match result {
+                        Ok(Ok(report)) => ApiResponse {
+                            status: if report.is_ok() { ResponseStatus::Success } else { ResponseStatus::Error },
+                            error_code: (!report.is_ok()).then_some(ErrorCode::CorruptChunk),
+                            message: format!(
+                                "Checked {} chunks, {} failed validation",
+                                report.chunks_checked, report.failures.len()
+                            ),
+                            data: Some(serde_json::to_value(report).unwrap()),
+                        },
+                        Ok(Err(e)) => ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::StorageFailure),
+                            message: format!("Failed to verify chunks: {}", e),
+                            data: None,
+                        },
+                        Err(e) => ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::Internal),
+                            message: format!("Verification task failed: {}", e),
+                            data: None,
+                        },
+                    };
+                    Ok::<Json, Infallible>(warp::reply::json(&response))
+                }
+            })
+    }
+
+    /// Endpoint for forcing dirty chunks to disk without a restart
+    fn post_flush(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("admin" / "flush")
+            .and(warp::post())
+            .and_then(move || {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    if !query_engine.persistence_active() {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::PersistenceDisabled),
+                            message: "Persistence is disabled, nothing to flush".to_string(),
+                            data: None,
+                        };
+                        return Ok::<Json, Infallible>(warp::reply::json(&response));
+                    }
+                    
+                    // Chunk writes and the WAL truncation are blocking disk work
+                    let result = tokio::task::spawn_blocking(move || query_engine.flush()).await;
+                    
+                    let response = match result {
+                        Ok(Ok(report)) => ApiResponse {
+                            status: ResponseStatus::Success,
+                            error_code: None,
+                            message: format!("Flushed {} dirty chunks", report.chunks_flushed),
+                            data: Some(serde_json::to_value(report).unwrap()),
+                        },
+                        Ok(Err(e)) => ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::StorageFailure),
+                            message: format!("Failed to flush: {}", e),
+                            data: None,
+                        },
+                        Err(e) => ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::Internal),
+                            message: format!("Flush task failed: {}", e),
+                            data: None,
+                        },
+                    };
+                    Ok::<Json, Infallible>(warp::reply::json(&response))
+                }
+            })
+    }
+
+    /// Merge neighbouring under-full chunks, see `StorageEngine::compact`
+    fn post_compact(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("admin" / "compact")
+            .and(warp::post())
+            .and_then(move || {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Rewriting chunk files is blocking disk work
+                    let result = tokio::task::spawn_blocking(move || query_engine.compact()).await;
+                    
+                    let response = match result {
+                        Ok(Ok(report)) => ApiResponse {
+                            status: ResponseStatus::Success,
+                            error_code: None,
+                            message: format!("Compacted {} chunks into {}", report.chunks_before, report.chunks_after),
+                            data: Some(serde_json::to_value(report).unwrap()),
+                        },
+                        Ok(Err(e)) => ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::StorageFailure),
+                            message: format!("Failed to compact: {}", e),
+                            data: None,
+                        },
+                        Err(e) => ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::Internal),
+                            message: format!("Compaction task failed: {}", e),
+                            data: None,
+                        },
+                    };
+                    Ok::<Json, Infallible>(warp::reply::json(&response))
+                }
+            })
+    }
+
+    fn post_bundle(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        let conditional_creates = Arc::clone(&self.conditional_creates);
+        let strict_body = self.strict_body;
+        
+        warp::path!("fhir")
+            .and(warp::post())
+            .and(fhir_json::<FHIRBundle, _>(self.create_body(), "Bundle", strict_body))
+            .and_then(move |bundle: FHIRBundle| {
+                let query_engine = Arc::clone(&query_engine);
+                let conditional_creates = Arc::clone(&conditional_creates);
+                async move {
+                    // Verify this is a Bundle
+                    if bundle.resourceType != "Bundle" {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidResourceType),
+                            message: "Expected a FHIR Bundle".to_string(),
+                            data: None,
+                        };
+                        return Ok::<Json, Infallible>(warp::reply::json(&response));
+                    }
+                    
+                    let mut processed_count = 0;
+                    let mut errors = Vec::new();
+                    let mut records_to_store: Vec<Record> = Vec::new();
+                    let mut record_entries: Vec<usize> = Vec::new(); // Bundle entry of each record
+                    
+                    // Conditional entries keep other conditional creates out until the batch is stored
+                    let _conditional = bundle.entry.iter()
+                        .any(|entry| entry.request.ifNoneExist.is_some())
+                        .then(|| conditional_creates.lock().unwrap());
+                    
+                    // Process each entry in the bundle
+                    for (entry_index, entry) in bundle.entry.into_iter().enumerate() {
+                        // Check if this is an Observation POST
+                        if let Some(resource_type) = entry.resource.get("resourceType").and_then(|v| v.as_str()) {
+                            if resource_type == "Observation" && entry.request.method == "POST" {
+                                // A conditional create is already satisfied by a matching observation
+                                if let Some(criteria) = &entry.request.ifNoneExist {
+                                    match find_existing_observation(&query_engine, criteria) {
+                                        Ok(Some(_)) => {
+                                            processed_count += 1;
+                                            continue;
+                                        }
+                                        Ok(None) => {}
+                                        Err(e) => {
+                                            errors.push(format!("Entry {}: {}", entry_index, e));
+                                            continue;
+                                        }
+                                    }
+                                }
+                                
+                                // Parse the observation
+                                match serde_json::from_value::<FHIRObservationRequest>(entry.resource.clone()) {
+                                    Ok(observation) => {
+                                        // Parse the timestamp
+                                        match parse_iso8601_to_unix(&observation.effectiveDateTime) {
+                                            Ok(timestamp) => {
+                                                // Create the appropriate FHIR Observation
+                                                let fhir_observation = observation_from_request(&observation, timestamp);
+                                                
+                                                if let Some(obs) = fhir_observation {
+                                                    // Convert to records and store in batch
+                                                    let mut new_records = obs.to_records();
+                                                    assign_resource_id(&mut new_records, observation.id.clone());
+                                                    record_entries.extend(std::iter::repeat_n(entry_index, new_records.len()));
+                                                    records_to_store.extend(new_records);
+                                                    processed_count += 1;
+                                                } else {
+                                                    errors.push("No valid observation value provided".to_string());
+                                                }
+                                            },
+                                            Err(_) => {
+                                                errors.push("Invalid timestamp format".to_string());
+                                            }
+                                        }
+                                    },
+                                    Err(e) => {
+                                        errors.push(format!("Failed to parse observation: {}", e));
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    
+                    // Store all records in a single batch operation
+                    if !records_to_store.is_empty() {
+                        match query_engine.store_records_partial(records_to_store) {
+                            Ok(result) => {
+                                let mut failed_entries = std::collections::BTreeSet::new();
+                                for (position, error) in result.failed {
+                                    let entry_index = record_entries[position];
+                                    if failed_entries.insert(entry_index) {
+                                        errors.push(format!("Entry {}: failed to store record: {}", entry_index, error));
+                                    }
+                                }
+                                processed_count -= failed_entries.len();
+                            }
+                            Err(err) => errors.push(format!("Failed to store some records: {:?}", err)),
+                        }
+                    }
+                    
+                    let response = ApiResponse {
+                        status: if errors.is_empty() { ResponseStatus::Success } else { ResponseStatus::Partial },
+                        error_code: None,
+                        message: format!("Processed {} observations with {} errors", processed_count, errors.len()),
+                        data: if errors.is_empty() { 
+                            None 
+                        } else { 
+                            Some(serde_json::to_value(errors).unwrap()) 
+                        },
+                    };
+                    
+                    Ok::<Json, Infallible>(warp::reply::json(&response))
+                }
+            })
+    }
+
+    /// Store records already in EmberDB's internal model, skipping FHIR parsing
+    fn post_internal_records(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("internal" / "records")
+            .and(warp::post())
+            .and(fhir_json::<Vec<Record>, _>(self.create_body(), "Record", false))
+            .and_then(move |records: Vec<Record>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    if let Some(position) = records.iter().position(|r| r.metric_name.is_empty() || r.resource_type.is_empty()) {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidResource),
+                            message: format!("Record {}: metric_name and resource_type must not be empty", position),
+                            data: None,
+                        };
+                        return Ok::<Json, Infallible>(warp::reply::json(&response));
+                    }
+                    
+                    let total = records.len();
+                    let response = match query_engine.store_records_partial(records) {
+                        Ok(result) if result.failed.is_empty() => ApiResponse {
+                            status: ResponseStatus::Success,
+                            error_code: None,
+                            message: format!("Stored {} records", result.inserted),
+                            data: None,
+                        },
+                        Ok(result) => ApiResponse {
+                            status: ResponseStatus::Partial,
+                            error_code: None,
+                            message: format!("Stored {} of {} records", result.inserted, total),
+                            data: Some(json!(result.failed.iter()
+                                .map(|(position, error)| format!("Record {}: {}", position, error))
+                                .collect::<Vec<_>>())),
+                        },
+                        Err(e) => ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::from(&e)),
+                            message: format!("Failed to store records: {}", e),
+                            data: None,
+                        },
+                    };
+                    Ok::<Json, Infallible>(warp::reply::json(&response))
+                }
+            })
+    }
+
+    /// Export a metric over a time range as CSV
+    fn export_csv(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("timeseries" / "export.csv")
+            .and(warp::get())
+            .and(time_query(self.query_window))
+            .and_then(move |params: std::collections::HashMap<String, String>| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    // Required parameter: metric
+                    let metric = match params.get("metric") {
+                        Some(m) => m.to_string(),
+                        None => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::MissingParameter),
+                                message: "Missing required parameter: metric".to_string(),
+                                data: None,
+                            };
+                            return Ok::<_, Infallible>(warp::reply::json(&response).into_response());
+                        }
+                    };
+                    
+                    // Parse time parameters
+                    let (start_time, end_time) = query_bounds(&params);
+                    
+                    if start_time >= end_time {
+                        let response = ApiResponse {
+                            status: ResponseStatus::Error,
+                            error_code: Some(ErrorCode::InvalidTimeRange),
+                            message: "Start time must be before end time".to_string(),
+                            data: None,
+                        };
+                        return Ok(warp::reply::json(&response).into_response());
+                    }
+                    
+                    let include_context = params.get("include_context").is_some_and(|v| v == "true");
+                    let header = if include_context {
+                        "timestamp,iso_date,value,context\n"
+                    } else {
+                        "timestamp,iso_date,value\n"
+                    };
+                    
+                    let body = stream_records_body(
+                        query_engine, vec![metric.clone()], start_time, end_time,
+                        Some(header.to_string()),
+                        move |record| format_record_as_csv(record, include_context).into_bytes(),
+                    );
+                    
+                    let filename: String = metric.chars()
+                        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+                        .collect();
+                    let response = warp::http::Response::builder()
+                        .header("Content-Type", "text/csv; charset=utf-8")
+                        .header("Content-Disposition", format!("attachment; filename=\"{}.csv\"", filename))
+                        .body(body)
+                        .unwrap();
+                    Ok(response)
+                }
+            })
+    }
+
+    /// Bulk import of newline-delimited FHIR resources
+    fn post_import(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("fhir" / "$import")
+            .and(warp::post())
+            .and(self.create_body())
+            .and_then(move |body: warp::hyper::body::Bytes| {
+                let query_engine = Arc::clone(&query_engine);
+                async move {
+                    let body = match std::str::from_utf8(&body) {
+                        Ok(body) => body,
+                        Err(_) => {
+                            let response = ApiResponse {
+                                status: ResponseStatus::Error,
+                                error_code: Some(ErrorCode::InvalidResource),
+                                message: "Import body must be UTF-8 NDJSON".to_string(),
+                                data: None,
+                            };
+                            return Ok::<Json, Infallible>(warp::reply::json(&response));
+                        }
+                    };
+                    
+                    let batch_size = query_engine.batch_size();
+                    let mut imported = 0;
+                    let mut errors: Vec<serde_json::Value> = Vec::new();
+                    
+                    // Records waiting to be stored, with the line each came from
+                    let mut batch: Vec<Record> = Vec::new();
+                    let mut batch_lines: Vec<usize> = Vec::new();
+                    
+                    let flush_batch = |batch: &mut Vec<Record>, batch_lines: &mut Vec<usize>,
+                                           imported: &mut usize, errors: &mut Vec<serde_json::Value>| {
+                        if batch_lines.is_empty() {
+                            return;
+                        }
+                        let mut lines: Vec<usize> = batch_lines.clone();
+                        lines.dedup();
+                        match query_engine.store_records_partial(std::mem::take(batch)) {
+                            Ok(result) => {
+                                // A line fails if any of its records does, and is reported once
+                                let mut failed_lines = std::collections::BTreeSet::new();
+                                for (position, error) in result.failed {
+                                    let line = batch_lines[position];
+                                    if failed_lines.insert(line) {
+                                        errors.push(json!({
+                                            "line": line,
+                                            "error": format!("Failed to store record: {}", error),
+                                        }));
+                                    }
+                                }
+                                *imported += lines.len() - failed_lines.len();
+                            }
+                            Err(e) => errors.extend(lines.iter().map(|line| json!({
+                                "line": line,
+                                "error": format!("Failed to store records: {:?}", e),
+                            }))),
+                        }
+                        batch_lines.clear();
+                    };
+                    
+                    for (index, line) in body.lines().enumerate() {
+                        let line_number = index + 1;
+                        if line.trim().is_empty() {
+                            continue;
+                        }
+                        
+                        match records_from_ndjson_line(line) {
+                            Ok(records) => {
+                                batch_lines.extend(std::iter::repeat_n(line_number, records.len()));
+                                batch.extend(records);
+                            }
+                            Err(e) => errors.push(json!({ "line": line_number, "error": e })),
+                        }
+                        
+                        if batch.len() >= batch_size {
+                            flush_batch(&mut batch, &mut batch_lines, &mut imported, &mut errors);
+                        }
+                    }
+                    flush_batch(&mut batch, &mut batch_lines, &mut imported, &mut errors);
+                    
+                    let response = ApiResponse {
+                        status: if errors.is_empty() { ResponseStatus::Success } else { ResponseStatus::Partial },
+                        error_code: None,
+                        message: format!("Imported {} resources with {} errors", imported, errors.len()),
+                        data: Some(json!({
+                            "imported": imported,
+                            "failed": errors.len(),
+                            "errors": errors,
+                        })),
+                    };
+                    Ok(warp::reply::json(&response))
+                }
+            })
+    }
+
+    fn debug_settings(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let query_engine = Arc::clone(&self.query_engine);
+        
+        warp::path!("debug" / "settings")
+            .and(warp::post())
+            .and(warp::body::json())
+            .map(move |settings: DebugSettings| {
+                // Apply settings to the query engine
+                if let Err(e) = query_engine.set_debug_settings(settings.memory_mode, settings.disable_wal, settings.batch_size) {
+                    return warp::reply::with_status(
+                        warp::reply::json(&json!({
+                            "status": "error",
+                            "message": format!("Failed to apply debug settings: {}", e)
+                        })),
+                        warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    );
+                }
+                
+                warp::reply::with_status(
+                    warp::reply::json(&json!({
+                        "status": "success",
+                        "message": "Debug settings applied"
+                    })),
+                    warp::http::StatusCode::OK,
+                )
+            })
+    }
+}
+
+/// Helper function to parse a FHIR `date`, `dateTime` or `instant` into a Unix timestamp
+///
+/// Accepts full RFC3339 instants, offsets without a colon (`+0000`), fractional
+/// seconds, times without an offset (treated as UTC) and partial dates
+/// (`YYYY`, `YYYY-MM`, `YYYY-MM-DD`), which resolve to midnight UTC.
+fn parse_iso8601_to_unix(iso_time: &str) -> Result<i64, Box<dyn std::error::Error>> {
+    use chrono::{DateTime, NaiveDate, NaiveDateTime};
+
+    let iso_time = iso_time.trim();
+
+    // Full instant with offset (the common case)
+    if let Ok(dt) = DateTime::parse_from_rfc3339(iso_time) {
+        return Ok(dt.timestamp());
+    }
+
+    // dateTime with an offset written without a colon, e.g. 2023-01-01T10:00:00+0000
+    for format in ["%Y-%m-%dT%H:%M:%S%.f%z", "%Y-%m-%dT%H:%M%z"] {
+        if let Ok(dt) = DateTime::parse_from_str(iso_time, format) {
+            return Ok(dt.timestamp());
+        }
+    }
+
+    // dateTime without any offset - assume UTC
+    for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%dT%H:%M"] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(iso_time, format) {
+            return Ok(dt.and_utc().timestamp());
+        }
+    }
+
+    // FHIR date (YYYY, YYYY-MM or YYYY-MM-DD) - midnight UTC on the first day
+    let date_parts: Vec<&str> = iso_time.split('-').collect();
+    let all_digits = date_parts.iter().all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()));
+    if all_digits && date_parts[0].len() == 4 && date_parts.len() <= 3 {
+        let year = date_parts[0].parse::<i32>()?;
+        let month = date_parts.get(1).map(|m| m.parse::<u32>()).transpose()?.unwrap_or(1);
+        let day = date_parts.get(2).map(|d| d.parse::<u32>()).transpose()?.unwrap_or(1);
+
+        if let Some(date) = NaiveDate::from_ymd_opt(year, month, day) {
+            if let Some(midnight) = date.and_hms_opt(0, 0, 0) {
+                return Ok(midnight.and_utc().timestamp());
+            }
+        }
+        return Err(format!("Invalid calendar date: '{}'", iso_time).into());
+    }
+
+    Err(format!("Unrecognized FHIR date/dateTime/instant: '{}'", iso_time).into())
+}
+
+/// Build the internal FHIR observation from a request, based on which value field is present
+fn observation_from_request(observation: &FHIRObservationRequest, timestamp: i64) -> Option<FHIRObservation> {
+    // Extract patient ID
+    let patient_id = observation.subject.reference.replace("Patient/", "");
+    
+    // Extract device ID if present
+    let device_id = observation.device.as_ref().map(|dev| dev.reference.replace("Device/", ""));
+    let category = observation.category.as_ref()
+        .and_then(|categories| categories.first())
+        .and_then(|category| category.coding.first())
+        .map(|coding| coding.code.clone());
+    let performer_id = observation.performer.as_ref()
+        .and_then(|performers| performers.first())
+        .map(|performer| performer.reference.replace("Practitioner/", ""));
+    
+    // Get the main code
+    let coding = &observation.code.coding[0];
+    let code = coding.code.clone();
+    
+    if let Some(value_quantity) = &observation.valueQuantity {
+        // Numeric observation
+        Some(FHIRObservation::Numeric {
+            code,
+            value: value_quantity.value,
+            unit: value_quantity.unit.clone(),
+            timestamp,
+            patient_id,
+            device_id,
+            category,
+            performer_id,
+        })
+    } else if let Some(components) = &observation.component {
+        // Component observation
+        let observation_components = components.iter()
+            .map(|component| ObservationComponent {
+                code: component.code.coding[0].code.clone(),
+                value: component.valueQuantity.value,
+                unit: component.valueQuantity.unit.clone(),
+            })
+            .collect();
+        
+        Some(FHIRObservation::Component {
+            code,
+            components: observation_components,
+            timestamp,
+            patient_id,
+            device_id,
+            category,
+            performer_id,
+        })
+    } else if let Some(sampled_data) = &observation.valueSampledData {
+        // Sampled data observation
+        // Parse the space-separated data values
+        let values: Vec<f64> = sampled_data.data
+            .split_whitespace()
+            .filter_map(|s| s.parse::<f64>().ok())
+            .collect();
+            
+        Some(FHIRObservation::SampledData {
+            code,
+            period: sampled_data.period,
+            factor: sampled_data.factor.unwrap_or(1.0),
+            data: values,
+            start_time: timestamp,
+            patient_id,
+            device_id,
+            category,
+            performer_id,
+        })
+    } else {
+        // Non-numeric observations
+        let (value, value_type, display) = if let Some(text) = &observation.valueString {
+            (text.clone(), "string", None)
+        } else if let Some(flag) = observation.valueBoolean {
+            (flag.to_string(), "boolean", None)
+        } else if let Some(concept) = &observation.valueCodeableConcept {
+            let concept_coding = concept.coding.first()?;
+            (concept_coding.code.clone(), "CodeableConcept", Some(concept_coding.display.clone()))
+        } else {
+            return None;
+        };
+        
+        Some(FHIRObservation::Categorical {
+            code,
+            value,
+            value_type: value_type.to_string(),
+            display,
+            timestamp,
+            patient_id,
+            device_id,
+            category,
+            performer_id,
+        })
+    }
+}
+
+/// Build a response body that streams one line per record as the range is scanned
+///
+/// The bounded channel keeps a slow client from buffering the whole range in memory.
+fn stream_records_body<F>(
+    query_engine: Arc<QueryEngine>,
+    metrics: Vec<String>,
+    start_time: i64,
+    end_time: i64,
+    header: Option<String>,
+    format_line: F,
+) -> warp::hyper::Body
+where
+    F: Fn(&Record) -> Vec<u8> + Send + 'static,
+{
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<warp::hyper::body::Bytes>(1024);
+    let (mut sender, body) = warp::hyper::Body::channel();
+    
+    // Storage access takes blocking locks, so the scan runs off the async workers
+    tokio::task::spawn_blocking(move || {
+        if let Some(header) = header {
+            if tx.blocking_send(header.into()).is_err() {
+                return;
+            }
+        }
+        
+        for metric in metrics {
+            let result = query_engine.query_range_streaming(&metric, start_time, end_time, |record| {
+                tx.blocking_send(format_line(record).into()).is_ok()
+            });
+            
+            if let Err(e) = result {
+                eprintln!("Error streaming metric {}: {:?}", metric, e);
+                break;
+            }
+            if tx.is_closed() {
+                break;
+            }
+        }
+    });
+    
+    tokio::spawn(async move {
+        while let Some(line) = rx.recv().await {
+            if sender.send_data(line).await.is_err() {
+                break; // Client went away
+            }
+        }
+    });
+    
+    body
+}
+
+/// Build a response body that streams one NDJSON line per time chunk as it's read
+fn stream_time_chunks_body(
+    query_engine: Arc<QueryEngine>,
+    resource_type: String,
+    start_time: i64,
+    end_time: i64,
+    chunk_size: u64,
+) -> warp::hyper::Body {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<warp::hyper::body::Bytes>(16);
+    let (mut sender, body) = warp::hyper::Body::channel();
+    
+    tokio::task::spawn_blocking(move || {
+        let result = query_engine.query_time_chunked_streaming(&resource_type, start_time, end_time, chunk_size, |chunk| {
+            let mut line = serde_json::to_vec(&time_chunk_json(&chunk)).unwrap();
+            line.push(b'\n');
+            tx.blocking_send(line.into()).is_ok()
+        });
+        if let Err(e) = result {
+            eprintln!("Error streaming time chunks for {}: {:?}", resource_type, e);
+        }
+    });
+    
+    tokio::spawn(async move {
+        while let Some(line) = rx.recv().await {
+            if sender.send_data(line).await.is_err() {
+                break; // Client went away
+            }
+        }
+    });
+    
+    body
+}
+
+/// One `/fhir/timeseries` chunk with its records formatted for the API
+fn time_chunk_json(chunk: &crate::timeseries::query::TimeChunk) -> serde_json::Value {
+    serde_json::json!({
+        "start_time": chunk.start_time,
+        "end_time": chunk.end_time,
+        "records": format_records_for_api(&chunk.records)
+    })
+}
+
+/// Quote a CSV field if it contains a delimiter, quote or line break
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Format a record as a `timestamp,iso_date,value[,context]` CSV row
+fn format_record_as_csv(record: &Record, include_context: bool) -> String {
+    let iso_date = chrono::DateTime::from_timestamp(record.timestamp, 0)
+        .map(|dt| dt.to_rfc3339())
+        .unwrap_or_default();
+    
+    let mut row = format!("{},{},{}", record.timestamp, iso_date, record.value);
+    if include_context {
+        // Sorted so the column is stable between exports
+        let context: std::collections::BTreeMap<_, _> = record.context.iter().collect();
+        row.push(',');
+        row.push_str(&csv_escape(&serde_json::to_string(&context).unwrap_or_default()));
+    }
+    row.push('\n');
+    row
+}
+
+/// Records of an Observation matching `If-None-Exist` search criteria, if any
+///
+/// Understands `patient`, `code` and `date`, the effective time to the precision
+/// given, so `date=2023-01-01` matches the whole day; the first match is
+/// returned along with every record sharing its id.
+fn find_existing_observation(query_engine: &QueryEngine, criteria: &str) -> Result<Option<Vec<Record>>, QueryError> {
+    let params: Vec<(String, String)> = serde_urlencoded::from_str(criteria.trim_start_matches('?'))
+        .map_err(|e| QueryError::InvalidParameter(format!("Invalid If-None-Exist criteria: {}", e)))?;
+    
+    let (mut patient, mut code, mut date) = (None, None, None);
+    for (key, value) in params {
+        match key.as_str() {
+            "patient" | "subject" => patient = Some(value.trim_start_matches("Patient/").to_string()),
+            // Token search may prefix the code with its system
+            "code" => code = value.rsplit('|').next().map(str::to_string),
+            "date" => {
+                let period = fhir_date_period(&value).ok()
+                    .or_else(|| value.parse::<i64>().ok().map(|timestamp| (timestamp, timestamp.saturating_add(1))))
+                    .ok_or_else(|| QueryError::InvalidParameter(format!("Invalid If-None-Exist date: {}", value)))?;
+                date = Some(period);
+            }
+            other => return Err(QueryError::InvalidParameter(format!(
+                "Unsupported If-None-Exist criterion: {}", other
+            ))),
+        }
+    }
+    let Some(patient) = patient else {
+        return Err(QueryError::InvalidParameter("If-None-Exist criteria must name a patient".to_string()));
+    };
+    
+    let prefix = match &code {
+        Some(code) => format!("{}|{}|", patient, code),
+        None => format!("{}|", patient),
+    };
+    for metric in query_engine.get_matching_metrics(&prefix)? {
+        let candidates = match date {
+            Some((start, end)) => query_engine.query_range_filtered(&metric, start, end, |_| true)?,
+            None => query_engine.query_latest(&metric)?.into_iter().collect(),
+        };
+        if let Some(record) = candidates.into_iter().find(|r| r.resource_type == "Observation") {
+            return match &record.id {
+                Some(id) => query_engine.query_by_id(id).map(Some),
+                None => Ok(Some(vec![record])),
+            };
+        }
+    }
+    Ok(None)
+}
+
+/// Parse one NDJSON line into the records it stores
+fn records_from_ndjson_line(line: &str) -> Result<Vec<Record>, String> {
+    let resource: serde_json::Value = serde_json::from_str(line)
+        .map_err(|e| format!("Invalid JSON: {}", e))?;
+    
+    match resource.get("resourceType").and_then(|v| v.as_str()) {
+        Some("Observation") => {
+            let observation = serde_json::from_value::<FHIRObservationRequest>(resource)
+                .map_err(|e| format!("Failed to parse observation: {}", e))?;
+            let timestamp = parse_iso8601_to_unix(&observation.effectiveDateTime)
+                .map_err(|_| "Invalid timestamp format".to_string())?;
+            let id = observation.id.clone();
+            let observation = observation_from_request(&observation, timestamp)
+                .ok_or_else(|| "No valid observation value provided".to_string())?;
+            let mut records = observation.to_records();
+            assign_resource_id(&mut records, id);
+            Ok(records)
+        }
+        Some(other) => Err(format!("Unsupported resource type for import: {}", other)),
+        None => Err("Missing resourceType".to_string()),
+    }
+}
+
+/// Build a record filter from every `value-quantity` query parameter, `category` and `performer`
+fn record_filter_from_params(params: &[(String, String)]) -> Result<RecordFilter, QueryError> {
+    let values: Vec<&str> = params.iter()
+        .filter(|(key, _)| key == "value-quantity")
+        .map(|(_, value)| value.as_str())
+        .collect();
+    let category = params.iter()
+        .find(|(key, _)| key == "category")
+        .map(|(_, category)| category.clone());
+    let context_filter = params.iter()
+        .find(|(key, _)| key == "performer")
+        .map(|(_, performer)| ("performer_id".to_string(), performer.trim_start_matches("Practitioner/").to_string()))
+        .into_iter()
+        .collect();
+    Ok(RecordFilter { value: ValueFilter::parse(&values)?, category, context_filter })
+}
+
+/// 400 with an OperationOutcome listing the resource types that can be queried
+fn unknown_resource_type_reply(resource_type: &str) -> warp::reply::Response {
+    let supported: Vec<&str> = ResourceType::ALL.iter().map(ResourceType::as_str).collect();
+    let outcome = json!({
+        "resourceType": "OperationOutcome",
+        "issue": [{
+            "severity": "error",
+            "code": "not-supported",
+            "diagnostics": format!(
+                "Unknown resource type '{}'. Supported types: {}", resource_type, supported.join(", ")
+            ),
+        }],
+    });
+    warp::reply::with_status(
+        with_header(warp::reply::json(&outcome), "Content-Type", "application/fhir+json"),
+        warp::http::StatusCode::BAD_REQUEST,
+    ).into_response()
+}
+
+/// Query parameters with `start` and `end` resolved to Unix seconds against the server clock
+///
+/// Besides Unix seconds, either accepts `now`, a duration before now in the
+/// `chunk_duration` grammar (e.g. `-24h`, `-7d`) or an ISO 8601 date. Both are
+/// always set afterwards, see `query_bounds`: a missing `end` is now, and a
+/// missing `start` is `window`'s default span before it, or 24h back. A query
+/// wider than the maximum span is rejected.
+fn time_query(window: QueryWindow) -> impl Filter<Extract = (std::collections::HashMap<String, String>,), Error = warp::Rejection> + Clone {
+    time_query_from(window, |now| now.saturating_sub(DEFAULT_START_SPAN_SECS))
+}
+
+/// `time_query` starting at `fallback_start(now)` when neither the query nor `window` sets a start
+fn time_query_from(window: QueryWindow, fallback_start: fn(i64) -> i64)
+    -> impl Filter<Extract = (std::collections::HashMap<String, String>,), Error = warp::Rejection> + Clone
+{
+    warp::query::<std::collections::HashMap<String, String>>()
+        .and_then(move |mut params: std::collections::HashMap<String, String>| async move {
+            let now = chrono::Utc::now().timestamp();
+            for name in ["start", "end"] {
+                if let Some(value) = params.get_mut(name) {
+                    let resolved = resolve_time_bound(value, now)
+                        .map_err(|e| warp::reject::custom(InvalidTimeBound(e)))?;
+                    *value = resolved.to_string();
+                }
+            }
+            
+            let end = params.entry("end".to_string()).or_insert_with(|| now.to_string())
+                .parse::<i64>().unwrap_or(now);
+            let start = params.entry("start".to_string())
+                .or_insert_with(|| window.default_start(end, fallback_start(now)).to_string())
+                .parse::<i64>().unwrap_or(0);
+            window.check_span(start, end, &params)
+                .map_err(|e| warp::reject::custom(QueryTooWide(e.to_string())))?;
+            Ok::<_, warp::Rejection>(params)
+        })
+}
+
+/// `start` and `end` of parameters that passed through `time_query`, which always sets both
+fn query_bounds(params: &std::collections::HashMap<String, String>) -> (i64, i64) {
+    let bound = |name: &str| params.get(name)
+        .and_then(|s| s.parse::<i64>().ok())
+        .expect("time_query resolves start and end");
+    (bound("start"), bound("end"))
+}
+
+/// Seconds of an aggregation interval, given as raw seconds (`300`) or a duration (`5m`, `1h`)
+fn parse_interval(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Ok(seconds);
+    }
+    parse_duration(value)
+        .map(|duration| duration.as_secs())
+        .map_err(|e| format!("Invalid interval '{}': {}", value, e))
+}
+
+/// The `interval` query parameter in seconds, or `None` if it wasn't given
+///
+/// `deprecated` are the names the endpoint took the same value under before
+/// `interval`, still honoured when `interval` itself is absent.
+fn interval_param(params: &std::collections::HashMap<String, String>, deprecated: &[&str]) 
+    -> Result<Option<u64>, QueryError> 
+{
+    std::iter::once("interval").chain(deprecated.iter().copied())
+        .find_map(|name| params.get(name))
+        .map(|value| parse_interval(value).map_err(QueryError::InvalidParameter))
+        .transpose()
+}
+
+/// An interval in seconds for the functions that take signed periods
+fn interval_seconds_i64(seconds: u64) -> i64 {
+    i64::try_from(seconds).unwrap_or(i64::MAX)
+}
+
+/// Unix seconds for one `start`/`end` value, see `time_query`
+fn resolve_time_bound(value: &str, now: i64) -> Result<i64, String> {
+    let value = value.trim();
+    if value == "now" {
+        return Ok(now);
+    }
+    if let Ok(seconds) = value.parse::<i64>() {
+        return Ok(seconds);
+    }
+    if let Some(ago) = value.strip_prefix('-') {
+        return parse_duration(ago).ok()
+            .and_then(|duration| i64::try_from(duration.as_secs()).ok())
+            .and_then(|seconds| now.checked_sub(seconds))
+            .ok_or_else(|| format!("Invalid relative time: {}", value));
+    }
+    parse_iso8601_to_unix(value).map_err(|_| format!(
+        "Invalid time '{}': expected Unix seconds, now, a duration ago like -24h, or an ISO 8601 date", value
+    ))
+}
+
+/// Turn an `InvalidTimeBound` or `QueryTooWide` rejection into a 400; every other rejection passes through
+async fn handle_invalid_time_bound(rejection: warp::Rejection) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Some(QueryTooWide(message)) = rejection.find::<QueryTooWide>() {
+        return Ok(bad_request_reply(QueryError::InvalidTimeRange(message.clone())));
+    }
+    match rejection.find::<InvalidTimeBound>() {
+        Some(InvalidTimeBound(message)) => Ok(bad_request_reply(QueryError::InvalidParameter(message.clone()))),
+        None => Err(rejection),
+    }
+}
+
+/// `?durable=true` on a create: reply only once the write is synced to disk
+fn durable_flag() -> impl Filter<Extract = (bool,), Error = Infallible> + Clone {
+    warp::query::<std::collections::HashMap<String, String>>()
+        .map(|params: std::collections::HashMap<String, String>| {
+            params.get("durable").is_some_and(|value| value == "true")
+        })
+        .or(warp::any().map(|| false))
+        .unify()
+}
+
+/// Error reply for `durable=true` when nothing can reach disk
+fn durability_unavailable_reply() -> Json {
+    warp::reply::json(&ApiResponse {
+        status: ResponseStatus::Error,
+        error_code: Some(ErrorCode::PersistenceDisabled),
+        message: "Persistence is disabled, durable writes are unavailable".to_string(),
+        data: None,
+    })
+}
+
+/// Read `_since` / `_until` as Unix seconds or ISO dates, defaulting to now and `window`'s default span
+///
+/// Without a configured default span, `_since` defaults to all records (timestamp 0).
+fn time_bounds_from_params(params: &std::collections::HashMap<String, String>, window: QueryWindow) -> (i64, i64) {
+    let parse_bound = |s: &String| s.parse::<i64>().ok().or_else(|| parse_iso8601_to_unix(s).ok());
+    
+    let end_time = params.get("_until")
+        .and_then(parse_bound)
+        .unwrap_or_else(|| chrono::Utc::now().timestamp());
+    
+    let start_time = params.get("_since")
+        .and_then(parse_bound)
+        .unwrap_or_else(|| window.default_start(end_time, 0));
+    
+    (start_time, end_time)
+}
+
+/// Narrow `(start, end)` by every FHIR `date` search parameter, e.g. `date=ge2023-01-01&date=lt2023-02`
+///
+/// A date covers its whole implicit period, so `le2023-01` includes all of January.
+/// A bound without a matching `date` param is left as is.
+fn date_bounds_from_params(params: &[(String, String)], (mut start, mut end): (i64, i64)) -> Result<(i64, i64), QueryError> {
+    for (_, value) in params.iter().filter(|(key, _)| key == "date") {
+        let (prefix, date) = match value.get(..2) {
+            Some(p @ ("eq" | "gt" | "lt" | "ge" | "le")) => (p, &value[2..]),
+            _ => ("eq", value.as_str()),
+        };
+        let (period_start, period_end) = fhir_date_period(date)
+            .map_err(|e| QueryError::InvalidFilter(format!("Invalid date '{}': {}", value, e)))?;
+        
+        match prefix {
+            "ge" => start = start.max(period_start),
+            "gt" => start = start.max(period_end),
+            "le" => end = end.min(period_end),
+            "lt" => end = end.min(period_start),
+            _ => {
+                start = start.max(period_start);
+                end = end.min(period_end);
+            }
+        }
+    }
+    Ok((start, end))
+}
+
+/// The half-open `[start, end)` range of seconds a FHIR date or dateTime stands for
+fn fhir_date_period(date: &str) -> Result<(i64, i64), Box<dyn std::error::Error>> {
+    let start = parse_iso8601_to_unix(date)?;
+    let date = date.trim();
+    if date.contains('T') {
+        return Ok((start, start.saturating_add(1)));
+    }
+    
+    let day = chrono::DateTime::from_timestamp(start, 0)
+        .ok_or("Date out of range")?
+        .date_naive();
+    let next = match date.split('-').count() {
+        1 => day.checked_add_months(chrono::Months::new(12)),
+        2 => day.checked_add_months(chrono::Months::new(1)),
+        _ => day.checked_add_days(chrono::Days::new(1)),
+    }
+    .ok_or("Date out of range")?;
+    Ok((start, next.and_time(chrono::NaiveTime::MIN).and_utc().timestamp()))
+}
+
+/// Read the optional `timeout_ms` query parameter
+fn timeout_from_params(params: &std::collections::HashMap<String, String>) -> Option<std::time::Duration> {
+    params.get("timeout_ms")
+        .and_then(|s| s.parse::<u64>().ok())
+        .map(std::time::Duration::from_millis)
+}
+
+/// Idempotency keys only collide with earlier requests to the same path
+fn idempotency_scope(path: &warp::path::FullPath, key: &str) -> String {
+    format!("{} {}", path.as_str(), key)
+}
+
+/// Fields kept by `_summary=true`: what a record is, whose it is, when, and its value
+const SUMMARY_ELEMENTS: &[&str] = &[
+    "timestamp", "iso_date", "value", "string_value", "subject", "metric_name", "code_display", "component",
+];
+
+/// How query endpoints render records, from the `tz`, `_elements` and `_summary` query parameters
+struct RenderOptions {
+    tz: Tz,                        // Zone of `iso_date`, UTC by default
+    elements: Option<Vec<String>>, // Top-level fields to keep; all of them if unset
+}
+
+impl RenderOptions {
+    /// Read `tz` (an IANA name, e.g. `America/New_York`) and FHIR `_elements` (e.g. `value,timestamp`)
+    ///
+    /// `_summary=true` abbreviates resources to `SUMMARY_ELEMENTS` unless `_elements` is given.
+    /// `_summary=count` is accepted here but only acted on by handlers that can count.
+    fn from_params(params: &std::collections::HashMap<String, String>) -> Result<Self, QueryError> {
+        let tz = match params.get("tz") {
+            Some(name) => name.parse::<Tz>()
+                .map_err(|_| QueryError::InvalidParameter(format!("Unknown time zone: {}", name)))?,
+            None => Tz::UTC,
+        };
+        let elements = params.get("_elements").map(|elements| {
+            elements.split(',')
+                .map(str::trim)
+                .filter(|element| !element.is_empty())
+                .map(String::from)
+                .collect()
+        });
+        let summary = match params.get("_summary").map(String::as_str) {
+            None | Some("false") | Some("count") => false,
+            Some("true") => true,
+            Some(other) => return Err(QueryError::InvalidParameter(
+                format!("Unsupported _summary: {} (expected true, false or count)", other)
+            )),
+        };
+        let elements = match elements {
+            None if summary => Some(SUMMARY_ELEMENTS.iter().map(|element| element.to_string()).collect()),
+            elements => elements,
+        };
+        Ok(RenderOptions { tz, elements })
+    }
+    
+    fn record(&self, record: &Record) -> serde_json::Value {
+        self.select_elements(format_record_for_api_in(record, self.tz))
+    }
+    
+    fn records(&self, records: &[Record]) -> serde_json::Value {
+        format_records_for_api_in(records, self.tz).into_iter()
+            .map(|resource| self.select_elements(resource))
+            .collect()
+    }
+    
+    /// Drop fields not asked for by `_elements`; `resourceType` and `id` always stay
+    fn select_elements(&self, mut resource: serde_json::Value) -> serde_json::Value {
+        if let (Some(elements), Some(fields)) = (&self.elements, resource.as_object_mut()) {
+            fields.retain(|key, _| {
+                key == "resourceType" || key == "id" || elements.iter().any(|element| element == key)
+            });
+        }
+        resource
+    }
+}
+
+/// 400 with the error as the message
+/// One `$stats` result as a Parameters entry
+fn statistic_parameter(stats: &TimeSeriesStats, name: &str) -> Result<serde_json::Value, QueryError> {
+    let value = match name {
+        "count" => return Ok(json!({ "name": name, "valueInteger": stats.count })),
+        "average" => stats.mean,
+        "min" | "minimum" => stats.min,
+        "max" | "maximum" => stats.max,
+        "median" => stats.median,
+        "std-dev" | "stddev" => stats.stddev,
+        _ => return Err(QueryError::InvalidParameter(format!(
+            "Unknown statistic '{}'; expected average, min, max, median, std-dev or count", name
+        ))),
+    };
+    Ok(json!({ "name": name, "valueDecimal": value }))
+}
+
+fn bad_request_reply(error: QueryError) -> warp::reply::Response {
+    let response = ApiResponse {
+        status: ResponseStatus::Error,
+        error_code: Some(ErrorCode::from(&error)),
+        message: error.to_string(),
+        data: None,
+    };
+    warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::BAD_REQUEST).into_response()
+}
+
+/// Weak ETag summarizing a query result and the parameters it was rendered with
+///
+/// Built from the count, the newest timestamp and a hash of every record's
+/// metric, timestamp and value, so a late insert or a corrected value changes it.
+fn records_etag(records: &[Record], params: &std::collections::HashMap<String, String>) -> String {
+    use std::hash::{Hash, Hasher};
+    
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    let mut sorted_params: Vec<_> = params.iter().collect();
+    sorted_params.sort();
+    sorted_params.hash(&mut hasher);
+    for record in records {
+        record.metric_name.hash(&mut hasher);
+        record.timestamp.hash(&mut hasher);
+        record.value.to_bits().hash(&mut hasher);
+        record.string_value.hash(&mut hasher);
+    }
+    
+    let newest = records.iter().map(|record| record.timestamp).max().unwrap_or(0);
+    format!("W/\"{}-{}-{:016x}\"", records.len(), newest, hasher.finish())
+}
+
+/// A metric's raw records over `start`..`end`, for `/fhir/timeseries?metric=` and `/timeseries/range`
+///
+/// Honours `timeout_ms`, the `RenderOptions` parameters and `If-None-Match`.
+fn metric_range_reply(query_engine: &QueryEngine, params: &std::collections::HashMap<String, String>,
+                      if_none_match: Option<&str>) -> warp::reply::Response {
+    // Required parameter: metric
+    let metric = match params.get("metric") {
+        Some(m) => m.to_string(),
+        None => {
+            let response = ApiResponse {
+                status: ResponseStatus::Error,
+                error_code: Some(ErrorCode::MissingParameter),
+                message: "Missing required parameter: metric".to_string(),
+                data: None,
+            };
+            return warp::reply::json(&response).into_response();
+        }
+    };
+    
+    let render = match RenderOptions::from_params(params) {
+        Ok(render) => render,
+        Err(e) => return bad_request_reply(e),
+    };
+    
+    // Parse time parameters
+    let (start_time, end_time) = query_bounds(params);
+    
+    let query = TimeSeriesQuery {
+        start_time,
+        end_time,
+        metrics: vec![metric.clone()],
+        aggregation: None,
+        interval: None,
+        timeout: timeout_from_params(params),
+    };
+    
+    match query_engine.query_range(query) {
+        Ok(records) => {
+            // Polling clients that already hold this result get a 304 instead
+            let etag = records_etag(&records, params);
+            conditional_reply(&etag, if_none_match, || {
+                let response = ApiResponse {
+                    status: ResponseStatus::Success,
+                    error_code: None,
+                    message: format!("Found {} records for metric: {}", records.len(), metric),
+                    data: Some(render.records(&records)),
+                };
+                warp::reply::json(&response)
+            })
+        },
+        Err(e) => {
+            let response = ApiResponse {
+                status: ResponseStatus::Error,
+                error_code: Some(ErrorCode::from(&e)),
+                message: format!("Failed to query range: {:?}", e),
+                data: None,
+            };
+            warp::reply::json(&response).into_response()
+        }
+    }
+}
+
+/// Whether an `If-None-Match` header names `etag`, comparing weakly as GET requires
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match.trim() == "*"
+        || if_none_match.split(',').any(|candidate| opaque(candidate) == opaque(etag))
+}
+
+/// Answer 304 with no body if the client already holds `etag`, otherwise build the reply
+///
+/// Either way the response carries the ETag.
+fn conditional_reply<R, F>(etag: &str, if_none_match: Option<&str>, reply: F) -> warp::reply::Response
+where
+    R: warp::Reply,
+    F: FnOnce() -> R,
+{
+    let mut response = if if_none_match.is_some_and(|header| etag_matches(header, etag)) {
+        warp::reply::with_status(warp::reply(), warp::http::StatusCode::NOT_MODIFIED).into_response()
+    } else {
+        reply().into_response()
+    };
+    if let Ok(value) = warp::http::HeaderValue::from_str(etag) {
+        response.headers_mut().insert(warp::http::header::ETAG, value);
+    }
+    response
+}
+
+/// Query each metric in range, keeping numeric records whose value passes the filter
+fn query_metrics_with_filter(
+    query_engine: &QueryEngine,
+    metrics: Result<Vec<String>, QueryError>,
+    start_time: i64,
+    end_time: i64,
+    filter: &RecordFilter,
+) -> Result<Vec<Record>, QueryError> {
+    let mut results = Vec::new();
+    for metric in metrics? {
+        results.extend(query_engine.query_range_matching(&metric, start_time, end_time, filter)?);
+    }
+    results.sort_by_key(|r| r.timestamp);
+    Ok(results)
+}
+
+/// Every component stored at the timestamp of `record`, if it is a component
+///
+/// Returns no records for anything other than a `{patient}|{code}|{component code}|{unit}` metric.
+fn observation_components(query_engine: &QueryEngine, record: &Record) -> Result<Vec<Record>, QueryError> {
+    let parts: Vec<&str> = record.metric_name.split('|').collect();
+    if parts.len() != 4 || parts[2] == "sampled" {
+        return Ok(Vec::new());
+    }
+    
+    let mut components = Vec::new();
+    for metric in query_engine.get_matching_metrics(&format!("{}|{}|", parts[0], parts[1]))? {
+        if metric.split('|').count() == 4 {
+            components.extend(query_engine.query_range_filtered(
+                &metric, record.timestamp, record.timestamp + 1,
+                |candidate| candidate.resource_type == record.resource_type,
+            )?);
+        }
+    }
+    Ok(components)
+}
+
+/// Give every record of a newly created resource the same id, a fresh UUID unless `requested`
+fn assign_resource_id(records: &mut [Record], requested: Option<String>) -> String {
+    let id = requested.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    for record in records.iter_mut() {
+        record.id = Some(id.clone());
+    }
+    id
+}
+
+/// Echo the id a create assigned in the stored resource
+fn with_resource_id(mut resource: serde_json::Value, id: &str) -> serde_json::Value {
+    if let Some(obj) = resource.as_object_mut() {
+        obj.insert("id".to_string(), serde_json::Value::String(id.to_string()));
+    }
+    resource
+}
+
+/// Group records into the sets that each make up a single FHIR resource
+///
+/// Component observations are regrouped by parent code and timestamp, sampled data
+/// by metric, and the diastolic half of a blood pressure reading is dropped because
+/// the systolic record already reconstructs the whole panel.
+fn group_records_into_resources(records: Vec<Record>) -> Vec<(String, Vec<Record>)> {
+    let mut groups: std::collections::BTreeMap<(String, String), Vec<Record>> = std::collections::BTreeMap::new();
+    
+    for record in records {
+        let parts: Vec<&str> = record.metric_name.split('|').collect();
+        
+        if record.resource_type == "VitalSigns" && parts.get(1) == Some(&"8462-4")
+            && record.context.contains_key("bp_systolic") {
+            continue;
+        }
+        
+        let key = if parts.get(2) == Some(&"sampled") {
+            record.metric_name.clone()
+        } else if record.resource_type == "Observation" && parts.len() >= 4 {
+            format!("{}|{}@{}", parts[0], parts[1], record.timestamp)
+        } else {
+            format!("{}@{}", record.metric_name, record.timestamp)
+        };
+        
+        groups.entry((record.resource_type.clone(), key)).or_default().push(record);
+    }
+    
+    groups.into_iter()
+        .map(|((resource_type, _), group)| (resource_type, group))
+        .collect()
+}
+
+/// Convert a group of records back into the JSON form of its FHIR resource
+fn resource_to_json(resource_type: &str, records: &[Record]) -> Result<serde_json::Value, FHIRError> {
+    let value = match resource_type {
+        "Observation" => serde_json::to_value(FHIRObservation::from_records(records)?),
+        "MedicationAdministration" => serde_json::to_value(MedicationAdministration::from_records(records)?),
+        "DeviceObservation" => serde_json::to_value(DeviceObservation::from_records(records)?),
+        "VitalSigns" => serde_json::to_value(VitalSigns::from_records(records)?),
+        "Condition" => serde_json::to_value(Condition::from_records(records)?),
+        "Encounter" => serde_json::to_value(Encounter::from_records(records)?),
+        "AllergyIntolerance" => serde_json::to_value(AllergyIntolerance::from_records(records)?),
+        "Patient" => serde_json::to_value(PatientRequest::from(&Patient::from_records(records)?)),
+        other => return Err(FHIRError::ConversionError(format!("Unsupported resource type: {}", other))),
+    };
+    
+    let mut value = value.map_err(|e| FHIRError::ConversionError(e.to_string()))?;
+    if let Some(obj) = value.as_object_mut() {
+        obj.insert("resourceType".to_string(), serde_json::Value::String(resource_type.to_string()));
+        if let Some(id) = records.iter().find_map(|r| r.id.as_ref()) {
+            obj.insert("id".to_string(), serde_json::Value::String(id.clone()));
+        }
+    }
+    Ok(value)
+}
+
+/// Compare tokens without bailing out at the first differing byte
+fn tokens_match(provided: &str, expected: &str) -> bool {
+    provided.len() == expected.len()
+        && provided.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Turn an auth rejection into a 401; every other rejection passes through
+async fn handle_unauthorized(rejection: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+    if rejection.find::<Unauthorized>().is_none() {
+        return Err(rejection);
+    }
+    
+    let response = ApiResponse {
+        status: ResponseStatus::Error,
+        error_code: Some(ErrorCode::Unauthorized),
+        message: "Missing or invalid bearer token".to_string(),
+        data: None,
+    };
+    Ok(with_header(
+        warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::UNAUTHORIZED),
+        "WWW-Authenticate", "Bearer",
+    ))
+}
+
+/// 409 for a create whose `Idempotency-Key` is held by a request still running
+async fn handle_idempotency_key_in_use(rejection: warp::Rejection) -> Result<warp::reply::Response, warp::Rejection> {
+    match rejection.find::<IdempotencyKeyInUse>() {
+        Some(_) => Ok(idempotency_key_in_use_reply()),
+        None => Err(rejection),
+    }
+}
+
+fn idempotency_key_in_use_reply() -> warp::reply::Response {
+    idempotency_error_reply(
+        ErrorCode::IdempotencyKeyInUse,
+        "A request with this Idempotency-Key is still in progress; retry shortly",
+    )
+}
+
+/// Error reply for a retried create: 409 while the key is in use, 422 for a changed body
+fn idempotency_error_reply(error_code: ErrorCode, message: &str) -> warp::reply::Response {
+    let status = match error_code {
+        ErrorCode::IdempotencyKeyInUse => warp::http::StatusCode::CONFLICT,
+        _ => warp::http::StatusCode::UNPROCESSABLE_ENTITY,
+    };
+    let response = ApiResponse {
+        status: ResponseStatus::Error,
+        error_code: Some(error_code),
+        message: message.to_string(),
+        data: None,
+    };
+    warp::reply::with_status(warp::reply::json(&response), status).into_response()
+}
+
+/// Push matching records to a WebSocket client until either side goes away
+async fn forward_updates(
+    socket: warp::ws::WebSocket,
+    mut updates: tokio::sync::broadcast::Receiver<Record>,
+    metric: String,
+) {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::sync::broadcast::error::RecvError;
+    
+    let (mut sender, mut incoming) = socket.split();
+    loop {
+        tokio::select! {
+            update = updates.recv() => match update {
+                Ok(record) if record.metric_name.starts_with(&metric) => {
+                    let message = warp::ws::Message::text(format_record_for_api(&record).to_string());
+                    if sender.send(message).await.is_err() {
+                        break;
+                    }
+                },
+                Ok(_) => continue,
+                // A slow client misses what it couldn't keep up with rather than holding up ingest
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("WebSocket subscriber for {} lagged, dropped {} updates", metric, skipped);
+                },
+                Err(RecvError::Closed) => break,
+            },
+            message = incoming.next() => match message {
+                Some(Ok(message)) if !message.is_close() => continue,
+                _ => break,
+            },
+        }
+    }
+}
+
+/// Helper function to transform a Record into an API-friendly response, dated in UTC
+fn format_record_for_api(record: &Record) -> serde_json::Value {
+    format_record_for_api_in(record, Tz::UTC)
+}
+
+/// Like `format_record_for_api`, with `iso_date` rendered in the given zone
+fn format_record_for_api_in(record: &Record, tz: Tz) -> serde_json::Value {
+    // Extract components from metric name (format: "{patient_id}|{code}|{unit}")
+    let parts: Vec<&str> = record.metric_name.split('|').collect();
+    
+    // Extract patient ID, code, and unit
+    let patient_id = parts.first().unwrap_or(&"unknown");
     let code = parts.get(1).unwrap_or(&"unknown");
     let unit = parts.get(2).unwrap_or(&"unknown");
     
@@ -1316,7 +5062,7 @@ fn format_record_for_api(record: &Record) -> serde_json::Value {
     let iso_date = if record.timestamp > 0 {
         use chrono::{DateTime, Utc};
         DateTime::<Utc>::from_timestamp(record.timestamp, 0)
-            .map(|dt| dt.to_rfc3339())
+            .map(|dt| dt.with_timezone(&tz).to_rfc3339())
             .unwrap_or_else(|| "invalid_timestamp".to_string())
     } else {
         "unknown".to_string()
@@ -1324,7 +5070,8 @@ fn format_record_for_api(record: &Record) -> serde_json::Value {
     
     // Build an enhanced API response
     let mut response = serde_json::json!({
-        "id": format!("{}:{}", record.resource_type, record.metric_name),
+        "id": record.id.clone()
+            .unwrap_or_else(|| format!("{}:{}", record.resource_type, record.metric_name)),
         "resourceType": record.resource_type,
         "timestamp": record.timestamp,
         "iso_date": iso_date,
@@ -1342,6 +5089,11 @@ fn format_record_for_api(record: &Record) -> serde_json::Value {
         "code_display": code_display
     });
     
+    // Non-numeric observations carry their actual value as text
+    if let Some(text) = &record.string_value {
+        response["string_value"] = serde_json::Value::String(text.clone());
+    }
+    
     // Add context elements directly to the top level
     if !record.context.is_empty() {
         let obj = response.as_object_mut().unwrap();
@@ -1349,13 +5101,2468 @@ fn format_record_for_api(record: &Record) -> serde_json::Value {
             obj.insert(key.clone(), serde_json::Value::String(value.clone()));
         }
     }
-    
-    response
-}
+    
+    response
+}
+
+/// Helper functions to format multiple records
+///
+/// Systolic and diastolic records for the same patient and timestamp are stored
+/// separately but returned as one blood pressure panel, like `VitalSigns::from_records`.
+fn format_records_for_api(records: &[Record]) -> Vec<serde_json::Value> {
+    format_records_for_api_in(records, Tz::UTC)
+}
+
+/// Like `format_records_for_api`, with `iso_date` rendered in the given zone
+fn format_records_for_api_in(records: &[Record], tz: Tz) -> Vec<serde_json::Value> {
+    let bp_key = |record: &Record, code: &str| {
+        let parts: Vec<&str> = record.metric_name.split('|').collect();
+        (parts.get(1) == Some(&code))
+            .then(|| (parts[0].to_string(), record.timestamp, record.resource_type.clone()))
+    };
+    
+    // Index diastolic records by (patient, timestamp, resource type) so each pairs at most once
+    let mut diastolic: std::collections::HashMap<(String, i64, String), usize> = std::collections::HashMap::new();
+    for (i, record) in records.iter().enumerate() {
+        if let Some(key) = bp_key(record, "8462-4") {
+            diastolic.entry(key).or_insert(i);
+        }
+    }
+    
+    // partner[i] is the diastolic index for systolic record i; consumed diastolics are skipped
+    let mut partner = vec![None; records.len()];
+    let mut consumed = vec![false; records.len()];
+    for (i, record) in records.iter().enumerate() {
+        if let Some(j) = bp_key(record, "8480-6").and_then(|key| diastolic.remove(&key)) {
+            partner[i] = Some(j);
+            consumed[j] = true;
+        }
+    }
+    
+    // Components stored as `{patient}|{code}|{component code}|{unit}` gather under the
+    // first record of their observation; members[i] lists the records of that observation
+    let component_key = |record: &Record| {
+        let parts: Vec<&str> = record.metric_name.split('|').collect();
+        (parts.len() == 4 && parts[2] != "sampled")
+            .then(|| (parts[0].to_string(), parts[1].to_string(), record.timestamp, record.resource_type.clone()))
+    };
+    let mut first_component: std::collections::HashMap<(String, String, i64, String), usize> = std::collections::HashMap::new();
+    let mut members: Vec<Vec<usize>> = vec![Vec::new(); records.len()];
+    for (i, record) in records.iter().enumerate() {
+        if consumed[i] || partner[i].is_some() {
+            continue;
+        }
+        if let Some(key) = component_key(record) {
+            let first = *first_component.entry(key).or_insert(i);
+            members[first].push(i);
+            consumed[i] = first != i;
+        }
+    }
+    
+    records.iter().enumerate()
+        .filter(|&(i, _)| !consumed[i])
+        .map(|(i, record)| match partner[i] {
+            Some(j) => format_blood_pressure_panel(record, &records[j], tz),
+            None if !members[i].is_empty() => {
+                let components: Vec<&Record> = members[i].iter().map(|&j| &records[j]).collect();
+                format_component_panel(&components, tz)
+            },
+            None => format_record_for_api_in(record, tz),
+        })
+        .collect()
+}
+
+/// One Observation holding every component recorded with it
+fn format_component_panel(components: &[&Record], tz: Tz) -> serde_json::Value {
+    let first = components[0];
+    let mut panel = format_record_for_api_in(first, tz);
+    let parts: Vec<&str> = first.metric_name.split('|').collect();
+    let (patient_id, code) = (parts[0], parts[1]);
+    let panel_metric = format!("{}|{}", patient_id, code);
+    
+    // A component recorded twice keeps its latest value
+    let mut entries: Vec<(&str, serde_json::Value)> = Vec::new();
+    for record in components {
+        let parts: Vec<&str> = record.metric_name.split('|').collect();
+        entries.retain(|(component_code, _)| *component_code != parts[2]);
+        entries.push((parts[2], json!({
+            "code": { "coding": [{ "system": "http://loinc.org", "code": parts[2] }] },
+            "valueQuantity": { "value": record.value, "unit": parts[3], "system": "http://unitsofmeasure.org" }
+        })));
+    }
+    
+    let obj = panel.as_object_mut().unwrap();
+    obj.remove("value");
+    obj.insert("id".to_string(), json!(format!("{}:{}", first.resource_type, panel_metric)));
+    obj.insert("metric_name".to_string(), json!(panel_metric));
+    obj.insert("metric_components".to_string(), json!({ "patient_id": patient_id, "code": code }));
+    obj.insert("component".to_string(), entries.into_iter().map(|(_, entry)| entry).collect());
+    
+    panel
+}
+
+/// One Observation with systolic and diastolic components
+fn format_blood_pressure_panel(systolic: &Record, diastolic: &Record, tz: Tz) -> serde_json::Value {
+    let mut panel = format_record_for_api_in(systolic, tz);
+    let parts: Vec<&str> = systolic.metric_name.split('|').collect();
+    let patient_id = parts.first().unwrap_or(&"unknown");
+    let unit = parts.get(2).unwrap_or(&"unknown");
+    let panel_metric = format!("{}|85354-9|{}", patient_id, unit);
+    
+    let component = |record: &Record, code: &str, display: &str| {
+        let unit = record.metric_name.split('|').nth(2).unwrap_or("unknown");
+        json!({
+            "code": { "coding": [{ "system": "http://loinc.org", "code": code, "display": display }] },
+            "valueQuantity": { "value": record.value, "unit": unit, "system": "http://unitsofmeasure.org" }
+        })
+    };
+    
+    let obj = panel.as_object_mut().unwrap();
+    // The per-component bookkeeping stored in context has no meaning on the panel
+    for key in ["value", "bp_systolic", "bp_diastolic"] {
+        obj.remove(key);
+    }
+    obj.insert("id".to_string(), json!(format!("{}:{}", systolic.resource_type, panel_metric)));
+    obj.insert("metric_name".to_string(), json!(panel_metric));
+    obj.insert("metric_components".to_string(), json!({ "patient_id": patient_id, "code": "85354-9", "unit": unit }));
+    obj.insert("code_display".to_string(), json!("Blood Pressure Panel"));
+    obj.insert("component".to_string(), json!([
+        component(systolic, "8480-6", "Systolic Blood Pressure"),
+        component(diastolic, "8462-4", "Diastolic Blood Pressure"),
+    ]));
+    
+    panel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, StorageConfig, ApiConfig, ChunkLayout, FsyncPolicy, WalFormat};
+    use crate::storage::StorageEngine;
+    use crate::storage::tests::TempDir;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    static TEST_DIR_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    /// A `RestApi` whose store's temp directory is removed when it is dropped
+    struct TestApi {
+        api: RestApi, // Dropped first, so the store is closed before its directory goes
+        _dir: TempDir,
+    }
+
+    impl TestApi {
+        /// Apply `RestApi` builder methods, keeping the directory
+        fn with(self, build: impl FnOnce(RestApi) -> RestApi) -> Self {
+            TestApi { api: build(self.api), _dir: self._dir }
+        }
+    }
+
+    impl std::ops::Deref for TestApi {
+        type Target = RestApi;
+
+        fn deref(&self) -> &RestApi {
+            &self.api
+        }
+    }
+
+    /// Build an API backed by a fresh storage engine in its own temp directory
+    fn test_api() -> TestApi {
+        let dir = TempDir::new(&format!("rest-test-{}", TEST_DIR_COUNTER.fetch_add(1, Ordering::SeqCst)));
+        
+        let config = Config {
+            storage: StorageConfig {
+                path: dir.to_string_lossy().to_string(),
+                max_chunk_size: 1048576,
+                wal_fsync: FsyncPolicy::Always,
+                max_resident_chunks: None,
+                rollup_resolution: None,
+                chunk_layout: ChunkLayout::default(),
+                metric_capacity_hint: None,
+                max_unflushed_bytes: None,
+                wal_format: WalFormat::default(),
+            },
+            api: ApiConfig {
+                host: "127.0.0.1".to_string(),
+                port: 5432,
+                auth_token: None,
+                strict_body: false,
+                default_query_window: None,
+                max_query_span: None,
+            },
+            chunk_duration: Duration::from_secs(3600),
+            query_cache_size: 16,
+            value_decimals: None,
+            unit_conversions: std::collections::HashMap::new(),
+        };
+        
+        let storage = Arc::new(StorageEngine::new(&config).unwrap());
+        TestApi { api: RestApi::new(Arc::new(QueryEngine::new(storage))), _dir: dir }
+    }
+
+    /// Builder for the Observation with one `valueQuantity` that most tests post
+    ///
+    /// Defaults to a glucose reading in mg/dL taken at 2023-01-01T10:00:00Z.
+    struct QuantityObservation {
+        patient: String,
+        value: f64,
+        code: (String, String), // LOINC code and display
+        unit: (String, String), // Unit as written and its UCUM code
+        effective: String,
+    }
+
+    fn quantity_observation(patient: &str, value: f64) -> QuantityObservation {
+        QuantityObservation {
+            patient: patient.to_string(),
+            value,
+            code: ("2339-0".to_string(), "Glucose".to_string()),
+            unit: ("mg/dL".to_string(), "mg/dL".to_string()),
+            effective: "2023-01-01T10:00:00Z".to_string(),
+        }
+    }
+
+    impl QuantityObservation {
+        fn code(mut self, code: &str, display: &str) -> Self {
+            self.code = (code.to_string(), display.to_string());
+            self
+        }
+
+        fn unit(mut self, unit: &str, ucum: &str) -> Self {
+            self.unit = (unit.to_string(), ucum.to_string());
+            self
+        }
+
+        fn at(mut self, effective: impl Into<String>) -> Self {
+            self.effective = effective.into();
+            self
+        }
+
+        fn json(self) -> serde_json::Value {
+            json!({
+                "resourceType": "Observation",
+                "status": "final",
+                "code": { "coding": [{ "system": "http://loinc.org", "code": self.code.0, "display": self.code.1 }] },
+                "subject": { "reference": format!("Patient/{}", self.patient) },
+                "effectiveDateTime": self.effective,
+                "valueQuantity": { "value": self.value, "unit": self.unit.0, "system": "http://unitsofmeasure.org", "code": self.unit.1 }
+            })
+        }
+    }
+
+    fn response_json(response: &warp::http::Response<warp::hyper::body::Bytes>) -> serde_json::Value {
+        serde_json::from_slice(response.body()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_store_and_retrieve_coded_observation() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        let observation = json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": { "coding": [{ "system": "http://loinc.org", "code": "883-9", "display": "ABO group" }] },
+            "subject": { "reference": "Patient/123" },
+            "effectiveDateTime": "2023-01-01T10:00:00Z",
+            "valueCodeableConcept": {
+                "coding": [{ "system": "http://loinc.org", "code": "LA19710-5", "display": "Group A" }]
+            }
+        });
+        
+        let response = warp::test::request()
+            .method("POST")
+            .path("/fhir/Observation")
+            .json(&observation)
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["status"], "success");
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/Observation?patient=123&code=883-9")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        assert_eq!(body["data"]["string_value"], "LA19710-5");
+        assert_eq!(body["data"]["value_display"], "Group A");
+        assert_eq!(body["data"]["value_type"], "CodeableConcept");
+    }
+
+    #[tokio::test]
+    async fn test_three_component_observation_comes_back_whole() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        let component = |code: &str, display: &str, value: f64| json!({
+            "code": { "coding": [{ "system": "http://loinc.org", "code": code, "display": display }] },
+            "valueQuantity": { "value": value, "unit": "mg/dL", "system": "http://unitsofmeasure.org", "code": "mg/dL" }
+        });
+        let observation = json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": { "coding": [{ "system": "http://loinc.org", "code": "57698-3", "display": "Lipid panel" }] },
+            "subject": { "reference": "Patient/123" },
+            "effectiveDateTime": "2023-01-01T10:00:00Z",
+            "component": [
+                component("2093-3", "Cholesterol", 190.0),
+                component("2085-9", "HDL Cholesterol", 55.0),
+                component("2571-8", "Triglyceride", 120.0),
+            ]
+        });
+        
+        let response = warp::test::request()
+            .method("POST")
+            .path("/fhir/Observation")
+            .json(&observation)
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["status"], "success");
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/Observation?patient=123&code=57698-3")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        assert_eq!(body["data"]["metric_name"], "123|57698-3");
+        let mut values: Vec<(String, f64)> = body["data"]["component"].as_array().unwrap().iter()
+            .map(|c| (c["code"]["coding"][0]["code"].as_str().unwrap().to_string(), c["valueQuantity"]["value"].as_f64().unwrap()))
+            .collect();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(values, vec![
+            ("2085-9".to_string(), 55.0),
+            ("2093-3".to_string(), 190.0),
+            ("2571-8".to_string(), 120.0),
+        ]);
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/Observation?patient=123&code=57698-3&value-quantity=gt0")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        let observations = body["data"].as_array().unwrap();
+        assert_eq!(observations.len(), 1);
+        assert_eq!(observations[0]["component"].as_array().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_durable_observation_requires_persistence() {
+        let observation = quantity_observation("dur", 72.0).code("8867-4", "Heart rate").unit("bpm", "/min").json();
+        
+        let api = test_api();
+        let response = warp::test::request()
+            .method("POST")
+            .path("/fhir/Observation?durable=true")
+            .json(&observation)
+            .reply(&api.routes())
+            .await;
+        assert_eq!(response_json(&response)["status"], "success");
+        assert_eq!(api.query_engine.query_latest("dur|8867-4|bpm").unwrap().unwrap().value, 72.0);
+        
+        let storage = Arc::new(StorageEngine::new_in_memory(Duration::from_secs(3600)));
+        let api = RestApi::new(Arc::new(QueryEngine::new(storage)));
+        let response = warp::test::request()
+            .method("POST")
+            .path("/fhir/Observation?durable=true")
+            .json(&observation)
+            .reply(&api.routes())
+            .await;
+        assert_eq!(response_json(&response)["error_code"], "PERSISTENCE_DISABLED");
+        assert!(api.query_engine.query_latest("dur|8867-4|bpm").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_device_observations_endpoint() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        let device_observation = |code: &str, value: f64, unit: &str, at: &str| json!({
+            "resourceType": "DeviceObservation",
+            "status": "final",
+            "device": { "reference": "Device/vent-1" },
+            "code": { "coding": [{ "system": "http://loinc.org", "code": code, "display": code }] },
+            "valueQuantity": { "value": value, "unit": unit, "system": "http://unitsofmeasure.org", "code": unit },
+            "effectiveDateTime": at,
+            "subject": { "reference": "Patient/123" },
+            "deviceType": "ventilator",
+            "metricType": "measurement"
+        });
+        for observation in [
+            device_observation("20112-9", 450.0, "mL", "2023-01-01T10:00:00Z"),
+            device_observation("19994-3", 40.0, "%", "2023-01-01T10:05:00Z"),
+        ] {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/fhir/DeviceObservation")
+                .json(&observation)
+                .reply(&routes)
+                .await;
+            assert_eq!(response_json(&response)["status"], "success");
+        }
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/Device/vent-1/observations?start=0&end=2000000000")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        assert_eq!(body["data"]["total"], 2);
+        let entries = body["data"]["entry"].as_array().unwrap();
+        assert_eq!(entries[0]["resource"]["code"], "20112-9");
+        assert_eq!(entries[0]["resource"]["value"], 450.0);
+        assert_eq!(entries[1]["resource"]["code"], "19994-3");
+        assert_eq!(entries[1]["resource"]["resourceType"], "DeviceObservation");
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/Device/vent-2/observations?start=0&end=2000000000")
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["data"]["total"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_value_decimals_rounds_only_when_configured() {
+        let temperature = quantity_observation("temp", 36.599999)
+            .code("8310-5", "Body temperature")
+            .unit("Cel", "Cel")
+            .json();
+        
+        for (decimals, expected) in [(Some(1), 36.6), (None, 36.599999)] {
+            let storage = Arc::new(StorageEngine::new_in_memory(Duration::from_secs(3600)));
+            let api = RestApi::new(Arc::new(QueryEngine::new(storage).with_value_decimals(decimals)));
+            let response = warp::test::request()
+                .method("POST")
+                .path("/fhir/Observation")
+                .json(&temperature)
+                .reply(&api.routes())
+                .await;
+            assert_eq!(response_json(&response)["status"], "success");
+            assert_eq!(api.query_engine.query_latest("temp|8310-5|Cel").unwrap().unwrap().value, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_range_honors_if_none_match() {
+        for path in [
+            "/timeseries/range?metric=etag%7C8867-4%7Cbpm&start=0&end=1000",
+            "/fhir/timeseries?metric=etag%7C8867-4%7Cbpm&start=0&end=1000",
+            "/fhir/timeseries?resource_type=Observation&start=0&end=1000",
+        ] {
+            assert_range_honors_if_none_match(path).await;
+        }
+    }
+
+    async fn assert_range_honors_if_none_match(path: &str) {
+        let api = test_api();
+        let routes = api.routes();
+        let make_record = |timestamp: i64, value: f64| Record {
+            timestamp,
+            metric_name: "etag|8867-4|bpm".to_string(),
+            value,
+            string_value: None,
+            context: std::collections::HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        api.query_engine.store_records(vec![make_record(100, 70.0), make_record(200, 72.0)]).unwrap();
+        
+        let get = |etag: Option<String>| {
+            let mut request = warp::test::request()
+                .method("GET")
+                .path(path);
+            if let Some(etag) = etag {
+                request = request.header("If-None-Match", etag);
+            }
+            request.reply(&routes)
+        };
+        
+        let first = get(None).await;
+        assert_eq!(first.status(), 200, "{}", path);
+        let etag = first.headers()["etag"].to_str().unwrap().to_string();
+        assert!(etag.starts_with("W/\""));
+        
+        let second = get(Some(etag.clone())).await;
+        assert_eq!(second.status(), 304, "{}", path);
+        assert!(second.body().is_empty());
+        assert_eq!(second.headers()["etag"], etag.as_str());
+        
+        // New data in range changes the tag
+        api.query_engine.store_record(make_record(150, 71.0)).unwrap();
+        let third = get(Some(etag.clone())).await;
+        assert_eq!(third.status(), 200, "{}", path);
+        assert_ne!(third.headers()["etag"], etag.as_str());
+        if path.contains("metric=") {
+            assert_eq!(response_json(&third)["data"].as_array().unwrap().len(), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_medication_administrations_by_patient() {
+        let api = test_api();
+        let routes = api.routes();
+        let administration = |patient: &str, code: &str, display: &str, when: &str| json!({
+            "resourceType": "MedicationAdministration",
+            "status": "completed",
+            "medication": { "coding": [{ "system": "http://www.nlm.nih.gov/research/umls/rxnorm", "code": code, "display": display }] },
+            "dosage": { "value": 500.0, "unit": "mg", "system": "http://unitsofmeasure.org", "code": "mg" },
+            "route": { "system": "http://snomed.info/sct", "code": "26643006", "display": "Oral" },
+            "subject": { "reference": format!("Patient/{}", patient) },
+            "effectiveDateTime": when,
+            "performer": { "reference": "Practitioner/nurse-7" },
+        });
+        for body in [
+            administration("med-search", "161", "Acetaminophen", "2023-05-01T08:00:00Z"),
+            administration("med-search", "5640", "Ibuprofen", "2023-05-01T12:00:00Z"),
+            administration("someone-else", "161", "Acetaminophen", "2023-05-01T09:00:00Z"),
+        ] {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/fhir/MedicationAdministration")
+                .json(&body)
+                .reply(&routes)
+                .await;
+            assert_eq!(response_json(&response)["status"], "success");
+        }
+        
+        let search = |query: &'static str| warp::test::request().path(query).reply(&routes);
+        let bundle = response_json(&search("/fhir/MedicationAdministration?patient=med-search").await)["data"].clone();
+        assert_eq!(bundle["total"], 2);
+        let first = &bundle["entry"][0]["resource"];
+        assert_eq!(first["resourceType"], "MedicationAdministration");
+        assert_eq!(first["medication_code"], "161");
+        assert_eq!(first["route"], "Oral");
+        assert_eq!(first["status"], "completed");
+        assert_eq!(first["practitioner_id"], "nurse-7");
+        assert_eq!(bundle["entry"][1]["resource"]["medication_code"], "5640");
+        
+        let bundle = response_json(&search("/fhir/MedicationAdministration?patient=med-search&medication=5640").await)["data"].clone();
+        assert_eq!(bundle["total"], 1);
+        
+        let missing = search("/fhir/MedicationAdministration?medication=161").await;
+        assert_eq!(missing.status(), 400);
+    }
+
+    #[test]
+    fn test_resolve_relative_time_bounds() {
+        let now = 1_700_000_000;
+        assert_eq!(resolve_time_bound("-1h", now), Ok(now - 3600));
+        assert_eq!(resolve_time_bound("-7d", now), Ok(now - 7 * 86400));
+        assert_eq!(resolve_time_bound("now", now), Ok(now));
+        assert_eq!(resolve_time_bound("1699990000", now), Ok(1_699_990_000));
+        assert_eq!(resolve_time_bound("2023-11-14T22:13:20Z", now), Ok(now));
+        assert!(resolve_time_bound("-1w", now).is_err());
+        assert!(resolve_time_bound("yesterday", now).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relative_time_window_on_query_endpoints() {
+        let api = test_api();
+        let routes = api.routes();
+        let now = chrono::Utc::now().timestamp();
+        let record = |timestamp: i64| Record {
+            timestamp,
+            metric_name: "rel|8867-4|bpm".to_string(),
+            value: 70.0,
+            string_value: None,
+            context: std::collections::HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        api.query_engine.store_records(vec![record(now - 600), record(now - 2 * 86400), record(now - 30 * 86400)]).unwrap();
+        
+        let routes = &routes;
+        let count = |query: &'static str| async move {
+            let response = warp::test::request()
+                .path(&format!("/timeseries/count?metric=rel%7C8867-4%7Cbpm&{}", query))
+                .reply(routes).await;
+            (response.status(), response_json(&response)["data"].clone())
+        };
+        assert_eq!(count("start=-1h&end=now").await, (warp::http::StatusCode::OK, json!(1)));
+        assert_eq!(count("start=-7d&end=now").await, (warp::http::StatusCode::OK, json!(2)));
+        assert_eq!(count("start=-1h&end=-5s").await.1, json!(1));
+        assert_eq!(count("start=-1fortnight").await.0, warp::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_parse_fhir_date_only() {
+        assert_eq!(parse_iso8601_to_unix("2023-01-01").unwrap(), 1672531200);
+        assert_eq!(parse_iso8601_to_unix("2023-02").unwrap(), 1675209600);
+        assert_eq!(parse_iso8601_to_unix("2023").unwrap(), 1672531200);
+    }
+
+    #[test]
+    fn test_parse_fhir_datetime_with_offset() {
+        assert_eq!(parse_iso8601_to_unix("2023-01-01T10:00:00Z").unwrap(), 1672567200);
+        assert_eq!(parse_iso8601_to_unix("2023-01-01T12:00:00+02:00").unwrap(), 1672567200);
+        assert_eq!(parse_iso8601_to_unix("2023-01-01T10:00:00+0000").unwrap(), 1672567200);
+        assert_eq!(parse_iso8601_to_unix("2023-01-01T10:00:00").unwrap(), 1672567200);
+    }
+
+    #[test]
+    fn test_parse_fhir_instant_with_fractional_seconds() {
+        assert_eq!(parse_iso8601_to_unix("2023-01-01T10:00:00.123Z").unwrap(), 1672567200);
+        assert_eq!(parse_iso8601_to_unix("2023-01-01T10:00:00.123456-05:00").unwrap(), 1672585200);
+    }
+
+    #[test]
+    fn test_parse_invalid_timestamps() {
+        assert!(parse_iso8601_to_unix("not a date").is_err());
+        assert!(parse_iso8601_to_unix("2023-13-01").is_err());
+        assert!(parse_iso8601_to_unix("2023-02-30").is_err());
+        assert!(parse_iso8601_to_unix("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_post_condition() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        let condition = json!({
+            "resourceType": "Condition",
+            "clinicalStatus": { "coding": [{ "system": "http://terminology.hl7.org/CodeSystem/condition-clinical", "code": "active", "display": "Active" }] },
+            "code": { "coding": [{ "system": "http://snomed.info/sct", "code": "44054006", "display": "Diabetes mellitus type 2" }] },
+            "subject": { "reference": "Patient/123" },
+            "onsetDateTime": "2023-01-01",
+            "severity": { "coding": [{ "system": "http://snomed.info/sct", "code": "6736007", "display": "Moderate" }] }
+        });
+        
+        let response = warp::test::request()
+            .method("POST")
+            .path("/fhir/Condition")
+            .json(&condition)
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["status"], "success");
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/resources/Condition?_since=0")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        let records = body["data"].as_array().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["metric_name"], "123|44054006|condition");
+        assert_eq!(records[0]["clinical_status"], "active");
+        assert_eq!(records[0]["severity"], "moderate");
+        assert_eq!(records[0]["timestamp"], 1672531200);
+    }
+
+    #[tokio::test]
+    async fn test_post_multi_day_encounter() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        let encounter = json!({
+            "resourceType": "Encounter",
+            "status": "finished",
+            "class": { "system": "http://terminology.hl7.org/CodeSystem/v3-ActCode", "code": "IMP", "display": "inpatient encounter" },
+            "subject": { "reference": "Patient/123" },
+            "period": { "start": "2023-01-01T08:00:00Z", "end": "2023-01-04T12:00:00Z" }
+        });
+        
+        let response = warp::test::request()
+            .method("POST")
+            .path("/fhir/Encounter")
+            .json(&encounter)
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["status"], "success");
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/resources/Encounter?_since=1672531200&_until=1672617600")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        let records = body["data"].as_array().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["metric_name"], "123|encounter|IMP");
+        assert_eq!(records[0]["value"], (3 * 86400 + 4 * 3600) as f64);
+    }
+
+    #[tokio::test]
+    async fn test_post_allergy_intolerance() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        let allergy = json!({
+            "resourceType": "AllergyIntolerance",
+            "code": { "coding": [{ "system": "http://www.nlm.nih.gov/research/umls/rxnorm", "code": "7980", "display": "Penicillin G" }] },
+            "criticality": "high",
+            "type": "allergy",
+            "patient": { "reference": "Patient/123" },
+            "recordedDate": "2023-01-01T10:00:00Z"
+        });
+        
+        let response = warp::test::request()
+            .method("POST")
+            .path("/fhir/AllergyIntolerance")
+            .json(&allergy)
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["status"], "success");
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/resources/AllergyIntolerance?_since=0")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        let records = body["data"].as_array().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["metric_name"], "123|7980|allergy");
+        assert_eq!(records[0]["value"], 2.0);
+        assert_eq!(records[0]["substance_display"], "Penicillin G");
+    }
+
+    #[tokio::test]
+    async fn test_patient_everything_bundle() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        let observation = quantity_observation("p42", 5.4).unit("mmol/L", "mmol/L").json();
+        let vital = json!({
+            "resourceType": "VitalSigns",
+            "code": { "coding": [{ "system": "http://loinc.org", "code": "85354-9", "display": "Blood pressure panel" }] },
+            "subject": { "reference": "Patient/p42" },
+            "effectiveDateTime": "2023-01-01T10:05:00Z",
+            "component": [
+                { "code": { "coding": [{ "system": "http://loinc.org", "code": "8480-6", "display": "Systolic" }] },
+                  "valueQuantity": { "value": 120.0, "unit": "mmHg", "system": "http://unitsofmeasure.org", "code": "mm[Hg]" } },
+                { "code": { "coding": [{ "system": "http://loinc.org", "code": "8462-4", "display": "Diastolic" }] },
+                  "valueQuantity": { "value": 80.0, "unit": "mmHg", "system": "http://unitsofmeasure.org", "code": "mm[Hg]" } }
+            ]
+        });
+        
+        for (path, body) in [("/fhir/Observation", &observation), ("/fhir/VitalSigns", &vital)] {
+            let response = warp::test::request()
+                .method("POST")
+                .path(path)
+                .json(body)
+                .reply(&routes)
+                .await;
+            assert_eq!(response_json(&response)["status"], "success");
+        }
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/Patient/p42/$everything")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        assert_eq!(body["data"]["resourceType"], "Bundle");
+        
+        let entries = body["data"]["entry"].as_array().unwrap();
+        let resource_types: Vec<&str> = entries.iter()
+            .map(|e| e["resource"]["resourceType"].as_str().unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert!(resource_types.contains(&"Observation"));
+        assert!(resource_types.contains(&"VitalSigns"));
+    }
+
+    #[tokio::test]
+    async fn test_create_then_read_patient() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        let patient = json!({
+            "resourceType": "Patient",
+            "id": "p7",
+            "name": [{ "family": "Chalmers", "given": ["Peter", "James"] }],
+            "birthDate": "1974-12-25",
+            "gender": "male"
+        });
+        
+        let response = warp::test::request()
+            .method("POST")
+            .path("/fhir/Patient")
+            .json(&patient)
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["status"], "success");
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/Patient/p7")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        assert_eq!(body["data"]["resourceType"], "Patient");
+        assert_eq!(body["data"]["id"], "p7");
+        assert_eq!(body["data"]["name"][0]["family"], "Chalmers");
+        assert_eq!(body["data"]["name"][0]["given"], json!(["Peter", "James"]));
+        assert_eq!(body["data"]["birthDate"], "1974-12-25");
+        assert_eq!(body["data"]["gender"], "male");
+    }
+
+    #[tokio::test]
+    async fn test_missing_patient_returns_404() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/Patient/nobody")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 404);
+        assert_eq!(response_json(&response)["status"], "error");
+    }
+
+    async fn post_glucose_values(routes: &(impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + 'static), values: &[f64]) {
+        for (i, value) in values.iter().enumerate() {
+            let observation = quantity_observation("vq", *value).at(format!("2023-01-01T10:0{}:00Z", i)).json();
+            let response = warp::test::request()
+                .method("POST")
+                .path("/fhir/Observation")
+                .json(&observation)
+                .reply(routes)
+                .await;
+            assert_eq!(response_json(&response)["status"], "success");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_value_quantity_gt_filter() {
+        let api = test_api();
+        let routes = api.routes();
+        post_glucose_values(&routes, &[90.0, 110.0, 150.0]).await;
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/Observation?patient=vq&code=2339-0&value-quantity=gt100")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        let values: Vec<f64> = body["data"].as_array().unwrap().iter()
+            .map(|r| r["value"].as_f64().unwrap())
+            .collect();
+        assert_eq!(values, vec![110.0, 150.0]);
+    }
+
+    #[tokio::test]
+    async fn test_value_quantity_combined_range() {
+        let api = test_api();
+        let routes = api.routes();
+        post_glucose_values(&routes, &[90.0, 100.0, 110.0, 120.0, 150.0]).await;
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/resources/Observation?value-quantity=ge100&value-quantity=le120")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        let values: Vec<f64> = body["data"].as_array().unwrap().iter()
+            .map(|r| r["value"].as_f64().unwrap())
+            .collect();
+        assert_eq!(values, vec![100.0, 110.0, 120.0]);
+    }
+
+    #[test]
+    fn test_value_filter_nan_handling() {
+        assert!(ValueFilter::parse(&["gtNaN"]).is_err());
+        let filter = ValueFilter::parse(&["ge1"]).unwrap();
+        assert!(!filter.matches(f64::NAN));
+        // eq uses the implicit precision of the given number
+        let filter = ValueFilter::parse(&["eq100"]).unwrap();
+        assert!(filter.matches(100.4));
+        assert!(!filter.matches(100.5));
+    }
+
+    #[tokio::test]
+    async fn test_stream_observations_ndjson() {
+        let api = test_api();
+        let routes = api.routes();
+        post_glucose_values(&routes, &[90.0, 110.0, 150.0]).await;
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/Observation/_stream?patient=vq&code=2339-0")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.headers()["Content-Type"], "application/x-ndjson");
+        
+        let body = std::str::from_utf8(response.body()).unwrap();
+        let values: Vec<f64> = body.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["value"].as_f64().unwrap())
+            .collect();
+        assert_eq!(values, vec![90.0, 110.0, 150.0]);
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_import_partial_failure() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        let observation = |minute: u32, value: f64| quantity_observation("imp", value)
+            .code("8867-4", "Heart rate")
+            .unit("beats/minute", "/min")
+            .at(format!("2023-01-01T10:{:02}:00Z", minute))
+            .json().to_string();
+        let payload = format!("{}\n{{\"resourceType\": \"Observation\", \n{}\n", observation(0, 60.0), observation(1, 62.0));
+        
+        let response = warp::test::request()
+            .method("POST")
+            .path("/fhir/$import")
+            .header("Content-Type", "application/fhir+ndjson")
+            .body(payload)
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "partial");
+        assert_eq!(body["data"]["imported"], 2);
+        assert_eq!(body["data"]["failed"], 1);
+        assert_eq!(body["data"]["errors"][0]["line"], 2);
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/Observation/_stream?patient=imp&code=8867-4")
+            .reply(&routes)
+            .await;
+        assert_eq!(std::str::from_utf8(response.body()).unwrap().lines().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_export_csv() {
+        let api = test_api();
+        let routes = api.routes();
+        post_glucose_values(&routes, &[90.0, 110.0]).await;
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/timeseries/export.csv?metric=vq|2339-0|mg/dL&start=1672531200&end=1672617600&include_context=true")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.headers()["Content-Type"], "text/csv; charset=utf-8");
+        assert_eq!(response.headers()["Content-Disposition"], "attachment; filename=\"vq_2339-0_mg_dL.csv\"");
+        
+        let body = std::str::from_utf8(response.body()).unwrap();
+        let mut lines = body.lines();
+        assert_eq!(lines.next(), Some("timestamp,iso_date,value,context"));
+        assert_eq!(lines.next(), Some("1672567200,2023-01-01T10:00:00+00:00,90,{}"));
+        assert_eq!(lines.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_time_chunked_ndjson_streams_one_line_per_chunk() {
+        let api = test_api();
+        let routes = api.routes();
+        post_glucose_values(&routes, &[90.0, 110.0, 150.0]).await;
+        let path = "/fhir/timeseries?start=1672567200&end=1672567500&chunk_size=60";
+        
+        let aggregated = warp::test::request().method("GET").path(path).reply(&routes).await;
+        assert_eq!(response_json(&aggregated)["data"].as_array().unwrap().len(), 3);
+        
+        let streamed = warp::test::request()
+            .method("GET")
+            .path(path)
+            .header("accept", "application/x-ndjson")
+            .reply(&routes)
+            .await;
+        assert_eq!(streamed.headers()["Content-Type"], "application/x-ndjson");
+        let chunks: Vec<serde_json::Value> = std::str::from_utf8(streamed.body()).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0]["start_time"], 1672567200);
+        assert_eq!(chunks[2]["end_time"], 1672567380);
+        assert_eq!(chunks[1]["records"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_csv_escape() {
+        assert_eq!(csv_escape("plain"), "plain");
+        assert_eq!(csv_escape("a,b"), "\"a,b\"");
+        assert_eq!(csv_escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[tokio::test]
+    async fn test_correlation_matrix() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        // Heart rate and a perfectly correlated derived series, plus one with no overlap
+        let mut records = Vec::new();
+        for i in 0..10 {
+            let value = 60.0 + (i * i) as f64;
+            for (metric, value, timestamp) in [
+                ("corr|8867-4|bpm", value, 1000 + i * 60),
+                ("corr|8480-6|mmHg", value * 2.0 + 10.0, 1000 + i * 60),
+                ("corr|8310-5|Cel", 37.0 + i as f64, 5000 + i * 60),
+            ] {
+                records.push(Record {
+                    timestamp,
+                    metric_name: metric.to_string(),
+                    value,
+                    string_value: None,
+                    context: std::collections::HashMap::new(),
+                    resource_type: "Observation".to_string(),
+                    id: None,
+                });
+            }
+        }
+        api.query_engine.store_records(records).unwrap();
+        
+        let response = warp::test::request()
+            .method("POST")
+            .path("/timeseries/correlation")
+            .json(&json!({
+                "metrics": ["corr|8867-4|bpm", "corr|8480-6|mmHg", "corr|8310-5|Cel"],
+                "start": 0,
+                "end": 10000
+            }))
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        
+        let matrix = &body["data"]["matrix"];
+        assert!((matrix[0][1].as_f64().unwrap() - 1.0).abs() < 1e-9);
+        assert_eq!(matrix[1][0], matrix[0][1]);
+        assert_eq!(matrix[0][2], 0.0);
+        assert_eq!(matrix[2][2], 1.0);
+        
+        // A single metric is just its self-correlation
+        let matrix = api.query_engine.correlation_matrix(&["corr|8867-4|bpm".to_string()], 0, 10000).unwrap();
+        assert_eq!(matrix, vec![vec![1.0]]);
+    }
 
-/// Helper functions to format multiple records
-fn format_records_for_api(records: &[Record]) -> Vec<serde_json::Value> {
-    records.iter()
-        .map(|record| format_record_for_api(record))
-        .collect()
-} 
\ No newline at end of file
+    #[tokio::test]
+    async fn test_rate_of_change_zero_period_is_an_error() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/timeseries/rate?metric=vq|2339-0|mg/dL&start=0&end=10000&period=0")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "error");
+        assert!(body["message"].as_str().unwrap().contains("period must be positive"));
+    }
+
+    #[tokio::test]
+    async fn test_detection_config_update_changes_threshold() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        // A clear step from ~80 to ~160 halfway through
+        let values: Vec<f64> = (0..20)
+            .map(|i| if i < 10 { 80.0 + (i % 2) as f64 } else { 160.0 + (i % 2) as f64 })
+            .collect();
+        let base = 1_672_567_200; // 2023-01-01T10:00:00Z
+        let records: Vec<Record> = values.iter().enumerate()
+            .map(|(i, &value)| Record {
+                timestamp: base + i as i64 * 60,
+                metric_name: "vq|2339-0|mg/dL".to_string(),
+                value,
+                string_value: None,
+                context: std::collections::HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            })
+            .collect();
+        api.query_engine.store_records(records).unwrap();
+        
+        let path = format!(
+            "/timeseries/changepoints?metric=vq%7C2339-0%7Cmg%2FdL&start={}&end={}",
+            base, base + 3600
+        );
+        let count_changepoints = |response: &warp::http::Response<warp::hyper::body::Bytes>| {
+            let body = response_json(response);
+            assert_eq!(body["status"], "success", "{}", body);
+            body["data"]["changepoints"].as_array().unwrap().len()
+        };
+        
+        let response = warp::test::request().method("GET").path(&path).reply(&routes).await;
+        assert!(count_changepoints(&response) > 0);
+        
+        // Raise the CUSUM threshold far beyond anything the series can reach
+        let mut config = serde_json::to_value(crate::timeseries::detection::PatternDetector::new().config()).unwrap();
+        config["changepoint"]["threshold"] = json!(1000.0);
+        let response = warp::test::request()
+            .method("POST")
+            .path("/config/detection")
+            .json(&config)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        
+        let response = warp::test::request().method("GET").path(&path).reply(&routes).await;
+        assert_eq!(count_changepoints(&response), 0);
+        
+        // Nonsense values are rejected and leave the active config alone
+        config["changepoint"]["threshold"] = json!(-1.0);
+        let response = warp::test::request()
+            .method("POST")
+            .path("/config/detection")
+            .json(&config)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 400);
+        
+        let response = warp::test::request().method("GET").path(&path).reply(&routes).await;
+        assert_eq!(count_changepoints(&response), 0);
+    }
+
+    #[tokio::test]
+    async fn test_query_timeout_interrupts_large_scan() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        // A week of one-minute samples spans 168 chunks
+        let total = 7 * 24 * 60;
+        let records: Vec<Record> = (0..total)
+            .map(|i| Record {
+                timestamp: i * 60,
+                metric_name: "vq|8867-4|bpm".to_string(),
+                value: 60.0 + (i % 20) as f64,
+                string_value: None,
+                context: std::collections::HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            })
+            .collect();
+        api.query_engine.store_records(records).unwrap();
+        
+        for endpoint in ["timeseries/range", "timeseries/stats", "timeseries/outliers", "fhir/timeseries"] {
+            let path = format!(
+                "/{}?metric=vq%7C8867-4%7Cbpm&start=0&end={}&timeout_ms=0",
+                endpoint, total * 60
+            );
+            let response = warp::test::request().method("GET").path(&path).reply(&routes).await;
+            let body = response_json(&response);
+            assert_eq!(body["status"], "error", "{}: {}", endpoint, body);
+            assert!(body["message"].as_str().unwrap().contains("Timeout"), "{}: {}", endpoint, body);
+        }
+        
+        // A generous timeout lets the same scan finish
+        let path = format!(
+            "/timeseries/range?metric=vq%7C8867-4%7Cbpm&start=0&end={}&timeout_ms=60000",
+            total * 60
+        );
+        let response = warp::test::request().method("GET").path(&path).reply(&routes).await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success", "{}", body);
+        assert_eq!(body["data"].as_array().unwrap().len(), total as usize);
+        
+        // The time-chunked view of the same data honours the timeout too
+        let path = format!("/fhir/timeseries?resource_type=Observation&start=0&end={}&timeout_ms=0", total * 60);
+        let response = warp::test::request().method("GET").path(&path).reply(&routes).await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "error", "{}", body);
+        assert_eq!(body["error_code"], "TIMEOUT", "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_ws_subscribe_receives_matching_inserts() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        let mut client = warp::test::ws()
+            .path("/ws/subscribe?metric=vq%7C8867-4")
+            .handshake(routes)
+            .await
+            .expect("handshake");
+        
+        let make_record = |metric: &str, value: f64| Record {
+            timestamp: 1_672_567_200,
+            metric_name: metric.to_string(),
+            value,
+            string_value: None,
+            context: std::collections::HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        
+        // Only the second insert matches the subscribed prefix
+        api.query_engine.store_record(make_record("vq|2339-0|mg/dL", 95.0)).unwrap();
+        api.query_engine.store_record(make_record("vq|8867-4|bpm", 72.0)).unwrap();
+        
+        let message = client.recv().await.expect("update");
+        let update: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        assert_eq!(update["value"], 72.0);
+        assert_eq!(update["metric_name"], "vq|8867-4|bpm");
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token_auth() {
+        let api = test_api().with(|api| api.with_auth_token(Some("s3cret".to_string())));
+        let routes = api.routes();
+        let patient = json!({
+            "resourceType": "Patient",
+            "id": "auth1",
+            "name": [{ "family": "Doe", "given": ["Jane"] }]
+        });
+        
+        // Missing and wrong tokens are both rejected before the handler runs
+        for header in [None, Some("Bearer wrong"), Some("s3cret")] {
+            let mut request = warp::test::request().method("POST").path("/fhir/Patient").json(&patient);
+            if let Some(value) = header {
+                request = request.header("Authorization", value);
+            }
+            let response = request.reply(&routes).await;
+            assert_eq!(response.status(), 401, "header {:?}", header);
+            assert_eq!(response.headers()["WWW-Authenticate"], "Bearer");
+        }
+        
+        let response = warp::test::request()
+            .method("POST")
+            .path("/fhir/Patient")
+            .header("Authorization", "Bearer s3cret")
+            .json(&patient)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response_json(&response)["status"], "success");
+        
+        // Once authorized, unknown paths fall through to warp's usual rejection
+        let response = warp::test::request()
+            .method("GET")
+            .path("/no/such/route")
+            .header("Authorization", "Bearer s3cret")
+            .reply(&routes)
+            .await;
+        assert_ne!(response.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn test_no_token_configured_leaves_api_open() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        let response = warp::test::request().method("GET").path("/debug/stats").reply(&routes).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_latest_observations_endpoint() {
+        let api = test_api();
+        let routes = api.routes();
+        post_glucose_values(&routes, &[90.0, 110.0, 150.0]).await;
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/Observation/latest?metric=vq%7C2339-0%7Cmg%2FdL&n=2")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        let values: Vec<f64> = body["data"].as_array().unwrap().iter()
+            .map(|r| r["value"].as_f64().unwrap())
+            .collect();
+        assert_eq!(values, vec![150.0, 110.0]);
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/Observation/latest?metric=vq%7C2339-0%7Cmg%2FdL&n=0")
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["status"], "error");
+    }
+
+    #[tokio::test]
+    async fn test_batch_query_reports_missing_metric() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        for (code, display, unit, value) in [("8867-4", "Heart rate", "beats/min", 72.0), ("2339-0", "Glucose", "mg/dL", 95.0)] {
+            let observation = quantity_observation("batch", value).code(code, display).unit(unit, unit).json();
+            let response = warp::test::request()
+                .method("POST")
+                .path("/fhir/Observation")
+                .json(&observation)
+                .reply(&routes)
+                .await;
+            assert_eq!(response_json(&response)["status"], "success");
+        }
+        
+        let start = chrono::DateTime::parse_from_rfc3339("2023-01-01T00:00:00Z").unwrap().timestamp();
+        let response = warp::test::request()
+            .method("POST")
+            .path("/timeseries/batch")
+            .json(&json!({
+                "metrics": ["batch|8867-4|beats/min", "batch|2339-0|mg/dL", "batch|0000-0|none"],
+                "start": start,
+                "end": start + 86400,
+            }))
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        
+        let data = &body["data"];
+        assert_eq!(data["batch|8867-4|beats/min"]["status"], "success");
+        assert_eq!(data["batch|8867-4|beats/min"]["records"][0]["value"], 72.0);
+        assert_eq!(data["batch|2339-0|mg/dL"]["records"][0]["value"], 95.0);
+        assert_eq!(data["batch|0000-0|none"]["status"], "error");
+        
+        // Aggregations apply per metric
+        let response = warp::test::request()
+            .method("POST")
+            .path("/timeseries/batch")
+            .json(&json!({
+                "metrics": ["batch|8867-4|beats/min"],
+                "start": start,
+                "end": start + 86400,
+                "aggregation": "count",
+            }))
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["data"]["batch|8867-4|beats/min"]["records"][0]["value"], 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_metadata_capability_statement() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        let response = warp::test::request().method("GET").path("/fhir/metadata").reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["Content-Type"], "application/fhir+json");
+        
+        let body = response_json(&response);
+        assert_eq!(body["resourceType"], "CapabilityStatement");
+        let resources = body["rest"][0]["resource"].as_array().unwrap();
+        let observation = resources.iter().find(|r| r["type"] == "Observation").unwrap();
+        assert!(observation["interaction"].as_array().unwrap().contains(&json!({ "code": "create" })));
+    }
+
+    #[tokio::test]
+    async fn test_advertised_interactions_have_routes() {
+        use crate::fhir::capability::{Interaction, SUPPORTED_RESOURCES};
+        
+        let api = test_api();
+        let routes = api.routes();
+        
+        // A missing route is a 404/405 rejection; an existing one handles or rejects the body
+        for resource in SUPPORTED_RESOURCES {
+            for interaction in resource.interactions {
+                let response = match interaction {
+                    Interaction::Create => warp::test::request()
+                        .method("POST")
+                        .path(&format!("/fhir/{}", resource.resource_type))
+                        .json(&json!({}))
+                        .reply(&routes)
+                        .await,
+                    Interaction::SearchType => warp::test::request()
+                        .method("GET")
+                        .path(&format!("/fhir/{}", resource.resource_type))
+                        .reply(&routes)
+                        .await,
+                    Interaction::Read => warp::test::request()
+                        .method("GET")
+                        .path(&format!("/fhir/{}/unknown-id", resource.resource_type))
+                        .reply(&routes)
+                        .await,
+                };
+                let handled = serde_json::from_slice::<serde_json::Value>(response.body()).is_ok()
+                    || ![404, 405].contains(&response.status().as_u16());
+                assert!(handled, "{} {} has no route", resource.resource_type, interaction.code());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blood_pressure_returned_as_panel() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        let vital = json!({
+            "resourceType": "VitalSigns",
+            "code": { "coding": [{ "system": "http://loinc.org", "code": "85354-9", "display": "Blood pressure panel" }] },
+            "subject": { "reference": "Patient/bp1" },
+            "effectiveDateTime": "2023-01-01T10:05:00Z",
+            "component": [
+                { "code": { "coding": [{ "system": "http://loinc.org", "code": "8480-6", "display": "Systolic" }] },
+                  "valueQuantity": { "value": 120.0, "unit": "mmHg", "system": "http://unitsofmeasure.org", "code": "mm[Hg]" } },
+                { "code": { "coding": [{ "system": "http://loinc.org", "code": "8462-4", "display": "Diastolic" }] },
+                  "valueQuantity": { "value": 80.0, "unit": "mmHg", "system": "http://unitsofmeasure.org", "code": "mm[Hg]" } }
+            ]
+        });
+        let response = warp::test::request()
+            .method("POST")
+            .path("/fhir/VitalSigns")
+            .json(&vital)
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["status"], "success");
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/resources/VitalSigns")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        let observations = body["data"].as_array().unwrap();
+        assert_eq!(observations.len(), 1);
+        
+        let panel = &observations[0];
+        assert_eq!(panel["metric_components"]["code"], "85354-9");
+        assert_eq!(panel["subject"]["reference"], "Patient/bp1");
+        assert!(panel.get("value").is_none());
+        let components = panel["component"].as_array().unwrap();
+        assert_eq!(components.len(), 2);
+        assert_eq!(components[0]["code"]["coding"][0]["code"], "8480-6");
+        assert_eq!(components[0]["valueQuantity"]["value"], 120.0);
+        assert_eq!(components[1]["code"]["coding"][0]["code"], "8462-4");
+        assert_eq!(components[1]["valueQuantity"]["value"], 80.0);
+    }
+
+    #[test]
+    fn test_date_params_translate_to_range() {
+        let params = |pairs: &[&str]| -> Vec<(String, String)> {
+            pairs.iter().map(|v| ("date".to_string(), v.to_string())).collect()
+        };
+        let defaults = (0, 2_000_000_000);
+        
+        // 2023-01-01T00:00:00Z through the end of 2023-02-01
+        assert_eq!(
+            date_bounds_from_params(&params(&["ge2023-01-01", "le2023-02-01"]), defaults).unwrap(),
+            (1672531200, 1675296000),
+        );
+        // Exclusive prefixes flip which end of the period they use
+        assert_eq!(
+            date_bounds_from_params(&params(&["gt2023-01-01", "lt2023-02-01"]), defaults).unwrap(),
+            (1672617600, 1675209600),
+        );
+        // Open-ended either way
+        assert_eq!(date_bounds_from_params(&params(&["ge2023-01"]), defaults).unwrap(), (1672531200, 2_000_000_000));
+        assert_eq!(date_bounds_from_params(&params(&["lt2023"]), defaults).unwrap(), (0, 1672531200));
+        // No prefix means within the period
+        assert_eq!(date_bounds_from_params(&params(&["2023-01"]), defaults).unwrap(), (1672531200, 1675209600));
+        assert_eq!(
+            date_bounds_from_params(&params(&["le2023-01-01T10:00:00Z"]), defaults).unwrap(),
+            (0, 1672567201),
+        );
+        
+        assert!(date_bounds_from_params(&params(&["ge2023-13-01"]), defaults).is_err());
+        assert!(date_bounds_from_params(&params(&["sa2023-01-01"]), defaults).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resource_search_by_date_prefixes() {
+        let api = test_api();
+        let routes = api.routes();
+        for (i, date) in ["2022-12-31", "2023-01-01", "2023-01-15", "2023-02-01", "2023-02-02"].iter().enumerate() {
+            let observation = quantity_observation("dates", i as f64).at(format!("{}T12:00:00Z", date)).json();
+            let response = warp::test::request()
+                .method("POST")
+                .path("/fhir/Observation")
+                .json(&observation)
+                .reply(&routes)
+                .await;
+            assert_eq!(response_json(&response)["status"], "success");
+        }
+        
+        let values = |body: serde_json::Value| -> Vec<f64> {
+            body["data"].as_array().unwrap().iter().map(|r| r["value"].as_f64().unwrap()).collect()
+        };
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/resources/Observation?date=ge2023-01-01&date=le2023-02-01")
+            .reply(&routes)
+            .await;
+        assert_eq!(values(response_json(&response)), vec![1.0, 2.0, 3.0]);
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/resources/Observation?date=gt2023-01-15")
+            .reply(&routes)
+            .await;
+        assert_eq!(values(response_json(&response)), vec![3.0, 4.0]);
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/resources/Observation?date=notadate")
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["status"], "error");
+    }
+
+    #[tokio::test]
+    async fn test_rate_alerts_return_breaching_point() {
+        let api = test_api();
+        let routes = api.routes();
+        // A minute apart from 10:00, with a 40 mg/dL jump at 10:04
+        post_glucose_values(&routes, &[100.0, 101.0, 99.0, 100.0, 140.0, 141.0]).await;
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/timeseries/alerts/rate?metric=vq|2339-0|mg/dL&start=1672567200&end=1672567800&period=60&max_rate=20")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        let breaches = body["data"].as_array().unwrap();
+        assert_eq!(breaches.len(), 1);
+        assert_eq!(breaches[0]["timestamp"], 1672567440);
+        assert_eq!(breaches[0]["rate"], 40.0);
+        assert_eq!(breaches[0]["before"]["value"], 100.0);
+        assert_eq!(breaches[0]["after"]["value"], 140.0);
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/timeseries/alerts/rate?metric=vq|2339-0|mg/dL&start=1672567200&end=1672567800")
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["status"], "error");
+    }
+
+    #[tokio::test]
+    async fn test_query_span_limit_and_default_window() {
+        let api = test_api().with(|api| api.with_query_window(QueryWindow {
+            default_span: None,
+            max_span: Some(Duration::from_secs(7 * 86400)),
+        }));
+        let routes = api.routes();
+        post_glucose_values(&routes, &[90.0, 100.0, 110.0]).await;
+        let get = |path: &str| warp::test::request().path(path).reply(&routes);
+        
+        // `_since` defaults to the epoch, far wider than a week
+        for path in [
+            "/fhir/resources/Observation",
+            "/fhir/resources/Observation/_count",
+            "/fhir/Patient/vq/$everything",
+            "/timeseries/range?metric=vq%7C2339-0%7Cmg%2FdL&start=0&end=2000000000",
+        ] {
+            let response = get(path).await;
+            assert_eq!(response.status(), 400, "{}", path);
+            assert_eq!(response_json(&response)["error_code"], "INVALID_TIME_RANGE", "{}", path);
+        }
+        
+        let response = get("/fhir/resources/Observation?allow_full_scan=true").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response_json(&response)["data"].as_array().unwrap().len(), 3);
+        
+        let response = get("/fhir/resources/Observation?_since=1672567200&_until=1672570800").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response_json(&response)["data"].as_array().unwrap().len(), 3);
+        
+        let response = get("/timeseries/range?metric=vq%7C2339-0%7Cmg%2FdL&start=1672567200&end=1672570800").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response_json(&response)["data"].as_array().unwrap().len(), 3);
+        
+        // Queries posted as JSON bodies are held to the same limit
+        let post = |path: &str, body: serde_json::Value| warp::test::request()
+            .method("POST")
+            .path(path)
+            .json(&body)
+            .reply(&routes);
+        let batch = json!({ "metrics": ["vq|2339-0|mg/dL"], "start": 1_672_000_000, "end": 1_673_000_000 });
+        let matrix = json!({ "metrics": ["vq|2339-0|mg/dL"], "start": 1_672_000_000, "end": 1_673_000_000, "interval": "1h" });
+        for (path, body) in [("/timeseries/batch", &batch), ("/timeseries/matrix", &matrix)] {
+            let response = post(path, body.clone()).await;
+            assert_eq!(response.status(), 400, "{}", path);
+            assert_eq!(response_json(&response)["error_code"], "INVALID_TIME_RANGE", "{}", path);
+            let response = post(&format!("{}?allow_full_scan=true", path), body.clone()).await;
+            assert_eq!(response_json(&response)["status"], "success", "{}", path);
+        }
+        
+        // A configured default window reaches back from `end` when `start` is left out
+        let api = test_api().with(|api| api.with_query_window(QueryWindow {
+            default_span: Some(Duration::from_secs(3600)),
+            max_span: Some(Duration::from_secs(7 * 86400)),
+        }));
+        let routes = api.routes();
+        post_glucose_values(&routes, &[90.0, 100.0, 110.0]).await;
+        let response = warp::test::request()
+            .path("/timeseries/range?metric=vq%7C2339-0%7Cmg%2FdL&end=1672570800")
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["data"].as_array().unwrap().len(), 3);
+        let response = warp::test::request()
+            .path("/fhir/resources/Observation?_until=1672570800")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response_json(&response)["data"].as_array().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_count_endpoints() {
+        let api = test_api();
+        let routes = api.routes();
+        post_glucose_values(&routes, &[90.0, 100.0, 110.0, 120.0]).await;
+        
+        // 10:00 through 10:02 inclusive
+        let response = warp::test::request()
+            .method("GET")
+            .path("/timeseries/count?metric=vq|2339-0|mg/dL&start=1672567200&end=1672567321")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        assert_eq!(body["data"], 3);
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/resources/Observation/_count?_since=1672567200&_until=1672570800")
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["data"], 4);
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/resources/Observation/_count?date=ge2023-01-02")
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["data"], 0);
+        
+        let response = warp::test::request()
+            .method("GET")
+            .path("/timeseries/count?start=0")
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["status"], "error");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_resource_type_is_rejected() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        for path in ["/fhir/resources/Banana", "/fhir/resources/Banana/_count", "/fhir/resources/observation"] {
+            let response = warp::test::request()
+                .method("GET")
+                .path(path)
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), 400, "{}", path);
+            
+            let body = response_json(&response);
+            assert_eq!(body["resourceType"], "OperationOutcome");
+            let diagnostics = body["issue"][0]["diagnostics"].as_str().unwrap();
+            for resource_type in ResourceType::ALL {
+                assert!(diagnostics.contains(resource_type.as_str()), "{} missing {}", path, resource_type);
+            }
+        }
+        
+        // Known types with no data are still a plain empty success
+        let response = warp::test::request()
+            .method("GET")
+            .path("/fhir/resources/Encounter")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_admin_flush_persists_dirty_chunks() {
+        let api = test_api();
+        let routes = api.routes();
+        post_glucose_values(&routes, &[95.0, 110.0]).await;
+        assert_eq!(api.query_engine.dirty_chunk_count(), 1);
+        
+        let response = warp::test::request()
+            .method("POST")
+            .path("/admin/flush")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        assert_eq!(body["data"]["chunks_flushed"], 1);
+        assert_eq!(body["data"]["wal_truncated"], true);
+        assert_eq!(api.query_engine.dirty_chunk_count(), 0);
+        
+        // Without persistence there is nothing to flush to
+        api.query_engine.set_debug_settings(true, true, None).unwrap();
+        let response = warp::test::request()
+            .method("POST")
+            .path("/admin/flush")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "error");
+        assert!(body["message"].as_str().unwrap().contains("Persistence is disabled"));
+    }
+
+    #[tokio::test]
+    async fn test_tz_param_controls_iso_date_zone() {
+        let api = test_api();
+        let routes = api.routes();
+        post_glucose_values(&routes, &[95.0]).await;
+        
+        let iso_date = |body: &serde_json::Value| body["data"][0]["iso_date"].as_str().unwrap().to_string();
+        let response = warp::test::request()
+            .path("/fhir/resources/Observation?_since=0")
+            .reply(&routes)
+            .await;
+        assert_eq!(iso_date(&response_json(&response)), "2023-01-01T10:00:00+00:00");
+        
+        // New York is UTC-5 in January
+        let response = warp::test::request()
+            .path("/fhir/resources/Observation?_since=0&tz=America/New_York")
+            .reply(&routes)
+            .await;
+        assert_eq!(iso_date(&response_json(&response)), "2023-01-01T05:00:00-05:00");
+        
+        let response = warp::test::request()
+            .path("/timeseries/range?metric=vq%7C2339-0%7Cmg%2FdL&start=0&end=2000000000&tz=America/New_York")
+            .reply(&routes)
+            .await;
+        assert_eq!(iso_date(&response_json(&response)), "2023-01-01T05:00:00-05:00");
+        
+        let response = warp::test::request()
+            .path("/timeseries/range?metric=vq%7C2339-0%7Cmg%2FdL&tz=Mars/Olympus_Mons")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 400);
+        assert!(response_json(&response)["message"].as_str().unwrap().contains("Unknown time zone"));
+    }
+
+    #[tokio::test]
+    async fn test_bucketed_stats_endpoint() {
+        let api = test_api();
+        let routes = api.routes();
+        post_glucose_values(&routes, &[90.0, 100.0, 110.0]).await;
+        
+        // Readings at 10:00-10:02; 10-minute buckets split nothing, 1-minute buckets split all
+        let response = warp::test::request()
+            .path("/timeseries/stats/bucketed?metric=vq%7C2339-0%7Cmg%2FdL&start=1672567200&end=1672570800&interval=600")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        assert_eq!(body["data"].as_array().unwrap().len(), 1);
+        assert_eq!(body["data"][0]["bucket_start"], 1672567200);
+        assert_eq!(body["data"][0]["stats"]["mean"], 100.0);
+        
+        let response = warp::test::request()
+            .path("/timeseries/stats/bucketed?metric=vq%7C2339-0%7Cmg%2FdL&start=1672567200&end=1672570800&interval=60")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        let means: Vec<f64> = body["data"].as_array().unwrap().iter()
+            .map(|bucket| bucket["stats"]["mean"].as_f64().unwrap())
+            .collect();
+        assert_eq!(means, vec![90.0, 100.0, 110.0]);
+    }
+
+    #[tokio::test]
+    async fn test_interval_accepts_seconds_or_duration() {
+        let api = test_api();
+        let routes = api.routes();
+        post_glucose_values(&routes, &[90.0, 100.0, 110.0, 120.0, 130.0, 140.0]).await;
+        
+        let get = |path: String| {
+            let routes = routes.clone();
+            async move { response_json(&warp::test::request().path(&path).reply(&routes).await) }
+        };
+        let window = "start=1672567200&end=1672570800";
+        let metric = "metric=vq%7C2339-0%7Cmg%2FdL";
+        
+        for (endpoint, deprecated) in [
+            ("/fhir/timeseries", Some("chunk_size")),
+            ("/timeseries/stats/bucketed", None),
+            ("/timeseries/rate", Some("period")),
+        ] {
+            let path = |interval: &str| format!("{}?{}&{}&{}", endpoint, metric, window, interval);
+            let human = get(path("interval=2m")).await;
+            assert_eq!(human["status"], "success", "{}: {}", endpoint, human);
+            assert!(!human["data"].as_array().unwrap().is_empty());
+            assert_eq!(get(path("interval=120")).await["data"], human["data"], "{}", endpoint);
+            if let Some(deprecated) = deprecated {
+                assert_eq!(get(path(&format!("{}=120", deprecated))).await["data"], human["data"], "{}", endpoint);
+                assert_eq!(get(path(&format!("{}=2m", deprecated))).await["data"], human["data"], "{}", endpoint);
+            }
+            
+            let response = warp::test::request().path(&path("interval=2x")).reply(&routes).await;
+            assert_eq!(response.status(), 400, "{}", endpoint);
+            assert_eq!(response_json(&response)["error_code"], "INVALID_PARAMETER");
+        }
+        
+        // Two-minute buckets over readings a minute apart hold two readings each
+        let body = get(format!("/timeseries/stats/bucketed?{}&{}&interval=2m", metric, window)).await;
+        let means: Vec<f64> = body["data"].as_array().unwrap().iter()
+            .map(|bucket| bucket["stats"]["mean"].as_f64().unwrap())
+            .collect();
+        assert_eq!(means, vec![95.0, 115.0, 135.0]);
+    }
+
+    #[tokio::test]
+    async fn test_elements_param_trims_fields() {
+        let api = test_api();
+        let routes = api.routes();
+        post_glucose_values(&routes, &[95.0]).await;
+        
+        for path in [
+            "/fhir/resources/Observation?_since=0&_elements=value",
+            "/timeseries/range?metric=vq%7C2339-0%7Cmg%2FdL&start=0&end=2000000000&_elements=value",
+        ] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            let body = response_json(&response);
+            let mut keys: Vec<&str> = body["data"][0].as_object().unwrap().keys().map(String::as_str).collect();
+            keys.sort();
+            assert_eq!(keys, ["id", "resourceType", "value"], "{}", path);
+            assert_eq!(body["data"][0]["value"], 95.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_summary_count_returns_empty_bundle() {
+        let api = test_api();
+        let routes = api.routes();
+        post_glucose_values(&routes, &[95.0, 101.0, 110.0]).await;
+        
+        let response = warp::test::request()
+            .path("/fhir/resources/Observation?_since=0&_summary=count")
+            .reply(&routes).await;
+        let bundle = &response_json(&response)["data"];
+        assert_eq!(bundle["resourceType"], "Bundle");
+        assert_eq!(bundle["total"], 3);
+        assert_eq!(bundle["entry"], json!([]));
+        
+        let response = warp::test::request()
+            .path("/fhir/resources/Observation?_since=0&_summary=true")
+            .reply(&routes).await;
+        let body = response_json(&response);
+        assert_eq!(body["data"].as_array().unwrap().len(), 3);
+        assert!(body["data"][0].get("value").is_some());
+        assert!(body["data"][0].get("metric_components").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_debug_metric_counts() {
+        let api = test_api();
+        let routes = api.routes();
+        let record = |metric: &str, timestamp: i64| Record {
+            timestamp,
+            metric_name: metric.to_string(),
+            value: 1.0,
+            string_value: None,
+            context: std::collections::HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        // The heart rate series spans two chunks
+        let mut records: Vec<Record> = (0..5).map(|i| record("mc|8867-4|bpm", 1000 + i * 3000)).collect();
+        records.extend((0..2).map(|i| record("mc|8310-5|Cel", 2000 + i * 10)));
+        api.query_engine.store_records(records).unwrap();
+        
+        let response = warp::test::request().path("/debug/metrics/counts").reply(&routes).await;
+        let body = response_json(&response);
+        assert_eq!(body["data"], json!([
+            { "metric": "mc|8867-4|bpm", "record_count": 5, "earliest": 1000, "latest": 13000 },
+            { "metric": "mc|8310-5|Cel", "record_count": 2, "earliest": 2000, "latest": 2010 },
+        ]));
+    }
+
+    #[tokio::test]
+    async fn test_debug_chunk_summary_matches_inserted_values() {
+        let api = test_api();
+        let routes = api.routes();
+        let values = [90.0, 105.0, 120.0, 97.0];
+        post_glucose_values(&routes, &values).await;
+        
+        let response = warp::test::request().path("/debug/chunks").reply(&routes).await;
+        let body = response_json(&response);
+        let chunks = body["data"].as_array().unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0]["record_count"], values.len());
+        assert_eq!(chunks[0]["compressed"], false);
+        let chunk_id = chunks[0]["id"].as_i64().unwrap();
+        assert!(chunks[0]["start_time"].as_i64().unwrap() <= 1672567200);
+        
+        let response = warp::test::request()
+            .path(&format!("/debug/chunks/{}/summary?metric=vq%7C2339-0%7Cmg%2FdL", chunk_id))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        let summary = &response_json(&response)["data"];
+        let avg = values.iter().sum::<f64>() / values.len() as f64;
+        assert_eq!(summary["count"], values.len());
+        assert_eq!(summary["min"], 90.0);
+        assert_eq!(summary["max"], 120.0);
+        assert!((summary["avg"].as_f64().unwrap() - avg).abs() < 1e-9);
+        
+        let response = warp::test::request()
+            .path(&format!("/debug/chunks/{}/summary?metric=missing", chunk_id))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_prevents_duplicate_create() {
+        let api = test_api();
+        let routes = api.routes();
+        let observation = quantity_observation("vq", 95.0).json();
+        let post = || warp::test::request()
+            .method("POST")
+            .path("/fhir/Observation")
+            .header("Idempotency-Key", "retry-1")
+            .json(&observation);
+        
+        let first = post().reply(&routes).await;
+        assert_eq!(response_json(&first)["status"], "success");
+        assert!(first.headers().get("idempotent-replayed").is_none());
+        let retry = post().reply(&routes).await;
+        assert_eq!(retry.headers()["idempotent-replayed"], "true");
+        assert_eq!(retry.body(), first.body());
+        
+        let response = warp::test::request()
+            .path("/timeseries/range?metric=vq%7C2339-0%7Cmg%2FdL&start=0&end=2000000000")
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["data"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_rejects_changed_body_and_concurrent_retry() {
+        let api = test_api();
+        let routes = api.routes();
+        let observation = |value: f64| quantity_observation("idem", value).json();
+        let post = |value: f64| warp::test::request()
+            .method("POST")
+            .path("/fhir/Observation")
+            .header("Idempotency-Key", "retry-2")
+            .json(&observation(value));
+        
+        let (first, second) = tokio::join!(post(95.0).reply(&routes), post(95.0).reply(&routes));
+        for response in [&first, &second] {
+            assert!(
+                response.status() == 409 || response_json(response)["status"] == "success",
+                "unexpected response: {:?}", response.body()
+            );
+        }
+        
+        let changed = post(120.0).reply(&routes).await;
+        assert_eq!(changed.status(), 422);
+        assert_eq!(response_json(&changed)["error_code"], "IDEMPOTENCY_KEY_MISMATCH");
+        
+        let response = warp::test::request()
+            .path("/timeseries/range?metric=idem%7C2339-0%7Cmg%2FdL&start=0&end=2000000000")
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["data"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_ignored_on_reads() {
+        let api = test_api();
+        let routes = api.routes();
+        let search = || warp::test::request()
+            .path("/fhir/Observation?patient=idem-read&code=2339-0&_since=0")
+            .header("Idempotency-Key", "read-1");
+        
+        let before = search().reply(&routes).await;
+        assert_eq!(response_json(&before)["error_code"], "NOT_FOUND");
+        let observation = quantity_observation("idem-read", 95.0).json();
+        let response = warp::test::request()
+            .method("POST")
+            .path("/fhir/Observation")
+            .json(&observation)
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["status"], "success");
+        
+        let after = search().reply(&routes).await;
+        assert!(after.headers().get("idempotent-replayed").is_none());
+        assert_eq!(response_json(&after)["data"]["value"], 95.0);
+    }
+
+    #[tokio::test]
+    async fn test_category_filter_on_observation_search() {
+        let api = test_api();
+        let routes = api.routes();
+        let readings = [
+            ("vital-signs", "8867-4", "/min", 72.0),
+            ("laboratory", "2339-0", "mg/dL", 95.0),
+            ("laboratory", "2339-0", "mg/dL", 101.0),
+        ];
+        for (i, (category, code, unit, value)) in readings.iter().enumerate() {
+            let mut observation = quantity_observation("cat", *value)
+                .code(code, code)
+                .unit(unit, unit)
+                .at(format!("2023-01-01T10:0{}:00Z", i))
+                .json();
+            observation["category"] = json!([{ "coding": [{
+                "system": "http://terminology.hl7.org/CodeSystem/observation-category",
+                "code": category,
+                "display": category
+            }] }]);
+            let response = warp::test::request()
+                .method("POST")
+                .path("/fhir/Observation")
+                .json(&observation)
+                .reply(&routes)
+                .await;
+            assert_eq!(response_json(&response)["status"], "success");
+        }
+        
+        let response = warp::test::request()
+            .path("/fhir/Observation?patient=cat&category=laboratory&_since=0")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        let values: Vec<f64> = body["data"].as_array().unwrap().iter()
+            .map(|observation| observation["value"].as_f64().unwrap())
+            .collect();
+        assert_eq!(values, [95.0, 101.0]);
+        assert!(body["data"].as_array().unwrap().iter().all(|observation| observation["category"] == "laboratory"));
+        
+        let response = warp::test::request()
+            .path("/fhir/Observation?patient=cat&code=2339-0&category=vital-signs&_since=0")
+            .reply(&routes)
+            .await;
+        assert!(response_json(&response)["data"].as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_performer_filter_on_observation_search() {
+        let api = test_api();
+        let routes = api.routes();
+        for (i, (performer, value)) in [("Practitioner/nurse-a", 95.0), ("Practitioner/nurse-b", 101.0)].iter().enumerate() {
+            let mut observation = quantity_observation("perf", *value).at(format!("2023-01-01T10:0{}:00Z", i)).json();
+            observation["performer"] = json!([{ "reference": performer }]);
+            let response = warp::test::request()
+                .method("POST")
+                .path("/fhir/Observation")
+                .json(&observation)
+                .reply(&routes)
+                .await;
+            assert_eq!(response_json(&response)["status"], "success");
+        }
+        
+        for path in [
+            "/fhir/Observation?patient=perf&code=2339-0&performer=Practitioner/nurse-b&_since=0",
+            "/fhir/Observation?patient=perf&performer=nurse-b&_since=0",
+        ] {
+            let body = response_json(&warp::test::request().path(path).reply(&routes).await);
+            let observations = body["data"].as_array().unwrap();
+            assert_eq!(observations.len(), 1, "{}: {}", path, body);
+            assert_eq!(observations[0]["value"], 101.0);
+            assert_eq!(observations[0]["performer_id"], "nurse-b");
+        }
+        
+        let response = warp::test::request()
+            .path("/fhir/Observation?patient=perf&performer=nurse-c&_since=0")
+            .reply(&routes)
+            .await;
+        assert!(response_json(&response)["data"].as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_observation_stats_operation_returns_requested_parameters() {
+        let api = test_api();
+        let routes = api.routes();
+        for (i, value) in [90.0, 100.0, 110.0].iter().enumerate() {
+            let observation = quantity_observation("st", *value).at(format!("2023-01-01T10:0{}:00Z", i)).json();
+            warp::test::request().method("POST").path("/fhir/Observation").json(&observation).reply(&routes).await;
+        }
+        
+        let response = warp::test::request()
+            .path("/fhir/Observation/$stats?patient=st&code=2339-0&start=0&statistic=average,max")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["data"]["resourceType"], "Parameters");
+        let parameters = body["data"]["parameter"].as_array().unwrap();
+        let names: Vec<&str> = parameters.iter().map(|p| p["name"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["average", "max"]);
+        assert_eq!(parameters[0]["valueDecimal"], 100.0);
+        assert_eq!(parameters[1]["valueDecimal"], 110.0);
+        
+        let response = warp::test::request()
+            .path("/fhir/Observation/$stats?patient=st&code=2339-0&start=0&statistic=mode")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_created_observation_is_readable_by_its_id() {
+        let api = test_api();
+        let routes = api.routes();
+        let mut ids = Vec::new();
+        for (i, value) in [72.0, 80.0].iter().enumerate() {
+            let observation = quantity_observation("rid", *value)
+                .code("8867-4", "Heart rate")
+                .unit("beats/min", "/min")
+                .at(format!("2023-01-01T10:0{}:00Z", i))
+                .json();
+            let response = warp::test::request()
+                .method("POST")
+                .path("/fhir/Observation")
+                .json(&observation)
+                .reply(&routes)
+                .await;
+            ids.push(response_json(&response)["data"]["id"].as_str().unwrap().to_string());
+        }
+        assert_ne!(ids[0], ids[1]);
+        
+        for (id, value) in ids.iter().zip([72.0, 80.0]) {
+            let response = warp::test::request()
+                .path(&format!("/fhir/Observation/{}", id))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), 200);
+            let body = response_json(&response);
+            assert_eq!(body["data"]["id"], id.as_str());
+            assert_eq!(body["data"]["resourceType"], "Observation");
+            assert_eq!(body["data"]["Numeric"]["value"], value, "{}", body);
+        }
+        
+        let response = warp::test::request()
+            .path("/fhir/Observation/no-such-id")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_create_with_if_none_exist() {
+        let api = test_api();
+        let routes = api.routes();
+        let observation = quantity_observation("cond", 72.0)
+            .code("8867-4", "Heart rate")
+            .unit("beats/min", "/min")
+            .json();
+        let post = || warp::test::request()
+            .method("POST")
+            .path("/fhir/Observation")
+            .header("If-None-Exist", "patient=cond&code=http://loinc.org|8867-4&date=2023-01-01T10:00:00Z")
+            .json(&observation);
+        
+        let created = post().reply(&routes).await;
+        assert_eq!(created.status(), 201);
+        let id = response_json(&created)["data"]["id"].as_str().unwrap().to_string();
+        
+        let existing = post().reply(&routes).await;
+        assert_eq!(existing.status(), 200);
+        assert_eq!(response_json(&existing)["data"]["id"], id.as_str());
+        
+        // A date without a time matches anything that day
+        let existing = warp::test::request()
+            .method("POST")
+            .path("/fhir/Observation")
+            .header("If-None-Exist", "patient=cond&code=8867-4&date=2023-01-01")
+            .json(&observation)
+            .reply(&routes)
+            .await;
+        assert_eq!(existing.status(), 200);
+        assert_eq!(response_json(&existing)["data"]["id"], id.as_str());
+        
+        let stored = api.query_engine.query_range_filtered("cond|8867-4|beats/min", 0, i64::MAX, |_| true).unwrap();
+        assert_eq!(stored.len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_conditional_creates_store_once() {
+        let api = test_api();
+        let routes = api.routes();
+        let observation = quantity_observation("cond-race", 72.0)
+            .code("8867-4", "Heart rate")
+            .unit("beats/min", "/min")
+            .json();
+        
+        let requests: Vec<_> = (0..16).map(|_| {
+            let routes = routes.clone();
+            let observation = observation.clone();
+            tokio::spawn(async move {
+                warp::test::request()
+                    .method("POST")
+                    .path("/fhir/Observation")
+                    .header("If-None-Exist", "patient=cond-race&code=8867-4&date=2023-01-01T10:00:00Z")
+                    .json(&observation)
+                    .reply(&routes)
+                    .await
+                    .status()
+            })
+        }).collect();
+        let mut created = 0;
+        for request in requests {
+            match request.await.unwrap().as_u16() {
+                201 => created += 1,
+                status => assert_eq!(status, 200),
+            }
+        }
+        
+        assert_eq!(created, 1);
+        let stored = api.query_engine.query_range_filtered("cond-race|8867-4|beats/min", 0, i64::MAX, |_| true).unwrap();
+        assert_eq!(stored.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_matrix_endpoint_fills_gaps_with_null() {
+        let api = test_api();
+        let routes = api.routes();
+        for (metric, timestamp, value) in [("mx|8867-4|bpm", 0, 70.0), ("mx|8867-4|bpm", 300, 74.0), ("mx|9279-1|/min", 310, 16.0)] {
+            api.query_engine.store_record(Record {
+                timestamp,
+                metric_name: metric.to_string(),
+                value,
+                string_value: None,
+                context: std::collections::HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).unwrap();
+        }
+        
+        let response = warp::test::request()
+            .method("POST")
+            .path("/timeseries/matrix")
+            .json(&json!({ "metrics": ["mx|8867-4|bpm", "mx|9279-1|/min"], "start": 0, "end": 600, "interval": "5m" }))
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["data"]["timestamps"], json!([0, 300]));
+        assert_eq!(body["data"]["columns"][0]["values"], json!([70.0, 74.0]));
+        assert_eq!(body["data"]["columns"][1]["values"], json!([null, 16.0]));
+        
+        let response = warp::test::request()
+            .method("POST")
+            .path("/timeseries/matrix")
+            .json(&json!({ "metrics": ["mx|8867-4|bpm"], "start": 0, "end": 600, "interval": "soon" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 400);
+        
+        let response = warp::test::request()
+            .method("POST")
+            .path("/timeseries/matrix")
+            .json(&json!({ "metrics": ["mx|8867-4|bpm"], "start": 0, "end": 600, "interval": u64::MAX }))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_admin_compact_merges_chunks() {
+        let api = test_api();
+        let routes = api.routes();
+        // One reading per hour lands each in its own chunk
+        for hour in 0..4 {
+            let observation = quantity_observation("vq", 90.0 + hour as f64)
+                .at(format!("2023-01-01T1{}:00:00Z", hour))
+                .json();
+            warp::test::request().method("POST").path("/fhir/Observation").json(&observation).reply(&routes).await;
+        }
+        
+        let response = warp::test::request().method("POST").path("/admin/compact").reply(&routes).await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        assert_eq!(body["data"]["chunks_before"], 4);
+        assert_eq!(body["data"]["chunks_after"], 1);
+        
+        let response = warp::test::request()
+            .path("/timeseries/range?metric=vq%7C2339-0%7Cmg%2FdL&start=0&end=2000000000")
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["data"].as_array().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_internal_records_bypass_fhir() {
+        let api = test_api();
+        let routes = api.routes();
+        let records = json!([
+            { "timestamp": 1672567200, "metric_name": "pipe|8867-4|bpm", "value": 61.0,
+              "context": { "source": "etl" }, "resource_type": "Observation" },
+            { "timestamp": 1672567260, "metric_name": "pipe|8867-4|bpm", "value": 64.0,
+              "context": {}, "resource_type": "Observation" },
+        ]);
+        let response = warp::test::request()
+            .method("POST")
+            .path("/internal/records")
+            .json(&records)
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["status"], "success");
+        
+        let response = warp::test::request()
+            .path("/timeseries/range?metric=pipe%7C8867-4%7Cbpm&start=0&end=2000000000")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0]["value"], 61.0);
+        assert_eq!(data[0]["source"], "etl");
+        assert_eq!(data[1]["value"], 64.0);
+        
+        let response = warp::test::request()
+            .method("POST")
+            .path("/internal/records")
+            .json(&json!([{ "timestamp": 1, "metric_name": "", "value": 1.0, "context": {}, "resource_type": "Observation" }]))
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["status"], "error");
+    }
+
+    #[tokio::test]
+    async fn test_sampled_data_is_reassembled() {
+        let api = test_api();
+        let routes = api.routes();
+        let observation = json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": { "coding": [{ "system": "urn:oid:2.16.840.1.113883.6.24", "code": "131328", "display": "MDC_ECG_ELEC_POTL" }] },
+            "subject": { "reference": "Patient/ecg" },
+            "effectiveDateTime": "2023-01-01T10:00:00Z",
+            "valueSampledData": {
+                "origin": { "value": 0.0, "unit": "mV", "system": "http://unitsofmeasure.org", "code": "mV" },
+                "period": 2000.0,
+                "factor": 0.5,
+                "dimensions": 1,
+                "data": "10 12 14 11"
+            }
+        });
+        let response = warp::test::request()
+            .method("POST")
+            .path("/fhir/Observation")
+            .json(&observation)
+            .reply(&routes)
+            .await;
+        assert_eq!(response_json(&response)["status"], "success");
+        
+        let response = warp::test::request()
+            .path("/fhir/Observation/sampled?patient=ecg&code=131328&start=1672560000&end=1672570000")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        let sampled = &body["data"]["valueSampledData"];
+        assert_eq!(sampled["period"], 2000.0);
+        assert_eq!(sampled["factor"], 0.5);
+        assert_eq!(sampled["data"], json!([10.0, 12.0, 14.0, 11.0]));
+        assert_eq!(body["data"]["effectiveDateTime"], "2023-01-01T10:00:00+00:00");
+        
+        let response = warp::test::request()
+            .path("/fhir/Observation/sampled?patient=ecg&code=0000-0&start=1672560000&end=1672570000")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_smooth_endpoint_validates_window() {
+        let api = test_api();
+        let routes = api.routes();
+        post_glucose_values(&routes, &[100.0, 130.0, 95.0, 125.0, 105.0]).await;
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/timeseries/smooth?metric=vq%7C2339-0%7Cmg%2FdL&start=0&end=2000000000&window=3&order=1")
+            .reply(&routes)
+            .await;
+        let body = response_json(&response);
+        assert_eq!(body["status"], "success");
+        let points = body["data"].as_array().unwrap();
+        assert_eq!(points.len(), 5);
+        assert_eq!(points[0]["metric_name"], "vq|2339-0|mg/dL_smooth");
+
+        for query in ["window=4&order=1", "window=3&order=3", "window=abc"] {
+            let response = warp::test::request()
+                .method("GET")
+                .path(&format!("/timeseries/smooth?metric=vq%7C2339-0%7Cmg%2FdL&{}", query))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), 400, "{}", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_responses_carry_stable_error_codes() {
+        let api = test_api();
+        let routes = api.routes();
+        
+        let observation = quantity_observation("codes", 72.0)
+            .code("8867-4", "Heart rate")
+            .unit("beats/minute", "/min")
+            .at("yesterday")
+            .json();
+        let vital = json!({
+            "resourceType": "VitalSigns",
+            "code": { "coding": [{ "system": "http://loinc.org", "code": "0000-0", "display": "Not a vital" }] },
+            "subject": { "reference": "Patient/codes" },
+            "effectiveDateTime": "2023-01-01T10:05:00Z",
+            "valueQuantity": { "value": 1.0, "unit": "1", "system": "http://unitsofmeasure.org", "code": "1" }
+        });
+        let cases = [
+            ("POST", "/fhir/Observation", Some(observation), "INVALID_TIMESTAMP"),
+            ("POST", "/fhir/VitalSigns", Some(vital), "UNKNOWN_VITAL_CODE"),
+            ("GET", "/timeseries/stats", None, "MISSING_PARAMETER"),
+            ("GET", "/timeseries/rate?metric=x&period=0", None, "INVALID_PARAMETER"),
+        ];
+        for (method, path, body, code) in cases {
+            let mut request = warp::test::request().method(method).path(path);
+            if let Some(body) = &body {
+                request = request.json(body);
+            }
+            let body = response_json(&request.reply(&routes).await);
+            assert_eq!(body["status"], "error", "{}", path);
+            assert_eq!(body["error_code"], code, "{}", path);
+            assert!(body["message"].as_str().is_some_and(|message| !message.is_empty()));
+        }
+        
+        // Successful responses have no error code at all
+        let body = response_json(&warp::test::request()
+            .method("GET")
+            .path("/timeseries/stats?metric=x")
+            .reply(&routes)
+            .await);
+        assert_eq!(body["status"], "success");
+        assert!(body.get("error_code").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_malformed_fhir_body_gets_operation_outcome() {
+        let api = test_api();
+        let routes = api.routes();
+        let mut observation = json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": { "coding": [{ "system": "http://loinc.org", "code": "8867-4", "display": "Heart rate" }] },
+            "subject": { "reference": "Patient/strict" },
+            "valueQuantity": { "value": 72.0, "unit": "beats/minute", "system": "http://unitsofmeasure.org", "code": "/min" }
+        });
+        let post = |routes, body: &serde_json::Value| warp::test::request()
+            .method("POST")
+            .path("/fhir/Observation")
+            .json(body)
+            .reply(routes);
+        
+        let response = post(&routes, &observation).await;
+        assert_eq!(response.status(), 400);
+        let outcome = response_json(&response);
+        assert_eq!(outcome["resourceType"], "OperationOutcome");
+        assert_eq!(outcome["issue"][0]["code"], "required");
+        assert_eq!(outcome["issue"][0]["expression"][0], "Observation.effectiveDateTime");
+        
+        observation["effectiveDateTime"] = json!("2023-01-01T10:00:00Z");
+        observation["valueQuantity"]["value"] = json!("seventy-two");
+        let response = post(&routes, &observation).await;
+        assert_eq!(response.status(), 400);
+        let outcome = response_json(&response);
+        assert_eq!(outcome["issue"][0]["code"], "value");
+        assert_eq!(outcome["issue"][0]["expression"][0], "Observation.valueQuantity.value");
+        
+        // Unknown elements are ignored unless strict mode is on
+        observation["valueQuantity"]["value"] = json!(72.0);
+        observation["interpretation"] = json!([{ "text": "normal" }]);
+        assert_eq!(response_json(&post(&routes, &observation).await)["status"], "success");
+        
+        let strict = test_api().with(|api| api.with_strict_body(true));
+        let strict_routes = strict.routes();
+        let response = post(&strict_routes, &observation).await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(response_json(&response)["issue"][0]["expression"][0], "Observation.interpretation");
+    }
+}
@@ -3,17 +3,134 @@ use std::path::Path;
 use std::time::Duration;
 use std::fmt;
 use std::error::Error;
+use std::collections::HashMap;
+use crate::fhir::units::UnitConversion;
+
+pub use duration_parser::parse_duration;
 
 #[derive(Debug, Deserialize)]
 pub struct StorageConfig {
     pub path: String,
     pub max_chunk_size: usize,
+    #[serde(default)]
+    pub wal_fsync: FsyncPolicy,
+    #[serde(default)]
+    pub max_resident_chunks: Option<usize>, // Evict least recently used chunks beyond this; unbounded if unset
+    #[serde(default, deserialize_with = "duration_parser::deserialize_option")]
+    pub rollup_resolution: Option<Duration>, // Bucket width of rollups computed on flush; none if unset
+    #[serde(default)]
+    pub chunk_layout: ChunkLayout,
+    #[serde(default)]
+    pub metric_capacity_hint: Option<usize>, // Records reserved per metric in new chunks; estimated from the previous chunk if unset
+    #[serde(default)]
+    pub max_unflushed_bytes: Option<usize>, // Writes are refused while dirty chunks hold more than this; unbounded if unset
+    #[serde(default)]
+    pub wal_format: WalFormat,
+}
+
+/// How chunk files are arranged under `chunks/`
+///
+/// Chunk files found in the other layout are moved over on startup, so either can be switched to.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkLayout {
+    /// Every chunk file directly in `chunks/`
+    Flat,
+    /// Chunk files two subdirectories deep, named from the chunk id, e.g. `chunks/63/b0/{id}.chunk`
+    #[default]
+    Sharded,
+}
+
+impl std::str::FromStr for ChunkLayout {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flat" => Ok(ChunkLayout::Flat),
+            "sharded" => Ok(ChunkLayout::Sharded),
+            _ => Err(format!("Unknown chunk layout: {}", s)),
+        }
+    }
+}
+
+/// How WAL entries are encoded
+///
+/// Each WAL file records its format in a header, so an existing WAL keeps its
+/// format until the next truncation and switching is always safe.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WalFormat {
+    /// One JSON document per entry, readable with ordinary tools
+    #[default]
+    Json,
+    /// Length-prefixed bincode, smaller and faster to write
+    Binary,
+}
+
+impl std::str::FromStr for WalFormat {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(WalFormat::Json),
+            "binary" => Ok(WalFormat::Binary),
+            _ => Err(format!("Unknown WAL format: {}", s)),
+        }
+    }
+}
+
+/// When the WAL forces its writes to disk
+///
+/// Anything but `Always` trades a bounded window of acknowledged writes that a
+/// crash can lose for ingest throughput.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub enum FsyncPolicy {
+    /// After every write or batch
+    #[default]
+    Always,
+    /// Once this long has passed since the last sync
+    Interval(Duration),
+    /// After this many WAL entries
+    EveryN(usize),
+}
+
+impl TryFrom<String> for FsyncPolicy {
+    type Error = String;
+    
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl std::str::FromStr for FsyncPolicy {
+    type Err = String;
+    
+    /// `always`, `interval:<duration>` (e.g. `interval:500ms`) or `every_n:<entries>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "always" => Ok(FsyncPolicy::Always),
+            Some(("interval", duration)) => duration_parser::parse_duration(duration).map(FsyncPolicy::Interval),
+            Some(("every_n", count)) => count.parse()
+                .map(FsyncPolicy::EveryN)
+                .map_err(|_| format!("Invalid entry count: {}", count)),
+            _ => Err(format!("Unknown fsync policy: {}", s)),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ApiConfig {
     pub host: String,
     pub port: u16,
+    #[serde(default)]
+    pub auth_token: Option<String>, // Require `Authorization: Bearer <token>` when set
+    #[serde(default)]
+    pub strict_body: bool, // Reject FHIR resources with elements the server doesn't know
+    #[serde(default, deserialize_with = "duration_parser::deserialize_option")]
+    pub default_query_window: Option<Duration>, // Span of queries giving no `start`/`_since`; each endpoint's own default if unset
+    #[serde(default, deserialize_with = "duration_parser::deserialize_option")]
+    pub max_query_span: Option<Duration>, // Wider queries need `allow_full_scan=true`; unbounded if unset
 }
 
 #[derive(Debug, Deserialize)]
@@ -22,12 +139,27 @@ pub struct Config {
     pub api: ApiConfig,
     #[serde(with = "duration_parser")]
     pub chunk_duration: Duration,
+    #[serde(default = "default_query_cache_size")]
+    pub query_cache_size: usize, // Memoized stats/trend results; 0 disables the cache
+    #[serde(default)]
+    pub value_decimals: Option<u8>, // Round ingested values to this many decimal places; stored as sent if unset
+    #[serde(default)]
+    pub unit_conversions: HashMap<String, UnitConversion>, // Source unit -> canonical unit applied on ingest
+}
+
+fn default_query_cache_size() -> usize {
+    crate::timeseries::cache::DEFAULT_QUERY_CACHE_SIZE
 }
 
 #[derive(Debug)]
 pub enum ConfigError {
     IoError(std::io::Error),
     ParseError(serde_yaml::Error),
+    TomlError(toml::de::Error),
+    JsonError(serde_json::Error),
+    UnsupportedFormat(String),
+    EnvError(String),
+    Validation(String),
 }
 
 impl fmt::Display for ConfigError {
@@ -35,6 +167,13 @@ impl fmt::Display for ConfigError {
         match self {
             ConfigError::IoError(e) => write!(f, "IO error: {}", e),
             ConfigError::ParseError(e) => write!(f, "Parse error: {}", e),
+            ConfigError::TomlError(e) => write!(f, "TOML parse error: {}", e),
+            ConfigError::JsonError(e) => write!(f, "JSON parse error: {}", e),
+            ConfigError::UnsupportedFormat(ext) => write!(
+                f, "Unsupported config format '{}' (expected .yaml, .yml, .toml or .json)", ext
+            ),
+            ConfigError::EnvError(msg) => write!(f, "Environment override error: {}", msg),
+            ConfigError::Validation(msg) => write!(f, "Invalid configuration: {}", msg),
         }
     }
 }
@@ -44,16 +183,172 @@ impl Error for ConfigError {
         match self {
             ConfigError::IoError(e) => Some(e),
             ConfigError::ParseError(e) => Some(e),
+            ConfigError::TomlError(e) => Some(e),
+            ConfigError::JsonError(e) => Some(e),
+            ConfigError::UnsupportedFormat(_)
+            | ConfigError::EnvError(_)
+            | ConfigError::Validation(_) => None,
         }
     }
 }
 
+impl Config {
+    /// Reject values that would only fail later at runtime
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.chunk_duration.is_zero() {
+            return Err(ConfigError::Validation("chunk_duration must be greater than zero".to_string()));
+        }
+        if self.storage.path.trim().is_empty() {
+            return Err(ConfigError::Validation("storage.path must not be empty".to_string()));
+        }
+        if self.api.port == 0 {
+            return Err(ConfigError::Validation("api.port must not be 0".to_string()));
+        }
+        if self.storage.max_resident_chunks == Some(0) {
+            return Err(ConfigError::Validation("storage.max_resident_chunks must be at least 1".to_string()));
+        }
+        if let Some(resolution) = self.storage.rollup_resolution {
+            if resolution.as_secs() == 0 || resolution.subsec_nanos() != 0 {
+                return Err(ConfigError::Validation("storage.rollup_resolution must be a whole number of seconds".to_string()));
+            }
+            if !self.chunk_duration.as_secs().is_multiple_of(resolution.as_secs()) {
+                return Err(ConfigError::Validation("storage.rollup_resolution must divide chunk_duration".to_string()));
+            }
+        }
+        if self.storage.wal_fsync == FsyncPolicy::EveryN(0) {
+            return Err(ConfigError::Validation("storage.wal_fsync every_n must be at least 1".to_string()));
+        }
+        if self.api.auth_token.as_deref().is_some_and(|t| t.trim().is_empty()) {
+            return Err(ConfigError::Validation("api.auth_token must not be blank".to_string()));
+        }
+        for (unit, conversion) in &self.unit_conversions {
+            if conversion.unit.trim().is_empty() || conversion.unit.contains('|') {
+                return Err(ConfigError::Validation(format!("unit_conversions.{} needs a canonical unit without '|'", unit)));
+            }
+            if !conversion.factor.is_normal() || !conversion.offset.is_finite() {
+                return Err(ConfigError::Validation(format!("unit_conversions.{} needs a finite, non-zero factor", unit)));
+            }
+        }
+        Ok(())
+    }
+    
+    /// Apply `EMBERDB_*` environment variables on top of the file values
+    ///
+    /// Supported: `EMBERDB_STORAGE_PATH`, `EMBERDB_STORAGE_MAX_CHUNK_SIZE`,
+    /// `EMBERDB_STORAGE_WAL_FSYNC` (e.g. "every_n:100"), `EMBERDB_STORAGE_MAX_RESIDENT_CHUNKS`,
+    /// `EMBERDB_STORAGE_ROLLUP_RESOLUTION`, `EMBERDB_STORAGE_CHUNK_LAYOUT` ("flat" or "sharded"),
+    /// `EMBERDB_STORAGE_METRIC_CAPACITY_HINT`, `EMBERDB_STORAGE_MAX_UNFLUSHED_BYTES`,
+    /// `EMBERDB_STORAGE_WAL_FORMAT` ("json" or "binary"), `EMBERDB_API_HOST`, `EMBERDB_API_PORT`,
+    /// `EMBERDB_API_AUTH_TOKEN`, `EMBERDB_API_STRICT_BODY`, `EMBERDB_API_DEFAULT_QUERY_WINDOW`,
+    /// `EMBERDB_API_MAX_QUERY_SPAN` (both durations, e.g. "1h"), `EMBERDB_QUERY_CACHE_SIZE`, `EMBERDB_VALUE_DECIMALS` and `EMBERDB_CHUNK_DURATION`
+    /// (same format as the file, e.g. "30m").
+    pub fn apply_env_overrides(&mut self) -> Result<(), ConfigError> {
+        self.apply_overrides(|name| std::env::var(name).ok())
+    }
+    
+    /// Apply overrides from any variable source; env vars in production, a map in tests
+    fn apply_overrides<F>(&mut self, lookup: F) -> Result<(), ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, ConfigError> {
+            value.trim().parse()
+                .map_err(|_| ConfigError::EnvError(format!("Invalid value for {}: {}", name, value)))
+        }
+        
+        if let Some(path) = lookup("EMBERDB_STORAGE_PATH") {
+            self.storage.path = path;
+        }
+        if let Some(size) = lookup("EMBERDB_STORAGE_MAX_CHUNK_SIZE") {
+            self.storage.max_chunk_size = parse("EMBERDB_STORAGE_MAX_CHUNK_SIZE", &size)?;
+        }
+        if let Some(policy) = lookup("EMBERDB_STORAGE_WAL_FSYNC") {
+            self.storage.wal_fsync = policy.trim().parse()
+                .map_err(|e| ConfigError::EnvError(format!("Invalid value for EMBERDB_STORAGE_WAL_FSYNC: {}", e)))?;
+        }
+        if let Some(count) = lookup("EMBERDB_STORAGE_MAX_RESIDENT_CHUNKS") {
+            self.storage.max_resident_chunks = Some(parse("EMBERDB_STORAGE_MAX_RESIDENT_CHUNKS", &count)?);
+        }
+        if let Some(resolution) = lookup("EMBERDB_STORAGE_ROLLUP_RESOLUTION") {
+            self.storage.rollup_resolution = Some(duration_parser::parse_duration(resolution.trim())
+                .map_err(|e| ConfigError::EnvError(format!("Invalid value for EMBERDB_STORAGE_ROLLUP_RESOLUTION: {}", e)))?);
+        }
+        if let Some(layout) = lookup("EMBERDB_STORAGE_CHUNK_LAYOUT") {
+            self.storage.chunk_layout = parse("EMBERDB_STORAGE_CHUNK_LAYOUT", &layout)?;
+        }
+        if let Some(hint) = lookup("EMBERDB_STORAGE_METRIC_CAPACITY_HINT") {
+            self.storage.metric_capacity_hint = Some(parse("EMBERDB_STORAGE_METRIC_CAPACITY_HINT", &hint)?);
+        }
+        if let Some(bytes) = lookup("EMBERDB_STORAGE_MAX_UNFLUSHED_BYTES") {
+            self.storage.max_unflushed_bytes = Some(parse("EMBERDB_STORAGE_MAX_UNFLUSHED_BYTES", &bytes)?);
+        }
+        if let Some(format) = lookup("EMBERDB_STORAGE_WAL_FORMAT") {
+            self.storage.wal_format = parse("EMBERDB_STORAGE_WAL_FORMAT", &format)?;
+        }
+        if let Some(host) = lookup("EMBERDB_API_HOST") {
+            self.api.host = host;
+        }
+        if let Some(port) = lookup("EMBERDB_API_PORT") {
+            self.api.port = parse("EMBERDB_API_PORT", &port)?;
+        }
+        if let Some(token) = lookup("EMBERDB_API_AUTH_TOKEN") {
+            self.api.auth_token = Some(token).filter(|t| !t.is_empty());
+        }
+        if let Some(strict) = lookup("EMBERDB_API_STRICT_BODY") {
+            self.api.strict_body = parse("EMBERDB_API_STRICT_BODY", &strict)?;
+        }
+        if let Some(window) = lookup("EMBERDB_API_DEFAULT_QUERY_WINDOW") {
+            self.api.default_query_window = Some(duration_parser::parse_duration(window.trim())
+                .map_err(|e| ConfigError::EnvError(format!("Invalid value for EMBERDB_API_DEFAULT_QUERY_WINDOW: {}", e)))?);
+        }
+        if let Some(span) = lookup("EMBERDB_API_MAX_QUERY_SPAN") {
+            self.api.max_query_span = Some(duration_parser::parse_duration(span.trim())
+                .map_err(|e| ConfigError::EnvError(format!("Invalid value for EMBERDB_API_MAX_QUERY_SPAN: {}", e)))?);
+        }
+        if let Some(size) = lookup("EMBERDB_QUERY_CACHE_SIZE") {
+            self.query_cache_size = parse("EMBERDB_QUERY_CACHE_SIZE", &size)?;
+        }
+        if let Some(decimals) = lookup("EMBERDB_VALUE_DECIMALS") {
+            self.value_decimals = Some(parse("EMBERDB_VALUE_DECIMALS", &decimals)?);
+        }
+        if let Some(duration) = lookup("EMBERDB_CHUNK_DURATION") {
+            self.chunk_duration = duration_parser::parse_duration(duration.trim())
+                .map_err(|e| ConfigError::EnvError(format!("Invalid value for EMBERDB_CHUNK_DURATION: {}", e)))?;
+        }
+        
+        Ok(())
+    }
+}
+
+/// Load the config file, then apply `EMBERDB_*` environment overrides
+///
+/// The format is picked from the extension: `.yaml`/`.yml`, `.toml` or `.json`.
 pub fn load_config(path: &Path) -> Result<Config, ConfigError> {
+    load_config_with(path, |name| std::env::var(name).ok())
+}
+
+fn load_config_with<F>(path: &Path, lookup: F) -> Result<Config, ConfigError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let extension = path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+        .unwrap_or_default();
+    
     let contents = std::fs::read_to_string(path)
         .map_err(ConfigError::IoError)?;
     
-    serde_yaml::from_str(&contents)
-        .map_err(ConfigError::ParseError)
+    let mut config: Config = match extension.as_str() {
+        "yaml" | "yml" => serde_yaml::from_str(&contents).map_err(ConfigError::ParseError)?,
+        "toml" => toml::from_str(&contents).map_err(ConfigError::TomlError)?,
+        "json" => serde_json::from_str(&contents).map_err(ConfigError::JsonError)?,
+        _ => return Err(ConfigError::UnsupportedFormat(extension)),
+    };
+    
+    config.apply_overrides(lookup)?;
+    config.validate()?;
+    Ok(config)
 }
 
 mod duration_parser {
@@ -68,16 +363,200 @@ mod duration_parser {
         parse_duration(&s).map_err(serde::de::Error::custom)
     }
 
-    fn parse_duration(duration_str: &str) -> Result<Duration, String> {
+    pub fn deserialize_option<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(deserializer)?
+            .map(|s| parse_duration(&s).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+
+    pub fn parse_duration(duration_str: &str) -> Result<Duration, String> {
+        if duration_str.is_empty() {
+            return Err("Empty duration".to_string());
+        }
+        if let Some(millis) = duration_str.strip_suffix("ms") {
+            return millis.parse().map(Duration::from_millis).map_err(|_| "Invalid duration value".to_string());
+        }
         let (value_str, unit) = duration_str.split_at(duration_str.len() - 1);
         let value: u64 = value_str.parse().map_err(|_| "Invalid duration value".to_string())?;
 
-        match unit {
-            "s" => Ok(Duration::from_secs(value)),
-            "m" => Ok(Duration::from_secs(value * 60)),
-            "h" => Ok(Duration::from_secs(value * 3600)),
-            "d" => Ok(Duration::from_secs(value * 86400)),
-            _ => Err(format!("Invalid duration unit: {}", unit)),
+        let seconds_per_unit = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => 86400,
+            _ => return Err(format!("Invalid duration unit: {}", unit)),
+        };
+        value.checked_mul(seconds_per_unit)
+            .map(Duration::from_secs)
+            .ok_or_else(|| "Duration too large".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static TEST_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    const YAML_CONFIG: &str = r#"
+storage:
+  path: "./data"
+  max_chunk_size: 1048576
+
+api:
+  host: "127.0.0.1"
+  port: 5432
+
+chunk_duration: "1h"
+"#;
+
+    /// Write a config file into a fresh temp path
+    fn write_config(extension: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "emberdb-config-test-{}-{}.{}",
+            std::process::id(),
+            TEST_FILE_COUNTER.fetch_add(1, Ordering::SeqCst),
+            extension,
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_env_overrides_take_precedence() {
+        let path = write_config("yaml", YAML_CONFIG);
+        
+        // The only test that touches the real environment, so it can't race another
+        std::env::set_var("EMBERDB_API_PORT", "8080");
+        std::env::set_var("EMBERDB_STORAGE_PATH", "/var/lib/emberdb");
+        std::env::set_var("EMBERDB_CHUNK_DURATION", "30m");
+        let config = load_config(&path);
+        std::env::remove_var("EMBERDB_API_PORT");
+        std::env::remove_var("EMBERDB_STORAGE_PATH");
+        std::env::remove_var("EMBERDB_CHUNK_DURATION");
+        
+        let config = config.unwrap();
+        assert_eq!(config.api.port, 8080);
+        assert_eq!(config.storage.path, "/var/lib/emberdb");
+        assert_eq!(config.chunk_duration, Duration::from_secs(1800));
+        
+        // Untouched values still come from the file
+        assert_eq!(config.api.host, "127.0.0.1");
+        assert_eq!(config.storage.max_chunk_size, 1048576);
+        
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_invalid_env_override_is_an_error() {
+        let path = write_config("yaml", YAML_CONFIG);
+        let env: HashMap<&str, &str> = [("EMBERDB_API_PORT", "not-a-port")].into();
+        
+        let result = load_config_with(&path, |name| env.get(name).map(|v| v.to_string()));
+        assert!(matches!(result, Err(ConfigError::EnvError(_))));
+        
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_validate_accepts_valid_config() {
+        let path = write_config("yaml", YAML_CONFIG);
+        let config = load_config_with(&path, |_| None).unwrap();
+        assert!(config.validate().is_ok());
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_validate_rejects_invalid_fields() {
+        let path = write_config("yaml", YAML_CONFIG);
+        
+        for (name, value) in [
+            ("EMBERDB_CHUNK_DURATION", "0s"),
+            ("EMBERDB_STORAGE_PATH", ""),
+            ("EMBERDB_API_PORT", "0"),
+            ("EMBERDB_STORAGE_ROLLUP_RESOLUTION", "0s"),
+            ("EMBERDB_STORAGE_ROLLUP_RESOLUTION", "7m"), // Doesn't divide the 1h chunks
+        ] {
+            let result = load_config_with(&path, |var| (var == name).then(|| value.to_string()));
+            assert!(
+                matches!(result, Err(ConfigError::Validation(_))),
+                "{}={:?} should fail validation", name, value
+            );
+        }
+        
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_load_config_in_all_formats() {
+        let toml_config = r#"
+chunk_duration = "1h"
+
+[storage]
+path = "./data"
+max_chunk_size = 1048576
+
+[api]
+host = "127.0.0.1"
+port = 5432
+"#;
+        let json_config = r#"{
+            "storage": { "path": "./data", "max_chunk_size": 1048576 },
+            "api": { "host": "127.0.0.1", "port": 5432 },
+            "chunk_duration": "1h"
+        }"#;
+        
+        for (extension, contents) in [
+            ("yaml", YAML_CONFIG),
+            ("yml", YAML_CONFIG),
+            ("toml", toml_config),
+            ("json", json_config),
+        ] {
+            let path = write_config(extension, contents);
+            let config = load_config_with(&path, |_| None)
+                .unwrap_or_else(|e| panic!("failed to load .{} config: {}", extension, e));
+            
+            assert_eq!(config.storage.path, "./data");
+            assert_eq!(config.storage.max_chunk_size, 1048576);
+            assert_eq!(config.api.host, "127.0.0.1");
+            assert_eq!(config.api.port, 5432);
+            assert_eq!(config.chunk_duration, Duration::from_secs(3600));
+            
+            std::fs::remove_file(path).ok();
         }
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_unknown_extension_is_rejected() {
+        let path = write_config("ini", YAML_CONFIG);
+        let result = load_config_with(&path, |_| None);
+        assert!(matches!(result, Err(ConfigError::UnsupportedFormat(ext)) if ext == "ini"));
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_wal_fsync_policy_parsing() {
+        let config: Config = serde_yaml::from_str(YAML_CONFIG).unwrap();
+        assert_eq!(config.storage.wal_fsync, FsyncPolicy::Always);
+        
+        let with_policy = YAML_CONFIG.replace(
+            "max_chunk_size: 1048576", "max_chunk_size: 1048576\n  wal_fsync: \"interval:250ms\"",
+        );
+        let config: Config = serde_yaml::from_str(&with_policy).unwrap();
+        assert_eq!(config.storage.wal_fsync, FsyncPolicy::Interval(Duration::from_millis(250)));
+        
+        assert_eq!("every_n:100".parse(), Ok(FsyncPolicy::EveryN(100)));
+        assert_eq!("interval:2s".parse(), Ok(FsyncPolicy::Interval(Duration::from_secs(2))));
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+        
+        let path = write_config("yaml", YAML_CONFIG);
+        let result = load_config_with(&path, |var| (var == "EMBERDB_STORAGE_WAL_FSYNC").then(|| "every_n:0".to_string()));
+        assert!(matches!(result, Err(ConfigError::Validation(_))));
+        std::fs::remove_file(path).ok();
+    }
+}
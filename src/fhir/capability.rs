@@ -0,0 +1,97 @@
+//! Server capabilities advertised through `GET /fhir/metadata`
+//!
+//! `SUPPORTED_RESOURCES` is the single list of resource types the REST API
+//! serves. Add an entry here whenever a resource route is added; the API tests
+//! check every listed interaction against the routes.
+
+use serde_json::{json, Value};
+use super::ResourceType;
+
+/// FHIR RESTful interactions offered on a resource type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interaction {
+    Read,
+    Create,
+    SearchType,
+}
+
+impl Interaction {
+    /// Code used in a CapabilityStatement
+    pub fn code(&self) -> &'static str {
+        match self {
+            Interaction::Read => "read",
+            Interaction::Create => "create",
+            Interaction::SearchType => "search-type",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ResourceSupport {
+    pub resource_type: ResourceType,
+    pub interactions: &'static [Interaction],
+}
+
+pub const SUPPORTED_RESOURCES: &[ResourceSupport] = &[
+    ResourceSupport {
+        resource_type: ResourceType::Observation,
+        interactions: &[Interaction::Create, Interaction::SearchType],
+    },
+    ResourceSupport {
+        resource_type: ResourceType::Patient,
+        interactions: &[Interaction::Read, Interaction::Create],
+    },
+    ResourceSupport {
+        resource_type: ResourceType::MedicationAdministration,
+        interactions: &[Interaction::Create, Interaction::SearchType],
+    },
+    ResourceSupport {
+        resource_type: ResourceType::DeviceObservation,
+        interactions: &[Interaction::Create],
+    },
+    ResourceSupport {
+        resource_type: ResourceType::VitalSigns,
+        interactions: &[Interaction::Create],
+    },
+    ResourceSupport {
+        resource_type: ResourceType::Condition,
+        interactions: &[Interaction::Create],
+    },
+    ResourceSupport {
+        resource_type: ResourceType::Encounter,
+        interactions: &[Interaction::Create],
+    },
+    ResourceSupport {
+        resource_type: ResourceType::AllergyIntolerance,
+        interactions: &[Interaction::Create],
+    },
+];
+
+/// A minimal CapabilityStatement describing `SUPPORTED_RESOURCES`
+pub fn capability_statement() -> Value {
+    let resources: Vec<Value> = SUPPORTED_RESOURCES.iter()
+        .map(|resource| json!({
+            "type": resource.resource_type.as_str(),
+            "interaction": resource.interactions.iter()
+                .map(|interaction| json!({ "code": interaction.code() }))
+                .collect::<Vec<_>>(),
+        }))
+        .collect();
+
+    json!({
+        "resourceType": "CapabilityStatement",
+        "status": "active",
+        "date": chrono::Utc::now().to_rfc3339(),
+        "kind": "instance",
+        "software": {
+            "name": "EmberDB",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "fhirVersion": "4.0.1",
+        "format": ["json"],
+        "rest": [{
+            "mode": "server",
+            "resource": resources,
+        }],
+    })
+}
@@ -5,6 +5,8 @@
 
 pub mod resources;
 pub mod conversion;
+pub mod capability;
+pub mod units;
 
 use serde::{Serialize, Deserialize};
 
@@ -15,6 +17,63 @@ pub enum FHIRError {
     NotFound(String),
 }
 
+/// Resource types EmberDB stores and serves
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResourceType {
+    Observation,
+    Patient,
+    MedicationAdministration,
+    DeviceObservation,
+    VitalSigns,
+    Condition,
+    Encounter,
+    AllergyIntolerance,
+}
+
+impl ResourceType {
+    pub const ALL: [ResourceType; 8] = [
+        ResourceType::Observation,
+        ResourceType::Patient,
+        ResourceType::MedicationAdministration,
+        ResourceType::DeviceObservation,
+        ResourceType::VitalSigns,
+        ResourceType::Condition,
+        ResourceType::Encounter,
+        ResourceType::AllergyIntolerance,
+    ];
+    
+    /// The FHIR `resourceType` name, also stored as `Record::resource_type`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResourceType::Observation => "Observation",
+            ResourceType::Patient => "Patient",
+            ResourceType::MedicationAdministration => "MedicationAdministration",
+            ResourceType::DeviceObservation => "DeviceObservation",
+            ResourceType::VitalSigns => "VitalSigns",
+            ResourceType::Condition => "Condition",
+            ResourceType::Encounter => "Encounter",
+            ResourceType::AllergyIntolerance => "AllergyIntolerance",
+        }
+    }
+}
+
+impl std::fmt::Display for ResourceType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for ResourceType {
+    type Err = FHIRError;
+    
+    /// Names are case-sensitive, as in FHIR
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ResourceType::ALL.into_iter()
+            .find(|resource_type| resource_type.as_str() == s)
+            .ok_or_else(|| FHIRError::ValidationError(format!("Unknown resource type: {}", s)))
+    }
+}
+
 /// Core FHIR data types that map to time-series data
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum FHIRObservation {
@@ -26,6 +85,8 @@ pub enum FHIRObservation {
         timestamp: i64,       // When the observation was recorded
         patient_id: String,   // The patient this observation belongs to
         device_id: Option<String>, // Optional device that recorded this observation
+        category: Option<String>,  // Observation category code, e.g. "vital-signs" or "laboratory"
+        performer_id: Option<String>, // Optional practitioner who took the measurement
     },
     
     /// Component observations like blood pressure with multiple numeric components
@@ -35,6 +96,8 @@ pub enum FHIRObservation {
         timestamp: i64,
         patient_id: String,
         device_id: Option<String>,
+        category: Option<String>,
+        performer_id: Option<String>,
     },
     
     /// Sampled data like ECG readings, EEG, etc.
@@ -46,6 +109,21 @@ pub enum FHIRObservation {
         start_time: i64,      // When sampling started
         patient_id: String,
         device_id: Option<String>,
+        category: Option<String>,
+        performer_id: Option<String>,
+    },
+
+    /// Non-numeric observations like coded findings, free text or booleans
+    Categorical {
+        code: String,         // The observation code
+        value: String,        // Text value (the coding code for CodeableConcept values)
+        value_type: String,   // "string", "boolean" or "CodeableConcept"
+        display: Option<String>, // Display text for coded values
+        timestamp: i64,
+        patient_id: String,
+        device_id: Option<String>,
+        category: Option<String>,
+        performer_id: Option<String>,
     },
 }
 
@@ -85,6 +163,38 @@ pub struct DeviceObservation {
     pub status: String,               // Device status during measurement
 }
 
+/// Condition resource for diagnoses and problem-list entries
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Condition {
+    pub code: String,                 // Condition code (SNOMED CT, ICD-10, etc.)
+    pub code_display: String,         // Human-readable condition name
+    pub clinical_status: String,      // active, recurrence, relapse, inactive, remission, resolved
+    pub onset: i64,                   // When the condition started
+    pub patient_id: String,           // Patient with the condition
+    pub severity: Option<String>,     // mild, moderate or severe
+}
+
+/// Encounter resource for admissions and visits
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Encounter {
+    pub class: String,                // Encounter class (IMP, AMB, EMER, etc.)
+    pub status: String,               // planned, in-progress, finished, etc.
+    pub period_start: i64,            // When the encounter started
+    pub period_end: Option<i64>,      // When the encounter ended (None while ongoing)
+    pub patient_id: String,           // Patient the encounter belongs to
+}
+
+/// AllergyIntolerance resource for recorded allergies and intolerances
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AllergyIntolerance {
+    pub substance_code: String,       // Substance code (RxNorm, SNOMED CT, etc.)
+    pub substance_display: String,    // Human-readable substance name
+    pub criticality: String,          // low, high or unable-to-assess
+    pub allergy_type: String,         // allergy or intolerance
+    pub recorded: i64,                // When the allergy was recorded
+    pub patient_id: String,           // Patient with the allergy
+}
+
 /// VitalSigns profile - a specialized observation for vital measurements
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VitalSigns {
@@ -124,6 +234,7 @@ pub trait FHIRConverter {
 #[cfg(test)]
 mod tests {
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn it_works() {
         assert!(true);
     }
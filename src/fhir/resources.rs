@@ -1,13 +1,27 @@
 use crate::fhir::{FHIRObservation, FHIRError, ObservationComponent, 
-                   MedicationAdministration, DeviceObservation, VitalSigns, VitalType};
+                   MedicationAdministration, DeviceObservation, VitalSigns, VitalType,
+                   Condition, Encounter, AllergyIntolerance};
 use crate::fhir::conversion::FHIRConverter;
 use crate::storage::Record;
 use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
 
 // Basic FHIR resource definitions
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Patient {
     pub id: String,
-    // @todo: add more fields as needed
+    pub family_name: Option<String>,
+    pub given_names: Vec<String>,
+    pub birth_date: Option<String>, // FHIR date, kept as given (YYYY, YYYY-MM or YYYY-MM-DD)
+    pub gender: Option<String>,     // male | female | other | unknown
+    pub registered: i64,            // When the demographics were stored
+}
+
+impl Patient {
+    /// Reserved metric holding a patient's demographics
+    pub fn demographics_metric(id: &str) -> String {
+        format!("{}|patient|demographics", id)
+    }
 }
 
 pub enum FHIRResource {
@@ -15,34 +29,51 @@ pub enum FHIRResource {
     MedicationAdministration(MedicationAdministration),
     DeviceObservation(DeviceObservation),
     VitalSigns(VitalSigns),
+    Condition(Condition),
+    Encounter(Encounter),
+    AllergyIntolerance(AllergyIntolerance),
     Patient(Patient),
 }
 
 impl FHIRConverter for FHIRObservation {
     fn to_records(&self) -> Vec<Record> {
         match self {
-            FHIRObservation::Numeric { code, value, unit, timestamp, patient_id, device_id } => {
+            FHIRObservation::Numeric { code, value, unit, timestamp, patient_id, device_id, category, performer_id } => {
                 let mut context = HashMap::new();
                 if let Some(device) = device_id {
                     context.insert("device_id".to_string(), device.clone());
                 }
+                if let Some(category) = category {
+                    context.insert("category".to_string(), category.clone());
+                }
+                if let Some(performer) = performer_id {
+                    context.insert("performer_id".to_string(), performer.clone());
+                }
                 
                 vec![Record {
                     timestamp: *timestamp,
                     metric_name: format!("{}|{}|{}", patient_id, code, unit),
                     value: *value,
+                    string_value: None,
                     context,
                     resource_type: "Observation".to_string(),
+                    id: None,
                 }]
             },
             
-            FHIRObservation::Component { code, components, timestamp, patient_id, device_id } => {
+            FHIRObservation::Component { code, components, timestamp, patient_id, device_id, category, performer_id } => {
                 let mut records = Vec::new();
                 let mut context = HashMap::new();
                 
                 if let Some(device) = device_id {
                     context.insert("device_id".to_string(), device.clone());
                 }
+                if let Some(category) = category {
+                    context.insert("category".to_string(), category.clone());
+                }
+                if let Some(performer) = performer_id {
+                    context.insert("performer_id".to_string(), performer.clone());
+                }
                 
                 // Add a record for each component
                 for component in components {
@@ -50,21 +81,29 @@ impl FHIRConverter for FHIRObservation {
                         timestamp: *timestamp,
                         metric_name: format!("{}|{}|{}|{}", patient_id, code, component.code, component.unit),
                         value: component.value,
+                        string_value: None,
                         context: context.clone(),
                         resource_type: "Observation".to_string(),
+                        id: None,
                     });
                 }
                 
                 records
             },
             
-            FHIRObservation::SampledData { code, period, factor, data, start_time, patient_id, device_id } => {
+            FHIRObservation::SampledData { code, period, factor, data, start_time, patient_id, device_id, category, performer_id } => {
                 let mut records = Vec::new();
                 let mut context = HashMap::new();
                 
                 if let Some(device) = device_id {
                     context.insert("device_id".to_string(), device.clone());
                 }
+                if let Some(category) = category {
+                    context.insert("category".to_string(), category.clone());
+                }
+                if let Some(performer) = performer_id {
+                    context.insert("performer_id".to_string(), performer.clone());
+                }
                 
                 // Add metadata to context
                 context.insert("sample_type".to_string(), "sampled_data".to_string());
@@ -80,13 +119,48 @@ impl FHIRConverter for FHIRObservation {
                         timestamp: point_timestamp,
                         metric_name: format!("{}|{}|sampled", patient_id, code),
                         value: *value * *factor, // Apply scaling factor
+                        string_value: None,
                         context: context.clone(),
                         resource_type: "Observation".to_string(),
+                        id: None,
                     });
                 }
                 
                 records
             },
+            
+            FHIRObservation::Categorical { code, value, value_type, display, timestamp, patient_id, device_id, category, performer_id } => {
+                let mut context = HashMap::new();
+                if let Some(device) = device_id {
+                    context.insert("device_id".to_string(), device.clone());
+                }
+                if let Some(category) = category {
+                    context.insert("category".to_string(), category.clone());
+                }
+                if let Some(performer) = performer_id {
+                    context.insert("performer_id".to_string(), performer.clone());
+                }
+                if let Some(display) = display {
+                    context.insert("value_display".to_string(), display.clone());
+                }
+                context.insert("value_type".to_string(), value_type.clone());
+                
+                // Booleans keep a numeric shadow value (1/0) so they can still be counted
+                let numeric_value = match (value_type.as_str(), value.as_str()) {
+                    ("boolean", "true") => 1.0,
+                    _ => 0.0,
+                };
+                
+                vec![Record {
+                    timestamp: *timestamp,
+                    metric_name: format!("{}|{}|{}", patient_id, code, value_type),
+                    value: numeric_value,
+                    string_value: Some(value.clone()),
+                    context,
+                    resource_type: "Observation".to_string(),
+                    id: None,
+                }]
+            },
         }
     }
 
@@ -110,63 +184,76 @@ impl FHIRConverter for FHIRObservation {
         
         // Get device_id from context if available
         let device_id = record.context.get("device_id").cloned();
+        let category = record.context.get("category").cloned();
+        let performer_id = record.context.get("performer_id").cloned();
         
-        // Check if this is a component observation (has 4 parts)
-        if parts.len() >= 4 && parts[2] != "sampled" {
-            // This is a component of a multi-component observation
-            let parent_code = code.clone();
-            let _component_code = parts[2].to_string();
-            let _component_unit = parts[3].to_string();
+        // Non-numeric observations carry their value as text
+        if let Some(value) = &record.string_value {
+            let value_type = record.context.get("value_type")
+                .cloned()
+                .unwrap_or_else(|| "string".to_string());
             
-            // Group records by timestamp to reassemble components
-            let mut components_by_time = HashMap::new();
-            
-            for rec in records {
+            return Ok(FHIRObservation::Categorical {
+                code,
+                value: value.clone(),
+                value_type,
+                display: record.context.get("value_display").cloned(),
+                timestamp: record.timestamp,
+                patient_id,
+                device_id,
+                category,
+                performer_id,
+            });
+        }
+        
+        // Components of one observation share its patient, code and timestamp
+        // and are stored as `{patient}|{code}|{component code}|{unit}`
+        if parts.len() == 4 && parts[2] != "sampled" {
+            let mut components: Vec<ObservationComponent> = Vec::new();
+            for rec in records.iter().filter(|rec| rec.timestamp == record.timestamp) {
                 let rec_parts: Vec<&str> = rec.metric_name.split('|').collect();
-                if rec_parts.len() >= 4 && rec_parts[1] == parent_code.as_str() {
-                    let comp_code = rec_parts[2].to_string();
-                    let comp_unit = rec_parts[3].to_string();
-                    
-                    let component = ObservationComponent {
-                        code: comp_code,
-                        value: rec.value,
-                        unit: comp_unit,
-                    };
-                    
-                    components_by_time
-                        .entry(rec.timestamp)
-                        .or_insert_with(Vec::new)
-                        .push(component);
+                if rec_parts.len() != 4 || rec_parts[0] != patient_id || rec_parts[1] != code {
+                    continue;
                 }
-            }
-            
-            // Use the first timestamp's components
-            if let Some((timestamp, components)) = components_by_time.into_iter().next() {
-                return Ok(FHIRObservation::Component {
-                    code: parent_code,
-                    components,
-                    timestamp,
-                    patient_id,
-                    device_id,
+                
+                // A component recorded twice keeps its latest value
+                components.retain(|component| component.code != rec_parts[2]);
+                components.push(ObservationComponent {
+                    code: rec_parts[2].to_string(),
+                    value: rec.value,
+                    unit: rec_parts[3].to_string(),
                 });
             }
+            
+            return Ok(FHIRObservation::Component {
+                code,
+                components,
+                timestamp: record.timestamp,
+                patient_id,
+                device_id,
+                category,
+                performer_id,
+            });
         }
         
         // Check if this is sampled data
         if parts.len() >= 3 && parts[2] == "sampled" {
-            // Get metadata from context
+            // Sort records by timestamp
+            let mut sorted_records = records.to_vec();
+            sorted_records.sort_by_key(|r| r.timestamp);
+            
+            // Get metadata from context; without it the samples' spacing gives the period
             let period = record.context.get("period_ms")
                 .and_then(|s| s.parse::<f64>().ok())
+                .or_else(|| sample_spacing_ms(&sorted_records))
                 .unwrap_or(1000.0); // Default to 1 second
                 
+            // Stored values are already scaled, so a factor of 1 returns them as they are
             let factor = record.context.get("factor")
                 .and_then(|s| s.parse::<f64>().ok())
+                .filter(|factor| *factor != 0.0)
                 .unwrap_or(1.0);
             
-            // Sort records by timestamp
-            let mut sorted_records = records.to_vec();
-            sorted_records.sort_by_key(|r| r.timestamp);
-            
             // Extract the values
             let data: Vec<f64> = sorted_records.iter().map(|r| r.value / factor).collect();
             let start_time = sorted_records.first().map(|r| r.timestamp).unwrap_or(0);
@@ -179,6 +266,8 @@ impl FHIRConverter for FHIRObservation {
                 start_time,
                 patient_id,
                 device_id,
+                category,
+                performer_id,
             });
         }
         
@@ -191,10 +280,21 @@ impl FHIRConverter for FHIRObservation {
             timestamp: record.timestamp,
             patient_id,
             device_id,
+            category,
+            performer_id,
         })
     }
 }
 
+/// Smallest gap between consecutive samples, in milliseconds; `records` must be sorted
+fn sample_spacing_ms(records: &[Record]) -> Option<f64> {
+    records.windows(2)
+        .map(|pair| pair[1].timestamp - pair[0].timestamp)
+        .filter(|gap| *gap > 0)
+        .min()
+        .map(|gap| gap as f64 * 1000.0)
+}
+
 impl FHIRConverter for MedicationAdministration {
     fn to_records(&self) -> Vec<Record> {
         let mut context = HashMap::new();
@@ -215,8 +315,10 @@ impl FHIRConverter for MedicationAdministration {
             timestamp: self.timestamp,
             metric_name,
             value: self.dose_value,
+            string_value: None,
             context,
             resource_type: "MedicationAdministration".to_string(),
+            id: None,
         }]
     }
 
@@ -290,8 +392,10 @@ impl FHIRConverter for DeviceObservation {
             timestamp: self.timestamp,
             metric_name,
             value: self.value,
+            string_value: None,
             context,
             resource_type: "DeviceObservation".to_string(),
+            id: None,
         }]
     }
 
@@ -376,8 +480,10 @@ impl FHIRConverter for VitalSigns {
                     timestamp: self.timestamp,
                     metric_name: format!("{}|8480-6|{}", self.patient_id, self.unit), // 8480-6 is LOINC for systolic
                     value: *systolic,
+                    string_value: None,
                     context: systolic_context,
                     resource_type: "VitalSigns".to_string(),
+                    id: None,
                 };
                 records.push(systolic_record);
                 
@@ -390,8 +496,10 @@ impl FHIRConverter for VitalSigns {
                     timestamp: self.timestamp,
                     metric_name: format!("{}|8462-4|{}", self.patient_id, self.unit), // 8462-4 is LOINC for diastolic
                     value: *diastolic,
+                    string_value: None,
                     context: diastolic_context,
                     resource_type: "VitalSigns".to_string(),
+                    id: None,
                 };
                 records.push(diastolic_record);
             },
@@ -414,8 +522,10 @@ impl FHIRConverter for VitalSigns {
                     timestamp: self.timestamp,
                     metric_name: format!("{}|{}|{}", self.patient_id, code, self.unit),
                     value: self.value,
+                    string_value: None,
                     context,
                     resource_type: "VitalSigns".to_string(),
+                    id: None,
                 };
                 records.push(record);
             }
@@ -511,4 +621,504 @@ impl FHIRConverter for VitalSigns {
             reliability,
         })
     }
-} 
\ No newline at end of file
+} 
+impl FHIRConverter for Condition {
+    fn to_records(&self) -> Vec<Record> {
+        let mut context = HashMap::new();
+        
+        // Add condition metadata to context
+        context.insert("code_display".to_string(), self.code_display.clone());
+        context.insert("clinical_status".to_string(), self.clinical_status.clone());
+        
+        if let Some(severity) = &self.severity {
+            context.insert("severity".to_string(), severity.clone());
+        }
+        
+        // Store severity as an ordinal so conditions can be trended (0 = unspecified)
+        let value = match self.severity.as_deref() {
+            Some("mild") => 1.0,
+            Some("moderate") => 2.0,
+            Some("severe") => 3.0,
+            _ => 0.0,
+        };
+        
+        // Create the metric name in format: {patient_id}|{code}|condition
+        let metric_name = format!("{}|{}|condition", self.patient_id, self.code);
+        
+        vec![Record {
+            timestamp: self.onset,
+            metric_name,
+            value,
+            string_value: None,
+            context,
+            resource_type: "Condition".to_string(),
+            id: None,
+        }]
+    }
+
+    fn from_records(records: &[Record]) -> Result<Self, FHIRError> {
+        if records.is_empty() {
+            return Err(FHIRError::ConversionError("No records provided".to_string()));
+        }
+
+        let record = &records[0];
+        
+        // Parse metric name components (patient_id|code|condition)
+        let parts: Vec<&str> = record.metric_name.split('|').collect();
+        if parts.len() < 3 || parts[2] != "condition" {
+            return Err(FHIRError::ConversionError(
+                format!("Invalid metric name format for condition: {}", record.metric_name)
+            ));
+        }
+        
+        let patient_id = parts[0].to_string();
+        let code = parts[1].to_string();
+        
+        // Extract metadata from context
+        let code_display = record.context.get("code_display")
+            .cloned()
+            .unwrap_or_default();
+            
+        let clinical_status = record.context.get("clinical_status")
+            .cloned()
+            .unwrap_or_else(|| "active".to_string());
+            
+        let severity = record.context.get("severity").cloned();
+        
+        Ok(Condition {
+            code,
+            code_display,
+            clinical_status,
+            onset: record.timestamp,
+            patient_id,
+            severity,
+        })
+    }
+}
+
+impl FHIRConverter for Encounter {
+    fn to_records(&self) -> Vec<Record> {
+        let mut context = HashMap::new();
+        context.insert("status".to_string(), self.status.clone());
+        
+        // The record value is the encounter length in seconds; ongoing encounters have none yet
+        let duration = match self.period_end {
+            Some(end) => (end - self.period_start) as f64,
+            None => {
+                context.insert("in_progress".to_string(), "true".to_string());
+                0.0
+            }
+        };
+        
+        // Create the metric name in format: {patient_id}|encounter|{class}
+        let metric_name = format!("{}|encounter|{}", self.patient_id, self.class);
+        
+        vec![Record {
+            timestamp: self.period_start,
+            metric_name,
+            value: duration,
+            string_value: None,
+            context,
+            resource_type: "Encounter".to_string(),
+            id: None,
+        }]
+    }
+
+    fn from_records(records: &[Record]) -> Result<Self, FHIRError> {
+        if records.is_empty() {
+            return Err(FHIRError::ConversionError("No records provided".to_string()));
+        }
+
+        let record = &records[0];
+        
+        // Parse metric name components (patient_id|encounter|class)
+        let parts: Vec<&str> = record.metric_name.split('|').collect();
+        if parts.len() < 3 || parts[1] != "encounter" {
+            return Err(FHIRError::ConversionError(
+                format!("Invalid metric name format for encounter: {}", record.metric_name)
+            ));
+        }
+        
+        let patient_id = parts[0].to_string();
+        let class = parts[2].to_string();
+        
+        let status = record.context.get("status")
+            .cloned()
+            .unwrap_or_else(|| "finished".to_string());
+            
+        let period_end = if record.context.get("in_progress").map(String::as_str) == Some("true") {
+            None
+        } else {
+            Some(record.timestamp + record.value as i64)
+        };
+        
+        Ok(Encounter {
+            class,
+            status,
+            period_start: record.timestamp,
+            period_end,
+            patient_id,
+        })
+    }
+}
+
+impl FHIRConverter for AllergyIntolerance {
+    fn to_records(&self) -> Vec<Record> {
+        let mut context = HashMap::new();
+        
+        // Keep the descriptive fields in context
+        context.insert("substance_display".to_string(), self.substance_display.clone());
+        context.insert("allergy_type".to_string(), self.allergy_type.clone());
+        
+        // Criticality is stored as an ordinal value (unable-to-assess = 0)
+        let value = match self.criticality.as_str() {
+            "low" => 1.0,
+            "high" => 2.0,
+            _ => 0.0,
+        };
+        
+        // Create the metric name in format: {patient_id}|{substance_code}|allergy
+        let metric_name = format!("{}|{}|allergy", self.patient_id, self.substance_code);
+        
+        vec![Record {
+            timestamp: self.recorded,
+            metric_name,
+            value,
+            string_value: None,
+            context,
+            resource_type: "AllergyIntolerance".to_string(),
+            id: None,
+        }]
+    }
+
+    fn from_records(records: &[Record]) -> Result<Self, FHIRError> {
+        if records.is_empty() {
+            return Err(FHIRError::ConversionError("No records provided".to_string()));
+        }
+
+        let record = &records[0];
+        
+        // Parse metric name components (patient_id|substance_code|allergy)
+        let parts: Vec<&str> = record.metric_name.split('|').collect();
+        if parts.len() < 3 || parts[2] != "allergy" {
+            return Err(FHIRError::ConversionError(
+                format!("Invalid metric name format for allergy: {}", record.metric_name)
+            ));
+        }
+        
+        let patient_id = parts[0].to_string();
+        let substance_code = parts[1].to_string();
+        
+        // Map the ordinal back to the FHIR criticality code
+        let criticality = match record.value as i64 {
+            1 => "low",
+            2 => "high",
+            _ => "unable-to-assess",
+        }.to_string();
+        
+        let substance_display = record.context.get("substance_display")
+            .cloned()
+            .unwrap_or_default();
+            
+        let allergy_type = record.context.get("allergy_type")
+            .cloned()
+            .unwrap_or_else(|| "allergy".to_string());
+        
+        Ok(AllergyIntolerance {
+            substance_code,
+            substance_display,
+            criticality,
+            allergy_type,
+            recorded: record.timestamp,
+            patient_id,
+        })
+    }
+}
+
+impl FHIRConverter for Patient {
+    fn to_records(&self) -> Vec<Record> {
+        // Demographics are not a time series, so everything lives in context
+        let mut context = HashMap::new();
+        if let Some(family) = &self.family_name {
+            context.insert("family_name".to_string(), family.clone());
+        }
+        if !self.given_names.is_empty() {
+            context.insert("given_names".to_string(), self.given_names.join(" "));
+        }
+        if let Some(birth_date) = &self.birth_date {
+            context.insert("birth_date".to_string(), birth_date.clone());
+        }
+        if let Some(gender) = &self.gender {
+            context.insert("gender".to_string(), gender.clone());
+        }
+        
+        vec![Record {
+            timestamp: self.registered,
+            metric_name: Patient::demographics_metric(&self.id),
+            value: 0.0,
+            string_value: None,
+            context,
+            resource_type: "Patient".to_string(),
+            id: None,
+        }]
+    }
+
+    fn from_records(records: &[Record]) -> Result<Self, FHIRError> {
+        // The most recent demographics record wins
+        let record = records.iter()
+            .max_by_key(|r| r.timestamp)
+            .ok_or_else(|| FHIRError::ConversionError("No records provided".to_string()))?;
+        
+        // Parse metric name components (patient_id|patient|demographics)
+        let parts: Vec<&str> = record.metric_name.split('|').collect();
+        if parts.len() != 3 || parts[1] != "patient" || parts[2] != "demographics" {
+            return Err(FHIRError::ConversionError(
+                format!("Invalid metric name format for patient: {}", record.metric_name)
+            ));
+        }
+        
+        let given_names = record.context.get("given_names")
+            .map(|names| names.split(' ').map(|s| s.to_string()).collect())
+            .unwrap_or_default();
+        
+        Ok(Patient {
+            id: parts[0].to_string(),
+            family_name: record.context.get("family_name").cloned(),
+            given_names,
+            birth_date: record.context.get("birth_date").cloned(),
+            gender: record.context.get("gender").cloned(),
+            registered: record.timestamp,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_categorical_observation_round_trip() {
+        let observation = FHIRObservation::Categorical {
+            code: "883-9".to_string(),
+            value: "LA19710-5".to_string(),
+            value_type: "CodeableConcept".to_string(),
+            display: Some("Group A".to_string()),
+            timestamp: 1672531200,
+            patient_id: "123".to_string(),
+            device_id: None,
+            category: None,
+            performer_id: None,
+        };
+        
+        let records = observation.to_records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].metric_name, "123|883-9|CodeableConcept");
+        assert_eq!(records[0].string_value.as_deref(), Some("LA19710-5"));
+        
+        match FHIRObservation::from_records(&records).unwrap() {
+            FHIRObservation::Categorical { code, value, value_type, display, timestamp, patient_id, .. } => {
+                assert_eq!(code, "883-9");
+                assert_eq!(value, "LA19710-5");
+                assert_eq!(value_type, "CodeableConcept");
+                assert_eq!(display.as_deref(), Some("Group A"));
+                assert_eq!(timestamp, 1672531200);
+                assert_eq!(patient_id, "123");
+            },
+            other => panic!("Expected categorical observation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_component_observation_keeps_every_component() {
+        let component = |code: &str, value: f64| ObservationComponent {
+            code: code.to_string(),
+            value,
+            unit: "mg/dL".to_string(),
+        };
+        let observation = FHIRObservation::Component {
+            code: "57698-3".to_string(),
+            components: vec![component("2093-3", 190.0), component("2085-9", 55.0), component("2571-8", 120.0)],
+            timestamp: 1000,
+            patient_id: "123".to_string(),
+            device_id: None,
+            category: None,
+            performer_id: None,
+        };
+        
+        // A later panel for the same patient must not bleed into this one
+        let mut records = observation.to_records();
+        let mut later = records[0].clone();
+        later.timestamp = 2000;
+        records.push(later);
+        
+        match FHIRObservation::from_records(&records).unwrap() {
+            FHIRObservation::Component { code, components, timestamp, .. } => {
+                assert_eq!(code, "57698-3");
+                assert_eq!(timestamp, 1000);
+                let codes: Vec<&str> = components.iter().map(|c| c.code.as_str()).collect();
+                assert_eq!(codes, vec!["2093-3", "2085-9", "2571-8"]);
+                assert_eq!(components[2].value, 120.0);
+                assert_eq!(components[2].unit, "mg/dL");
+            },
+            other => panic!("Expected component observation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_boolean_observation_keeps_numeric_shadow() {
+        let observation = FHIRObservation::Categorical {
+            code: "11778-8".to_string(),
+            value: "true".to_string(),
+            value_type: "boolean".to_string(),
+            display: None,
+            timestamp: 1000,
+            patient_id: "123".to_string(),
+            device_id: None,
+            category: None,
+            performer_id: None,
+        };
+        
+        let records = observation.to_records();
+        assert_eq!(records[0].value, 1.0);
+        assert_eq!(records[0].string_value.as_deref(), Some("true"));
+    }
+
+    #[test]
+    fn test_condition_round_trip() {
+        let condition = Condition {
+            code: "44054006".to_string(),
+            code_display: "Diabetes mellitus type 2".to_string(),
+            clinical_status: "active".to_string(),
+            onset: 1672531200,
+            patient_id: "123".to_string(),
+            severity: Some("moderate".to_string()),
+        };
+        
+        let records = condition.to_records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].metric_name, "123|44054006|condition");
+        assert_eq!(records[0].resource_type, "Condition");
+        assert_eq!(records[0].value, 2.0);
+        
+        let restored = Condition::from_records(&records).unwrap();
+        assert_eq!(restored.code, "44054006");
+        assert_eq!(restored.code_display, "Diabetes mellitus type 2");
+        assert_eq!(restored.clinical_status, "active");
+        assert_eq!(restored.onset, 1672531200);
+        assert_eq!(restored.patient_id, "123");
+        assert_eq!(restored.severity.as_deref(), Some("moderate"));
+    }
+
+    #[test]
+    fn test_multi_day_encounter_round_trip() {
+        let encounter = Encounter {
+            class: "IMP".to_string(),
+            status: "finished".to_string(),
+            period_start: 1672531200,               // 2023-01-01T00:00:00Z
+            period_end: Some(1672531200 + 3 * 86400 + 3600), // three days and an hour later
+            patient_id: "123".to_string(),
+        };
+        
+        let records = encounter.to_records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].metric_name, "123|encounter|IMP");
+        assert_eq!(records[0].timestamp, 1672531200);
+        assert_eq!(records[0].value, (3 * 86400 + 3600) as f64);
+        
+        let restored = Encounter::from_records(&records).unwrap();
+        assert_eq!(restored.class, "IMP");
+        assert_eq!(restored.status, "finished");
+        assert_eq!(restored.period_start, 1672531200);
+        assert_eq!(restored.period_end, Some(1672531200 + 3 * 86400 + 3600));
+        assert_eq!(restored.patient_id, "123");
+    }
+
+    #[test]
+    fn test_ongoing_encounter_has_no_end() {
+        let encounter = Encounter {
+            class: "EMER".to_string(),
+            status: "in-progress".to_string(),
+            period_start: 1000,
+            period_end: None,
+            patient_id: "123".to_string(),
+        };
+        
+        let restored = Encounter::from_records(&encounter.to_records()).unwrap();
+        assert_eq!(restored.period_end, None);
+        assert_eq!(restored.status, "in-progress");
+    }
+
+    #[test]
+    fn test_allergy_criticality_mapping() {
+        for (criticality, ordinal) in [("low", 1.0), ("high", 2.0), ("unable-to-assess", 0.0)] {
+            let allergy = AllergyIntolerance {
+                substance_code: "7980".to_string(),
+                substance_display: "Penicillin G".to_string(),
+                criticality: criticality.to_string(),
+                allergy_type: "allergy".to_string(),
+                recorded: 1672531200,
+                patient_id: "123".to_string(),
+            };
+            
+            let records = allergy.to_records();
+            assert_eq!(records[0].metric_name, "123|7980|allergy");
+            assert_eq!(records[0].value, ordinal);
+            
+            let restored = AllergyIntolerance::from_records(&records).unwrap();
+            assert_eq!(restored.criticality, criticality);
+            assert_eq!(restored.substance_code, "7980");
+            assert_eq!(restored.substance_display, "Penicillin G");
+            assert_eq!(restored.allergy_type, "allergy");
+            assert_eq!(restored.recorded, 1672531200);
+            assert_eq!(restored.patient_id, "123");
+        }
+    }
+
+    #[test]
+    fn test_patient_round_trip() {
+        let patient = Patient {
+            id: "123".to_string(),
+            family_name: Some("Chalmers".to_string()),
+            given_names: vec!["Peter".to_string(), "James".to_string()],
+            birth_date: Some("1974-12-25".to_string()),
+            gender: Some("male".to_string()),
+            registered: 1672531200,
+        };
+        
+        let records = patient.to_records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].metric_name, "123|patient|demographics");
+        
+        let restored = Patient::from_records(&records).unwrap();
+        assert_eq!(restored.id, "123");
+        assert_eq!(restored.family_name.as_deref(), Some("Chalmers"));
+        assert_eq!(restored.given_names, vec!["Peter", "James"]);
+        assert_eq!(restored.birth_date.as_deref(), Some("1974-12-25"));
+        assert_eq!(restored.gender.as_deref(), Some("male"));
+    }
+
+    #[test]
+    fn test_sampled_data_without_metadata_infers_period() {
+        let records: Vec<Record> = [10.0, 12.0, 11.0].iter().enumerate()
+            .map(|(i, value)| Record {
+                timestamp: 1000 + 2 * i as i64,
+                metric_name: "123|131328|sampled".to_string(),
+                value: *value,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            })
+            .collect();
+        
+        match FHIRObservation::from_records(&records).unwrap() {
+            FHIRObservation::SampledData { period, factor, data, start_time, .. } => {
+                assert_eq!(period, 2000.0);
+                assert_eq!(factor, 1.0);
+                assert_eq!(data, vec![10.0, 12.0, 11.0]);
+                assert_eq!(start_time, 1000);
+            },
+            other => panic!("Expected sampled data, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Conversion of ingested values into one canonical unit per quantity
+//!
+//! The unit is the last segment of a metric name, so the same vital posted in
+//! °F and °C would otherwise land in two metrics that never aggregate together.
+//! A `UnitNormalizer` rewrites records in a known source unit before they are
+//! stored, keeping the unit they arrived in as `original_unit` in the context.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use crate::storage::Record;
+
+/// How a source unit maps onto its canonical unit: `value * factor + offset`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct UnitConversion {
+    pub unit: String, // Canonical unit, e.g. "Cel"
+    pub factor: f64,
+    #[serde(default)]
+    pub offset: f64, // Only needed for scales with different zeros, like temperatures
+}
+
+/// Table of source units to convert, keyed by the unit as it appears in metric names
+#[derive(Debug, Clone, Default)]
+pub struct UnitNormalizer {
+    conversions: HashMap<String, UnitConversion>,
+}
+
+impl UnitNormalizer {
+    pub fn new(conversions: HashMap<String, UnitConversion>) -> Self {
+        UnitNormalizer { conversions }
+    }
+
+    /// The record in its canonical unit, or unchanged if its unit isn't in the table
+    ///
+    /// Records carrying a string value have no quantity to convert and are left alone.
+    pub fn normalize(&self, mut record: Record) -> Record {
+        if record.string_value.is_some() {
+            return record;
+        }
+        let Some((prefix, unit)) = record.metric_name.rsplit_once('|') else {
+            return record;
+        };
+        let Some(conversion) = self.conversions.get(unit).filter(|c| c.unit != unit) else {
+            return record;
+        };
+
+        record.context.insert("original_unit".to_string(), unit.to_string());
+        record.metric_name = format!("{}|{}", prefix, conversion.unit);
+        record.value = record.value * conversion.factor + conversion.offset;
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(metric_name: &str, value: f64) -> Record {
+        Record {
+            timestamp: 1000,
+            metric_name: metric_name.to_string(),
+            value,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        }
+    }
+
+    #[test]
+    fn test_normalize_to_canonical_units() {
+        let normalizer = UnitNormalizer::new(HashMap::from([
+            ("degF".to_string(), UnitConversion { unit: "Cel".to_string(), factor: 5.0 / 9.0, offset: -160.0 / 9.0 }),
+            ("mg".to_string(), UnitConversion { unit: "g".to_string(), factor: 0.001, offset: 0.0 }),
+        ]));
+
+        let temperature = normalizer.normalize(record("p1|8310-5|degF", 98.6));
+        assert_eq!(temperature.metric_name, "p1|8310-5|Cel");
+        assert!((temperature.value - 37.0).abs() < 1e-9);
+        assert_eq!(temperature.context["original_unit"], "degF");
+
+        let dose = normalizer.normalize(record("p1|161|mg", 500.0));
+        assert_eq!(dose.metric_name, "p1|161|g");
+        assert!((dose.value - 0.5).abs() < 1e-12);
+
+        // Already canonical, or not in the table
+        assert_eq!(normalizer.normalize(record("p1|8310-5|Cel", 37.0)), record("p1|8310-5|Cel", 37.0));
+        assert_eq!(normalizer.normalize(record("p1|8867-4|bpm", 70.0)), record("p1|8867-4|bpm", 70.0));
+    }
+}
@@ -10,7 +10,7 @@ pub mod config;
 pub mod api;
 pub mod error;
 
-use std::collections::HashMap;
+use std::collections::{hash_map, HashMap};
 use std::sync::RwLock;
 use std::time::Duration;
 use crate::storage::{TimeChunk, Record, ChunkError};
@@ -56,10 +56,12 @@ impl StorageEngine {
         let mut chunks = self.chunks.write().unwrap();
         
         // Create new chunk if needed
-        if !chunks.contains_key(&chunk_id) {
-            let start_time = chunk_id;
-            let end_time = start_time + self.chunk_duration.as_secs() as i64;
-            chunks.insert(chunk_id, TimeChunk::new(start_time, end_time));
+        if let hash_map::Entry::Vacant(entry) = chunks.entry(chunk_id) {
+            let chunk_end = chunk_id.checked_add(self.chunk_duration.as_secs() as i64)
+                .ok_or_else(|| StorageError::InvalidTimeRange(format!(
+                    "Chunk starting at {} would end past the largest supported timestamp", chunk_id
+                )))?;
+            entry.insert(TimeChunk::new(chunk_id, chunk_end));
         }
 
         // Insert into appropriate chunk
@@ -113,8 +115,8 @@ impl StorageEngine {
     }
 
     fn get_chunk_id(&self, timestamp: i64) -> i64 {
-        // Round down to nearest chunk boundary
-        timestamp - (timestamp % self.chunk_duration.as_secs() as i64)
+        // Round down to the nearest chunk boundary, saturating at i64::MIN
+        crate::storage::chunk_id_for_timestamp(timestamp, self.chunk_duration)
     }
 
     pub fn cleanup_old_chunks(&self, retention: Duration) -> Result<(), StorageError> {
@@ -2,34 +2,83 @@ use std::error::Error;
 use std::path::Path;
 use std::sync::Arc;
 use tokio::signal;
-use tokio::sync::oneshot;
-use crate::storage::StorageEngine;
-use crate::api::rest::RestApi;
-use crate::timeseries::query::QueryEngine;
-use crate::config::load_config;
-
-mod api;
-mod config;
-mod error;
-mod fhir;
-mod storage;
-mod timeseries;
+use tokio::sync::{oneshot, watch};
+use emberdb::storage::StorageEngine;
+use emberdb::api::rest::{QueryWindow, RestApi};
+use emberdb::timeseries::query::QueryEngine;
+use emberdb::config::{load_config, FsyncPolicy};
+use emberdb::fhir::units::UnitNormalizer;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     // Initialize components
     let config = load_config(Path::new("config.yaml"))
-        .map_err(|e| Box::<dyn Error>::from(e))?;
+        .map_err(Box::<dyn Error>::from)?;
     
     println!("Starting EmberDB with storage path: {}", config.storage.path);
     
     // Initialize storage with persistence
     let storage = StorageEngine::new(&config)
-        .map_err(|e| Box::<dyn Error>::from(e))?;
+        .map_err(Box::<dyn Error>::from)?;
     let storage = Arc::new(storage);
     
-    let query_engine = Arc::new(QueryEngine::new(Arc::clone(&storage)));
-    let api = RestApi::new(Arc::clone(&query_engine));
+    // Background tasks stop once this is dropped, so none is still writing during the final flush
+    let (stop_tasks, tasks_stopped) = watch::channel(());
+    
+    // Under an interval fsync policy, appends only sync once the interval has
+    // passed; this keeps a quiet WAL from holding unsynced writes indefinitely
+    let wal_sync_task = if let FsyncPolicy::Interval(interval) = config.storage.wal_fsync {
+        let storage = Arc::clone(&storage);
+        let mut tasks_stopped = tasks_stopped.clone();
+        Some(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = tasks_stopped.changed() => break,
+                }
+                let storage = Arc::clone(&storage);
+                if let Ok(Err(e)) = tokio::task::spawn_blocking(move || storage.sync_wal()).await {
+                    eprintln!("Error syncing WAL: {:?}", e);
+                }
+            }
+        }))
+    } else {
+        None
+    };
+    
+    // Writes refused for backpressure only succeed again after a flush, so
+    // one is started whenever the high-water mark is crossed
+    let pressure_flush_task = {
+        let storage = Arc::clone(&storage);
+        let mut tasks_stopped = tasks_stopped.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = storage.flush_requested() => {}
+                    _ = tasks_stopped.changed() => break,
+                }
+                let storage = Arc::clone(&storage);
+                if let Ok(Err(e)) = tokio::task::spawn_blocking(move || storage.flush_all()).await {
+                    eprintln!("Error flushing under backpressure: {:?}", e);
+                }
+            }
+        })
+    };
+    
+    let query_engine = Arc::new(
+        QueryEngine::new(Arc::clone(&storage))
+            .with_cache_capacity(config.query_cache_size)
+            .with_value_decimals(config.value_decimals)
+            .with_unit_normalizer(UnitNormalizer::new(config.unit_conversions.clone()))
+    );
+    let api = RestApi::new(Arc::clone(&query_engine))
+        .with_auth_token(config.api.auth_token.clone())
+        .with_strict_body(config.api.strict_body)
+        .with_query_window(QueryWindow {
+            default_span: config.api.default_query_window,
+            max_span: config.api.max_query_span,
+        });
 
     println!("Starting server on {}:{}", config.api.host, config.api.port);
     
@@ -57,20 +106,20 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // Start shutdown process
     shutdown_tx.send(()).ok();
     
-    // Wait for server to exit
-    server_handle.await.map_err(|e| Box::<dyn Error>::from(e))?;
+    // Wait for server to exit, then for the background tasks
+    server_handle.await.map_err(Box::<dyn Error>::from)?;
+    drop(stop_tasks);
+    if let Some(task) = wal_sync_task {
+        task.await.map_err(Box::<dyn Error>::from)?;
+    }
+    pressure_flush_task.await.map_err(Box::<dyn Error>::from)?;
     
-    // Flush all data to disk before exiting
+    // Nothing writes any more, though `query_engine` still shares `storage`;
+    // flushing only needs a shared reference, so it doesn't matter who else holds one
     println!("Flushing data to disk...");
-    
-    // Downcast to get access to the raw StorageEngine
-    let storage_ref = Arc::as_ref(&storage);
-    
-    // Flush all chunks to disk
-    if let Err(e) = storage_ref.flush_all() {
-        eprintln!("Error flushing data: {:?}", e);
-    } else {
-        println!("Data successfully flushed to disk");
+    match storage.flush_all() {
+        Ok(_) => println!("Data successfully flushed to disk"),
+        Err(e) => eprintln!("Error flushing data: {:?}", e),
     }
     
     println!("Server shutdown complete");
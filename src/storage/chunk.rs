@@ -1,8 +1,17 @@
-use std::collections::{HashMap, HashSet};
-use std::time::{SystemTime, UNIX_EPOCH};
-use super::Record;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use super::{Record, ROLLUP_RESOURCE_TYPE};
+use super::gorilla;
 use serde::{Serialize, Deserialize};
 
+/// Records a chunk holds before it counts as full
+pub const MAX_CHUNK_RECORDS: usize = 10_000;
+
+/// Estimated bytes a chunk holds before it counts as full
+pub const MAX_CHUNK_BYTES: usize = 1_000_000;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum CompressionState {
     Uncompressed,
@@ -14,10 +23,13 @@ pub enum CompressionState {
 #[allow(dead_code)]
 pub struct ChunkMetadata {
     created_at: i64,
-    last_access: i64,
+    pub(super) last_access: i64,
     compression_ratio: f64,
     record_count: usize,
     size_bytes: usize,
+    /// Set on chunks widened by `absorb`, whose span is deliberately longer than `chunk_duration`
+    #[serde(default)]
+    pub(super) compacted: bool,
 }
 
 #[derive(Debug)]
@@ -31,6 +43,7 @@ pub enum ChunkError {
     SerializationFailed(String),
     DeserializationFailed(String),
     DiskReadFailed(String),
+    Compressed(String),
 }
 
 impl std::fmt::Display for ChunkError {
@@ -45,6 +58,7 @@ impl std::fmt::Display for ChunkError {
             ChunkError::SerializationFailed(msg) => write!(f, "Serialization error: {}", msg),
             ChunkError::DeserializationFailed(msg) => write!(f, "Deserialization error: {}", msg),
             ChunkError::DiskReadFailed(msg) => write!(f, "Disk read error: {}", msg),
+            ChunkError::Compressed(msg) => write!(f, "Chunk is compressed: {}", msg),
         }
     }
 }
@@ -56,11 +70,91 @@ pub struct TimeChunk {
     pub start_time: i64,
     pub end_time: i64,
     pub records: HashMap<String, Vec<Record>>,
+    #[serde(default)]
     pub resource_metrics: HashMap<String, HashSet<String>>, // Resource type -> set of metrics
     pub metadata: ChunkMetadata,
     pub compression_state: CompressionState,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub compressed: HashMap<String, CompressedSeries>, // Metric -> records while compressed
     #[serde(skip)]
     pub dirty: bool, // Flag to indicate if chunk has been modified since last flush
+    #[serde(skip)]
+    generation: u64, // Bumped on every change, so a flush can tell its copy went stale
+    #[serde(skip)]
+    capacity_hint: usize, // Records reserved for each metric's first append
+    #[serde(skip)]
+    range_cache: RangeCache,
+    #[serde(skip)]
+    dirty_bytes: DirtyBytes,
+}
+
+/// The most recent `get_range` answer, as positions into the metric's records
+#[derive(Debug)]
+struct CachedRange {
+    metric: String,
+    start: i64,
+    end: i64,
+    positions: Vec<usize>,
+}
+
+/// Memo of the last range read from a chunk, dropped whenever the chunk changes
+///
+/// Dashboards tend to poll the same window over and over; the memo lets a repeat
+/// read skip the scan. Readers share a chunk, so one finding the memo in use
+/// scans without it rather than waiting. A cloned chunk starts with an empty memo.
+#[derive(Debug, Default)]
+struct RangeCache {
+    last: Mutex<Option<CachedRange>>,
+    hits: AtomicU64,
+}
+
+impl Clone for RangeCache {
+    fn clone(&self) -> Self {
+        RangeCache::default()
+    }
+}
+
+impl RangeCache {
+    fn invalidate(&mut self) {
+        *self.last.get_mut().unwrap() = None;
+    }
+}
+
+/// A chunk's share of a running total of unflushed bytes
+///
+/// Kept in step by `mark_modified` and `mark_clean`, and given back when the
+/// chunk is dropped. A cloned chunk is a copy nobody flushes, so it starts untracked.
+#[derive(Debug, Default)]
+struct DirtyBytes {
+    total: Option<Arc<AtomicUsize>>,
+    counted: usize,
+}
+
+impl Clone for DirtyBytes {
+    fn clone(&self) -> Self {
+        DirtyBytes::default()
+    }
+}
+
+impl Drop for DirtyBytes {
+    fn drop(&mut self) {
+        self.set(0);
+    }
+}
+
+impl DirtyBytes {
+    /// Count `bytes` for this chunk in place of what it counted before
+    fn set(&mut self, bytes: usize) {
+        let Some(total) = &self.total else {
+            return;
+        };
+        if bytes >= self.counted {
+            total.fetch_add(bytes - self.counted, Ordering::Relaxed);
+        } else {
+            total.fetch_sub(self.counted - bytes, Ordering::Relaxed);
+        }
+        self.counted = bytes;
+    }
 }
 
 impl TimeChunk {
@@ -81,66 +175,407 @@ impl TimeChunk {
                 compression_ratio: 1.0,
                 record_count: 0,
                 size_bytes: 0,
+                compacted: false,
             },
             compression_state: CompressionState::Uncompressed,
+            compressed: HashMap::new(),
             dirty: true,
+            generation: 0,
+            capacity_hint: 0,
+            range_cache: RangeCache::default(),
+            dirty_bytes: DirtyBytes::default(),
+        }
+    }
+
+    /// Reserve room for `records_per_metric` records whenever a metric first appears
+    pub fn with_capacity_hint(mut self, records_per_metric: usize) -> Self {
+        self.capacity_hint = records_per_metric.min(MAX_CHUNK_RECORDS);
+        self
+    }
+
+    /// Reserve room for `additional` more records of a metric the chunk already holds
+    pub fn reserve_records(&mut self, metric: &str, additional: usize) {
+        if let Some(records) = self.records.get_mut(metric) {
+            records.reserve(additional);
+        }
+    }
+
+    /// Records per metric, for sizing the chunk that follows this one
+    ///
+    /// Capped so the metrics together don't reserve more than `MAX_CHUNK_RECORDS`.
+    pub fn records_per_metric(&self) -> usize {
+        let metrics = self.records.len() + self.compressed.len();
+        if metrics == 0 {
+            return 0;
         }
+        (self.metadata.record_count / metrics).min(MAX_CHUNK_RECORDS / metrics)
     }
 
     pub fn append(&mut self, record: Record) -> std::result::Result<(), ChunkError> {
         if !self.can_accept(record.timestamp) {
             return Err(ChunkError::OutOfTimeRange("Record timestamp outside chunk range".to_string()));
         }
+        self.decompress()?;
 
         // Store the metric name before borrowing record
         let metric_name = record.metric_name.clone();
         let resource_type = record.resource_type.clone();
         
+        // A new metric also pays for its key in the index
+        if !self.records.contains_key(&metric_name) {
+            self.metadata.size_bytes += metric_entry_size(&metric_name);
+        }
+        self.metadata.size_bytes += record_size(&record);
+        
         // Add to main records index
         self.records
             .entry(metric_name.clone())
-            .or_insert_with(Vec::new)
+            .or_insert_with(|| Vec::with_capacity(self.capacity_hint))
             .push(record);
 
         // Add to resource type index
         self.resource_metrics
             .entry(resource_type)
-            .or_insert_with(HashSet::new)
+            .or_default()
             .insert(metric_name);
 
         self.metadata.record_count += 1;
         self.update_access_time();
-        self.dirty = true;
+        self.mark_modified();
+        Ok(())
+    }
+
+    /// Insert a record, replacing any existing record for the same metric and timestamp
+    ///
+    /// Returns whether an existing record was replaced.
+    pub fn upsert(&mut self, record: Record) -> std::result::Result<bool, ChunkError> {
+        if !self.can_accept(record.timestamp) {
+            return Err(ChunkError::OutOfTimeRange("Record timestamp outside chunk range".to_string()));
+        }
+        self.decompress()?;
+        
+        let existing = self.records.get_mut(&record.metric_name).and_then(|records| {
+            records.iter().position(|r| r.timestamp == record.timestamp).map(|index| (records, index))
+        });
+        
+        let Some((records, index)) = existing else {
+            self.append(record)?;
+            return Ok(false);
+        };
+        
+        // Rewriting a record unchanged, e.g. replaying the WAL into a reloaded chunk, is not a change
+        let timestamp = record.timestamp;
+        if records[index] == record && records.iter().filter(|r| r.timestamp == timestamp).count() == 1 {
+            return Ok(true);
+        }
+        
+        // Drop any earlier duplicates at this timestamp along with the replaced record
+        let mut removed_size = record_size(&records[index]);
+        let mut removed_count = 0;
+        let mut position = 0;
+        records.retain(|r| {
+            let keep = position <= index || r.timestamp != timestamp;
+            if !keep {
+                removed_size += record_size(r);
+                removed_count += 1;
+            }
+            position += 1;
+            keep
+        });
+        
+        let added_size = record_size(&record);
+        records[index] = record;
+        
+        self.metadata.record_count -= removed_count;
+        self.metadata.size_bytes = self.metadata.size_bytes + added_size - removed_size;
+        self.update_access_time();
+        self.mark_modified();
+        Ok(true)
+    }
+
+    /// Move every record of `old` to `new`, after any records `new` already holds
+    ///
+    /// Returns the number of records moved.
+    pub fn rename_metric(&mut self, old: &str, new: &str) -> std::result::Result<usize, ChunkError> {
+        self.decompress()?;
+        let Some(mut moved) = self.records.remove(old) else {
+            return Ok(0);
+        };
+        
+        for record in &mut moved {
+            record.metric_name = new.to_string();
+        }
+        for metrics in self.resource_metrics.values_mut() {
+            if metrics.remove(old) {
+                metrics.insert(new.to_string());
+            }
+        }
+        
+        // Rollups of the old name are stale; the next refresh builds them under the new one
+        let stale_prefix = format!("{}|rollup|", old);
+        let stale: Vec<String> = self.records.keys().filter(|m| m.starts_with(&stale_prefix)).cloned().collect();
+        for rollup_name in stale {
+            if let Some(previous) = self.records.remove(&rollup_name) {
+                self.metadata.record_count = self.metadata.record_count.saturating_sub(previous.len());
+            }
+            for metrics in self.resource_metrics.values_mut() {
+                metrics.remove(&rollup_name);
+            }
+        }
+        self.resource_metrics.retain(|_, metrics| !metrics.is_empty());
+        
+        let count = moved.len();
+        self.records.entry(new.to_string()).or_default().extend(moved);
+        self.refresh_size();
+        self.update_access_time();
+        self.mark_modified();
+        Ok(count)
+    }
+
+    /// Drop every record for which `keep` returns false
+    ///
+    /// Metrics left without records are forgotten. Returns the number of
+    /// records removed; the chunk is only marked dirty if there were any.
+    pub fn retain_records<F>(&mut self, mut keep: F) -> std::result::Result<usize, ChunkError>
+    where
+        F: FnMut(&Record) -> bool,
+    {
+        self.decompress()?;
+        
+        let mut removed = 0;
+        for records in self.records.values_mut() {
+            let before = records.len();
+            records.retain(|record| keep(record));
+            removed += before - records.len();
+        }
+        if removed == 0 {
+            return Ok(0);
+        }
+        
+        self.records.retain(|_, records| !records.is_empty());
+        self.rebuild_resource_metrics();
+        
+        self.metadata.record_count = self.metadata.record_count.saturating_sub(removed);
+        self.refresh_size();
+        self.update_access_time();
+        self.mark_modified();
+        Ok(removed)
+    }
+
+    /// Change the value of the record at this metric and timestamp
+    ///
+    /// Returns whether a record was found.
+    pub fn update_value(&mut self, metric: &str, timestamp: i64, value: f64) -> bool {
+        if self.decompress().is_err() {
+            return false;
+        }
+        let Some(record) = self.records.get_mut(metric)
+            .and_then(|records| records.iter_mut().rev().find(|r| r.timestamp == timestamp)) else {
+            return false;
+        };
+        if record.value.to_bits() == value.to_bits() {
+            return true;
+        }
+        
+        record.value = value;
+        self.update_access_time();
+        self.mark_modified();
+        true
+    }
+
+    /// Rebuild the `{metric}|rollup|{resolution}` records of every raw metric
+    ///
+    /// Each rollup record covers one epoch-aligned bucket of numeric records:
+    /// its value is their mean, with `min`, `max` and `count` in its context.
+    /// Rollups are recomputed from scratch, so later writes to the chunk are
+    /// picked up by the next call.
+    pub fn refresh_rollups(&mut self, resolution: Duration) -> std::result::Result<(), ChunkError> {
+        let resolution_secs = resolution.as_secs() as i64;
+        if resolution_secs <= 0 {
+            return Err(ChunkError::ValidationFailed("Rollup resolution must be at least one second".to_string()));
+        }
+        self.decompress()?;
+        
+        let mut rollups = Vec::new();
+        for (metric, records) in &self.records {
+            if super::is_rollup_metric(metric) {
+                continue;
+            }
+            
+            // Bucket start -> (sum, min, max, count)
+            let mut buckets: BTreeMap<i64, (f64, f64, f64, usize)> = BTreeMap::new();
+            for record in records.iter().filter(|r| r.string_value.is_none() && r.value.is_finite()) {
+                let bucket = record.timestamp - record.timestamp.rem_euclid(resolution_secs);
+                let (sum, min, max, count) = buckets.entry(bucket)
+                    .or_insert((0.0, f64::INFINITY, f64::NEG_INFINITY, 0));
+                *sum += record.value;
+                *min = min.min(record.value);
+                *max = max.max(record.value);
+                *count += 1;
+            }
+            if buckets.is_empty() {
+                continue;
+            }
+            
+            let rollup_name = super::rollup_metric_name(metric, resolution);
+            let rollup_records: Vec<Record> = buckets.into_iter()
+                .map(|(timestamp, (sum, min, max, count))| Record {
+                    timestamp,
+                    metric_name: rollup_name.clone(),
+                    value: sum / count as f64,
+                    string_value: None,
+                    context: HashMap::from([
+                        ("min".to_string(), min.to_string()),
+                        ("max".to_string(), max.to_string()),
+                        ("count".to_string(), count.to_string()),
+                        ("source_metric".to_string(), metric.clone()),
+                    ]),
+                    resource_type: ROLLUP_RESOURCE_TYPE.to_string(),
+                    id: None,
+                })
+                .collect();
+            rollups.push((rollup_name, rollup_records));
+        }
+        
+        for (rollup_name, rollup_records) in rollups {
+            // Unchanged rollups leave the chunk clean, so refreshing alone never forces a write
+            if self.records.get(&rollup_name) == Some(&rollup_records) {
+                continue;
+            }
+            if let Some(previous) = self.records.remove(&rollup_name) {
+                self.metadata.record_count = self.metadata.record_count.saturating_sub(previous.len());
+                self.metadata.size_bytes = self.metadata.size_bytes.saturating_sub(
+                    metric_entry_size(&rollup_name) + previous.iter().map(record_size).sum::<usize>()
+                );
+            }
+            self.metadata.record_count += rollup_records.len();
+            self.metadata.size_bytes += metric_entry_size(&rollup_name)
+                + rollup_records.iter().map(record_size).sum::<usize>();
+            self.resource_metrics
+                .entry(ROLLUP_RESOURCE_TYPE.to_string())
+                .or_default()
+                .insert(rollup_name.clone());
+            self.records.insert(rollup_name, rollup_records);
+            self.mark_modified();
+        }
         Ok(())
     }
 
     pub fn is_full(&self) -> bool {
-        // Example implementation - could be based on size, record count, or other metrics
-        self.metadata.record_count > 10_000 || self.get_size() > 1_000_000
+        // size_bytes is kept up to date on append, so this stays cheap on the insert path
+        self.metadata.record_count > MAX_CHUNK_RECORDS || self.metadata.size_bytes > MAX_CHUNK_BYTES
+    }
+    
+    /// Take over the records of the chunk that follows this one, extending the span to its end
+    ///
+    /// Each metric's records are re-sorted by timestamp, keeping insertion order within a timestamp.
+    pub fn absorb(&mut self, mut next: TimeChunk) -> std::result::Result<(), ChunkError> {
+        if next.start_time < self.end_time {
+            return Err(ChunkError::OutOfTimeRange(format!(
+                "chunk {} starts before chunk {} ends", next.start_time, self.start_time
+            )));
+        }
+        self.decompress()?;
+        next.decompress()?;
+        
+        for (metric, records) in next.records {
+            let merged = self.records.entry(metric).or_default();
+            merged.extend(records);
+            merged.sort_by_key(|r| r.timestamp);
+        }
+        for (resource_type, metrics) in next.resource_metrics {
+            self.resource_metrics.entry(resource_type).or_default().extend(metrics);
+        }
+        
+        self.end_time = next.end_time;
+        self.metadata.record_count += next.metadata.record_count;
+        self.metadata.compacted = true;
+        self.refresh_size();
+        self.update_access_time();
+        self.mark_modified();
+        Ok(())
+    }
+
+    /// Cut the chunk at every multiple of `duration_secs` inside its span
+    ///
+    /// Returns the pieces holding records, oldest first, or just the first piece
+    /// if the chunk is empty. Each metric's records keep their order.
+    pub fn split(mut self, duration_secs: i64) -> std::result::Result<Vec<TimeChunk>, ChunkError> {
+        if duration_secs <= 0 {
+            return Err(ChunkError::ValidationFailed("Chunk duration must be at least one second".to_string()));
+        }
+        self.decompress()?;
+        
+        let mut pieces = Vec::new();
+        let mut start = self.start_time;
+        while start < self.end_time {
+            let end = (start - start.rem_euclid(duration_secs)).saturating_add(duration_secs).min(self.end_time);
+            pieces.push(TimeChunk::new(start, end));
+            start = end;
+        }
+        
+        if self.records.is_empty() {
+            pieces.truncate(1);
+            return Ok(pieces);
+        }
+        for record in self.records.into_values().flatten() {
+            let index = pieces.partition_point(|piece| piece.end_time <= record.timestamp);
+            let Some(piece) = pieces.get_mut(index) else {
+                return Err(ChunkError::OutOfTimeRange("Record timestamp outside chunk range".to_string()));
+            };
+            piece.append(record)?;
+        }
+        // An unaligned first piece stays even when empty: the aligned chunk an
+        // insert would otherwise create there would overlap the chunk before
+        pieces.retain(|piece| piece.record_count() > 0 || piece.start_time.rem_euclid(duration_secs) != 0);
+        Ok(pieces)
     }
 
     pub fn can_accept(&self, timestamp: i64) -> bool {
         timestamp >= self.start_time && timestamp < self.end_time
     }
 
+    /// Estimated in-memory size in bytes, including string and context contents
     pub fn get_size(&self) -> usize {
         self.records.iter().fold(0, |acc, (k, v)| {
-            acc + k.len() + (v.len() * std::mem::size_of::<Record>())
+            acc + metric_entry_size(k) + v.iter().map(record_size).sum::<usize>()
         })
     }
 
+    /// Recompute `size_bytes` from the records, e.g. for chunks written before it was tracked
+    pub fn refresh_size(&mut self) {
+        self.metadata.size_bytes = self.get_size();
+    }
+
     pub fn get_range(&self, start: i64, end: i64, metric: &str) -> std::result::Result<Vec<&Record>, ChunkError> {
         if start > self.end_time || end < self.start_time {
             return Ok(Vec::new());
         }
+        self.ensure_readable()?;
 
         // Return empty Vec instead of error if metric not found
         match self.records.get(metric) {
             Some(records) => {
-                Ok(records
+                if let Ok(last) = self.range_cache.last.try_lock() {
+                    if let Some(cached) = last.as_ref()
+                        .filter(|c| c.metric == metric && c.start == start && c.end == end)
+                    {
+                        self.range_cache.hits.fetch_add(1, Ordering::Relaxed);
+                        return Ok(cached.positions.iter().map(|&i| &records[i]).collect());
+                    }
+                }
+
+                let positions: Vec<usize> = records
                     .iter()
-                    .filter(|r| r.timestamp >= start && r.timestamp < end)
-                    .collect())
+                    .enumerate()
+                    .filter(|(_, r)| r.timestamp >= start && r.timestamp < end)
+                    .map(|(i, _)| i)
+                    .collect();
+                let slice = positions.iter().map(|&i| &records[i]).collect();
+                if let Ok(mut last) = self.range_cache.last.try_lock() {
+                    *last = Some(CachedRange { metric: metric.to_string(), start, end, positions });
+                }
+                Ok(slice)
             },
             None => {
                 // Log that metric was not found but don't return error
@@ -151,15 +586,20 @@ impl TimeChunk {
     }
 
     pub fn get_metric(&mut self, metric: &str) -> std::result::Result<&Vec<Record>, ChunkError> {
+        self.decompress()?;
         self.update_access_time();
         self.records
             .get(metric)
             .ok_or(ChunkError::IndexError(format!("Metric not found: {}", metric)))
     }
 
+    /// The record with the greatest timestamp, whatever order records were appended in
+    ///
+    /// On a tie the most recently appended record wins.
     pub fn get_latest(&self, metric: &str) -> std::result::Result<Option<&Record>, ChunkError> {
+        self.ensure_readable()?;
         match self.records.get(metric) {
-            Some(records) if !records.is_empty() => Ok(Some(records.last().unwrap())),
+            Some(records) if !records.is_empty() => Ok(records.iter().max_by_key(|r| r.timestamp)),
             Some(_) => {
                 // Found the metric but it has no records
                 println!("Metric found but has no records: {}", metric);
@@ -173,11 +613,58 @@ impl TimeChunk {
         }
     }
 
+    /// All records the chunk holds for a metric, in insertion order
+    pub fn metric_records(&self, metric: &str) -> std::result::Result<&[Record], ChunkError> {
+        self.ensure_readable()?;
+        Ok(self.records.get(metric).map(Vec::as_slice).unwrap_or(&[]))
+    }
+
+    /// Timestamp of the newest record in the chunk
+    pub fn latest_timestamp(&self) -> Option<i64> {
+        let compressed = self.compressed.values().filter_map(|series| series.timestamps().ok()?.last().copied());
+        self.records.values()
+            .flat_map(|records| records.iter().map(|r| r.timestamp))
+            .chain(compressed)
+            .max()
+    }
+
+    /// A copy of one metric's records, compressed or not
+    pub fn decoded_metric_records(&self, metric: &str) -> std::result::Result<Vec<Record>, ChunkError> {
+        match self.compressed.get(metric) {
+            Some(series) => series.clone().decode(metric),
+            None => Ok(self.records.get(metric).cloned().unwrap_or_default()),
+        }
+    }
+
+    /// Whether the chunk holds the metric, compressed or not
+    pub fn has_metric(&self, metric: &str) -> bool {
+        self.records.contains_key(metric) || self.compressed.contains_key(metric)
+    }
+
+    /// Record count and earliest/latest timestamp of each metric, compressed or not
+    pub fn metric_extents(&self) -> std::result::Result<Vec<(&str, usize, i64, i64)>, ChunkError> {
+        let mut extents = Vec::new();
+        for (metric, records) in &self.records {
+            let timestamps = records.iter().map(|r| r.timestamp);
+            if let (Some(earliest), Some(latest)) = (timestamps.clone().min(), timestamps.max()) {
+                extents.push((metric.as_str(), records.len(), earliest, latest));
+            }
+        }
+        for (metric, series) in &self.compressed {
+            let timestamps = series.timestamps()?;
+            if let (Some(&earliest), Some(&latest)) = (timestamps.iter().min(), timestamps.iter().max()) {
+                extents.push((metric.as_str(), timestamps.len(), earliest, latest));
+            }
+        }
+        Ok(extents)
+    }
+
     pub fn get_metrics_list(&self) -> Vec<String> {
-        self.records.keys().cloned().collect()
+        self.records.keys().chain(self.compressed.keys()).cloned().collect()
     }
 
     pub fn summarize(&self, metric: &str) -> std::result::Result<ChunkSummary, ChunkError> {
+        self.ensure_readable()?;
         let records = self.records
             .get(metric)
             .ok_or_else(|| ChunkError::IndexError(format!("Metric not found: {}", metric)))?;
@@ -198,24 +685,86 @@ impl TimeChunk {
         })
     }
 
+    /// Move each metric's records into a compact columnar form to save memory
+    ///
+    /// Compression only changes the in-memory representation, so the dirty flag
+    /// is left alone. Reads need `decompress` first; writes decompress on their own.
     pub fn compress(&mut self) -> std::result::Result<(), ChunkError> {
+        if self.is_compressed() {
+            return Ok(());
+        }
         self.compression_state = CompressionState::InProgress;
+        self.range_cache.invalidate();
         
-        for records in self.records.values_mut() {
-            // Delta encoding for timestamps
-            let mut last_timestamp = 0;
-            for record in records.iter_mut() {
-                let delta = record.timestamp - last_timestamp;
-                last_timestamp = record.timestamp;
-                record.timestamp = delta;
+        let metrics: Vec<String> = self.records.keys().cloned().collect();
+        for metric in metrics {
+            // Metrics mixing resource types stay as plain records
+            if let Some(series) = CompressedSeries::encode(&self.records[&metric]) {
+                self.records.remove(&metric);
+                self.compressed.insert(metric, series);
             }
-            
-            // Value compression using gorilla algorithm would go here
         }
         
         self.compression_state = CompressionState::Compressed;
         self.metadata.compression_ratio = self.calculate_compression_ratio();
-        self.dirty = true;
+        Ok(())
+    }
+    
+    /// Restore plain records from the compressed form; a no-op on uncompressed chunks
+    pub fn decompress(&mut self) -> std::result::Result<(), ChunkError> {
+        if !self.is_compressed() {
+            return Ok(());
+        }
+        
+        self.range_cache.invalidate();
+        for (metric, series) in std::mem::take(&mut self.compressed) {
+            let records = series.decode(&metric)?;
+            self.records.insert(metric, records);
+        }
+        
+        self.compression_state = CompressionState::Uncompressed;
+        self.metadata.compression_ratio = 1.0;
+        self.update_access_time();
+        Ok(())
+    }
+    
+    pub fn is_compressed(&self) -> bool {
+        matches!(self.compression_state, CompressionState::Compressed)
+    }
+    
+    /// Records held across all metrics, compressed or not
+    pub fn record_count(&self) -> usize {
+        self.metadata.record_count
+    }
+    
+    /// Estimated in-memory size, kept up to date on every write
+    pub fn size_bytes(&self) -> usize {
+        self.metadata.size_bytes
+    }
+    
+    /// Unix time of the last write or decompression
+    pub fn last_access(&self) -> i64 {
+        self.metadata.last_access
+    }
+    
+    /// Count the chunk as used now, e.g. when it is reloaded from disk
+    pub fn touch(&mut self) {
+        self.update_access_time();
+    }
+    
+    /// Estimated bytes currently held, counting compressed series at their compressed size
+    pub fn resident_size(&self) -> usize {
+        self.get_size() + self.compressed.iter()
+            .map(|(metric, series)| metric_entry_size(metric) + series.size())
+            .sum::<usize>()
+    }
+    
+    fn ensure_readable(&self) -> std::result::Result<(), ChunkError> {
+        if self.is_compressed() {
+            return Err(ChunkError::Compressed(format!(
+                "chunk {} must be decompressed before reading", self.start_time
+            )));
+        }
         Ok(())
     }
 
@@ -267,11 +816,71 @@ impl TimeChunk {
     
     pub fn mark_clean(&mut self) {
         self.dirty = false;
+        self.dirty_bytes.set(0);
+        self.range_cache.invalidate();
+    }
+
+    /// Count the chunk's size into `total` whenever it is dirty, from now on
+    pub fn track_dirty_bytes(&mut self, total: Arc<AtomicUsize>) {
+        self.dirty_bytes.set(0);
+        self.dirty_bytes.total = Some(total);
+        if self.dirty {
+            self.dirty_bytes.set(self.metadata.size_bytes);
+        }
+    }
+
+    /// Changes made to the chunk so far; a copy taken now carries the same number
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Mark the chunk clean if nothing changed since its copy at `generation` was saved
+    ///
+    /// Returns whether it was marked clean.
+    pub fn mark_clean_as_of(&mut self, generation: u64) -> bool {
+        let unchanged = self.generation == generation;
+        if unchanged {
+            self.mark_clean();
+        }
+        unchanged
     }
 
+    /// Flag the chunk for flushing and drop the memoized range, which may now be stale
+    fn mark_modified(&mut self) {
+        self.dirty = true;
+        self.generation += 1;
+        self.dirty_bytes.set(self.metadata.size_bytes);
+        self.range_cache.invalidate();
+    }
+
+    /// Reads `get_range` answered from its memo rather than by scanning
+    pub fn range_cache_hits(&self) -> u64 {
+        self.range_cache.hits.load(Ordering::Relaxed)
+    }
+
+    /// Uncompressed size over resident size
     pub fn calculate_compression_ratio(&self) -> f64 {
-        // Simple implementation for now
-        1.0
+        let resident = self.resident_size();
+        if resident == 0 {
+            return 1.0;
+        }
+        self.metadata.size_bytes as f64 / resident as f64
+    }
+
+    /// Recompute the resource type index from the records held
+    ///
+    /// Chunk files written before the index was persisted, or edited by hand,
+    /// may carry a stale or missing one.
+    pub fn rebuild_resource_metrics(&mut self) {
+        self.resource_metrics.clear();
+        for (metric, records) in &self.records {
+            for record in records {
+                self.resource_metrics
+                    .entry(record.resource_type.clone())
+                    .or_default()
+                    .insert(metric.clone());
+            }
+        }
     }
 
     // Get all metrics for a specific resource type
@@ -279,10 +888,172 @@ impl TimeChunk {
         self.resource_metrics
             .get(resource_type)
             .map(|metrics| metrics.iter().cloned().collect())
-            .unwrap_or_else(Vec::new)
+            .unwrap_or_default()
+    }
+}
+
+/// How a compressed series stores its timestamps
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum TimestampFormat {
+    /// Plain deltas in `timestamp_deltas`; series compressed before delta-of-delta existed
+    #[default]
+    Delta,
+    /// Delta-of-delta bitstream in `timestamp_bits`, see `gorilla`
+    DeltaOfDelta,
+}
+
+/// One metric's records in columnar form while their chunk is compressed
+///
+/// The metric name and resource type are stored once instead of per record,
+/// timestamps as delta-of-deltas, values as a Gorilla XOR bitstream, and the
+/// rarely-set string values and contexts sparsely.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CompressedSeries {
+    resource_type: String,
+    #[serde(default)]
+    timestamp_format: TimestampFormat,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    timestamp_deltas: Vec<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    timestamp_bits: Vec<u8>,
+    #[serde(default)]
+    count: usize, // Records in the series; only needed for the bitstream formats
+    values: Vec<u8>, // One value per timestamp, see `gorilla`
+    string_values: Vec<(usize, String)>,
+    contexts: Vec<(usize, HashMap<String, String>)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ids: Vec<(usize, String)>,
+}
+
+impl CompressedSeries {
+    /// Returns `None` if the records don't share a resource type
+    fn encode(records: &[Record]) -> Option<Self> {
+        let resource_type = records.first().map(|r| r.resource_type.clone()).unwrap_or_default();
+        if records.iter().any(|r| r.resource_type != resource_type) {
+            return None;
+        }
+        
+        let mut series = CompressedSeries {
+            resource_type,
+            timestamp_format: TimestampFormat::DeltaOfDelta,
+            timestamp_deltas: Vec::new(),
+            timestamp_bits: gorilla::encode_timestamps(&records.iter().map(|r| r.timestamp).collect::<Vec<_>>()),
+            count: records.len(),
+            values: gorilla::encode_values(&records.iter().map(|r| r.value).collect::<Vec<_>>()),
+            string_values: Vec::new(),
+            contexts: Vec::new(),
+            ids: Vec::new(),
+        };
+        
+        for (i, record) in records.iter().enumerate() {
+            if let Some(string_value) = &record.string_value {
+                series.string_values.push((i, string_value.clone()));
+            }
+            if !record.context.is_empty() {
+                series.contexts.push((i, record.context.clone()));
+            }
+            if let Some(id) = &record.id {
+                series.ids.push((i, id.clone()));
+            }
+        }
+        
+        Some(series)
+    }
+    
+    fn decode(self, metric_name: &str) -> std::result::Result<Vec<Record>, ChunkError> {
+        let values = gorilla::decode_values(&self.values, self.len())
+            .map_err(|e| ChunkError::DataCorrupted(format!("Compressed series {}: {}", metric_name, e)))?;
+        
+        let mut records: Vec<Record> = self.timestamps()?
+            .into_iter()
+            .zip(values)
+            .map(|(timestamp, value)| Record {
+                timestamp,
+                metric_name: metric_name.to_string(),
+                value,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: self.resource_type.clone(),
+                id: None,
+            })
+            .collect();
+        
+        for (i, string_value) in self.string_values {
+            let record = records.get_mut(i)
+                .ok_or_else(|| ChunkError::DataCorrupted(format!("String value index {} out of range", i)))?;
+            record.string_value = Some(string_value);
+        }
+        for (i, context) in self.contexts {
+            let record = records.get_mut(i)
+                .ok_or_else(|| ChunkError::DataCorrupted(format!("Context index {} out of range", i)))?;
+            record.context = context;
+        }
+        for (i, id) in self.ids {
+            let record = records.get_mut(i)
+                .ok_or_else(|| ChunkError::DataCorrupted(format!("Id index {} out of range", i)))?;
+            record.id = Some(id);
+        }
+        
+        Ok(records)
+    }
+    
+    fn len(&self) -> usize {
+        match self.timestamp_format {
+            TimestampFormat::Delta => self.timestamp_deltas.len(),
+            TimestampFormat::DeltaOfDelta => self.count,
+        }
+    }
+    
+    /// Absolute timestamps, undoing whichever encoding the series was written with
+    fn timestamps(&self) -> std::result::Result<Vec<i64>, ChunkError> {
+        match self.timestamp_format {
+            TimestampFormat::Delta => Ok(self.timestamp_deltas.iter()
+                .scan(0, |timestamp, delta| {
+                    *timestamp += delta;
+                    Some(*timestamp)
+                })
+                .collect()),
+            TimestampFormat::DeltaOfDelta => gorilla::decode_timestamps(&self.timestamp_bits, self.count)
+                .map_err(|e| ChunkError::DataCorrupted(format!("Compressed timestamps: {}", e))),
+        }
+    }
+    
+    /// Estimated bytes held, on the same basis as `record_size`
+    fn size(&self) -> usize {
+        let context_size: usize = self.contexts.iter()
+            .flat_map(|(_, context)| context.iter())
+            .map(|(k, v)| std::mem::size_of::<(String, String)>() + k.len() + v.len())
+            .sum();
+        
+        std::mem::size_of::<Self>()
+            + self.resource_type.len()
+            + self.timestamp_deltas.len() * std::mem::size_of::<i64>()
+            + self.timestamp_bits.len()
+            + self.values.len()
+            + self.string_values.iter().map(|(_, s)| std::mem::size_of::<(usize, String)>() + s.len()).sum::<usize>()
+            + self.contexts.len() * std::mem::size_of::<(usize, HashMap<String, String>)>()
+            + context_size
     }
 }
 
+/// Estimated bytes for one record: the struct itself plus everything it owns on the heap
+fn record_size(record: &Record) -> usize {
+    let context_size: usize = record.context.iter()
+        .map(|(k, v)| std::mem::size_of::<(String, String)>() + k.len() + v.len())
+        .sum();
+    
+    std::mem::size_of::<Record>()
+        + record.metric_name.len()
+        + record.resource_type.len()
+        + record.string_value.as_ref().map_or(0, |s| s.len())
+        + context_size
+}
+
+/// Estimated bytes for a metric's slot in the records index
+fn metric_entry_size(metric_name: &str) -> usize {
+    std::mem::size_of::<String>() + std::mem::size_of::<Vec<Record>>() + metric_name.len()
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChunkSummary {
     pub count: usize,
@@ -302,4 +1073,325 @@ impl From<serde_json::Error> for ChunkError {
     fn from(error: serde_json::Error) -> Self {
         ChunkError::DataCorrupted(format!("JSON error: {}", error))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capacity_hint_avoids_reallocation() {
+        let record = |i: i64| Record {
+            timestamp: i,
+            metric_name: "burst|8867-4|bpm".to_string(),
+            value: 70.0,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        // Number of times the metric's vector had to grow while appending 2000 records
+        let reallocations = |mut chunk: TimeChunk| {
+            let mut capacity = 0;
+            let mut grown = 0;
+            for i in 0..2000 {
+                chunk.append(record(i)).unwrap();
+                let now = chunk.records["burst|8867-4|bpm"].capacity();
+                grown += usize::from(now != capacity);
+                capacity = now;
+            }
+            grown - 1 // The first append allocates either way
+        };
+        
+        assert!(reallocations(TimeChunk::new(0, 3600)) >= 8);
+        assert_eq!(reallocations(TimeChunk::new(0, 3600).with_capacity_hint(2000)), 0);
+        
+        // A chunk follows the one before it: 2000 records over 4 metrics
+        let mut previous = TimeChunk::new(0, 3600);
+        for i in 0..2000 {
+            let mut record = record(i);
+            record.metric_name = format!("burst|{}|bpm", i % 4);
+            previous.append(record).unwrap();
+        }
+        assert_eq!(previous.records_per_metric(), 500);
+    }
+
+    #[test]
+    fn test_size_estimate_tracks_serialized_size() {
+        let mut chunk = TimeChunk::new(0, 3600);
+        
+        // SampledData-style records carry most of their weight in context
+        for i in 0..50 {
+            let mut context = HashMap::new();
+            context.insert("origin".to_string(), "0".to_string());
+            context.insert("period".to_string(), "10".to_string());
+            context.insert("data".to_string(), "1.25 ".repeat(100));
+            context.insert("device_id".to_string(), format!("monitor-{}", i));
+            
+            chunk.append(Record {
+                timestamp: i,
+                metric_name: "patient-123|131328|sampled".to_string(),
+                value: i as f64,
+                string_value: None,
+                context,
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).unwrap();
+        }
+        
+        let serialized: usize = chunk.records.values()
+            .flatten()
+            .map(|r| serde_json::to_vec(r).unwrap().len())
+            .sum();
+        let estimate = chunk.get_size();
+        
+        // The estimate counts heap contents, so it should be in the same ballpark as the JSON
+        assert!(estimate >= serialized / 2 && estimate <= serialized * 2,
+                "estimate {} too far from serialized size {}", estimate, serialized);
+        
+        // Incremental tracking on append agrees with a full recount
+        assert_eq!(chunk.metadata.size_bytes, estimate);
+    }
+
+    #[test]
+    fn test_compress_round_trip_is_lossless() {
+        let mut chunk = TimeChunk::new(0, 3600);
+        for i in 0..100 {
+            let mut context = HashMap::new();
+            if i % 10 == 0 {
+                context.insert("device_id".to_string(), "monitor-1".to_string());
+            }
+            chunk.append(Record {
+                timestamp: i * 30,
+                metric_name: "patient-123|8867-4|bpm".to_string(),
+                value: 60.0 + (i % 7) as f64 * 0.5,
+                string_value: (i == 42).then(|| "irregular".to_string()),
+                context,
+                resource_type: "Observation".to_string(),
+                id: (i % 3 == 0).then(|| format!("obs-{}", i)),
+            }).unwrap();
+        }
+        chunk.mark_clean();
+        let original = chunk.records.clone();
+        
+        chunk.compress().unwrap();
+        assert!(chunk.is_compressed());
+        assert!(!chunk.is_dirty());
+        assert!(chunk.resident_size() < chunk.metadata.size_bytes);
+        assert!(chunk.metadata.compression_ratio > 1.0);
+        assert!(chunk.get_range(0, 3600, "patient-123|8867-4|bpm").is_err());
+        assert_eq!(chunk.latest_timestamp(), Some(99 * 30));
+        
+        chunk.decompress().unwrap();
+        assert!(!chunk.is_compressed());
+        
+        let restored = &chunk.records["patient-123|8867-4|bpm"];
+        let expected = &original["patient-123|8867-4|bpm"];
+        assert_eq!(restored.len(), expected.len());
+        for (a, b) in restored.iter().zip(expected) {
+            assert_eq!(a.timestamp, b.timestamp);
+            assert_eq!(a.value, b.value);
+            assert_eq!(a.string_value, b.string_value);
+            assert_eq!(a.context, b.context);
+            assert_eq!(a.resource_type, b.resource_type);
+        }
+    }
+
+    #[test]
+    fn test_compressed_values_are_gorilla_encoded() {
+        let mut chunk = TimeChunk::new(0, 3600);
+        // Heart rate once a second, drifting slowly around 72 bpm
+        for i in 0..1800 {
+            chunk.append(Record {
+                timestamp: i * 2,
+                metric_name: "patient-123|8867-4|bpm".to_string(),
+                value: 72.0 + ((i / 30) % 6) as f64,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).unwrap();
+        }
+        
+        chunk.compress().unwrap();
+        let series = &chunk.compressed["patient-123|8867-4|bpm"];
+        let raw_values = 1800 * std::mem::size_of::<f64>();
+        assert!(series.values.len() * 8 < raw_values, "{} value bytes from {}", series.values.len(), raw_values);
+        assert_eq!(chunk.metadata.compression_ratio, chunk.calculate_compression_ratio());
+        
+        chunk.decompress().unwrap();
+        let values: Vec<f64> = chunk.records["patient-123|8867-4|bpm"].iter().map(|r| r.value).collect();
+        assert_eq!(values, (0..1800).map(|i| 72.0 + ((i / 30) % 6) as f64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_compress_uses_delta_of_delta_timestamps() {
+        let make_chunk = |count: i64| {
+            let mut chunk = TimeChunk::new(0, 3600);
+            for i in 0..count {
+                chunk.append(Record {
+                    timestamp: i,
+                    metric_name: "patient-123|8867-4|bpm".to_string(),
+                    value: 72.0,
+                    string_value: None,
+                    context: HashMap::new(),
+                    resource_type: "Observation".to_string(),
+                    id: None,
+                }).unwrap();
+            }
+            chunk.compress().unwrap();
+            chunk
+        };
+        
+        // Regular sampling costs about the same however many records there are
+        let small = make_chunk(60);
+        let large = make_chunk(3600);
+        let series = &large.compressed["patient-123|8867-4|bpm"];
+        assert_eq!(series.timestamp_format, TimestampFormat::DeltaOfDelta);
+        assert!(series.timestamp_deltas.is_empty());
+        assert!(series.timestamp_bits.len() <= small.compressed["patient-123|8867-4|bpm"].timestamp_bits.len() + 2);
+        assert_eq!(large.latest_timestamp(), Some(3599));
+        
+        // Series written with the older delta format still decode
+        let legacy: CompressedSeries = serde_json::from_value(serde_json::json!({
+            "resource_type": "Observation",
+            "timestamp_deltas": [100, 30, 30],
+            "values": gorilla::encode_values(&[1.0, 2.0, 3.0]),
+            "string_values": [],
+            "contexts": [],
+        })).unwrap();
+        assert_eq!(legacy.timestamp_format, TimestampFormat::Delta);
+        let records = legacy.decode("legacy|0000-0|x").unwrap();
+        assert_eq!(records.iter().map(|r| r.timestamp).collect::<Vec<_>>(), vec![100, 130, 160]);
+        assert_eq!(records.iter().map(|r| r.value).collect::<Vec<_>>(), vec![1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn test_get_latest_ignores_insertion_order() {
+        let mut chunk = TimeChunk::new(0, 3600);
+        for (timestamp, value) in [(100, 1.0), (50, 2.0), (75, 3.0)] {
+            chunk.append(Record {
+                timestamp,
+                metric_name: "p1|8867-4|bpm".to_string(),
+                value,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).unwrap();
+        }
+        
+        let latest = chunk.get_latest("p1|8867-4|bpm").unwrap().unwrap();
+        assert_eq!(latest.timestamp, 100);
+        assert_eq!(latest.value, 1.0);
+        assert!(chunk.get_latest("p1|missing|x").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_repeat_range_read_is_memoized() {
+        let record = |timestamp: i64| Record {
+            timestamp,
+            metric_name: "p1|8867-4|bpm".to_string(),
+            value: 70.0,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        let metric = "p1|8867-4|bpm";
+        let mut chunk = TimeChunk::new(0, 3600);
+        for timestamp in [10, 20, 30] {
+            chunk.append(record(timestamp)).unwrap();
+        }
+
+        assert_eq!(chunk.get_range(0, 25, metric).unwrap().len(), 2);
+        assert_eq!(chunk.range_cache_hits(), 0);
+        let again: Vec<i64> = chunk.get_range(0, 25, metric).unwrap().iter().map(|r| r.timestamp).collect();
+        assert_eq!(again, vec![10, 20]);
+        assert_eq!(chunk.range_cache_hits(), 1);
+
+        // An append may land inside the memoized window
+        chunk.append(record(15)).unwrap();
+        assert_eq!(chunk.get_range(0, 25, metric).unwrap().len(), 3);
+        assert_eq!(chunk.range_cache_hits(), 1);
+
+        chunk.mark_clean();
+        assert_eq!(chunk.get_range(0, 25, metric).unwrap().len(), 3);
+        assert_eq!(chunk.range_cache_hits(), 1);
+
+        // A reader finding the memo in use scans instead of waiting for it
+        let held = chunk.range_cache.last.lock().unwrap();
+        assert_eq!(chunk.get_range(0, 25, metric).unwrap().len(), 3);
+        assert_eq!(chunk.range_cache_hits(), 1);
+        drop(held);
+        assert_eq!(chunk.get_range(0, 25, metric).unwrap().len(), 3);
+        assert_eq!(chunk.range_cache_hits(), 2);
+    }
+
+    #[test]
+    fn test_only_real_changes_mark_chunk_dirty() {
+        let record = |timestamp: i64, value: f64| Record {
+            timestamp,
+            metric_name: "p1|8867-4|bpm".to_string(),
+            value,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        let mut chunk = TimeChunk::new(0, 3600);
+        chunk.append(record(60, 70.0)).unwrap();
+        chunk.append(record(120, 72.0)).unwrap();
+        chunk.refresh_rollups(Duration::from_secs(60)).unwrap();
+        chunk.mark_clean();
+        
+        // Rewrites that leave the records as they were
+        assert!(chunk.update_value("p1|8867-4|bpm", 60, 70.0));
+        assert!(chunk.upsert(record(120, 72.0)).unwrap());
+        chunk.refresh_rollups(Duration::from_secs(60)).unwrap();
+        chunk.compress().unwrap();
+        chunk.compress().unwrap();
+        chunk.decompress().unwrap();
+        assert!(chunk.get_latest("p1|8867-4|bpm").unwrap().is_some());
+        assert_eq!(chunk.retain_records(|_| true).unwrap(), 0);
+        assert!(!chunk.is_dirty());
+        
+        assert!(chunk.update_value("p1|8867-4|bpm", 60, 71.0));
+        assert!(chunk.is_dirty());
+        chunk.mark_clean();
+        chunk.refresh_rollups(Duration::from_secs(60)).unwrap();
+        assert!(chunk.is_dirty(), "the rollup of the updated minute changed");
+        chunk.mark_clean();
+        assert!(chunk.upsert(record(120, 73.0)).unwrap());
+        assert!(chunk.is_dirty());
+    }
+
+    #[test]
+    fn test_split_cuts_at_duration_boundaries() {
+        let mut chunk = TimeChunk::new(600, 6000);
+        for timestamp in [4000, 700, 1900, 1850] {
+            chunk.append(Record {
+                timestamp,
+                metric_name: "p1|8867-4|bpm".to_string(),
+                value: timestamp as f64,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).unwrap();
+        }
+        
+        // The unaligned first piece is kept, the empty aligned one is not
+        let pieces = chunk.split(1800).unwrap();
+        let spans: Vec<(i64, i64, Vec<i64>)> = pieces.iter()
+            .map(|piece| {
+                let timestamps = piece.records["p1|8867-4|bpm"].iter().map(|r| r.timestamp).collect();
+                (piece.start_time, piece.end_time, timestamps)
+            })
+            .collect();
+        assert_eq!(spans, vec![(600, 1800, vec![700]), (1800, 3600, vec![1900, 1850]), (3600, 5400, vec![4000])]);
+        assert!(pieces.iter().all(|piece| piece.is_dirty() && !piece.metadata.compacted));
+        
+        assert_eq!(TimeChunk::new(0, 3600).split(600).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,374 @@
+//! Gorilla-style compression for `f64` values and `i64` timestamps
+//!
+//! Each value is XORed with its predecessor. Slowly changing vitals share most
+//! of their bits with the previous reading, so the XOR is mostly zeros and only
+//! the "meaningful" middle bits need to be written (Pelkonen et al., VLDB 2015).
+//!
+//! Value layout after the first value (stored as 64 raw bits):
+//! - `0`: same value as the previous one
+//! - `10` + meaningful bits: XOR fits inside the previous leading/trailing zero window
+//! - `11` + 5 bits leading zeros + 6 bits length + meaningful bits: new window
+//!
+//! Timestamps store the first timestamp and first delta as 64 raw bits each,
+//! then the delta-of-delta of every following timestamp:
+//! - `0` + Elias gamma run length: a run of zero delta-of-deltas
+//! - `10` + 7 bits, `110` + 9 bits, `1110` + 12 bits: small signed delta-of-delta
+//! - `1111` + 64 bits: anything else
+//!
+//! Regularly sampled series are one long zero run, so their size barely grows
+//! with the number of records.
+
+/// Appends bits most-significant first into a byte buffer
+struct BitWriter {
+    bytes: Vec<u8>,
+    bit_len: usize,
+}
+
+impl BitWriter {
+    fn with_capacity(values: usize) -> Self {
+        BitWriter { bytes: Vec::with_capacity(values * 2), bit_len: 0 }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.bit_len.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if bit {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= 0x80 >> (self.bit_len % 8);
+        }
+        self.bit_len += 1;
+    }
+
+    /// Write the low `count` bits of `value`
+    fn write_bits(&mut self, value: u64, count: u32) {
+        for shift in (0..count).rev() {
+            self.write_bit((value >> shift) & 1 == 1);
+        }
+    }
+}
+
+/// Reads bits back in the order `BitWriter` wrote them
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<bool, String> {
+        let byte = self.bytes.get(self.position / 8)
+            .ok_or_else(|| format!("Bitstream ended after {} bits", self.position))?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, count: u32) -> Result<u64, String> {
+        let mut value = 0u64;
+        for _ in 0..count {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Ok(value)
+    }
+}
+
+/// Compress values into a Gorilla bitstream
+pub(super) fn encode_values(values: &[f64]) -> Vec<u8> {
+    let mut writer = BitWriter::with_capacity(values.len());
+    let Some((first, rest)) = values.split_first() else {
+        return writer.bytes;
+    };
+
+    let mut previous = first.to_bits();
+    writer.write_bits(previous, 64);
+
+    // Leading/trailing zero counts of the last window written; none yet
+    let mut window: Option<(u32, u32)> = None;
+    for value in rest {
+        let bits = value.to_bits();
+        let xor = bits ^ previous;
+        previous = bits;
+
+        if xor == 0 {
+            writer.write_bit(false);
+            continue;
+        }
+        writer.write_bit(true);
+
+        // Leading zeros are stored in 5 bits
+        let leading = xor.leading_zeros().min(31);
+        let trailing = xor.trailing_zeros();
+
+        match window {
+            Some((window_leading, window_trailing)) if leading >= window_leading && trailing >= window_trailing => {
+                writer.write_bit(false);
+                writer.write_bits(xor >> window_trailing, 64 - window_leading - window_trailing);
+            },
+            _ => {
+                let meaningful = 64 - leading - trailing;
+                writer.write_bit(true);
+                writer.write_bits(leading as u64, 5);
+                // A 64-bit window doesn't fit in 6 bits and is stored as 0
+                writer.write_bits((meaningful % 64) as u64, 6);
+                writer.write_bits(xor >> trailing, meaningful);
+                window = Some((leading, trailing));
+            }
+        }
+    }
+
+    writer.bytes
+}
+
+/// Decompress `count` values from a bitstream produced by `encode_values`
+pub(super) fn decode_values(bytes: &[u8], count: usize) -> Result<Vec<f64>, String> {
+    let mut values = Vec::with_capacity(count);
+    if count == 0 {
+        return Ok(values);
+    }
+
+    let mut reader = BitReader::new(bytes);
+    let mut previous = reader.read_bits(64)?;
+    values.push(f64::from_bits(previous));
+
+    let mut window: Option<(u32, u32)> = None;
+    while values.len() < count {
+        if reader.read_bit()? {
+            let (leading, trailing) = if reader.read_bit()? {
+                let leading = reader.read_bits(5)? as u32;
+                let meaningful = match reader.read_bits(6)? as u32 {
+                    0 => 64,
+                    n => n,
+                };
+                if leading + meaningful > 64 {
+                    return Err(format!("Invalid XOR window of {} bits after {} leading zeros", meaningful, leading));
+                }
+                (leading, 64 - leading - meaningful)
+            } else {
+                window.ok_or("XOR window reused before one was set")?
+            };
+            window = Some((leading, trailing));
+
+            previous ^= reader.read_bits(64 - leading - trailing)? << trailing;
+        }
+        values.push(f64::from_bits(previous));
+    }
+
+    Ok(values)
+}
+
+/// Signed delta-of-delta buckets: (control bits, control length, payload bits)
+const DOD_BUCKETS: [(u64, u32, u32); 3] = [(0b10, 2, 7), (0b110, 3, 9), (0b1110, 4, 12)];
+
+/// Compress timestamps with delta-of-delta encoding
+///
+/// Arithmetic wraps, so any sequence round-trips, sorted or not.
+pub(super) fn encode_timestamps(timestamps: &[i64]) -> Vec<u8> {
+    let mut writer = BitWriter::with_capacity(timestamps.len() / 8);
+    let Some((&first, rest)) = timestamps.split_first() else {
+        return writer.bytes;
+    };
+    writer.write_bits(first as u64, 64);
+
+    let mut previous = first;
+    let mut previous_delta: Option<i64> = None;
+    let mut zero_run = 0u64;
+    for &timestamp in rest {
+        let delta = timestamp.wrapping_sub(previous);
+        previous = timestamp;
+        let Some(last_delta) = previous_delta.replace(delta) else {
+            writer.write_bits(delta as u64, 64);
+            continue;
+        };
+
+        let dod = delta.wrapping_sub(last_delta);
+        if dod == 0 {
+            zero_run += 1;
+            continue;
+        }
+        write_zero_run(&mut writer, &mut zero_run);
+
+        match DOD_BUCKETS.iter().find(|&&(_, _, bits)| fits_signed(dod, bits)) {
+            Some(&(control, control_len, bits)) => {
+                writer.write_bits(control, control_len);
+                writer.write_bits(dod as u64 & ((1 << bits) - 1), bits);
+            },
+            None => {
+                writer.write_bits(0b1111, 4);
+                writer.write_bits(dod as u64, 64);
+            }
+        }
+    }
+    write_zero_run(&mut writer, &mut zero_run);
+
+    writer.bytes
+}
+
+/// Decompress `count` timestamps from a bitstream produced by `encode_timestamps`
+pub(super) fn decode_timestamps(bytes: &[u8], count: usize) -> Result<Vec<i64>, String> {
+    let mut timestamps = Vec::with_capacity(count);
+    if count == 0 {
+        return Ok(timestamps);
+    }
+
+    let mut reader = BitReader::new(bytes);
+    let mut previous = reader.read_bits(64)? as i64;
+    timestamps.push(previous);
+    if count == 1 {
+        return Ok(timestamps);
+    }
+    let mut delta = reader.read_bits(64)? as i64;
+    previous = previous.wrapping_add(delta);
+    timestamps.push(previous);
+
+    while timestamps.len() < count {
+        let mut ones = 0;
+        while ones < 4 && reader.read_bit()? {
+            ones += 1;
+        }
+
+        let (run, dod) = match ones {
+            0 => (read_gamma(&mut reader)?, 0),
+            4 => (1, reader.read_bits(64)? as i64),
+            n => {
+                let bits = DOD_BUCKETS[n - 1].2;
+                (1, sign_extend(reader.read_bits(bits)?, bits))
+            }
+        };
+        if run > (count - timestamps.len()) as u64 {
+            return Err(format!("Zero run of {} overruns {} timestamps", run, count));
+        }
+
+        for _ in 0..run {
+            delta = delta.wrapping_add(dod);
+            previous = previous.wrapping_add(delta);
+            timestamps.push(previous);
+        }
+    }
+
+    Ok(timestamps)
+}
+
+fn write_zero_run(writer: &mut BitWriter, run: &mut u64) {
+    if *run == 0 {
+        return;
+    }
+    writer.write_bit(false);
+    // Elias gamma: one zero per bit after the leading one, then the number itself
+    let bits = 64 - run.leading_zeros();
+    writer.write_bits(0, bits - 1);
+    writer.write_bits(*run, bits);
+    *run = 0;
+}
+
+fn read_gamma(reader: &mut BitReader) -> Result<u64, String> {
+    let mut zeros = 0;
+    while !reader.read_bit()? {
+        zeros += 1;
+        if zeros > 63 {
+            return Err("Run length longer than 64 bits".to_string());
+        }
+    }
+    Ok((1 << zeros) | reader.read_bits(zeros)?)
+}
+
+fn fits_signed(value: i64, bits: u32) -> bool {
+    let half = 1i64 << (bits - 1);
+    (-half..half).contains(&value)
+}
+
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A minute of heart rate and SpO2-like readings at 1 Hz
+    fn vital_signs() -> Vec<f64> {
+        (0..600)
+            .map(|i| match i % 4 {
+                0 | 1 => 72.0 + ((i / 7) % 5) as f64,
+                2 => 97.5,
+                _ => 98.0 + ((i / 13) % 2) as f64,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip_is_bit_exact() {
+        let mut values = vital_signs();
+        values.extend([0.0, -0.0, 36.6, 36.65, f64::MIN_POSITIVE, f64::MAX, f64::INFINITY, f64::NAN, 1e-300]);
+
+        let decoded = decode_values(&encode_values(&values), values.len()).unwrap();
+
+        let original: Vec<u64> = values.iter().map(|v| v.to_bits()).collect();
+        let restored: Vec<u64> = decoded.iter().map(|v| v.to_bits()).collect();
+        assert_eq!(original, restored);
+    }
+
+    #[test]
+    fn test_vitals_compress_well() {
+        let values = vital_signs();
+        let raw = values.len() * std::mem::size_of::<f64>();
+        let compressed = encode_values(&values).len();
+
+        assert!(compressed * 4 < raw, "compressed {} bytes from {} raw", compressed, raw);
+    }
+
+    #[test]
+    fn test_edge_cases() {
+        assert!(encode_values(&[]).is_empty());
+        assert!(decode_values(&[], 0).unwrap().is_empty());
+        assert_eq!(decode_values(&encode_values(&[42.0]), 1).unwrap(), vec![42.0]);
+
+        // A constant series costs one bit per repeat
+        assert_eq!(encode_values(&[5.0; 81]).len(), 8 + 10);
+
+        // Asking for more values than were written fails instead of inventing data
+        assert!(decode_values(&encode_values(&[1.0, 2.0]), 50).is_err());
+    }
+
+    #[test]
+    fn test_regular_timestamps_encode_in_near_constant_size() {
+        // One sample per second, in milliseconds
+        let regular = |count: i64| (0..count).map(|i| 1_700_000_000_000 + i * 1000).collect::<Vec<_>>();
+
+        let small = encode_timestamps(&regular(100));
+        let large = encode_timestamps(&regular(100_000));
+        assert!(large.len() <= small.len() + 4, "{} bytes vs {} bytes", large.len(), small.len());
+        assert!(large.len() < 24);
+
+        for count in [100, 100_000] {
+            let timestamps = regular(count);
+            let encoded = encode_timestamps(&timestamps);
+            assert_eq!(decode_timestamps(&encoded, timestamps.len()).unwrap(), timestamps);
+        }
+    }
+
+    #[test]
+    fn test_irregular_timestamps_round_trip() {
+        let mut timestamps = vec![0, 30, 60, 95, 90, 400, 402, 5000, 5001, 5002, 5003, 1_000_000, -7];
+        timestamps.extend([i64::MAX, i64::MIN, 0]);
+        for count in 0..=timestamps.len() {
+            let slice = &timestamps[..count];
+            assert_eq!(decode_timestamps(&encode_timestamps(slice), count).unwrap(), slice);
+        }
+
+        // Every delta-of-delta bucket, both signs, at its edges
+        let mut timestamps = vec![0i64, 0];
+        for dod in [63, -64, 64, 255, -256, 256, 2047, -2048, 2048, -2049, 1 << 40] {
+            let delta = timestamps[timestamps.len() - 1] - timestamps[timestamps.len() - 2];
+            timestamps.push(timestamps[timestamps.len() - 1] + delta + dod);
+        }
+        assert_eq!(decode_timestamps(&encode_timestamps(&timestamps), timestamps.len()).unwrap(), timestamps);
+
+        // Asking for more timestamps than were written fails instead of inventing data
+        assert!(decode_timestamps(&encode_timestamps(&[1, 2, 3]), 10).is_err());
+    }
+}
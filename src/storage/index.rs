@@ -0,0 +1,116 @@
+//! Metric and resource type lookups that avoid scanning every chunk
+//!
+//! `StorageEngine` updates the index while holding the chunk write lock, whenever
+//! a chunk gains a metric or is deleted, so each entry names exactly the chunks
+//! holding that metric or resource type. Chunks evicted to disk stay indexed, so
+//! lookups know to reload them.
+//!
+//! The index also keeps each chunk's time span, so range queries find the
+//! chunks they overlap even when some were created under a different
+//! `chunk_duration` than the one currently configured.
+
+use std::collections::{BTreeMap, HashMap};
+use super::chunk::TimeChunk;
+
+#[derive(Debug, Default)]
+pub(super) struct ChunkIndex {
+    metric_chunks: HashMap<String, Vec<i64>>,   // Metric -> sorted chunk ids
+    resource_chunks: HashMap<String, Vec<i64>>, // Resource type -> sorted chunk ids
+    spans: BTreeMap<i64, i64>, // Chunk id (its start time) -> end time
+    longest_span: i64,
+}
+
+impl ChunkIndex {
+    /// Note that `chunk` holds a record of this metric and resource type
+    pub fn insert(&mut self, metric: &str, resource_type: &str, chunk_id: i64, chunk: &TimeChunk) {
+        insert_id(&mut self.metric_chunks, metric, chunk_id);
+        insert_id(&mut self.resource_chunks, resource_type, chunk_id);
+        self.insert_span(chunk_id, chunk);
+    }
+
+    /// Index everything a chunk holds, e.g. after loading it or a batch insert
+    pub fn insert_chunk(&mut self, chunk_id: i64, chunk: &TimeChunk) {
+        for metric in chunk.records.keys().chain(chunk.compressed.keys()) {
+            insert_id(&mut self.metric_chunks, metric, chunk_id);
+        }
+        for resource_type in chunk.resource_metrics.keys() {
+            insert_id(&mut self.resource_chunks, resource_type, chunk_id);
+        }
+        self.insert_span(chunk_id, chunk);
+    }
+
+    fn insert_span(&mut self, chunk_id: i64, chunk: &TimeChunk) {
+        self.spans.insert(chunk_id, chunk.end_time);
+        self.longest_span = self.longest_span.max(chunk.end_time - chunk.start_time);
+    }
+
+    /// Point the chunks holding `old` at `new` instead, forgetting rollups of `old`
+    pub fn rename_metric(&mut self, old: &str, new: &str) {
+        for chunk_id in self.metric_chunks.remove(old).unwrap_or_default() {
+            insert_id(&mut self.metric_chunks, new, chunk_id);
+        }
+        let stale_prefix = format!("{}|rollup|", old);
+        self.metric_chunks.retain(|metric, _| !metric.starts_with(&stale_prefix));
+    }
+
+    /// Forget a deleted chunk
+    pub fn remove_chunk(&mut self, chunk_id: i64) {
+        for entries in [&mut self.metric_chunks, &mut self.resource_chunks] {
+            entries.retain(|_, ids| {
+                if let Ok(position) = ids.binary_search(&chunk_id) {
+                    ids.remove(position);
+                }
+                !ids.is_empty()
+            });
+        }
+        self.spans.remove(&chunk_id);
+    }
+
+    /// Chunks holding the metric, oldest first
+    pub fn chunks_for_metric(&self, metric: &str) -> &[i64] {
+        self.metric_chunks.get(metric).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Chunks holding records of the resource type, oldest first
+    pub fn chunks_for_resource_type(&self, resource_type: &str) -> &[i64] {
+        self.resource_chunks.get(resource_type).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Chunks whose span overlaps `[start, end)`, oldest first
+    pub fn chunks_overlapping(&self, start: i64, end: i64) -> Vec<i64> {
+        // No chunk starting before `start - longest_span` can reach `start`
+        self.spans.range(start.saturating_sub(self.longest_span)..end)
+            .filter(|(_, &chunk_end)| chunk_end > start)
+            .map(|(&chunk_id, _)| chunk_id)
+            .collect()
+    }
+
+    /// The chunk whose span contains `timestamp`, if any
+    pub fn chunk_containing(&self, timestamp: i64) -> Option<i64> {
+        self.spans.range(timestamp.saturating_sub(self.longest_span)..=timestamp)
+            .rev()
+            .find(|(_, &chunk_end)| chunk_end > timestamp)
+            .map(|(&chunk_id, _)| chunk_id)
+    }
+
+    /// Every chunk's id and end time, oldest first
+    pub fn spans(&self) -> Vec<(i64, i64)> {
+        self.spans.iter().map(|(&chunk_id, &end_time)| (chunk_id, end_time)).collect()
+    }
+
+    /// Every metric held by at least one chunk
+    pub fn metrics(&self) -> impl Iterator<Item = &String> {
+        self.metric_chunks.keys()
+    }
+}
+
+fn insert_id(entries: &mut HashMap<String, Vec<i64>>, key: &str, chunk_id: i64) {
+    // Only allocate the key the first time it is seen
+    if !entries.contains_key(key) {
+        entries.insert(key.to_string(), Vec::new());
+    }
+    let ids = entries.get_mut(key).expect("key was just inserted");
+    if let Err(position) = ids.binary_search(&chunk_id) {
+        ids.insert(position, chunk_id);
+    }
+}
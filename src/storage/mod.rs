@@ -6,27 +6,35 @@
 //! - Hot/warm/cold data management
 
 mod chunk;
-pub use chunk::{TimeChunk, ChunkError};
+pub use chunk::{TimeChunk, ChunkError, ChunkSummary, MAX_CHUNK_RECORDS};
 mod persistence;
-use persistence::PersistenceManager;
+mod gorilla;
+mod index;
+use persistence::{PersistenceManager, WalEntry};
+use index::ChunkIndex;
 
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
-use std::sync::{RwLock, Arc, Mutex};
-use std::time::Duration;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::sync::{RwLock, RwLockReadGuard, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
 use crate::config::Config;
 use std::fmt;
 use crate::timeseries::query::DebugMetricsInfo;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use tokio::sync::{broadcast, Notify};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Record {
     pub timestamp: i64,      // When the measurement was taken
     pub metric_name: String, // Identifier for the measurement type
     pub value: f64,          // The numeric value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub string_value: Option<String>, // Non-numeric value (valueString, valueCodeableConcept, ...)
     pub context: HashMap<String, String>, // Additional context (device_id, etc.)
     pub resource_type: String, // FHIR resource type (Observation, DeviceMetric, etc.)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>, // Id of the FHIR resource the record belongs to, shared by its records
 }
 
 #[derive(Debug)]
@@ -35,6 +43,9 @@ pub enum StorageError {
     ChunkError(ChunkError),
     InvalidTimeRange(String),
     PersistenceError(String),
+    Timeout(String),
+    Backpressure(String), // Too much unflushed data; retry once a flush catches up
+    InvalidSnapshotDestination(String),
 }
 
 impl fmt::Display for StorageError {
@@ -44,6 +55,9 @@ impl fmt::Display for StorageError {
             StorageError::ChunkError(err) => write!(f, "Chunk error: {:?}", err),
             StorageError::InvalidTimeRange(msg) => write!(f, "Invalid time range: {}", msg),
             StorageError::PersistenceError(msg) => write!(f, "Persistence error: {}", msg),
+            StorageError::Timeout(msg) => write!(f, "Timed out: {}", msg),
+            StorageError::Backpressure(msg) => write!(f, "Backpressure: {}", msg),
+            StorageError::InvalidSnapshotDestination(msg) => write!(f, "Invalid snapshot destination: {}", msg),
         }
     }
 }
@@ -69,14 +83,192 @@ impl std::error::Error for StorageError {
     }
 }
 
+/// Result of checking every persisted chunk with `StorageEngine::verify_integrity`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct IntegrityReport {
+    pub chunks_checked: usize,
+    pub failures: Vec<ChunkIntegrityFailure>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkIntegrityFailure {
+    pub chunk_id: i64,
+    pub error: String,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Outcome of `StorageEngine::insert_batch_partial`
+#[derive(Debug, Default)]
+pub struct BatchResult {
+    pub inserted: usize,
+    pub failed: Vec<(usize, ChunkError)>, // Position in the batch and why the chunk rejected it
+}
+
+/// Outcome of `StorageEngine::flush_all`
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct FlushReport {
+    pub chunks_flushed: usize,
+    pub wal_truncated: bool,
+}
+
+/// Outcome of `StorageEngine::compact`
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct CompactionReport {
+    pub chunks_before: usize,
+    pub chunks_after: usize,
+    pub records_moved: usize, // Records now held by a different chunk than before
+}
+
+/// One resident chunk as listed by `StorageEngine::chunk_infos`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkInfo {
+    pub id: i64,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub record_count: usize,
+    pub dirty: bool,
+    pub compressed: bool,
+}
+
+/// Records held for one metric, as listed by `StorageEngine::metric_counts`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetricCount {
+    pub metric: String,
+    pub record_count: usize,
+    pub earliest: i64,
+    pub latest: i64,
+}
+
+/// Point-in-time copy of the ingest counters, see `StorageEngine::stats`
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct IngestStats {
+    pub records_inserted: u64,
+    pub wal_bytes_written: u64,
+    pub chunks_persisted: u64,
+    pub unflushed_bytes: u64,             // Held by dirty resident chunks
+    pub max_unflushed_bytes: Option<u64>, // Writes are refused above this
+    pub backpressure: bool,               // Whether writes are currently refused
+}
+
+/// Lock-free counters bumped on the ingest and persist paths
+#[derive(Debug, Default)]
+struct IngestCounters {
+    records_inserted: AtomicU64,
+    wal_bytes_written: AtomicU64,
+    chunks_persisted: AtomicU64,
+}
+
+impl IngestCounters {
+    fn snapshot(&self) -> IngestStats {
+        IngestStats {
+            records_inserted: self.records_inserted.load(Ordering::Relaxed),
+            wal_bytes_written: self.wal_bytes_written.load(Ordering::Relaxed),
+            chunks_persisted: self.chunks_persisted.load(Ordering::Relaxed),
+            ..IngestStats::default()
+        }
+    }
+}
+
+/// Resource type of the summary records written by rollups
+pub const ROLLUP_RESOURCE_TYPE: &str = "Rollup";
+
+/// Name rollups of `metric` at `resolution` are stored under, e.g. `{metric}|rollup|1m`
+pub fn rollup_metric_name(metric: &str, resolution: Duration) -> String {
+    let secs = resolution.as_secs();
+    let label = if secs.is_multiple_of(3600) {
+        format!("{}h", secs / 3600)
+    } else if secs.is_multiple_of(60) {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    };
+    format!("{}|rollup|{}", metric, label)
+}
+
+/// Whether a metric holds derived rollups rather than raw records
+pub fn is_rollup_metric(metric: &str) -> bool {
+    metric.contains("|rollup|")
+}
+
+/// The name a metric written at WAL position `position` ends up under after later renames
+///
+/// `renames` holds the position, source and target of every rename in the log,
+/// in order. `None` if no later rename moves the metric.
+fn final_metric_name(renames: &[(usize, String, String)], position: usize, metric: &str) -> Option<String> {
+    let mut name = metric;
+    for (_, from, to) in renames.iter().filter(|(at, _, _)| *at > position) {
+        if from == name {
+            name = to;
+        }
+    }
+    (name != metric).then(|| name.to_string())
+}
+
+/// Records buffered per live subscriber before the slowest ones start missing updates
+const UPDATE_CHANNEL_CAPACITY: usize = 1024;
+
+/// Most chunk files `flush_all` writes at once
+const FLUSH_WORKERS: usize = 8;
+
 #[derive(Debug)]
 pub struct StorageEngine {
     chunks: RwLock<HashMap<i64, TimeChunk>>,
+    index: RwLock<ChunkIndex>, // Written only while holding the `chunks` write lock
+    evicted: Mutex<HashSet<i64>>, // Chunks flushed and dropped from memory, reloaded on demand
+    pinned: Mutex<HashMap<i64, usize>>, // Chunks a running scan needs resident -> number of scans
+    write_gate: RwLock<()>, // Shared from a WAL append until memory holds it; exclusive while a flush snapshots
+    max_resident_chunks: Option<usize>,
+    rollup_resolution: Option<Duration>, // Rollups are computed on flush when set
+    metric_capacity_hint: Option<usize>, // Records reserved per metric in new chunks; estimated if unset
+    max_unflushed_bytes: Option<usize>, // Writes fail with `Backpressure` above this
+    dirty_bytes: Arc<AtomicUsize>, // Size of dirty resident chunks, kept by the chunks themselves
+    flush_wanted: Notify, // Signalled when backpressure refuses a write
     chunk_duration: Duration,
-    persistence: Arc<PersistenceManager>,
+    persistence: Option<Arc<PersistenceManager>>, // None for in-memory engines
     persistence_enabled: AtomicBool,
     active_records: Mutex<HashMap<String, i64>>, // metric_name -> latest timestamp
     debug_mode: RwLock<DebugSettings>,           // Performance optimization settings
+    ingest: IngestCounters,
+    updates: broadcast::Sender<Record>, // Newly inserted records, for live subscribers
+}
+
+/// Pins taken by `StorageEngine::pin_chunks`, released on drop
+struct PinnedChunks<'a> {
+    storage: &'a StorageEngine,
+    chunk_ids: Vec<i64>,
+}
+
+impl Drop for PinnedChunks<'_> {
+    fn drop(&mut self) {
+        let mut pinned = self.storage.pinned.lock().unwrap();
+        for chunk_id in &self.chunk_ids {
+            if let Some(count) = pinned.get_mut(chunk_id) {
+                *count -= 1;
+                if *count == 0 {
+                    pinned.remove(chunk_id);
+                }
+            }
+        }
+    }
+}
+
+/// A chunk copied into a compaction run, pinned resident until the run is merged
+struct RunMember<'a> {
+    chunk_id: i64,
+    chunk: TimeChunk,
+    pin: PinnedChunks<'a>,
+}
+
+/// How an insert treats an existing record at the same metric and timestamp
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InsertMode {
+    Append, // Keep both, as raw sensor streams expect
+    Dedup,  // Last write wins
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -90,47 +282,93 @@ impl StorageEngine {
     pub fn new(config: &Config) -> Result<Self, StorageError> {
         // Create the storage directories
         let data_path = PathBuf::from(&config.storage.path);
-        let persistence = match PersistenceManager::new(&data_path) {
-            Ok(p) => Arc::new(p),
+        let persistence = match PersistenceManager::new(&data_path, config.storage.wal_fsync) {
+            Ok(p) => Arc::new(p.with_layout(config.storage.chunk_layout)?.with_wal_format(config.storage.wal_format)),
             Err(e) => return Err(StorageError::PersistenceError(format!("Failed to initialize persistence: {}", e))),
         };
         
-        let mut engine = StorageEngine {
+        let mut engine = Self::with_backend(config.chunk_duration, Some(persistence));
+        engine.max_resident_chunks = config.storage.max_resident_chunks;
+        engine.rollup_resolution = config.storage.rollup_resolution;
+        engine.metric_capacity_hint = config.storage.metric_capacity_hint;
+        engine.max_unflushed_bytes = config.storage.max_unflushed_bytes;
+        
+        // Recover from disk and WAL
+        engine.recover()?;
+        engine.evict_to_limit(|_| false)?;
+        
+        // Replayed records aren't new ingest
+        engine.ingest = IngestCounters::default();
+        
+        Ok(engine)
+    }
+    
+    /// An engine with no disk backing at all, for tests and ephemeral use
+    ///
+    /// Nothing is written to or recovered from disk; data lives as long as the engine.
+    pub fn new_in_memory(chunk_duration: Duration) -> Self {
+        Self::with_backend(chunk_duration, None)
+    }
+    
+    fn with_backend(chunk_duration: Duration, persistence: Option<Arc<PersistenceManager>>) -> Self {
+        StorageEngine {
             chunks: RwLock::new(HashMap::new()),
-            chunk_duration: config.chunk_duration,
+            index: RwLock::new(ChunkIndex::default()),
+            evicted: Mutex::new(HashSet::new()),
+            pinned: Mutex::new(HashMap::new()),
+            write_gate: RwLock::new(()),
+            max_resident_chunks: None,
+            rollup_resolution: None,
+            metric_capacity_hint: None,
+            max_unflushed_bytes: None,
+            dirty_bytes: Arc::new(AtomicUsize::new(0)),
+            flush_wanted: Notify::new(),
+            chunk_duration,
+            persistence_enabled: AtomicBool::new(persistence.is_some()),
             persistence,
-            persistence_enabled: AtomicBool::new(true),
             active_records: Mutex::new(HashMap::new()),
             debug_mode: RwLock::new(DebugSettings {
                 memory_mode: false,
                 disable_wal: false,
                 batch_size: 500,
             }),
-        };
-        
-        // Recover from disk and WAL
-        engine.recover()?;
-        
-        Ok(engine)
+            ingest: IngestCounters::default(),
+            updates: broadcast::channel(UPDATE_CHANNEL_CAPACITY).0,
+        }
+    }
+    
+    /// The persistence backend, unless there is none or it has been switched off
+    fn backend(&self) -> Option<&PersistenceManager> {
+        self.persistence.as_deref()
+            .filter(|_| self.persistence_enabled.load(Ordering::SeqCst))
     }
     
     /// Recover chunks from disk and replay the WAL to recover recent records
     fn recover(&mut self) -> Result<(), StorageError> {
+        let Some(persistence) = self.persistence.clone() else {
+            return Ok(());
+        };
         println!("Starting recovery process...");
         
         // First, load any existing chunks from disk
-        let chunk_ids = self.persistence.list_chunks()?;
+        let chunk_ids = persistence.list_chunks()?;
         println!("Found {} chunks on disk", chunk_ids.len());
         
         let mut chunks = self.chunks.write().unwrap();
+        let index = self.index.get_mut().unwrap();
         
         for chunk_id in chunk_ids {
             println!("Loading chunk {} from disk", chunk_id);
-            match self.persistence.load_chunk(chunk_id) {
-                Ok(chunk) => {
+            match persistence.load_chunk(chunk_id) {
+                Ok(mut chunk) => {
+                    chunk.refresh_size();
                     println!("Successfully loaded chunk {} with {} records", 
                              chunk_id, 
                              chunk.records.values().map(|v| v.len()).sum::<usize>());
+                    // Everything in a loaded chunk is already durable
+                    persistence.mark_chunk_durable(&chunk)?;
+                    chunk.track_dirty_bytes(Arc::clone(&self.dirty_bytes));
+                    index.insert_chunk(chunk_id, &chunk);
                     chunks.insert(chunk_id, chunk);
                 },
                 Err(e) => {
@@ -140,20 +378,165 @@ impl StorageEngine {
             }
         }
         
+        // A compaction interrupted before deleting the chunks it merged leaves them
+        // on disk, inside the merged chunk's span and holding nothing it lacks
+        let mut loaded: Vec<i64> = chunks.keys().copied().collect();
+        loaded.sort_unstable();
+        let mut widest: Option<i64> = None; // Loaded chunk reaching furthest so far
+        for chunk_id in loaded {
+            let end_time = chunks[&chunk_id].end_time;
+            match widest {
+                Some(outer) if end_time <= chunks[&outer].end_time => {
+                    if holds_all(&chunks[&outer], &chunks[&chunk_id]) {
+                        println!("Dropping chunk {} already merged into chunk {}", chunk_id, outer);
+                        chunks.remove(&chunk_id);
+                        index.remove_chunk(chunk_id);
+                        persistence.delete_chunk(chunk_id)?;
+                    }
+                }
+                _ => widest = Some(chunk_id),
+            }
+        }
+        
+        // A chunk written under a longer `chunk_duration` would go on taking records
+        // past the current one, so it is split into pieces no longer than that.
+        // Compacted chunks are wide on purpose and left alone.
+        let duration_secs = self.chunk_duration.as_secs() as i64;
+        let too_wide: Vec<i64> = chunks.iter()
+            .filter(|(_, chunk)| !chunk.metadata.compacted && chunk.end_time - chunk.start_time > duration_secs)
+            .map(|(&chunk_id, _)| chunk_id)
+            .collect();
+        for chunk_id in too_wide {
+            let chunk = chunks.remove(&chunk_id).expect("listed above");
+            index.remove_chunk(chunk_id);
+            let pieces = chunk.split(duration_secs)?;
+            println!("Splitting chunk {} into {} chunks of at most {}s", chunk_id, pieces.len(), duration_secs);
+            
+            // A piece starting where the chunk did replaces its file, so it is written
+            // last; pieces left by an interrupted split are held by the intact original
+            let reuses_file = pieces[0].start_time == chunk_id;
+            for mut piece in pieces.into_iter().rev() {
+                persistence.save_chunk(&piece)?;
+                persistence.mark_chunk_durable(&piece)?;
+                piece.mark_clean();
+                piece.track_dirty_bytes(Arc::clone(&self.dirty_bytes));
+                index.insert_chunk(piece.start_time, &piece);
+                chunks.insert(piece.start_time, piece);
+            }
+            if !reuses_file {
+                persistence.delete_chunk(chunk_id)?;
+            }
+        }
+        
         // Then, replay the WAL to recover any records not yet in chunks
         println!("Replaying write-ahead log...");
-        let wal_records = self.persistence.replay_wal()?;
-        println!("Found {} records in WAL", wal_records.len());
+        // A chunk may have been flushed before the WAL was truncated, so records
+        // at or below its durable watermark that it already holds are skipped.
+        // Appends are matched on the whole record, each copy in the chunk standing
+        // for one entry, so a repeated Append or a new value at the same timestamp
+        // is still replayed; one whose value a later Update corrects is matched on
+        // its timestamp. (A copy flushed before the last truncation can't be told
+        // from one the log still holds.) Upserts and Updates are idempotent and always replayed,
+        // except an Upsert a later rename would merge into a chunk already holding
+        // it. A chunk flushed after a rename holds the record under its final name.
+        // The log is streamed rather than loaded whole: the first pass collects
+        // renames and updates, the second decides which entries to replay against
+        // the chunks as loaded, keeping one flag per entry, and the third replays.
+        let mut renames: Vec<(usize, String, String)> = Vec::new();
+        let mut updated: HashMap<(String, i64), usize> = HashMap::new(); // Key -> position of its last Update
+        let mut position = 0;
+        let entry_count = persistence.replay_wal_with(|entry| {
+            match entry {
+                WalEntry::Rename { from, to } => renames.push((position, from, to)),
+                WalEntry::Update { metric_name, timestamp, .. } => {
+                    updated.insert((metric_name, timestamp), position);
+                }
+                WalEntry::Append(_) | WalEntry::Upsert(_) => {}
+            }
+            position += 1;
+        })?;
+        println!("Found {} records in WAL", entry_count);
+        
+        // Records of each (chunk, metric) not yet matched to an entry, filled on first use
+        let mut unmatched: HashMap<(i64, String), Vec<Record>> = HashMap::new();
+        let mut durable = |position: usize, record: &Record| -> Result<bool, StorageError> {
+            let chunk_id = self.chunk_id_for(record.timestamp);
+            let (Some(chunk), Some(watermark)) = (chunks.get(&chunk_id), persistence.durable_watermark(chunk_id)) else {
+                return Ok(false);
+            };
+            if record.timestamp > watermark {
+                return Ok(false);
+            }
+            
+            let metric_name = final_metric_name(&renames, position, &record.metric_name)
+                .unwrap_or_else(|| record.metric_name.clone());
+            let corrected = updated.get(&(record.metric_name.clone(), record.timestamp))
+                .is_some_and(|&at| at > position);
+            let candidates = match unmatched.entry((chunk_id, metric_name)) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    let records = chunk.decoded_metric_records(entry.key().1.as_str())?;
+                    entry.insert(records)
+                }
+            };
+            let matched = candidates.iter().position(|held| {
+                held.timestamp == record.timestamp
+                    && (corrected || (held.value.to_bits() == record.value.to_bits()
+                        && held.string_value == record.string_value))
+            });
+            Ok(matched.map(|i| candidates.swap_remove(i)).is_some())
+        };
+        
+        let mut replay: Vec<bool> = Vec::with_capacity(entry_count);
+        let mut failure = None;
+        persistence.replay_wal_with(|entry| {
+            let position = replay.len();
+            let decision = match &entry {
+                WalEntry::Append(record) => durable(position, record).map(|durable| !durable),
+                WalEntry::Upsert(record) if final_metric_name(&renames, position, &record.metric_name).is_some() => {
+                    durable(position, record).map(|durable| !durable)
+                }
+                WalEntry::Upsert(_) | WalEntry::Update { .. } | WalEntry::Rename { .. } => Ok(true),
+            };
+            replay.push(decision.unwrap_or_else(|e| {
+                failure.get_or_insert(e);
+                true
+            }));
+        })?;
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        println!("{} WAL records not yet in durable chunks", replay.iter().filter(|&&replayed| replayed).count());
         
         drop(chunks); // Release the lock before inserting records
         
-        for (i, record) in wal_records.into_iter().enumerate() {
-            println!("Replaying WAL record {}: metric={}, value={}", 
-                     i, record.metric_name, record.value);
-            if let Err(e) = self.insert_internal(record, false) {
+        let mut position = 0;
+        persistence.replay_wal_with(|entry| {
+            let i = position;
+            position += 1;
+            // The log may have grown since it was first read
+            if !replay.get(i).copied().unwrap_or(true) {
+                return;
+            }
+            
+            match entry.key() {
+                Some((metric_name, timestamp)) => {
+                    println!("Replaying WAL record {}: metric={}, timestamp={}", i, metric_name, timestamp);
+                }
+                None => println!("Replaying WAL record {}: {:?}", i, entry),
+            }
+            let result = match entry {
+                WalEntry::Append(record) => self.insert_internal(record, false, InsertMode::Append),
+                WalEntry::Upsert(record) => self.insert_internal(record, false, InsertMode::Dedup),
+                WalEntry::Update { metric_name, timestamp, value } => {
+                    self.update_record_internal(&metric_name, timestamp, value, false).map(|_| ())
+                }
+                WalEntry::Rename { from, to } => self.rename_metric_internal(&from, &to, false).map(|_| ()),
+            };
+            if let Err(e) = result {
                 eprintln!("Error during WAL replay: {:?}", e);
             }
-        }
+        })?;
         
         println!("Recovery process completed");
         Ok(())
@@ -161,37 +544,81 @@ impl StorageEngine {
 
     /// Insert a record into the appropriate time chunk
     pub fn insert(&self, record: Record) -> Result<(), StorageError> {
-        self.insert_internal(record, self.persistence_enabled.load(Ordering::SeqCst))
+        self.insert_internal(record, self.persistence_enabled.load(Ordering::SeqCst), InsertMode::Append)
+    }
+    
+    /// Insert a record and sync the WAL before returning, whatever the fsync policy
+    ///
+    /// Fails without inserting when persistence is disabled, since nothing could be made durable.
+    pub fn insert_durable(&self, record: Record) -> Result<(), StorageError> {
+        let Some(persistence) = self.backend() else {
+            return Err(StorageError::PersistenceError(
+                "Cannot make a write durable while persistence is disabled".to_string()
+            ));
+        };
+        
+        self.insert_internal(record, true, InsertMode::Append)?;
+        persistence.sync_wal()
+    }
+    
+    /// Insert a record, overwriting any existing record for the same metric and timestamp
+    pub fn insert_dedup(&self, record: Record) -> Result<(), StorageError> {
+        self.insert_internal(record, self.persistence_enabled.load(Ordering::SeqCst), InsertMode::Dedup)
     }
     
     /// Internal insert method that can optionally write to WAL
-    fn insert_internal(&self, record: Record, write_wal: bool) -> Result<(), StorageError> {
+    fn insert_internal(&self, record: Record, write_wal: bool, mode: InsertMode) -> Result<(), StorageError> {
+        // A record no chunk can hold must not reach the WAL, or recovery would trip on it
+        chunk_end_for(chunk_id_for_timestamp(record.timestamp, self.chunk_duration), self.chunk_duration)?;
+        
         // First, write to WAL if persistence is enabled
-        if write_wal && self.persistence_enabled.load(Ordering::SeqCst) {
-            self.persistence.append_record(&record)?;
+        let logging = self.backend().filter(|_| write_wal);
+        if logging.is_some() {
+            self.check_backpressure()?;
+        }
+        let logged = self.write_guard();
+        if let Some(persistence) = logging {
+            let written = match mode {
+                InsertMode::Append => persistence.append_record(&record)?,
+                InsertMode::Dedup => persistence.append_entry(&WalEntry::Upsert(record.clone()))?,
+            };
+            self.ingest.wal_bytes_written.fetch_add(written, Ordering::Relaxed);
         }
         
-        let chunk_id = self.get_chunk_id(record.timestamp);
+        let update = self.has_subscribers().then(|| record.clone());
         let mut chunks = self.chunks.write().unwrap();
+        let chunk_id = self.chunk_id_for(record.timestamp);
         
-        // Create new chunk if needed
+        // Create new chunk if needed, unless it was only evicted
+        self.reload_evicted(&mut chunks, |id| id == chunk_id)?;
         if !chunks.contains_key(&chunk_id) {
-            let start_time = chunk_id;
-            let end_time = start_time + self.chunk_duration.as_secs() as i64;
-            chunks.insert(chunk_id, TimeChunk::new(start_time, end_time));
+            let chunk = self.new_chunk(&chunks, chunk_id)?;
+            chunks.insert(chunk_id, chunk);
         }
 
         // Insert into appropriate chunk
         let chunk = chunks.get_mut(&chunk_id)
             .ok_or_else(|| StorageError::ChunkNotFound("Chunk not found after creation".to_string()))?;
         
-        chunk.append(record).map_err(StorageError::from)?;
+        let (metric_name, resource_type) = (record.metric_name.clone(), record.resource_type.clone());
+        match mode {
+            InsertMode::Append => chunk.append(record).map_err(StorageError::from)?,
+            InsertMode::Dedup => {
+                chunk.upsert(record).map_err(StorageError::from)?;
+            }
+        }
+        self.index.write().unwrap().insert(&metric_name, &resource_type, chunk_id, chunk);
+        self.ingest.records_inserted.fetch_add(1, Ordering::Relaxed);
+        if let Some(update) = update {
+            self.publish(update);
+        }
         
         // Check if the chunk is full and should be persisted
         let should_persist = chunk.is_full();
         
         // If the chunk is full, we need to persist it, but we'll do that after releasing the lock
-        let chunk_to_persist = if should_persist && self.persistence_enabled.load(Ordering::SeqCst) {
+        let chunk_to_persist = if should_persist && self.backend().is_some() {
+            self.refresh_rollups(chunk_id, chunk)?;
             Some((chunk_id, chunk.clone()))
         } else {
             None
@@ -199,53 +626,285 @@ impl StorageEngine {
         
         // Release the lock
         drop(chunks);
+        drop(logged);
         
         // Persist the chunk if needed
-        if let Some((chunk_id, chunk)) = chunk_to_persist {
-            // Save the chunk
-            self.persistence.save_chunk(&chunk)?;
-            
-            // Mark the chunk as durable in the WAL
-            let chunk_duration_secs = self.chunk_duration.as_secs() as i64;
-            self.persistence.mark_chunk_durable(chunk.start_time, chunk_duration_secs)?;
-            
-            // Mark chunk as clean with a separate write lock
-            let mut chunks = self.chunks.write().unwrap();
-            if let Some(chunk) = chunks.get_mut(&chunk_id) {
-                chunk.mark_clean();
-            }
+        if let (Some((chunk_id, chunk)), Some(persistence)) = (chunk_to_persist, self.backend()) {
+            self.save_full_chunk(persistence, chunk_id, &chunk)?;
         }
         
+        self.evict_to_limit(|id| id == chunk_id)
+    }
+    
+    /// Save a copy of a chunk that filled up, marking it clean unless it changed meanwhile
+    fn save_full_chunk(&self, persistence: &PersistenceManager, chunk_id: i64, chunk: &TimeChunk)
+        -> Result<(), StorageError>
+    {
+        persistence.save_chunk(chunk)?;
+        
+        // Mark the chunk as durable in the WAL
+        persistence.mark_chunk_durable(chunk)?;
+        self.ingest.chunks_persisted.fetch_add(1, Ordering::Relaxed);
+        
+        // Records that arrived after the copy still need a flush
+        let mut chunks = self.chunks.write().unwrap();
+        if let Some(resident) = chunks.get_mut(&chunk_id) {
+            resident.mark_clean_as_of(chunk.generation());
+        }
         Ok(())
     }
+    
+    /// Hold off `flush_all` until the guard is dropped
+    ///
+    /// Writers that log to the WAL before taking the chunk lock hold this from
+    /// the append until memory holds the write, so a flush never truncates an
+    /// entry its copy of the chunks lacks.
+    pub fn write_guard(&self) -> RwLockReadGuard<'_, ()> {
+        self.write_gate.read().unwrap()
+    }
+
+    /// Correct the value of an existing record in place
+    ///
+    /// The update is logged to the WAL so it survives recovery. Returns whether
+    /// a record was found at that metric and timestamp.
+    pub fn update_record(&self, metric: &str, timestamp: i64, new_value: f64) -> Result<bool, StorageError> {
+        self.update_record_internal(metric, timestamp, new_value, self.persistence_enabled.load(Ordering::SeqCst))
+    }
+    
+    fn update_record_internal(&self, metric: &str, timestamp: i64, new_value: f64, write_wal: bool) 
+        -> Result<bool, StorageError> 
+    {
+        let mut chunks = self.chunks.write().unwrap();
+        let chunk_id = self.chunk_id_for(timestamp);
+        self.reload_evicted(&mut chunks, |id| id == chunk_id)?;
+        
+        let Some(chunk) = chunks.get_mut(&chunk_id) else {
+            return Ok(false);
+        };
+        
+        chunk.decompress()?;
+        let exists = chunk.records.get(metric)
+            .is_some_and(|records| records.iter().any(|r| r.timestamp == timestamp));
+        if !exists {
+            return Ok(false);
+        }
+        
+        // Log before applying, still under the lock so the WAL order matches memory
+        if let Some(persistence) = self.backend().filter(|_| write_wal) {
+            let written = persistence.append_entry(&WalEntry::Update {
+                metric_name: metric.to_string(),
+                timestamp,
+                value: new_value,
+            })?;
+            self.ingest.wal_bytes_written.fetch_add(written, Ordering::Relaxed);
+        }
+        
+        Ok(chunk.update_value(metric, timestamp, new_value))
+    }
+
+    /// Move every record of `old` to the metric `new`, e.g. to fix a mis-coded batch
+    ///
+    /// Records `new` already holds are kept. The rename is logged to the WAL so it
+    /// survives recovery. Returns the number of records moved.
+    pub fn rename_metric(&self, old: &str, new: &str) -> Result<usize, StorageError> {
+        self.rename_metric_internal(old, new, self.persistence_enabled.load(Ordering::SeqCst))
+    }
+    
+    fn rename_metric_internal(&self, old: &str, new: &str, write_wal: bool) -> Result<usize, StorageError> {
+        if old == new {
+            return Ok(0);
+        }
+        
+        let mut chunks = self.chunks.write().unwrap();
+        let chunk_ids = self.index.read().unwrap().chunks_for_metric(old).to_vec();
+        if chunk_ids.is_empty() {
+            return Ok(0);
+        }
+        self.reload_evicted(&mut chunks, |id| chunk_ids.binary_search(&id).is_ok())?;
+        
+        // Log before applying, still under the lock so the WAL order matches memory
+        if let Some(persistence) = self.backend().filter(|_| write_wal) {
+            let written = persistence.append_entry(&WalEntry::Rename {
+                from: old.to_string(),
+                to: new.to_string(),
+            })?;
+            self.ingest.wal_bytes_written.fetch_add(written, Ordering::Relaxed);
+        }
+        
+        let mut moved = 0;
+        for chunk_id in &chunk_ids {
+            if let Some(chunk) = chunks.get_mut(chunk_id) {
+                moved += chunk.rename_metric(old, new)?;
+            }
+        }
+        self.index.write().unwrap().rename_metric(old, new);
+        drop(chunks);
+        
+        self.evict_to_limit(|_| false)?;
+        Ok(moved)
+    }
 
     pub fn query_range(&self, start: i64, end: i64, metric: &str) -> Result<Vec<Record>, StorageError> {
         if start >= end {
             return Err(StorageError::InvalidTimeRange("Start time must be before end time".to_string()));
         }
 
+        let chunk_ids = self.chunks_overlapping(start, end);
+        self.ensure_resident(|chunk_id| chunk_ids.binary_search(&chunk_id).is_ok())?;
+        self.decompress_chunks(|chunk_id, _| chunk_ids.binary_search(&chunk_id).is_ok())?;
+
         let chunks = self.chunks.read().unwrap();
         let mut results = Vec::new();
 
-        let start_chunk = self.get_chunk_id(start);
-        let end_chunk = self.get_chunk_id(end);
-
-        for chunk_id in (start_chunk..=end_chunk).step_by(self.chunk_duration.as_secs() as usize) {
-            if let Some(chunk) = chunks.get(&chunk_id) {
+        // Not every chunk holds every metric; a gap in the series just contributes nothing
+        for chunk_id in &chunk_ids {
+            if let Some(chunk) = chunks.get(chunk_id).filter(|chunk| chunk.has_metric(metric)) {
                 let records = chunk.get_range(start, end, metric)
                     .map_err(StorageError::from)?;
                 results.extend(records.iter().map(|&r| r.clone()));
             }
         }
+        drop(chunks);
+
+        self.evict_to_limit(|_| false)?;
+        Ok(results)
+    }
+
+    /// Range query over several metrics under a single read lock
+    ///
+    /// A metric that no chunk holds maps to `None`, so callers can tell it apart
+    /// from a metric that simply has no records in the range.
+    pub fn query_range_many(&self, start: i64, end: i64, metrics: &[String]) 
+        -> Result<HashMap<String, Option<Vec<Record>>>, StorageError> 
+    {
+        if start >= end {
+            return Err(StorageError::InvalidTimeRange("Start time must be before end time".to_string()));
+        }
+
+        let chunk_ids = self.chunks_overlapping(start, end);
+        self.ensure_resident(|chunk_id| chunk_ids.binary_search(&chunk_id).is_ok())?;
+        self.decompress_chunks(|chunk_id, _| chunk_ids.binary_search(&chunk_id).is_ok())?;
+
+        let chunks = self.chunks.read().unwrap();
+        let index = self.index.read().unwrap();
+        let mut results = HashMap::with_capacity(metrics.len());
+
+        for metric in metrics {
+            if index.chunks_for_metric(metric).is_empty() {
+                results.insert(metric.clone(), None);
+                continue;
+            }
+            
+            let mut records = Vec::new();
+            for chunk_id in &chunk_ids {
+                if let Some(chunk) = chunks.get(chunk_id).filter(|chunk| chunk.has_metric(metric)) {
+                    let in_range = chunk.get_range(start, end, metric).map_err(StorageError::from)?;
+                    records.extend(in_range.into_iter().cloned());
+                }
+            }
+            results.insert(metric.clone(), Some(records));
+        }
+        drop((chunks, index));
 
+        self.evict_to_limit(|_| false)?;
         Ok(results)
     }
 
+    /// Visit a metric's records chunk by chunk instead of materializing the whole range
+    ///
+    /// One read lock is held for the whole scan, so an eviction can't pull chunks out
+    /// from under it. The visitor returns `false` to stop early. Returns the number
+    /// of records visited.
+    pub fn query_range_streaming<F>(&self, start: i64, end: i64, metric: &str, visit: F) 
+        -> Result<usize, StorageError> 
+    where
+        F: FnMut(&Record) -> bool,
+    {
+        self.query_range_streaming_until(start, end, metric, None, visit)
+    }
+
+    /// Like `query_range_streaming`, but gives up with `StorageError::Timeout` once `deadline` passes
+    ///
+    /// The deadline is checked before each chunk, so a long scan is interrupted
+    /// part way rather than only after it finishes.
+    pub fn query_range_streaming_until<F>(&self, start: i64, end: i64, metric: &str, 
+                                          deadline: Option<Instant>, mut visit: F) 
+        -> Result<usize, StorageError> 
+    where
+        F: FnMut(&Record) -> bool,
+    {
+        if start >= end {
+            return Err(StorageError::InvalidTimeRange("Start time must be before end time".to_string()));
+        }
+
+        // Only the chunk ids are collected up front; pinning keeps other threads from evicting them
+        let chunk_ids = self.chunks_overlapping(start, end);
+        let pin = self.pin_chunks(&chunk_ids);
+        self.ensure_resident(|chunk_id| chunk_ids.binary_search(&chunk_id).is_ok())?;
+        self.decompress_chunks(|chunk_id, _| chunk_ids.binary_search(&chunk_id).is_ok())?;
+
+        let visited = self.visit_resident_chunks(&chunk_ids, start, end, metric, deadline, &mut visit);
+        drop(pin);
+        let visited = visited?;
+        self.evict_to_limit(|_| false)?;
+        Ok(visited)
+    }
+
+    /// Scan `chunk_ids` under a single read lock
+    ///
+    /// The chunks are pinned, so one that isn't resident but still counted as evicted
+    /// means it never made it back into memory. Chunks deleted outright hold nothing
+    /// to visit and are skipped.
+    fn visit_resident_chunks<F>(&self, chunk_ids: &[i64], start: i64, end: i64, metric: &str,
+                                deadline: Option<Instant>, visit: &mut F) -> Result<usize, StorageError>
+    where
+        F: FnMut(&Record) -> bool,
+    {
+        let chunks = self.chunks.read().unwrap();
+        let mut visited = 0;
+        for chunk_id in chunk_ids {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(StorageError::Timeout(format!(
+                    "Query on {} stopped after {} records", metric, visited
+                )));
+            }
+            
+            let Some(chunk) = chunks.get(chunk_id) else {
+                if self.evicted.lock().unwrap().contains(chunk_id) {
+                    return Err(StorageError::ChunkNotFound(format!(
+                        "Chunk {} is not resident for a query on {}", chunk_id, metric
+                    )));
+                }
+                continue;
+            };
+            for record in chunk.get_range(start, end, metric).map_err(StorageError::from)? {
+                visited += 1;
+                if !visit(record) {
+                    return Ok(visited);
+                }
+            }
+        }
+        Ok(visited)
+    }
+
+    /// Keep `chunk_ids` from being evicted until the returned guard is dropped
+    fn pin_chunks(&self, chunk_ids: &[i64]) -> PinnedChunks<'_> {
+        let mut pinned = self.pinned.lock().unwrap();
+        for &chunk_id in chunk_ids {
+            *pinned.entry(chunk_id).or_default() += 1;
+        }
+        PinnedChunks { storage: self, chunk_ids: chunk_ids.to_vec() }
+    }
+
     pub fn get_latest(&self, metric: &str) -> Result<Option<Record>, StorageError> {
+        let chunk_ids = self.index.read().unwrap().chunks_for_metric(metric).to_vec();
+        self.ensure_resident(|chunk_id| chunk_ids.binary_search(&chunk_id).is_ok())?;
+        self.decompress_chunks(|_, chunk| chunk.has_metric(metric))?;
+        
         let chunks = self.chunks.read().unwrap();
         let mut latest: Option<&Record> = None;
         
-        for chunk in chunks.values() {
+        for chunk in chunk_ids.iter().filter_map(|id| chunks.get(id)) {
             match chunk.get_latest(metric) {
                 Ok(Some(record)) => {
                     if latest.is_none() || record.timestamp > latest.unwrap().timestamp {
@@ -256,129 +915,715 @@ impl StorageEngine {
                 Err(e) => return Err(StorageError::ChunkError(e)),
             }
         }
+        let latest = latest.cloned();
+        drop(chunks);
 
-        Ok(latest.cloned())
-    }
-
-    fn get_chunk_id(&self, timestamp: i64) -> i64 {
-        timestamp - (timestamp % self.chunk_duration.as_secs() as i64)
+        self.evict_to_limit(|_| false)?;
+        Ok(latest)
     }
 
-    /// Persist all dirty chunks to disk
-    pub fn flush_all(&self) -> Result<(), StorageError> {
-        if !self.persistence_enabled.load(Ordering::SeqCst) {
-            println!("Persistence disabled, skipping flush");
-            return Ok(());
+    /// The `n` most recent records for a metric, newest first
+    ///
+    /// Chunks cover disjoint time ranges, so they are scanned newest-first and
+    /// the scan stops as soon as `n` records have been collected.
+    pub fn get_latest_n(&self, metric: &str, n: usize) -> Result<Vec<Record>, StorageError> {
+        if n == 0 {
+            return Ok(Vec::new());
         }
+        let chunk_ids = self.index.read().unwrap().chunks_for_metric(metric).to_vec();
         
-        println!("Starting to flush all dirty chunks to disk...");
-        
-        // First, identify dirty chunks while holding the read lock
-        let chunks_to_flush = {
-            let chunks = self.chunks.read().unwrap();
-            println!("Total chunks in memory: {}", chunks.len());
-            
-            chunks.iter()
-                .filter(|(_, chunk)| chunk.is_dirty())
-                .map(|(id, chunk)| (*id, chunk.clone()))
-                .collect::<Vec<_>>()
-        };
-        
-        // Now flush each dirty chunk without holding any locks
-        let mut flushed_count = 0;
-        for (chunk_id, chunk) in &chunks_to_flush {
-            println!("Flushing dirty chunk with ID: {}", chunk_id);
-            
-            // Save the chunk
-            if let Err(e) = self.persistence.save_chunk(chunk) {
-                println!("Error saving chunk {}: {:?}", chunk_id, e);
-                return Err(e);
-            }
+        let mut latest = Vec::with_capacity(n);
+        for &chunk_id in chunk_ids.iter().rev() {
+            // Evicted chunks are only reloaded once the scan reaches them
+            self.ensure_resident(|id| id == chunk_id)?;
+            self.decompress_chunks(|id, _| id == chunk_id)?;
             
-            // Mark the chunk as durable in the WAL
-            let chunk_duration_secs = self.chunk_duration.as_secs() as i64;
-            if let Err(e) = self.persistence.mark_chunk_durable(chunk.start_time, chunk_duration_secs) {
-                println!("Error marking chunk {} as durable: {:?}", chunk_id, e);
-                return Err(e);
+            let chunks = self.chunks.read().unwrap();
+            let Some(chunk) = chunks.get(&chunk_id) else {
+                continue;
+            };
+            let mut records: Vec<&Record> = chunk.metric_records(metric)?.iter().collect();
+            records.sort_by_key(|r| std::cmp::Reverse(r.timestamp));
+            latest.extend(records.into_iter().take(n - latest.len()).cloned());
+            if latest.len() == n {
+                break;
             }
-            
-            flushed_count += 1;
         }
         
-        // Finally, mark all flushed chunks as clean with a write lock
-        if !chunks_to_flush.is_empty() {
-            let mut chunks = self.chunks.write().unwrap();
-            for (chunk_id, _) in chunks_to_flush {
-                if let Some(chunk) = chunks.get_mut(&chunk_id) {
-                    chunk.mark_clean();
-                }
+        self.evict_to_limit(|_| false)?;
+        Ok(latest)
+    }
+
+    /// Compress every chunk not written to or read from within `idle`
+    ///
+    /// Returns how many chunks were compressed. They are decompressed again on
+    /// the next query or write that touches them.
+    pub fn compress_idle_chunks(&self, idle: Duration) -> Result<usize, StorageError> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let cutoff = now - idle.as_secs() as i64;
+        
+        let mut chunks = self.chunks.write().unwrap();
+        let mut compressed = 0;
+        for chunk in chunks.values_mut() {
+            if !chunk.is_compressed() && chunk.last_access() < cutoff {
+                chunk.compress()?;
+                compressed += 1;
             }
         }
         
-        println!("Flushed {} dirty chunks", flushed_count);
+        Ok(compressed)
+    }
+    
+    /// Decompress the chunks a read is about to touch
+    ///
+    /// Takes the write lock only if one of them is actually compressed.
+    fn decompress_chunks<F>(&self, wanted: F) -> Result<(), StorageError>
+    where
+        F: Fn(i64, &TimeChunk) -> bool,
+    {
+        let needed = self.chunks.read().unwrap().iter()
+            .any(|(&chunk_id, chunk)| chunk.is_compressed() && wanted(chunk_id, chunk));
+        if !needed {
+            return Ok(());
+        }
         
-        // Truncate the WAL after all chunks are persisted
-        println!("Truncating WAL...");
-        match self.persistence.truncate_wal() {
-            Ok(_) => println!("WAL truncated successfully"),
-            Err(e) => {
-                println!("Error truncating WAL: {:?}", e);
-                return Err(e);
+        let mut chunks = self.chunks.write().unwrap();
+        for (&chunk_id, chunk) in chunks.iter_mut() {
+            if chunk.is_compressed() && wanted(chunk_id, chunk) {
+                chunk.decompress()?;
             }
         }
-        
-        println!("Flush completed successfully");
         Ok(())
     }
 
-    pub fn cleanup_old_chunks(&self, retention: Duration) -> Result<(), StorageError> {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-            
-        let cutoff = now - retention.as_secs() as i64;
-        
-        // First flush all chunks to disk before removing old ones
-        self.flush_all()?;
+    /// Reload the evicted chunks a read is about to touch
+    ///
+    /// Other chunks are evicted to make room, but the wanted ones stay resident
+    /// even past `max_resident_chunks`, so a read spanning more chunks than the
+    /// limit still sees them all. Readers call `evict_to_limit` once done.
+    fn ensure_resident<F>(&self, wanted: F) -> Result<(), StorageError>
+    where
+        F: Fn(i64) -> bool,
+    {
+        if self.evicted.lock().unwrap().is_empty() {
+            return Ok(());
+        }
         
-        // Then remove old chunks
         let mut chunks = self.chunks.write().unwrap();
-        chunks.retain(|&chunk_start, _| chunk_start >= cutoff);
+        let reloaded = self.reload_evicted(&mut chunks, &wanted)?;
+        drop(chunks);
         
+        if reloaded > 0 {
+            self.evict_to_limit(wanted)?;
+        }
         Ok(())
     }
     
-    /// Enable or disable persistence
-    pub fn set_persistence(&mut self, enabled: bool) {
-        self.persistence_enabled.store(enabled, Ordering::SeqCst);
+    /// Load wanted chunks back from disk, returning how many were reloaded
+    fn reload_evicted<F>(&self, chunks: &mut HashMap<i64, TimeChunk>, wanted: F) -> Result<usize, StorageError>
+    where
+        F: Fn(i64) -> bool,
+    {
+        let mut evicted = self.evicted.lock().unwrap();
+        let Some(persistence) = self.persistence.as_deref() else {
+            return Ok(0);
+        };
+        
+        let chunk_ids: Vec<i64> = evicted.iter().copied().filter(|&id| wanted(id)).collect();
+        for &chunk_id in &chunk_ids {
+            let mut chunk = persistence.load_chunk(chunk_id)?;
+            chunk.refresh_size();
+            chunk.touch();
+            chunk.track_dirty_bytes(Arc::clone(&self.dirty_bytes));
+            chunks.insert(chunk_id, chunk);
+            evicted.remove(&chunk_id);
+        }
+        Ok(chunk_ids.len())
     }
-
-    pub fn get_matching_metrics(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
-        println!("StorageEngine: finding metrics with prefix: {}", prefix);
-        let chunks = self.chunks.read().unwrap();
-        let mut matching_metrics = Vec::new();
+    
+    /// Drop least recently used chunks until at most `max_resident_chunks` remain
+    ///
+    /// Dirty chunks are flushed before being dropped, so nothing but clean
+    /// chunks ever leaves memory. Chunks matching `keep` are left alone.
+    fn evict_to_limit<F>(&self, keep: F) -> Result<(), StorageError>
+    where
+        F: Fn(i64) -> bool,
+    {
+        let (Some(limit), Some(persistence)) = (self.max_resident_chunks, self.backend()) else {
+            return Ok(());
+        };
+        if self.chunks.read().unwrap().len() <= limit {
+            return Ok(());
+        }
         
-        for chunk in chunks.values() {
-            // Collect all metric names that start with the prefix
-            for metric_name in chunk.records.keys() {
-                if metric_name.starts_with(prefix) && !matching_metrics.contains(metric_name) {
-                    println!("Found matching metric: {}", metric_name);
-                    matching_metrics.push(metric_name.clone());
+        let mut chunks = self.chunks.write().unwrap();
+        let pinned = self.pinned.lock().unwrap();
+        while chunks.len() > limit {
+            let victim = chunks.iter()
+                .filter(|(&chunk_id, _)| !keep(chunk_id) && !pinned.contains_key(&chunk_id))
+                .min_by_key(|(&chunk_id, chunk)| (chunk.last_access(), chunk_id))
+                .map(|(&chunk_id, _)| chunk_id);
+            let Some(chunk_id) = victim else {
+                break; // Everything left is in use
+            };
+            
+            let chunk = chunks.get_mut(&chunk_id).expect("victim is resident");
+            self.persist_chunk(persistence, chunk_id, chunk)?;
+            chunks.remove(&chunk_id);
+            self.evicted.lock().unwrap().insert(chunk_id);
+        }
+        Ok(())
+    }
+    
+    /// Persist one chunk if it is dirty, then mark it durable and clean
+    ///
+    /// Returns whether anything was written, which is never the case with
+    /// persistence disabled or for a chunk that is clean or not resident (an
+    /// evicted chunk was flushed on the way out). Unlike `flush_all` this leaves
+    /// the WAL alone, since it still holds records of other chunks.
+    pub fn flush_chunk(&self, chunk_id: i64) -> Result<bool, StorageError> {
+        let Some(persistence) = self.backend() else {
+            return Ok(false);
+        };
+        let mut chunks = self.chunks.write().unwrap();
+        match chunks.get_mut(&chunk_id) {
+            Some(chunk) => self.persist_chunk(persistence, chunk_id, chunk),
+            None => Ok(false),
+        }
+    }
+    
+    /// Write a dirty chunk (with fresh rollups) to its file and mark it durable and clean
+    ///
+    /// Must be called while holding the `chunks` write lock.
+    fn persist_chunk(&self, persistence: &PersistenceManager, chunk_id: i64, chunk: &mut TimeChunk)
+        -> Result<bool, StorageError>
+    {
+        if !chunk.is_dirty() {
+            return Ok(false);
+        }
+        self.refresh_rollups(chunk_id, chunk)?;
+        persistence.save_chunk(chunk)?;
+        persistence.mark_chunk_durable(chunk)?;
+        chunk.mark_clean();
+        self.ingest.chunks_persisted.fetch_add(1, Ordering::Relaxed);
+        Ok(true)
+    }
+    
+    /// Recompute a chunk's rollups ahead of flushing it, if rollups are enabled
+    ///
+    /// Must be called while holding the `chunks` write lock.
+    fn refresh_rollups(&self, chunk_id: i64, chunk: &mut TimeChunk) -> Result<(), StorageError> {
+        let Some(resolution) = self.rollup_resolution else {
+            return Ok(());
+        };
+        chunk.refresh_rollups(resolution)?;
+        self.index.write().unwrap().insert_chunk(chunk_id, chunk);
+        Ok(())
+    }
+    
+    /// Number of chunks currently held in memory
+    pub fn resident_chunk_count(&self) -> usize {
+        self.chunks.read().unwrap().len()
+    }
+
+    /// Ids of the chunks, resident or evicted, overlapping `[start, end)`, oldest first
+    ///
+    /// Chunk spans come from the index rather than the configured
+    /// `chunk_duration`, which may have changed since older chunks were written.
+    fn chunks_overlapping(&self, start: i64, end: i64) -> Vec<i64> {
+        self.index.read().unwrap().chunks_overlapping(start, end)
+    }
+
+    /// The chunk that holds, or would hold, a record at `timestamp`
+    ///
+    /// That is the `chunk_duration`-aligned chunk, unless an existing chunk already
+    /// covers the timestamp, e.g. one widened by `compact`.
+    pub fn chunk_id_for(&self, timestamp: i64) -> i64 {
+        self.index.read().unwrap().chunk_containing(timestamp)
+            .unwrap_or_else(|| chunk_id_for_timestamp(timestamp, self.chunk_duration))
+    }
+
+    /// A new chunk at `chunk_id`, sized for the records each metric is expected to bring
+    ///
+    /// Unless `metric_capacity_hint` is configured, the chunk just before it is the estimate.
+    fn new_chunk(&self, chunks: &HashMap<i64, TimeChunk>, chunk_id: i64) -> Result<TimeChunk, StorageError> {
+        let end_time = chunk_end_for(chunk_id, self.chunk_duration)?;
+        let records_per_metric = self.metric_capacity_hint.unwrap_or_else(|| {
+            let previous = chunk_id.checked_sub(1)
+                .and_then(|timestamp| self.index.read().unwrap().chunk_containing(timestamp));
+            previous.and_then(|id| chunks.get(&id)).map_or(0, TimeChunk::records_per_metric)
+        });
+        let mut chunk = TimeChunk::new(chunk_id, end_time).with_capacity_hint(records_per_metric);
+        chunk.track_dirty_bytes(Arc::clone(&self.dirty_bytes));
+        Ok(chunk)
+    }
+
+    /// Sync WAL writes still pending under an `interval`/`every_n` fsync policy
+    pub fn sync_wal(&self) -> Result<(), StorageError> {
+        match self.backend() {
+            Some(persistence) => persistence.sync_wal(),
+            None => Ok(()),
+        }
+    }
+    
+    /// Persist all dirty chunks to disk, then truncate the WAL
+    ///
+    /// Writes may go on while the chunks are saved: only the WAL up to the
+    /// copy is truncated, and a chunk written to since its copy stays dirty.
+    /// Does nothing when persistence is disabled.
+    pub fn flush_all(&self) -> Result<FlushReport, StorageError> {
+        let Some(persistence) = self.backend() else {
+            println!("Persistence disabled, skipping flush");
+            return Ok(FlushReport::default());
+        };
+        
+        let (dirty, wal_position) = {
+            let _writes = self.write_gate.write().unwrap();
+            self.copy_dirty_chunks(persistence)?
+        };
+        self.save_dirty_chunks(persistence, dirty, wal_position)
+    }
+    
+    /// Copy the dirty chunks (with fresh rollups) and note where the WAL ends
+    ///
+    /// The caller holds `write_gate` exclusively, so every write logged up to
+    /// that position is already in memory and part of the copy.
+    fn copy_dirty_chunks(&self, persistence: &PersistenceManager) -> Result<(Vec<(i64, TimeChunk)>, u64), StorageError> {
+        println!("Starting to flush all dirty chunks to disk...");
+        let mut chunks = self.chunks.write().unwrap();
+        println!("Total chunks in memory: {}", chunks.len());
+        
+        let mut dirty = Vec::new();
+        for (&chunk_id, chunk) in chunks.iter_mut().filter(|(_, chunk)| chunk.is_dirty()) {
+            self.refresh_rollups(chunk_id, chunk)?;
+            dirty.push((chunk_id, chunk.clone()));
+        }
+        Ok((dirty, persistence.wal_position()?))
+    }
+    
+    /// Save chunks copied by `copy_dirty_chunks`, then truncate the WAL up to the copy
+    fn save_dirty_chunks(&self, persistence: &PersistenceManager, chunks_to_flush: Vec<(i64, TimeChunk)>,
+                         wal_position: u64) -> Result<FlushReport, StorageError> {
+        // Save the chunks in parallel without holding any locks. Every save
+        // runs to completion, so one failure doesn't cost the others their flush
+        let results = save_chunks_parallel(persistence, &chunks_to_flush);
+        let mut flushed = Vec::new();
+        let mut first_error = None;
+        for ((chunk_id, chunk), result) in chunks_to_flush.iter().zip(results) {
+            // Mark the chunk as durable in the WAL
+            match result.and_then(|()| persistence.mark_chunk_durable(chunk)) {
+                Ok(()) => {
+                    flushed.push((*chunk_id, chunk.generation()));
+                    self.ingest.chunks_persisted.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    println!("Error flushing chunk {}: {:?}", chunk_id, e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        
+        // Then mark the saved chunks clean, unless they changed after the copy
+        if !flushed.is_empty() {
+            let mut chunks = self.chunks.write().unwrap();
+            for (chunk_id, generation) in &flushed {
+                if let Some(chunk) = chunks.get_mut(chunk_id) {
+                    chunk.mark_clean_as_of(*generation);
+                }
+            }
+        }
+        
+        println!("Flushed {} of {} dirty chunks", flushed.len(), chunks_to_flush.len());
+        
+        // The WAL is the only copy of a failed chunk's records, so it stays
+        if let Some(e) = first_error {
+            return Err(e);
+        }
+        
+        // Entries logged after the copy are kept for the chunks' next flush
+        persistence.truncate_wal_before(wal_position)?;
+        
+        println!("Flush completed successfully");
+        Ok(FlushReport { chunks_flushed: flushed.len(), wal_truncated: true })
+    }
+    
+    /// Whether writes currently reach the WAL and chunk files
+    pub fn persistence_active(&self) -> bool {
+        self.backend().is_some()
+    }
+    
+    /// Resident chunks holding writes not yet flushed to their chunk file
+    pub fn dirty_chunk_count(&self) -> usize {
+        self.chunks.read().unwrap().values().filter(|chunk| chunk.is_dirty()).count()
+    }
+    
+    /// Estimated bytes held by dirty resident chunks, i.e. what a flush would write
+    pub fn unflushed_bytes(&self) -> usize {
+        self.dirty_bytes.load(Ordering::Relaxed)
+    }
+    
+    /// Wait until backpressure has refused a write, meaning a flush is due
+    ///
+    /// A refusal while nobody waits is remembered, so the next wait returns at once.
+    pub async fn flush_requested(&self) {
+        self.flush_wanted.notified().await
+    }
+    
+    /// Refuse a logged write while unflushed data is over the high-water mark
+    ///
+    /// Only writes that reach the WAL are refused: without persistence nothing
+    /// is ever flushed, so there is nothing for the writer to wait on. A refusal
+    /// wakes `flush_requested` so the data gets written out.
+    fn check_backpressure(&self) -> Result<(), StorageError> {
+        let Some(limit) = self.max_unflushed_bytes else {
+            return Ok(());
+        };
+        let unflushed = self.unflushed_bytes();
+        if unflushed > limit {
+            self.flush_wanted.notify_one();
+            return Err(StorageError::Backpressure(format!(
+                "{} unflushed bytes exceed the limit of {}; retry after a flush", unflushed, limit
+            )));
+        }
+        Ok(())
+    }
+
+    /// Write a consistent point-in-time copy of the store into `dest`
+    ///
+    /// `dest` must not exist yet, or be an empty directory, outside the storage
+    /// path. Writes are held off only while dirty chunks are copied and the files
+    /// of clean ones are linked; the copies are then written out both to the
+    /// snapshot and as a regular flush. `dest` can be opened directly with
+    /// `StorageEngine::new`.
+    pub fn snapshot(&self, dest: &Path) -> Result<(), StorageError> {
+        let Some(persistence) = self.backend() else {
+            return Err(StorageError::PersistenceError(
+                "Cannot snapshot while persistence is disabled".to_string()
+            ));
+        };
+        let staging = persistence.stage_snapshot(dest)?;
+        
+        let (dirty, wal_position) = {
+            let _writes = self.write_gate.write().unwrap();
+            let (dirty, wal_position) = self.copy_dirty_chunks(persistence)?;
+            // Every other chunk's file is current; the read lock keeps retention from deleting one
+            let _chunks = self.chunks.read().unwrap();
+            let dirty_ids: HashSet<i64> = dirty.iter().map(|(chunk_id, _)| *chunk_id).collect();
+            persistence.link_chunk_files(&staging, |chunk_id| !dirty_ids.contains(&chunk_id))?;
+            (dirty, wal_position)
+        };
+        
+        persistence.stage_chunks(&staging, &dirty)?;
+        self.save_dirty_chunks(persistence, dirty, wal_position)?;
+        staging.finish()
+    }
+
+    /// Load and validate every chunk on disk, collecting all failures
+    ///
+    /// Unreadable chunks and chunks failing `TimeChunk::validate` are reported
+    /// individually rather than stopping at the first bad one.
+    pub fn verify_integrity(&self) -> Result<IntegrityReport, StorageError> {
+        let mut report = IntegrityReport::default();
+        let Some(persistence) = self.persistence.as_deref() else {
+            return Ok(report); // Nothing on disk to check
+        };
+        
+        for chunk_id in persistence.list_chunks()? {
+            report.chunks_checked += 1;
+            
+            let result = persistence.load_chunk(chunk_id)
+                .and_then(|chunk| {
+                    if chunk.start_time != chunk_id {
+                        return Err(StorageError::ChunkError(ChunkError::DataCorrupted(format!(
+                            "Chunk file {} holds chunk starting at {}", chunk_id, chunk.start_time
+                        ))));
+                    }
+                    chunk.validate().map_err(StorageError::from)
+                });
+            
+            if let Err(e) = result {
+                report.failures.push(ChunkIntegrityFailure {
+                    chunk_id,
+                    error: e.to_string(),
+                });
+            }
+        }
+        
+        Ok(report)
+    }
+
+    /// Merge runs of neighbouring under-full chunks into fewer, wider ones
+    ///
+    /// Chunks merge in time order while the merged record count stays under half
+    /// of `MAX_CHUNK_RECORDS`, leaving room to grow. Chunks reaching past the
+    /// current time are still being written and are left alone. Runs are planned
+    /// from the index and brought into memory one chunk at a time. Each run is
+    /// merged and written beside the file of its first chunk without holding the
+    /// chunk lock, which is only taken to swap the merged chunk in; a run written
+    /// to in the meantime is left for the next compaction. The other files of a
+    /// run are deleted after the swap; recovery drops any of those left behind
+    /// by a crash in between.
+    pub fn compact(&self) -> Result<CompactionReport, StorageError> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let record_limit = MAX_CHUNK_RECORDS / 2;
+        
+        // Only clean chunks are merged, so a flush can't overwrite a merged file with an older copy
+        self.flush_all()?;
+        let spans = self.index.read().unwrap().spans();
+        let mut report = CompactionReport { chunks_before: spans.len(), ..Default::default() };
+        
+        let mut run: Vec<RunMember> = Vec::new();
+        let (mut run_end, mut run_records) = (i64::MIN, 0);
+        let mut merged_away = 0;
+        for (chunk_id, end_time) in spans {
+            let member = if end_time > now { None } else { self.run_member(chunk_id)? };
+            let Some(member) = member.filter(|member| member.chunk.record_count() < record_limit) else {
+                merged_away += self.merge_run(std::mem::take(&mut run), &mut report)?;
+                continue;
+            };
+            let records = member.chunk.record_count();
+            if run.is_empty() || member.chunk.start_time < run_end || run_records + records > record_limit {
+                merged_away += self.merge_run(std::mem::take(&mut run), &mut report)?;
+                run_records = 0;
+            }
+            run_end = member.chunk.end_time;
+            run_records += records;
+            run.push(member);
+        }
+        merged_away += self.merge_run(run, &mut report)?;
+        
+        report.chunks_after = report.chunks_before - merged_away;
+        self.evict_to_limit(|_| false)?;
+        Ok(report)
+    }
+    
+    /// Copy of a chunk for a compaction run, reloading it if evicted and pinning it meanwhile
+    fn run_member(&self, chunk_id: i64) -> Result<Option<RunMember<'_>>, StorageError> {
+        let pin = self.pin_chunks(&[chunk_id]);
+        let mut chunks = self.chunks.write().unwrap();
+        self.reload_evicted(&mut chunks, |id| id == chunk_id)?;
+        Ok(chunks.get(&chunk_id).map(|chunk| RunMember { chunk_id, chunk: chunk.clone(), pin }))
+    }
+    
+    /// Merge a compaction run into its first chunk and swap the result in
+    ///
+    /// Returns the number of chunks merged away.
+    fn merge_run(&self, run: Vec<RunMember<'_>>, report: &mut CompactionReport) -> Result<usize, StorageError> {
+        if run.len() < 2 {
+            return Ok(0);
+        }
+        let copied: Vec<(i64, u64)> = run.iter().map(|member| (member.chunk_id, member.chunk.generation())).collect();
+        let mut members = run.into_iter();
+        let first = members.next().expect("run has members");
+        let mut merged = first.chunk;
+        let mut records_moved = 0;
+        // The chunks stay pinned until the swap, so none is evicted in between
+        let mut _pins = vec![first.pin];
+        for member in members {
+            records_moved += member.chunk.record_count();
+            merged.absorb(member.chunk)?;
+            _pins.push(member.pin);
+        }
+        if let Some(resolution) = self.rollup_resolution {
+            merged.refresh_rollups(resolution)?;
+        }
+        let persistence = self.backend();
+        let prepared = persistence.map(|persistence| persistence.prepare_chunk(&merged)).transpose()?;
+        
+        let mut chunks = self.chunks.write().unwrap();
+        let changed = copied.iter().any(|(chunk_id, generation)| {
+            chunks.get(chunk_id).is_none_or(|chunk| {
+                chunk.generation() != *generation || (persistence.is_some() && chunk.is_dirty())
+            })
+        });
+        // A chunk written to since its copy was taken leaves the run for the next compaction
+        if changed {
+            return Ok(0);
+        }
+        if let (Some(persistence), Some(prepared)) = (persistence, prepared) {
+            prepared.commit()?;
+            persistence.mark_chunk_durable(&merged)?;
+            merged.mark_clean();
+            self.ingest.chunks_persisted.fetch_add(1, Ordering::Relaxed);
+        }
+        
+        let mut index = self.index.write().unwrap();
+        for (chunk_id, _) in &copied {
+            chunks.remove(chunk_id);
+            index.remove_chunk(*chunk_id);
+        }
+        merged.track_dirty_bytes(Arc::clone(&self.dirty_bytes));
+        index.insert_chunk(copied[0].0, &merged);
+        chunks.insert(copied[0].0, merged);
+        drop(index);
+        drop(chunks);
+        
+        if let Some(persistence) = persistence {
+            for (chunk_id, _) in &copied[1..] {
+                persistence.delete_chunk(*chunk_id)?;
+            }
+        }
+        report.records_moved += records_moved;
+        Ok(copied.len() - 1)
+    }
+
+    pub fn cleanup_old_chunks(&self, retention: Duration) -> Result<(), StorageError> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+            
+        let cutoff = now - retention.as_secs() as i64;
+        
+        // First flush all chunks to disk before removing old ones
+        self.flush_all()?;
+        
+        // Then remove old chunks
+        let mut chunks = self.chunks.write().unwrap();
+        let mut index = self.index.write().unwrap();
+        chunks.retain(|&chunk_start, _| {
+            let keep = chunk_start >= cutoff;
+            if !keep {
+                index.remove_chunk(chunk_start);
+            }
+            keep
+        });
+        self.evicted.lock().unwrap().retain(|&chunk_start| {
+            let keep = chunk_start >= cutoff;
+            if !keep {
+                index.remove_chunk(chunk_start);
+            }
+            keep
+        });
+        
+        Ok(())
+    }
+    
+    /// Prune records older than the retention of the longest policy prefix matching their metric
+    ///
+    /// Chunks mix metrics, so unlike `cleanup_old_chunks` this works record by
+    /// record, e.g. to expire raw ECG samples after a day while keeping weights
+    /// for years. The empty prefix matches every metric and so acts as the
+    /// default; metrics matching no policy are kept. Rollups share their source
+    /// metric's prefix and expire with it. Evicted chunks are pruned one at a
+    /// time, chunks left empty are deleted, and a final flush drops the pruned
+    /// records' WAL entries so recovery can't bring them back. Returns the
+    /// number of records removed.
+    pub fn apply_retention(&self, policies: &HashMap<String, Duration>) -> Result<usize, StorageError> {
+        let Some(shortest) = policies.values().min() else {
+            return Ok(0);
+        };
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let cutoff_for = |metric: &str| {
+            policies.iter()
+                .filter(|(prefix, _)| metric.starts_with(prefix.as_str()))
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map(|(_, retention)| now - retention.as_secs() as i64)
+        };
+        let keep = |record: &Record| {
+            cutoff_for(&record.metric_name).is_none_or(|cutoff| record.timestamp >= cutoff)
+        };
+        // No chunk starting after the latest cutoff holds anything to prune
+        let latest_cutoff = now - shortest.as_secs() as i64;
+        
+        let mut removed = self.prune_chunks(&mut self.chunks.write().unwrap(), |id| id < latest_cutoff, keep)?;
+        
+        let evicted: Vec<i64> = self.evicted.lock().unwrap().iter()
+            .copied()
+            .filter(|&id| id < latest_cutoff)
+            .collect();
+        for chunk_id in evicted {
+            let mut chunks = self.chunks.write().unwrap();
+            self.reload_evicted(&mut chunks, |id| id == chunk_id)?;
+            removed += self.prune_chunks(&mut chunks, |id| id == chunk_id, keep)?;
+            drop(chunks);
+            self.evict_to_limit(|_| false)?;
+        }
+        
+        // The pruned records' appends are still in the WAL until a flush truncates it
+        if removed > 0 {
+            self.flush_all()?;
+        }
+        Ok(removed)
+    }
+    
+    /// Drop the records failing `keep` from the resident chunks matching `wanted`
+    ///
+    /// Chunks left empty are removed along with their files, unless a running
+    /// scan has them pinned. Returns the number of records removed.
+    fn prune_chunks<W, K>(&self, chunks: &mut HashMap<i64, TimeChunk>, wanted: W, keep: K) -> Result<usize, StorageError>
+    where
+        W: Fn(i64) -> bool,
+        K: Fn(&Record) -> bool,
+    {
+        let mut removed = 0;
+        let mut emptied = Vec::new();
+        let mut index = self.index.write().unwrap();
+        for (&chunk_id, chunk) in chunks.iter_mut().filter(|(&id, _)| wanted(id)) {
+            let pruned = chunk.retain_records(&keep)?;
+            if pruned > 0 {
+                index.remove_chunk(chunk_id);
+                removed += pruned;
+                if chunk.records.is_empty() {
+                    emptied.push(chunk_id);
+                } else {
+                    index.insert_chunk(chunk_id, chunk);
                 }
             }
         }
         
+        let pinned = self.pinned.lock().unwrap();
+        for chunk_id in emptied {
+            if pinned.contains_key(&chunk_id) {
+                index.insert_chunk(chunk_id, &chunks[&chunk_id]);
+                continue;
+            }
+            chunks.remove(&chunk_id);
+            if let Some(persistence) = self.backend() {
+                persistence.delete_chunk(chunk_id)?;
+            }
+        }
+        Ok(removed)
+    }
+    
+    /// Enable or disable persistence
+    pub fn set_persistence(&mut self, enabled: bool) {
+        self.persistence_enabled.store(enabled, Ordering::SeqCst);
+    }
+
+    pub fn get_matching_metrics(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        println!("StorageEngine: finding metrics with prefix: {}", prefix);
+        let index = self.index.read().unwrap();
+        let mut matching_metrics: Vec<String> = index.metrics()
+            .filter(|metric_name| metric_name.starts_with(prefix))
+            // Derived rollups only show up when asked for by name
+            .filter(|metric_name| !is_rollup_metric(metric_name) || is_rollup_metric(prefix))
+            .cloned()
+            .collect();
+        matching_metrics.sort_unstable();
+        
         Ok(matching_metrics)
     }
     
     /// Get metrics by resource type
     pub fn get_metrics_by_resource_type(&self, resource_type: &str) -> Result<Vec<String>, StorageError> {
         println!("StorageEngine: finding metrics for resource type: {}", resource_type);
+        let chunk_ids = self.index.read().unwrap().chunks_for_resource_type(resource_type).to_vec();
+        self.ensure_resident(|chunk_id| chunk_ids.binary_search(&chunk_id).is_ok())?;
+        
         let chunks = self.chunks.read().unwrap();
         let mut matching_metrics = Vec::new();
         
-        for chunk in chunks.values() {
+        for chunk in chunk_ids.iter().filter_map(|id| chunks.get(id)) {
             if let Some(metrics) = chunk.resource_metrics.get(resource_type) {
                 for metric in metrics {
                     if !matching_metrics.contains(metric) {
@@ -391,12 +1636,77 @@ impl StorageEngine {
         Ok(matching_metrics)
     }
     
+    /// Number of records for a metric in `[start, end)`, counted without cloning any
+    pub fn count_range(&self, start: i64, end: i64, metric: &str) -> Result<usize, StorageError> {
+        self.query_range_streaming(start, end, metric, |_| true)
+    }
+    
+    /// Number of records `query_by_resource_type` would return
+    pub fn count_by_resource_type(&self, resource_type: &str, start: i64, end: i64) 
+        -> Result<usize, StorageError> 
+    {
+        let mut count = 0;
+        for metric in self.resource_type_metrics(resource_type) {
+            count += self.count_range(start, end, &metric)?;
+        }
+        Ok(count)
+    }
+    
     /// Query records by resource type and time range
     pub fn query_by_resource_type(&self, resource_type: &str, start: i64, end: i64) 
         -> Result<Vec<Record>, StorageError> 
     {
         println!("StorageEngine: querying records for resource type: {}", resource_type);
         
+        let metrics = self.resource_type_metrics(resource_type);
+        let mut results = Vec::new();
+        
+        // Then query each metric within the time range
+        for metric in metrics {
+            let records = self.query_range(start, end, &metric)?;
+            results.extend(records);
+        }
+        
+        Ok(results)
+    }
+    
+    /// Like `query_by_resource_type`, but gives up with `StorageError::Timeout` once `deadline` passes
+    pub fn query_by_resource_type_until(&self, resource_type: &str, start: i64, end: i64,
+                                        deadline: Option<Instant>) -> Result<Vec<Record>, StorageError> 
+    {
+        let mut results = Vec::new();
+        for metric in self.resource_type_metrics(resource_type) {
+            self.query_range_streaming_until(start, end, &metric, deadline, |record| {
+                results.push(record.clone());
+                true
+            })?;
+        }
+        Ok(results)
+    }
+    
+    /// Records of the FHIR resource with the given id, oldest first
+    ///
+    /// Ids aren't indexed, so every chunk is scanned.
+    pub fn query_by_id(&self, id: &str) -> Result<Vec<Record>, StorageError> {
+        self.ensure_resident(|_| true)?;
+        self.decompress_chunks(|_, _| true)?;
+        
+        let chunks = self.chunks.read().unwrap();
+        let mut records: Vec<Record> = chunks.values()
+            .flat_map(|chunk| chunk.records.values().flatten())
+            .filter(|record| record.id.as_deref() == Some(id))
+            .cloned()
+            .collect();
+        drop(chunks);
+        self.evict_to_limit(|_| false)?;
+        
+        records.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.metric_name.cmp(&b.metric_name)));
+        Ok(records)
+    }
+    
+    /// Metrics holding records of a resource type, falling back to a scan of
+    /// chunks whose resource index is empty
+    fn resource_type_metrics(&self, resource_type: &str) -> Vec<String> {
         // First get all metrics for this resource type
         let mut metrics = self.get_metrics_by_resource_type(resource_type).unwrap_or_default();
         
@@ -408,7 +1718,7 @@ impl StorageEngine {
                 for (metric, records) in &chunk.records {
                     // Check a sample record to see if it has the right resource_type
                     if let Some(record) = records.first() {
-                        if record.resource_type == resource_type {
+                        if record.resource_type == resource_type && !metrics.contains(metric) {
                             metrics.push(metric.clone());
                         }
                     }
@@ -417,16 +1727,7 @@ impl StorageEngine {
         }
         
         println!("Found {} metrics for resource type {}", metrics.len(), resource_type);
-        
-        let mut results = Vec::new();
-        
-        // Then query each metric within the time range
-        for metric in metrics {
-            let records = self.query_range(start, end, &metric)?;
-            results.extend(records);
-        }
-        
-        Ok(results)
+        metrics
     }
 
     /// Get debug metrics information
@@ -438,9 +1739,9 @@ impl StorageEngine {
         // Collect metrics from all chunks
         for chunk in chunks.values() {
             // Get all metrics
-            for metric in chunk.records.keys() {
-                if !all_metrics.contains(metric) {
-                    all_metrics.push(metric.clone());
+            for metric in chunk.get_metrics_list() {
+                if !all_metrics.contains(&metric) {
+                    all_metrics.push(metric);
                 }
             }
             
@@ -472,18 +1773,71 @@ impl StorageEngine {
         })
     }
 
+    /// Record count and time bounds of every metric in memory, largest first
+    ///
+    /// Like `chunk_infos`, only resident chunks are counted.
+    pub fn metric_counts(&self) -> Result<Vec<MetricCount>, StorageError> {
+        let chunks = self.chunks.read().unwrap();
+        let mut counts: HashMap<&str, MetricCount> = HashMap::new();
+        for chunk in chunks.values() {
+            for (metric, record_count, earliest, latest) in chunk.metric_extents()? {
+                let entry = counts.entry(metric).or_insert_with(|| MetricCount {
+                    metric: metric.to_string(),
+                    record_count: 0,
+                    earliest,
+                    latest,
+                });
+                entry.record_count += record_count;
+                entry.earliest = entry.earliest.min(earliest);
+                entry.latest = entry.latest.max(latest);
+            }
+        }
+        
+        let mut counts: Vec<MetricCount> = counts.into_values().collect();
+        counts.sort_by(|a, b| b.record_count.cmp(&a.record_count).then_with(|| a.metric.cmp(&b.metric)));
+        Ok(counts)
+    }
+
+    /// Every chunk currently in memory, oldest first; evicted chunks are not listed
+    pub fn chunk_infos(&self) -> Vec<ChunkInfo> {
+        let chunks = self.chunks.read().unwrap();
+        let mut infos: Vec<ChunkInfo> = chunks.iter()
+            .map(|(&id, chunk)| ChunkInfo {
+                id,
+                start_time: chunk.start_time,
+                end_time: chunk.end_time,
+                record_count: chunk.record_count(),
+                dirty: chunk.is_dirty(),
+                compressed: chunk.is_compressed(),
+            })
+            .collect();
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+
+    /// Count/min/max/avg of one metric within a resident chunk
+    pub fn summarize_chunk(&self, chunk_id: i64, metric: &str) -> Result<ChunkSummary, StorageError> {
+        self.decompress_chunks(|id, _| id == chunk_id)?;
+        let chunks = self.chunks.read().unwrap();
+        let chunk = chunks.get(&chunk_id)
+            .ok_or_else(|| StorageError::ChunkNotFound(format!("No resident chunk {}", chunk_id)))?;
+        Ok(chunk.summarize(metric)?)
+    }
+
     pub fn chunk_duration(&self) -> Duration {
         self.chunk_duration
     }
     
     /// Append multiple records to the WAL in a single operation 
     pub fn append_records_to_wal(&self, records: Vec<Record>) -> Result<(), StorageError> {
-        if !self.persistence_enabled.load(Ordering::SeqCst) || records.is_empty() {
+        let Some(persistence) = self.backend().filter(|_| !records.is_empty()) else {
             return Ok(());
-        }
+        };
+        self.check_backpressure()?;
         
         // Batch write to WAL
-        self.persistence.append_records(&records)?;
+        let written = persistence.append_records(&records)?;
+        self.ingest.wal_bytes_written.fetch_add(written, Ordering::Relaxed);
         
         // Update the active records map
         let mut active_records = self.active_records.lock().unwrap();
@@ -495,36 +1849,73 @@ impl StorageEngine {
     }
     
     /// Insert a batch of records into a specific chunk
+    ///
+    /// Every record is attempted; the first one the chunk rejects is returned as the error.
     pub fn insert_batch(&self, chunk_id: i64, records: Vec<Record>) -> Result<(), StorageError> {
+        let result = self.insert_batch_partial(chunk_id, records)?;
+        match result.failed.into_iter().next() {
+            Some((_, error)) => Err(StorageError::ChunkError(error)),
+            None => Ok(()),
+        }
+    }
+    
+    /// Insert a batch of records into a specific chunk, collecting the ones it rejects
+    ///
+    /// A rejected record, e.g. one outside the chunk's time range, doesn't stop
+    /// the rest of the batch. Only failures affecting the whole chunk are errors.
+    pub fn insert_batch_partial(&self, chunk_id: i64, records: Vec<Record>) -> Result<BatchResult, StorageError> {
+        let mut result = BatchResult::default();
         if records.is_empty() {
-            return Ok(());
+            return Ok(result);
         }
         
         let mut chunks = self.chunks.write().unwrap();
+        // The chunk may have been merged into a wider one since the caller picked it
+        let chunk_id = self.chunk_id_for(chunk_id);
         
-        // Create new chunk if needed
+        // Create new chunk if needed, unless it was only evicted
+        self.reload_evicted(&mut chunks, |id| id == chunk_id)?;
         if !chunks.contains_key(&chunk_id) {
-            let start_time = chunk_id;
-            let end_time = start_time + self.chunk_duration.as_secs() as i64;
-            chunks.insert(chunk_id, TimeChunk::new(start_time, end_time));
+            let chunk = self.new_chunk(&chunks, chunk_id)?;
+            chunks.insert(chunk_id, chunk);
         }
 
         // Get the chunk
         let chunk = chunks.get_mut(&chunk_id)
             .ok_or_else(|| StorageError::ChunkNotFound("Chunk not found after creation".to_string()))?;
         
+        // Once a metric's first record is in, room is made for the rest of the batch at once
+        let mut pending: HashMap<String, usize> = HashMap::new();
+        for record in &records {
+            *pending.entry(record.metric_name.clone()).or_default() += 1;
+        }
+        
         // Insert all records
-        for record in records {
-            if let Err(e) = chunk.append(record) {
-                return Err(e.into());
+        let publish = self.has_subscribers();
+        for (position, record) in records.into_iter().enumerate() {
+            let update = publish.then(|| record.clone());
+            let reserve = pending.remove_entry(&record.metric_name);
+            if let Err(error) = chunk.append(record) {
+                result.failed.push((position, error));
+                continue;
+            }
+            if let Some((metric, count)) = reserve {
+                chunk.reserve_records(&metric, count - 1);
+            }
+            result.inserted += 1;
+            self.ingest.records_inserted.fetch_add(1, Ordering::Relaxed);
+            if let Some(update) = update {
+                self.publish(update);
             }
         }
+        self.index.write().unwrap().insert_chunk(chunk_id, chunk);
         
         // Check if the chunk is full and should be persisted
         let should_persist = chunk.is_full();
         
         // If the chunk is full, we need to persist it, but we'll do that after releasing the lock
-        let chunk_to_persist = if should_persist && self.persistence_enabled.load(Ordering::SeqCst) {
+        let chunk_to_persist = if should_persist && self.backend().is_some() {
+            self.refresh_rollups(chunk_id, chunk)?;
             Some((chunk_id, chunk.clone()))
         } else {
             None
@@ -534,28 +1925,47 @@ impl StorageEngine {
         drop(chunks);
         
         // Persist the chunk if needed
-        if let Some((chunk_id, chunk)) = chunk_to_persist {
-            // Save the chunk
-            self.persistence.save_chunk(&chunk)?;
-            
-            // Mark the chunk as durable in the WAL
-            let chunk_duration_secs = self.chunk_duration.as_secs() as i64;
-            self.persistence.mark_chunk_durable(chunk.start_time, chunk_duration_secs)?;
-            
-            // Mark chunk as clean with a separate write lock
-            let mut chunks = self.chunks.write().unwrap();
-            if let Some(chunk) = chunks.get_mut(&chunk_id) {
-                chunk.mark_clean();
-            }
+        if let (Some((chunk_id, chunk)), Some(persistence)) = (chunk_to_persist, self.backend()) {
+            self.save_full_chunk(persistence, chunk_id, &chunk)?;
         }
         
-        Ok(())
+        self.evict_to_limit(|id| id == chunk_id)?;
+        Ok(result)
     }
 
-    /// Set debug settings for performance testing
-    pub fn set_debug_settings(&self, memory_mode: bool, disable_wal: bool, batch_size: Option<usize>) -> Result<(), StorageError> {
-        let mut debug_settings = self.debug_mode.write().unwrap();
-        debug_settings.memory_mode = memory_mode;
+    /// Receive every record inserted from now on
+    ///
+    /// Publishing never blocks ingestion: a subscriber that falls more than
+    /// `UPDATE_CHANNEL_CAPACITY` records behind gets `RecvError::Lagged` and
+    /// misses the oldest updates.
+    pub fn subscribe(&self) -> broadcast::Receiver<Record> {
+        self.updates.subscribe()
+    }
+    
+    fn has_subscribers(&self) -> bool {
+        self.updates.receiver_count() > 0
+    }
+    
+    fn publish(&self, record: Record) {
+        // Only fails when every subscriber has gone away, which is fine
+        let _ = self.updates.send(record);
+    }
+
+    /// Ingest counters since the engine was opened, with the current write pressure
+    pub fn stats(&self) -> IngestStats {
+        let unflushed = self.unflushed_bytes();
+        IngestStats {
+            unflushed_bytes: unflushed as u64,
+            max_unflushed_bytes: self.max_unflushed_bytes.map(|limit| limit as u64),
+            backpressure: self.max_unflushed_bytes.is_some_and(|limit| unflushed > limit),
+            ..self.ingest.snapshot()
+        }
+    }
+
+    /// Set debug settings for performance testing
+    pub fn set_debug_settings(&self, memory_mode: bool, disable_wal: bool, batch_size: Option<usize>) -> Result<(), StorageError> {
+        let mut debug_settings = self.debug_mode.write().unwrap();
+        debug_settings.memory_mode = memory_mode;
         debug_settings.disable_wal = disable_wal;
         
         if let Some(size) = batch_size {
@@ -567,54 +1977,1601 @@ impl StorageEngine {
         
         Ok(())
     }
+
+    /// Preferred number of records per batched write
+    pub fn batch_size(&self) -> usize {
+        self.debug_mode.read().unwrap().batch_size.max(1)
+    }
 }
 
 // Add this function outside the StorageEngine implementation
 // to make it available for the query engine
 pub fn chunk_id_for_timestamp(timestamp: i64, chunk_duration: Duration) -> i64 {
-    timestamp - (timestamp % chunk_duration.as_secs() as i64)
+    // Rounds down, so negative timestamps land in the chunk covering them too; near
+    // i64::MIN the start saturates, leaving a chunk that still covers the timestamp
+    match i64::try_from(chunk_duration.as_secs()) {
+        Ok(duration) if duration > 0 => timestamp.saturating_sub(timestamp.rem_euclid(duration)),
+        _ => timestamp,
+    }
+}
+
+/// End of the aligned chunk starting at `chunk_id`
+///
+/// Errors rather than wrapping when the chunk would end past `i64::MAX`.
+pub fn chunk_end_for(chunk_id: i64, chunk_duration: Duration) -> Result<i64, StorageError> {
+    i64::try_from(chunk_duration.as_secs()).ok()
+        .and_then(|duration| chunk_id.checked_add(duration))
+        .ok_or_else(|| StorageError::InvalidTimeRange(format!(
+            "Chunk starting at {} would end past the largest supported timestamp", chunk_id
+        )))
+}
+
+/// Whether `outer` holds a record at every metric and timestamp `inner` does
+fn holds_all(outer: &TimeChunk, inner: &TimeChunk) -> bool {
+    inner.records.iter().all(|(metric, records)| {
+        let Some(outer_records) = outer.records.get(metric) else {
+            return records.is_empty();
+        };
+        let timestamps: HashSet<i64> = outer_records.iter().map(|r| r.timestamp).collect();
+        records.iter().all(|r| timestamps.contains(&r.timestamp))
+    })
+}
+
+/// Save chunks on up to `FLUSH_WORKERS` threads, returning one result per chunk in order
+///
+/// The chunks are copies, so no lock is held while the saves run; writes
+/// that land meanwhile are left for the next flush.
+fn save_chunks_parallel(persistence: &PersistenceManager, chunks: &[(i64, TimeChunk)]) -> Vec<Result<(), StorageError>> {
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, Result<(), StorageError>)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..FLUSH_WORKERS.min(chunks.len()))
+            .map(|_| scope.spawn(|| {
+                let mut saved = Vec::new();
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some((_, chunk)) = chunks.get(i) else {
+                        break saved;
+                    };
+                    saved.push((i, persistence.save_chunk(chunk)));
+                }
+            }))
+            .collect();
+        workers.into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use std::time::Duration;
+    use crate::config::{ChunkLayout, FsyncPolicy, WalFormat};
 
     fn create_test_config() -> Config {
         Config {
             storage: crate::config::StorageConfig {
                 path: "./data".to_string(),
                 max_chunk_size: 1048576,
+                wal_fsync: FsyncPolicy::Always,
+                max_resident_chunks: None,
+                rollup_resolution: None,
+                chunk_layout: ChunkLayout::default(),
+                metric_capacity_hint: None,
+                max_unflushed_bytes: None,
+                wal_format: WalFormat::default(),
             },
             api: crate::config::ApiConfig {
                 host: "127.0.0.1".to_string(),
                 port: 5432,
+                auth_token: None,
+                strict_body: false,
+                default_query_window: None,
+                max_query_span: None,
             },
             chunk_duration: Duration::from_secs(3600),
+            query_cache_size: 16,
+            value_decimals: None,
+            unit_conversions: std::collections::HashMap::new(),
         }
     }
 
+    /// Scratch directory under the system temp dir, removed again on drop
+    pub(crate) struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("emberdb-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            TempDir(path)
+        }
+    }
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempDir {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Test config whose storage path is a fresh `TempDir`; keep the guard alive for the test
+    fn temp_config(name: &str) -> (Config, TempDir) {
+        let dir = TempDir::new(name);
+        let mut config = create_test_config();
+        config.storage.path = dir.to_string_lossy().to_string();
+        (config, dir)
+    }
+
+    /// Where a test store in `dir` keeps a chunk's file
+    fn chunk_file(dir: &Path, chunk_id: i64) -> PathBuf {
+        persistence::chunk_file_path(&dir.join("chunks"), ChunkLayout::default(), chunk_id)
+    }
+
     #[test]
     fn test_basic_operations() {
-        let config = create_test_config();
-        let storage = StorageEngine::new(&config).unwrap();
-        
-        // Disable persistence for tests
-        let mut storage_mut = unsafe { &mut *((&storage) as *const StorageEngine as *mut StorageEngine) };
-        storage_mut.set_persistence(false);
+        let storage = StorageEngine::new_in_memory(Duration::from_secs(3600));
 
         let record = Record {
             timestamp: 1000,
             metric_name: "test".to_string(),
             value: 42.0,
+            string_value: None,
             context: HashMap::new(),
             resource_type: "Observation".to_string(),
+            id: None,
         };
 
         assert!(storage.insert(record.clone()).is_ok());
         
         let result = storage.get_latest("test");
         assert!(result.is_ok());
-        assert_eq!(result.unwrap().value, 42.0);
+        assert_eq!(result.unwrap().unwrap().value, 42.0);
+    }
+
+    #[test]
+    fn test_wal_replay_skips_durable_records() {
+        let (config, _dir) = temp_config("wal-replay");
+        
+        let make_record = |timestamp: i64, value: f64| Record {
+            timestamp,
+            metric_name: "p1|8867-4|bpm".to_string(),
+            value,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        
+        {
+            let storage = StorageEngine::new(&config).unwrap();
+            storage.insert(make_record(1000, 60.0)).unwrap();
+            storage.insert(make_record(1010, 62.0)).unwrap();
+            
+            // Persist the chunk but leave the WAL untouched, as if we crashed before truncation
+            let chunk = storage.chunks.read().unwrap().get(&0).cloned().unwrap();
+            storage.persistence.as_ref().unwrap().save_chunk(&chunk).unwrap();
+            
+            // A record that only made it to the WAL
+            storage.insert(make_record(1020, 64.0)).unwrap();
+        }
+        
+        // Recover twice to make sure repeated restarts don't multiply records
+        for _ in 0..2 {
+            let storage = StorageEngine::new(&config).unwrap();
+            let records = storage.query_range(0, 3600, "p1|8867-4|bpm").unwrap();
+            let values: Vec<f64> = records.iter().map(|r| r.value).collect();
+            assert_eq!(values, vec![60.0, 62.0, 64.0]);
+            
+            // Persist what we recovered, again without truncating the WAL
+            let chunk = storage.chunks.read().unwrap().get(&0).cloned().unwrap();
+            storage.persistence.as_ref().unwrap().save_chunk(&chunk).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_wal_replay_keeps_writes_to_durable_timestamps() {
+        let (config, _dir) = temp_config("wal-replay-rewrites");
+        let make_record = |metric: &str, value: f64| Record {
+            timestamp: 1000,
+            metric_name: metric.to_string(),
+            value,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        
+        {
+            let storage = StorageEngine::new(&config).unwrap();
+            // Last write wins after a full flush truncated the WAL
+            storage.insert_dedup(make_record("rw|dedup|bpm", 60.0)).unwrap();
+            storage.insert(make_record("rw|fixed|bpm", 3.7)).unwrap();
+            storage.flush_all().unwrap();
+            storage.insert_dedup(make_record("rw|dedup|bpm", 61.0)).unwrap();
+            
+            // The chunk is flushed again but the WAL kept, as on eviction, then
+            // takes a repeat of a record it already holds
+            storage.insert(make_record("rw|append|bpm", 70.0)).unwrap();
+            storage.insert(make_record("rw|append|bpm", 71.0)).unwrap();
+            assert!(storage.update_record("rw|fixed|bpm", 1000, 37.0).unwrap());
+            assert!(storage.flush_chunk(0).unwrap());
+            storage.insert(make_record("rw|append|bpm", 70.0)).unwrap();
+        }
+        
+        for _ in 0..2 {
+            let storage = StorageEngine::new(&config).unwrap();
+            let values = |metric: &str| -> Vec<f64> {
+                storage.query_range(0, 3600, metric).unwrap().iter().map(|r| r.value).collect()
+            };
+            assert_eq!(values("rw|dedup|bpm"), vec![61.0]);
+            assert_eq!(values("rw|append|bpm"), vec![70.0, 71.0, 70.0]);
+            assert_eq!(values("rw|fixed|bpm"), vec![37.0]);
+        }
+    }
+
+    #[test]
+    fn test_query_range_streaming_counts_large_range() {
+        let storage = StorageEngine::new_in_memory(Duration::from_secs(3600));
+        
+        // One record a minute for a week spans 168 one-hour chunks
+        let total = 7 * 24 * 60;
+        for i in 0..total {
+            storage.insert(Record {
+                timestamp: i * 60,
+                metric_name: "stream|8867-4|bpm".to_string(),
+                value: (i % 100) as f64,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).unwrap();
+        }
+        
+        // Only a running count and the last timestamp are kept while streaming
+        let mut count = 0;
+        let mut last_timestamp = -1;
+        let visited = storage.query_range_streaming(0, total * 60, "stream|8867-4|bpm", |record| {
+            assert!(record.timestamp > last_timestamp);
+            last_timestamp = record.timestamp;
+            count += 1;
+            true
+        }).unwrap();
+        
+        assert_eq!(count, total as usize);
+        assert_eq!(visited, total as usize);
+        
+        // Stopping early visits no more records
+        let visited = storage.query_range_streaming(0, total * 60, "stream|8867-4|bpm", |_| false).unwrap();
+        assert_eq!(visited, 1);
+    }
+
+    #[test]
+    fn test_insert_appends_duplicate_timestamps() {
+        let storage = StorageEngine::new_in_memory(Duration::from_secs(3600));
+        
+        let mut record = Record {
+            timestamp: 1000,
+            metric_name: "dup|8867-4|bpm".to_string(),
+            value: 60.0,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        storage.insert(record.clone()).unwrap();
+        record.value = 61.0;
+        storage.insert(record).unwrap();
+        
+        let values: Vec<f64> = storage.query_range(0, 3600, "dup|8867-4|bpm").unwrap()
+            .iter().map(|r| r.value).collect();
+        assert_eq!(values, vec![60.0, 61.0]);
+    }
+
+    #[test]
+    fn test_insert_dedup_last_write_wins() {
+        let (config, _dir) = temp_config("dedup");
+        
+        let mut record = Record {
+            timestamp: 1000,
+            metric_name: "dedup|8867-4|bpm".to_string(),
+            value: 60.0,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        
+        {
+            let storage = StorageEngine::new(&config).unwrap();
+            storage.insert_dedup(record.clone()).unwrap();
+            record.value = 61.0;
+            storage.insert_dedup(record.clone()).unwrap();
+            
+            let values: Vec<f64> = storage.query_range(0, 3600, "dedup|8867-4|bpm").unwrap()
+                .iter().map(|r| r.value).collect();
+            assert_eq!(values, vec![61.0]);
+        }
+        
+        // Replaying the WAL keeps last-write-wins semantics
+        let storage = StorageEngine::new(&config).unwrap();
+        let values: Vec<f64> = storage.query_range(0, 3600, "dedup|8867-4|bpm").unwrap()
+            .iter().map(|r| r.value).collect();
+        assert_eq!(values, vec![61.0]);
+    }
+
+    #[test]
+    fn test_update_record_survives_recovery() {
+        let (config, _dir) = temp_config("update");
+        
+        {
+            let storage = StorageEngine::new(&config).unwrap();
+            storage.insert(Record {
+                timestamp: 1000,
+                metric_name: "upd|8310-5|Cel".to_string(),
+                value: 3.7, // mis-entered 37.0
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).unwrap();
+            
+            assert!(storage.update_record("upd|8310-5|Cel", 1000, 37.0).unwrap());
+            assert!(storage.chunks.read().unwrap()[&0].is_dirty());
+            assert_eq!(storage.get_latest("upd|8310-5|Cel").unwrap().unwrap().value, 37.0);
+        }
+        
+        let storage = StorageEngine::new(&config).unwrap();
+        let records = storage.query_range(0, 3600, "upd|8310-5|Cel").unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].value, 37.0);
+    }
+
+    #[test]
+    fn test_insert_durable_survives_recovery() {
+        let (mut config, _dir) = temp_config("durable");
+        // Left to the policy, nothing would be synced for an hour
+        config.storage.wal_fsync = FsyncPolicy::Interval(Duration::from_secs(3600));
+        
+        let record = Record {
+            timestamp: 1000,
+            metric_name: "dur|med-1|mg".to_string(),
+            value: 5.0,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "MedicationAdministration".to_string(),
+            id: None,
+        };
+        {
+            let storage = StorageEngine::new(&config).unwrap();
+            storage.insert_durable(record.clone()).unwrap();
+        }
+        
+        let storage = StorageEngine::new(&config).unwrap();
+        assert_eq!(storage.query_range(0, 3600, "dur|med-1|mg").unwrap(), vec![record.clone()]);
+        
+        let in_memory = StorageEngine::new_in_memory(Duration::from_secs(3600));
+        assert!(in_memory.insert_durable(record).is_err());
+        assert!(in_memory.get_latest("dur|med-1|mg").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_extreme_timestamps_are_rejected_not_wrapped() {
+        let storage = StorageEngine::new_in_memory(Duration::from_secs(3600));
+        let record = |timestamp: i64| Record {
+            timestamp,
+            metric_name: "edge|8867-4|bpm".to_string(),
+            value: 70.0,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        
+        // The chunk holding i64::MAX would end past it
+        assert!(matches!(storage.insert(record(i64::MAX - 1)), Err(StorageError::InvalidTimeRange(_))));
+        assert!(matches!(storage.insert_dedup(record(i64::MAX)), Err(StorageError::InvalidTimeRange(_))));
+        
+        let last_chunk = chunk_id_for_timestamp(i64::MAX, Duration::from_secs(3600));
+        storage.insert(record(last_chunk - 1)).unwrap();
+        storage.insert(record(i64::MIN + 1)).unwrap();
+        assert_eq!(storage.query_range(last_chunk - 3600, last_chunk, "edge|8867-4|bpm").unwrap().len(), 1);
+        assert_eq!(storage.query_range(i64::MIN, i64::MIN + 3600, "edge|8867-4|bpm").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_resource_type_index_rebuilt_on_load() {
+        let (config, dir) = temp_config("resource-index");
+        
+        {
+            let storage = StorageEngine::new(&config).unwrap();
+            storage.insert(Record {
+                timestamp: 1000,
+                metric_name: "idx|med-1|mg".to_string(),
+                value: 5.0,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "MedicationAdministration".to_string(),
+                id: None,
+            }).unwrap();
+            storage.flush_all().unwrap();
+        }
+        
+        // A chunk file without the index, as written before it was persisted
+        let chunk_path = chunk_file(&dir, 0);
+        let mut chunk: serde_json::Value = serde_json::from_slice(&std::fs::read(&chunk_path).unwrap()).unwrap();
+        chunk.as_object_mut().unwrap().remove("resource_metrics");
+        std::fs::write(&chunk_path, serde_json::to_vec(&chunk).unwrap()).unwrap();
+        
+        // get_metrics_by_resource_type only consults the index, never the fallback scan
+        let storage = StorageEngine::new(&config).unwrap();
+        assert_eq!(storage.index.read().unwrap().chunks_for_resource_type("MedicationAdministration"), &[0]);
+        assert_eq!(storage.get_metrics_by_resource_type("MedicationAdministration").unwrap(), vec!["idx|med-1|mg".to_string()]);
+        assert_eq!(storage.query_by_resource_type("MedicationAdministration", 0, 3600).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_rename_metric_moves_records_and_survives_recovery() {
+        let (config, _dir) = temp_config("rename");
+        let (old, new) = ("ren|2339-0|mg/dL", "ren|2345-7|mg/dL"); // Glucose logged under the wrong LOINC code
+        
+        {
+            let storage = StorageEngine::new(&config).unwrap();
+            for (timestamp, value) in [(1000, 95.0), (2000, 101.0), (5000, 110.0)] {
+                storage.insert(Record {
+                    timestamp,
+                    metric_name: old.to_string(),
+                    value,
+                    string_value: None,
+                    context: HashMap::new(),
+                    resource_type: "Observation".to_string(),
+                    id: None,
+                }).unwrap();
+            }
+            
+            assert_eq!(storage.rename_metric(old, new).unwrap(), 3);
+            assert!(storage.query_range(0, 10_000, old).unwrap().is_empty());
+            let records = storage.query_range(0, 10_000, new).unwrap();
+            assert_eq!(records.len(), 3);
+            assert!(records.iter().all(|r| r.metric_name == new));
+            assert!(storage.chunks.read().unwrap().values().all(|chunk| chunk.is_dirty()));
+            assert_eq!(storage.rename_metric(old, new).unwrap(), 0);
+        }
+        
+        // Replaying the WAL applies the rename after the inserts
+        let storage = StorageEngine::new(&config).unwrap();
+        assert!(storage.query_range(0, 10_000, old).unwrap().is_empty());
+        assert_eq!(storage.query_range(0, 10_000, new).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_update_missing_record_is_noop() {
+        let storage = StorageEngine::new_in_memory(Duration::from_secs(3600));
+        
+        storage.insert(Record {
+            timestamp: 1000,
+            metric_name: "upd-missing|8310-5|Cel".to_string(),
+            value: 37.0,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        }).unwrap();
+        
+        assert!(!storage.update_record("upd-missing|8310-5|Cel", 1001, 38.0).unwrap());
+        assert!(!storage.update_record("upd-missing|8310-5|Cel", 90_000, 38.0).unwrap());
+        assert_eq!(storage.get_latest("upd-missing|8310-5|Cel").unwrap().unwrap().value, 37.0);
+    }
+
+    #[test]
+    fn test_snapshot_is_loadable_by_fresh_engine() {
+        let (config, _dir) = temp_config("snapshot-src");
+        let dest = TempDir::new("snapshot-dest");
+        
+        // Spread records over several chunks
+        let storage = StorageEngine::new(&config).unwrap();
+        for i in 0..30 {
+            storage.insert(Record {
+                timestamp: i * 600,
+                metric_name: "snap|8867-4|bpm".to_string(),
+                value: 60.0 + i as f64,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).unwrap();
+        }
+        storage.snapshot(&dest).unwrap();
+        let original = storage.query_range(0, 30 * 600, "snap|8867-4|bpm").unwrap();
+        
+        let mut snapshot_config = create_test_config();
+        snapshot_config.storage.path = dest.to_string_lossy().to_string();
+        let restored = StorageEngine::new(&snapshot_config).unwrap();
+        let copied = restored.query_range(0, 30 * 600, "snap|8867-4|bpm").unwrap();
+        
+        assert_eq!(copied.len(), 30);
+        let pairs = |records: &[Record]| records.iter().map(|r| (r.timestamp, r.value)).collect::<Vec<_>>();
+        assert_eq!(pairs(&copied), pairs(&original));
+    }
+
+    #[test]
+    fn test_snapshot_rejects_unsafe_destinations() {
+        let (config, dir) = temp_config("snapshot-unsafe-src");
+        let dest = TempDir::new("snapshot-unsafe-dest");
+        let storage = StorageEngine::new(&config).unwrap();
+        storage.insert(Record {
+            timestamp: 100,
+            metric_name: "snap-unsafe|8867-4|bpm".to_string(),
+            value: 70.0,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        }).unwrap();
+        
+        let invalid = |dest: &Path| matches!(storage.snapshot(dest), Err(StorageError::InvalidSnapshotDestination(_)));
+        assert!(invalid(&dir));
+        assert!(invalid(&dir.join("backup")));
+        assert!(invalid(dir.parent().unwrap()));
+        std::fs::create_dir_all(dest.join("stale")).unwrap();
+        assert!(invalid(&dest));
+        assert!(!dir.join("backup").exists());
+        
+        // An empty directory is filled in, with nothing staged left beside it
+        std::fs::remove_dir(dest.join("stale")).unwrap();
+        storage.snapshot(&dest).unwrap();
+        let staged = dest.with_file_name(format!(".{}.partial", dest.file_name().unwrap().to_string_lossy()));
+        assert!(!staged.exists());
+        
+        // The live store still recovers its writes
+        drop(storage);
+        let reopened = StorageEngine::new(&config).unwrap();
+        assert_eq!(reopened.query_range(0, 200, "snap-unsafe|8867-4|bpm").unwrap().len(), 1);
+        let mut snapshot_config = create_test_config();
+        snapshot_config.storage.path = dest.to_string_lossy().to_string();
+        let restored = StorageEngine::new(&snapshot_config).unwrap();
+        assert_eq!(restored.query_range(0, 200, "snap-unsafe|8867-4|bpm").unwrap().len(), 1);
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_snapshot_during_writes_is_point_in_time() {
+        let (config, _dir) = temp_config("snapshot-live-src");
+        let dest = TempDir::new("snapshot-live-dest");
+        let storage = Arc::new(StorageEngine::new(&config).unwrap());
+        
+        let writer = {
+            let storage = Arc::clone(&storage);
+            std::thread::spawn(move || {
+                for i in 0..500 {
+                    storage.insert(Record {
+                        timestamp: i * 60,
+                        metric_name: "snap-live|8867-4|bpm".to_string(),
+                        value: i as f64,
+                        string_value: None,
+                        context: HashMap::new(),
+                        resource_type: "Observation".to_string(),
+                        id: None,
+                    }).unwrap();
+                }
+            })
+        };
+        while storage.query_range(0, 500 * 60, "snap-live|8867-4|bpm").unwrap().len() < 100 {
+            std::thread::yield_now();
+        }
+        storage.snapshot(&dest).unwrap();
+        writer.join().unwrap();
+        
+        // Records go in one after another, so a consistent copy has no gaps
+        let mut snapshot_config = create_test_config();
+        snapshot_config.storage.path = dest.to_string_lossy().to_string();
+        let restored = StorageEngine::new(&snapshot_config).unwrap();
+        let copied = restored.query_range(0, 500 * 60, "snap-live|8867-4|bpm").unwrap();
+        assert!(copied.len() >= 100);
+        let timestamps: Vec<i64> = copied.iter().map(|r| r.timestamp).collect();
+        assert_eq!(timestamps, (0..copied.len() as i64).map(|i| i * 60).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_verify_integrity_reports_bad_chunks() {
+        let (config, dir) = temp_config("verify");
+        
+        let storage = StorageEngine::new(&config).unwrap();
+        let make_record = |timestamp: i64| Record {
+            timestamp,
+            metric_name: "verify|8867-4|bpm".to_string(),
+            value: 70.0,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        
+        // A healthy chunk
+        storage.insert(make_record(100)).unwrap();
+        storage.flush_all().unwrap();
+        
+        // A chunk holding a record from well outside its range
+        let mut bad_chunk = TimeChunk::new(3600, 7200);
+        bad_chunk.append(make_record(3700)).unwrap();
+        bad_chunk.records.get_mut("verify|8867-4|bpm").unwrap().push(make_record(90000));
+        storage.persistence.as_ref().unwrap().save_chunk(&bad_chunk).unwrap();
+        
+        // And one that isn't a chunk at all
+        std::fs::write(chunk_file(&dir, 10800), b"not json").unwrap();
+        
+        let report = storage.verify_integrity().unwrap();
+        assert_eq!(report.chunks_checked, 3);
+        assert!(!report.is_ok());
+        
+        let failed: Vec<i64> = report.failures.iter().map(|f| f.chunk_id).collect();
+        assert_eq!(failed, vec![3600, 10800]);
+        assert!(report.failures[0].error.contains("outside chunk range"), "{}", report.failures[0].error);
+        assert!(report.failures[1].error.contains("deserialize"), "{}", report.failures[1].error);
+    }
+
+    #[test]
+    fn test_idle_chunks_compress_and_decompress_on_query() {
+        let storage = StorageEngine::new_in_memory(Duration::from_secs(3600));
+        
+        for i in 0..50 {
+            storage.insert(Record {
+                timestamp: i * 60,
+                metric_name: "warm|8867-4|bpm".to_string(),
+                value: 70.0 + (i % 5) as f64,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).unwrap();
+        }
+        let expected = storage.query_range(0, 3600, "warm|8867-4|bpm").unwrap();
+        
+        // Nothing is idle yet
+        assert_eq!(storage.compress_idle_chunks(Duration::from_secs(600)).unwrap(), 0);
+        
+        // Age the chunk past the threshold
+        storage.chunks.write().unwrap().get_mut(&0).unwrap().metadata.last_access -= 3600;
+        assert_eq!(storage.compress_idle_chunks(Duration::from_secs(600)).unwrap(), 1);
+        assert!(storage.chunks.read().unwrap()[&0].is_compressed());
+        
+        // Queries see the same data and leave the chunk decompressed
+        let records = storage.query_range(0, 3600, "warm|8867-4|bpm").unwrap();
+        assert!(!storage.chunks.read().unwrap()[&0].is_compressed());
+        let pairs = |records: &[Record]| records.iter().map(|r| (r.timestamp, r.value)).collect::<Vec<_>>();
+        assert_eq!(pairs(&records), pairs(&expected));
+        
+        // get_latest decompresses too
+        storage.chunks.write().unwrap().get_mut(&0).unwrap().metadata.last_access -= 3600;
+        assert_eq!(storage.compress_idle_chunks(Duration::from_secs(600)).unwrap(), 1);
+        assert_eq!(storage.get_latest("warm|8867-4|bpm").unwrap().unwrap().timestamp, 49 * 60);
+        assert!(!storage.chunks.read().unwrap()[&0].is_compressed());
+    }
+
+    #[test]
+    fn test_ingest_stats_count_inserts() {
+        let (config, _dir) = temp_config("ingest-stats");
+        
+        let storage = Arc::new(StorageEngine::new(&config).unwrap());
+        
+        // Counters are shared across writer threads without a lock
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let storage = Arc::clone(&storage);
+                std::thread::spawn(move || {
+                    for i in 0..25 {
+                        storage.insert(Record {
+                            timestamp: t * 100 + i,
+                            metric_name: format!("stats{}|8867-4|bpm", t),
+                            value: 70.0,
+                            string_value: None,
+                            context: HashMap::new(),
+                            resource_type: "Observation".to_string(),
+                            id: None,
+                        }).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        
+        let stats = storage.stats();
+        assert_eq!(stats.records_inserted, 100);
+        assert!(stats.wal_bytes_written > 0);
+        assert_eq!(stats.chunks_persisted, 0);
+        
+        storage.flush_all().unwrap();
+        assert_eq!(storage.stats().chunks_persisted, 1);
+    }
+
+    #[test]
+    fn test_get_latest_n_returns_newest_first() {
+        let storage = StorageEngine::new_in_memory(Duration::from_secs(600));
+        
+        // 50 points a minute apart span several chunks
+        for i in 0..50 {
+            storage.insert(Record {
+                timestamp: i * 60,
+                metric_name: "latest|8867-4|bpm".to_string(),
+                value: i as f64,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).unwrap();
+        }
+        
+        let latest = storage.get_latest_n("latest|8867-4|bpm", 10).unwrap();
+        let timestamps: Vec<i64> = latest.iter().map(|r| r.timestamp).collect();
+        assert_eq!(timestamps, (40..50).rev().map(|i| i * 60).collect::<Vec<_>>());
+        
+        assert_eq!(storage.get_latest_n("latest|8867-4|bpm", 100).unwrap().len(), 50);
+        assert!(storage.get_latest_n("latest|8867-4|bpm", 0).unwrap().is_empty());
+        assert!(storage.get_latest_n("missing|0000-0|x", 5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_in_memory_engine_end_to_end() {
+        let storage = StorageEngine::new_in_memory(Duration::from_secs(3600));
+        let make_record = |timestamp: i64, value: f64| Record {
+            timestamp,
+            metric_name: "mem|8867-4|bpm".to_string(),
+            value,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        
+        // Plain, dedup, batch and WAL-append paths all work without a backend
+        storage.insert(make_record(100, 60.0)).unwrap();
+        storage.insert_dedup(make_record(100, 61.0)).unwrap();
+        storage.append_records_to_wal(vec![make_record(4000, 62.0)]).unwrap();
+        storage.insert_batch(3600, vec![make_record(4000, 62.0), make_record(4100, 63.0)]).unwrap();
+        assert!(storage.update_record("mem|8867-4|bpm", 4100, 64.0).unwrap());
+        
+        let values: Vec<f64> = storage.query_range(0, 7200, "mem|8867-4|bpm").unwrap()
+            .iter().map(|r| r.value).collect();
+        assert_eq!(values, vec![61.0, 62.0, 64.0]);
+        assert_eq!(storage.get_latest("mem|8867-4|bpm").unwrap().unwrap().value, 64.0);
+        assert_eq!(storage.get_matching_metrics("mem|").unwrap(), vec!["mem|8867-4|bpm".to_string()]);
+        
+        // Flushing is a no-op, and there is nothing on disk to snapshot or verify
+        storage.flush_all().unwrap();
+        assert!(storage.snapshot(&std::env::temp_dir().join("emberdb-in-memory-snapshot")).is_err());
+        assert_eq!(storage.verify_integrity().unwrap().chunks_checked, 0);
+        assert_eq!(storage.stats().wal_bytes_written, 0);
+    }
+
+    #[test]
+    fn test_chunk_index_matches_brute_force_scan() {
+        let storage = StorageEngine::new_in_memory(Duration::from_secs(3600));
+        let now = chrono::Utc::now().timestamp();
+        let make_record = |timestamp: i64, metric: &str, resource_type: &str| Record {
+            timestamp,
+            metric_name: metric.to_string(),
+            value: 1.0,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: resource_type.to_string(),
+            id: None,
+        };
+        
+        for hours_ago in 0..10 {
+            let timestamp = now - hours_ago * 3600;
+            storage.insert(make_record(timestamp, &format!("idx|{}|a", hours_ago % 3), "Observation")).unwrap();
+            if hours_ago % 2 == 0 {
+                storage.insert_dedup(make_record(timestamp, "idx|dedup|b", "VitalSigns")).unwrap();
+            }
+        }
+        let batch_chunk = storage.chunk_id_for(now - 7 * 3600);
+        storage.insert_batch(batch_chunk, vec![
+            make_record(batch_chunk, "idx|batch|c", "Condition"),
+            make_record(batch_chunk + 1, "idx|batch|d", "Observation"),
+        ]).unwrap();
+        
+        let assert_consistent = |storage: &StorageEngine| {
+            let chunks = storage.chunks.read().unwrap();
+            let index = storage.index.read().unwrap();
+            
+            let mut metrics: Vec<String> = chunks.values().flat_map(|c| c.get_metrics_list()).collect();
+            metrics.sort_unstable();
+            metrics.dedup();
+            let mut indexed: Vec<String> = index.metrics().cloned().collect();
+            indexed.sort_unstable();
+            assert_eq!(indexed, metrics);
+            
+            for metric in &metrics {
+                let mut expected: Vec<i64> = chunks.iter()
+                    .filter(|(_, chunk)| chunk.has_metric(metric))
+                    .map(|(&id, _)| id)
+                    .collect();
+                expected.sort_unstable();
+                assert_eq!(index.chunks_for_metric(metric), expected.as_slice(), "metric {}", metric);
+            }
+            for resource_type in ["Observation", "VitalSigns", "Condition", "Patient"] {
+                let mut expected: Vec<i64> = chunks.iter()
+                    .filter(|(_, chunk)| chunk.resource_metrics.contains_key(resource_type))
+                    .map(|(&id, _)| id)
+                    .collect();
+                expected.sort_unstable();
+                assert_eq!(index.chunks_for_resource_type(resource_type), expected.as_slice());
+            }
+        };
+        
+        assert_consistent(&storage);
+        
+        // Drop the chunks older than five hours
+        storage.cleanup_old_chunks(Duration::from_secs(5 * 3600)).unwrap();
+        assert_consistent(&storage);
+        assert!(storage.index.read().unwrap().chunks_for_metric("idx|batch|c").is_empty());
+        assert!(storage.get_metrics_by_resource_type("Condition").unwrap().is_empty());
+        
+        storage.insert(make_record(now - 8 * 3600, "idx|batch|c", "Condition")).unwrap();
+        assert_consistent(&storage);
+        assert_eq!(storage.get_metrics_by_resource_type("Condition").unwrap(), vec!["idx|batch|c".to_string()]);
+    }
+
+    #[test]
+    fn test_query_range_spans_chunk_without_metric() {
+        let storage = StorageEngine::new_in_memory(Duration::from_secs(3600));
+        let make_record = |timestamp: i64, metric: &str| Record {
+            timestamp,
+            metric_name: metric.to_string(),
+            value: timestamp as f64,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        
+        // The middle chunk only has data for a different metric
+        storage.insert(make_record(100, "gap|8867-4|bpm")).unwrap();
+        storage.insert(make_record(3700, "gap|2339-0|mg/dL")).unwrap();
+        storage.insert(make_record(7300, "gap|8867-4|bpm")).unwrap();
+        
+        let timestamps: Vec<i64> = storage.query_range(0, 10800, "gap|8867-4|bpm").unwrap()
+            .iter().map(|r| r.timestamp).collect();
+        assert_eq!(timestamps, vec![100, 7300]);
+        
+        let many = storage.query_range_many(0, 10800, &["gap|8867-4|bpm".to_string()]).unwrap();
+        assert_eq!(many["gap|8867-4|bpm"].as_ref().unwrap().len(), 2);
+        
+        let mut streamed = 0;
+        storage.query_range_streaming(0, 10800, "gap|8867-4|bpm", |_| { streamed += 1; true }).unwrap();
+        assert_eq!(streamed, 2);
+        
+        // A range covering only the gap is empty rather than an error
+        assert!(storage.query_range(3600, 7200, "gap|8867-4|bpm").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_lru_eviction_caps_resident_chunks() {
+        let (mut config, _dir) = temp_config("eviction");
+        config.storage.max_resident_chunks = Some(2);
+        
+        let make_record = |timestamp: i64, value: f64| Record {
+            timestamp,
+            metric_name: "evict|8867-4|bpm".to_string(),
+            value,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        
+        {
+            let storage = StorageEngine::new(&config).unwrap();
+            for hour in 0..6 {
+                storage.insert(make_record(hour * 3600, hour as f64)).unwrap();
+                storage.insert(make_record(hour * 3600 + 60, hour as f64)).unwrap();
+                assert!(storage.resident_chunk_count() <= 2);
+            }
+            assert_eq!(storage.resident_chunk_count(), 2);
+            
+            // Reads reload evicted chunks and shrink back to the cap afterwards
+            assert_eq!(storage.query_range(0, 6 * 3600, "evict|8867-4|bpm").unwrap().len(), 12);
+            assert_eq!(storage.resident_chunk_count(), 2);
+            assert_eq!(storage.get_latest_n("evict|8867-4|bpm", 12).unwrap().len(), 12);
+            assert_eq!(storage.get_matching_metrics("evict|").unwrap().len(), 1);
+            assert_eq!(storage.get_metrics_by_resource_type("Observation").unwrap().len(), 1);
+            
+            // Writes to an evicted chunk land on top of its flushed records
+            assert!(storage.update_record("evict|8867-4|bpm", 60, 42.0).unwrap());
+            storage.insert(make_record(120, 7.0)).unwrap();
+            let values: Vec<f64> = storage.query_range(0, 3600, "evict|8867-4|bpm").unwrap()
+                .iter().map(|r| r.value).collect();
+            assert_eq!(values, vec![0.0, 42.0, 7.0]);
+            assert_eq!(storage.resident_chunk_count(), 2);
+            
+            storage.flush_all().unwrap();
+        }
+        
+        // Recovery also respects the cap
+        let storage = StorageEngine::new(&config).unwrap();
+        assert_eq!(storage.resident_chunk_count(), 2);
+        assert_eq!(storage.query_range(0, 6 * 3600, "evict|8867-4|bpm").unwrap().len(), 13);
+        assert_eq!(storage.get_latest("evict|8867-4|bpm").unwrap().unwrap().timestamp, 5 * 3600 + 60);
+    }
+
+    #[test]
+    fn test_streaming_scans_keep_their_chunks_under_concurrent_eviction() {
+        let (mut config, _dir) = temp_config("streaming-eviction");
+        config.storage.max_resident_chunks = Some(2);
+        let storage = Arc::new(StorageEngine::new(&config).unwrap());
+        for hour in 0..6 {
+            storage.insert(Record {
+                timestamp: hour * 3600 + 60,
+                metric_name: "pin|8867-4|bpm".to_string(),
+                value: hour as f64,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).unwrap();
+        }
+        
+        // Each scan wants a different window, so the others keep evicting its chunks
+        let scans: Vec<_> = (0..4).map(|offset| {
+            let storage = Arc::clone(&storage);
+            std::thread::spawn(move || {
+                let (start, end) = (offset * 3600, (offset + 3) * 3600);
+                for _ in 0..50 {
+                    let visited = storage.query_range_streaming(start, end, "pin|8867-4|bpm", |_| true).unwrap();
+                    assert_eq!(visited, 3);
+                }
+            })
+        }).collect();
+        for scan in scans {
+            scan.join().unwrap();
+        }
+        
+        assert!(storage.pinned.lock().unwrap().is_empty());
+        assert_eq!(storage.resident_chunk_count(), 2);
+    }
+
+    #[test]
+    fn test_flush_all_keeps_writes_that_land_while_it_saves() {
+        let (config, _dir) = temp_config("flush-race");
+        let storage = Arc::new(StorageEngine::new(&config).unwrap());
+        
+        let writers: Vec<_> = (0..4).map(|writer| {
+            let storage = Arc::clone(&storage);
+            std::thread::spawn(move || {
+                for i in 0..200 {
+                    storage.insert(Record {
+                        timestamp: i * 10 + writer,
+                        metric_name: "race|8867-4|bpm".to_string(),
+                        value: i as f64,
+                        string_value: None,
+                        context: HashMap::new(),
+                        resource_type: "Observation".to_string(),
+                        id: None,
+                    }).unwrap();
+                }
+            })
+        }).collect();
+        for _ in 0..20 {
+            storage.flush_all().unwrap();
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        
+        // Whatever the last flush missed must still be in the WAL
+        drop(storage);
+        let storage = StorageEngine::new(&config).unwrap();
+        assert_eq!(storage.query_range(0, 2000, "race|8867-4|bpm").unwrap().len(), 800);
+    }
+
+    #[test]
+    fn test_count_range_matches_query_range() {
+        let storage = StorageEngine::new_in_memory(Duration::from_secs(3600));
+        for i in 0..200 {
+            storage.insert(Record {
+                timestamp: i * 97,
+                metric_name: "count|8867-4|bpm".to_string(),
+                value: i as f64,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).unwrap();
+        }
+        
+        for (start, end) in [(0, 20_000), (1000, 9000), (3600, 7200), (50_000, 60_000)] {
+            let expected = storage.query_range(start, end, "count|8867-4|bpm").unwrap().len();
+            assert_eq!(storage.count_range(start, end, "count|8867-4|bpm").unwrap(), expected);
+        }
+        assert_eq!(storage.count_by_resource_type("Observation", 1000, 9000).unwrap(),
+                   storage.query_by_resource_type("Observation", 1000, 9000).unwrap().len());
+        assert_eq!(storage.count_range(0, 20_000, "count|missing|x").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_query_spans_chunks_from_an_older_chunk_duration() {
+        let (mut config, _dir) = temp_config("duration-change");
+        
+        let make_record = |timestamp: i64| Record {
+            timestamp,
+            metric_name: "resize|8867-4|bpm".to_string(),
+            value: timestamp as f64,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        
+        // Two 1h chunks, with records in both halves of each
+        {
+            let storage = StorageEngine::new(&config).unwrap();
+            for timestamp in [600, 2400, 4200, 6000] {
+                storage.insert(make_record(timestamp)).unwrap();
+            }
+            storage.flush_all().unwrap();
+        }
+        
+        config.chunk_duration = Duration::from_secs(1800);
+        let storage = StorageEngine::new(&config).unwrap();
+        storage.insert(make_record(6600)).unwrap(); // Lands in the old 1h chunk covering it
+        
+        let timestamps = |start, end| -> Vec<i64> {
+            let mut timestamps: Vec<i64> = storage.query_range(start, end, "resize|8867-4|bpm").unwrap()
+                .iter().map(|r| r.timestamp).collect();
+            timestamps.sort_unstable();
+            timestamps
+        };
+        assert_eq!(timestamps(0, 7200), vec![600, 2400, 4200, 6000, 6600]);
+        // Starting mid-way through an old 1h chunk still finds its later half
+        assert_eq!(timestamps(1800, 5400), vec![2400, 4200]);
+        assert_eq!(timestamps(5400, 7200), vec![6000, 6600]);
+        assert_eq!(storage.count_range(1800, 7200, "resize|8867-4|bpm").unwrap(), 4);
+    }
+
+    #[test]
+    fn test_compact_merges_small_chunks_on_disk() {
+        let (config, dir) = temp_config("compact");
+        let chunk_files = || (0..5).filter(|hour| chunk_file(&dir, hour * 3600).is_file()).count();
+        let make_record = |timestamp: i64| Record {
+            timestamp,
+            metric_name: "compact|8867-4|bpm".to_string(),
+            value: timestamp as f64,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        let timestamps = |storage: &StorageEngine| -> Vec<i64> {
+            let mut timestamps: Vec<i64> = storage.query_range(0, 20_000, "compact|8867-4|bpm").unwrap()
+                .iter().map(|r| r.timestamp).collect();
+            timestamps.sort_unstable();
+            timestamps
+        };
+        
+        {
+            let storage = StorageEngine::new(&config).unwrap();
+            // Out of order within a chunk, and one hour (7200-10800) left empty
+            for timestamp in [1200, 600, 4200, 11_000, 15_000] {
+                storage.insert(make_record(timestamp)).unwrap();
+            }
+            storage.flush_all().unwrap();
+            assert_eq!(chunk_files(), 4);
+            
+            let report = storage.compact().unwrap();
+            assert_eq!((report.chunks_before, report.chunks_after, report.records_moved), (4, 1, 3));
+            assert_eq!(chunk_files(), 1);
+            assert_eq!(timestamps(&storage), vec![600, 1200, 4200, 11_000, 15_000]);
+            
+            // Later writes inside the merged span go to the merged chunk
+            storage.insert(make_record(8000)).unwrap();
+            assert_eq!(storage.chunk_infos().len(), 1);
+        }
+        
+        let storage = StorageEngine::new(&config).unwrap();
+        assert_eq!(timestamps(&storage), vec![600, 1200, 4200, 8000, 11_000, 15_000]);
+    }
+
+    #[test]
+    fn test_compact_reloads_evicted_chunks_one_run_at_a_time() {
+        let (mut config, dir) = temp_config("compact-evicted");
+        config.storage.max_resident_chunks = Some(1);
+        let storage = StorageEngine::new(&config).unwrap();
+        for hour in 0..4 {
+            storage.insert(Record {
+                timestamp: hour * 3600 + 60,
+                metric_name: "compact-evicted|8867-4|bpm".to_string(),
+                value: hour as f64,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).unwrap();
+        }
+        storage.flush_all().unwrap();
+        assert_eq!(storage.resident_chunk_count(), 1);
+        
+        let report = storage.compact().unwrap();
+        assert_eq!((report.chunks_before, report.chunks_after, report.records_moved), (4, 1, 3));
+        assert_eq!(storage.resident_chunk_count(), 1);
+        assert!(storage.pinned.lock().unwrap().is_empty());
+        assert_eq!((0..4).filter(|hour| chunk_file(&dir, hour * 3600).is_file()).count(), 1);
+        
+        let values: Vec<f64> = storage.query_range(0, 4 * 3600, "compact-evicted|8867-4|bpm").unwrap()
+            .iter().map(|r| r.value).collect();
+        assert_eq!(values, [0.0, 1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn test_insert_batch_partial_stores_all_but_rejected_records() {
+        let storage = StorageEngine::new_in_memory(Duration::from_secs(3600));
+        let make_record = |timestamp: i64| Record {
+            timestamp,
+            metric_name: "partial|8867-4|bpm".to_string(),
+            value: timestamp as f64,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        
+        // The second record belongs to the next chunk
+        let result = storage.insert_batch_partial(0, vec![
+            make_record(100), make_record(3700), make_record(200), make_record(300),
+        ]).unwrap();
+        assert_eq!(result.inserted, 3);
+        assert_eq!(result.failed.len(), 1);
+        assert!(matches!(result.failed[0], (1, ChunkError::OutOfTimeRange(_))));
+        
+        let stored: Vec<i64> = storage.query_range(0, 7200, "partial|8867-4|bpm").unwrap()
+            .iter().map(|r| r.timestamp).collect();
+        assert_eq!(stored, vec![100, 200, 300]);
+        
+        // The all-or-error form still stores the good records but reports the bad one
+        assert!(storage.insert_batch(0, vec![make_record(400), make_record(-5)]).is_err());
+        assert_eq!(storage.count_range(0, 3600, "partial|8867-4|bpm").unwrap(), 4);
+    }
+
+    #[test]
+    fn test_apply_retention_prunes_per_metric() {
+        let storage = StorageEngine::new_in_memory(Duration::from_secs(3600));
+        let now = chrono::Utc::now().timestamp();
+        let make_record = |timestamp: i64, metric: &str| Record {
+            timestamp,
+            metric_name: metric.to_string(),
+            value: 1.0,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        for metric in ["ret|ecg|mV", "ret|29463-7|kg", "ret|other|1"] {
+            for age_hours in [1, 30, 24 * 100] {
+                storage.insert(make_record(now - age_hours * 3600, metric)).unwrap();
+            }
+        }
+        
+        let policies = HashMap::from([
+            ("ret|ecg".to_string(), Duration::from_secs(2 * 3600)),
+            ("ret|29463-7".to_string(), Duration::from_secs(365 * 24 * 3600)),
+            (String::new(), Duration::from_secs(7 * 24 * 3600)),
+        ]);
+        assert_eq!(storage.apply_retention(&policies).unwrap(), 3);
+        
+        let ages = |metric: &str| {
+            let mut ages: Vec<i64> = storage.query_range(0, now + 1, metric).unwrap()
+                .iter()
+                .map(|r| (now - r.timestamp) / 3600)
+                .collect();
+            ages.sort_unstable();
+            ages
+        };
+        assert_eq!(ages("ret|ecg|mV"), vec![1]);
+        assert_eq!(ages("ret|29463-7|kg"), vec![1, 30, 24 * 100]);
+        assert_eq!(ages("ret|other|1"), vec![1, 30]);
+        
+        // Pruned chunks no longer list the metric, and a second pass is a no-op
+        let oldest_chunk = storage.chunk_id_for(now - 24 * 100 * 3600);
+        let index = storage.index.read().unwrap();
+        assert!(!index.chunks_for_metric("ret|ecg|mV").contains(&oldest_chunk));
+        assert!(index.chunks_for_metric("ret|29463-7|kg").contains(&oldest_chunk));
+        drop(index);
+        assert_eq!(storage.apply_retention(&policies).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_apply_retention_deletes_emptied_chunks_and_stays_pruned() {
+        let (mut config, dir) = temp_config("retention-persist");
+        config.storage.max_resident_chunks = Some(2);
+        let now = chrono::Utc::now().timestamp();
+        let make_record = |timestamp: i64| Record {
+            timestamp,
+            metric_name: "ret|8867-4|bpm".to_string(),
+            value: 1.0,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        
+        let storage = StorageEngine::new(&config).unwrap();
+        for age_hours in [70, 60, 50, 2, 1] {
+            storage.insert(make_record(now - age_hours * 3600)).unwrap();
+        }
+        // The old chunks reach disk through eviction or `flush_chunk`, leaving their appends in the WAL
+        let old_chunks: Vec<i64> = [50, 60, 70].iter().map(|hours| storage.chunk_id_for(now - hours * 3600)).collect();
+        for &id in &old_chunks {
+            storage.flush_chunk(id).unwrap();
+        }
+        assert!(!storage.evicted.lock().unwrap().is_empty());
+        assert!(old_chunks.iter().all(|&id| chunk_file(&dir, id).is_file()));
+        
+        let policies = HashMap::from([(String::new(), Duration::from_secs(24 * 3600))]);
+        assert_eq!(storage.apply_retention(&policies).unwrap(), 3);
+        assert!(storage.resident_chunk_count() <= 2);
+        
+        // Emptied chunks are gone from memory, the index and disk
+        let chunks = storage.chunks.read().unwrap();
+        for &id in &old_chunks {
+            assert!(!chunks.contains_key(&id));
+            assert!(!storage.evicted.lock().unwrap().contains(&id));
+            assert!(!chunk_file(&dir, id).exists());
+        }
+        drop(chunks);
+        let indexed = storage.index.read().unwrap().chunks_for_metric("ret|8867-4|bpm").to_vec();
+        assert!(old_chunks.iter().all(|id| !indexed.contains(id)));
+        drop(storage);
+        
+        // Recovery doesn't replay the pruned records back in
+        let storage = StorageEngine::new(&config).unwrap();
+        assert_eq!(storage.query_range(0, now + 1, "ret|8867-4|bpm").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_flush_all_saves_chunks_in_parallel_and_keeps_failures_dirty() {
+        let (config, dir) = temp_config("parallel-flush");
+        let storage = StorageEngine::new(&config).unwrap();
+        
+        for hour in 0..20 {
+            storage.insert(Record {
+                timestamp: hour * 3600 + 60,
+                metric_name: "flush|8867-4|/min".to_string(),
+                value: 60.0 + hour as f64,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).unwrap();
+        }
+        assert_eq!(storage.dirty_chunk_count(), 20);
+        
+        // A directory squatting on one chunk's file name makes its save fail
+        let blocked = chunk_file(&dir, 7 * 3600);
+        std::fs::create_dir_all(blocked.join("occupied")).unwrap();
+        assert!(storage.flush_all().is_err());
+        assert_eq!(storage.dirty_chunk_count(), 1);
+        assert!(storage.chunks.read().unwrap()[&(7 * 3600)].is_dirty());
+        for hour in (0..20).filter(|&hour| hour != 7) {
+            assert!(chunk_file(&dir, hour * 3600).is_file(), "hour {}", hour);
+        }
+        
+        std::fs::remove_dir_all(&blocked).unwrap();
+        let report = storage.flush_all().unwrap();
+        assert_eq!(report.chunks_flushed, 1);
+        assert_eq!(storage.dirty_chunk_count(), 0);
+        drop(storage);
+        
+        let reopened = StorageEngine::new(&config).unwrap();
+        assert_eq!(reopened.query_range(0, 20 * 3600, "flush|8867-4|/min").unwrap().len(), 20);
+    }
+
+    #[test]
+    fn test_writes_during_parallel_save_stay_dirty_and_logged() {
+        let (config, _dir) = temp_config("parallel-flush-race");
+        let storage = StorageEngine::new(&config).unwrap();
+        let make_record = |timestamp: i64| Record {
+            timestamp,
+            metric_name: "flush-race|8867-4|/min".to_string(),
+            value: 60.0,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        for hour in 0..4 {
+            storage.insert(make_record(hour * 3600 + 60)).unwrap();
+        }
+        
+        // Copy, then write to one copied chunk before its save
+        let persistence = storage.backend().unwrap();
+        let (dirty, wal_position) = storage.copy_dirty_chunks(persistence).unwrap();
+        storage.insert(make_record(2 * 3600 + 120)).unwrap();
+        let report = storage.save_dirty_chunks(persistence, dirty, wal_position).unwrap();
+        assert_eq!(report.chunks_flushed, 4);
+        
+        // The written chunk's file misses the record, but the WAL still has it
+        assert_eq!(storage.dirty_chunk_count(), 1);
+        assert!(storage.chunks.read().unwrap()[&(2 * 3600)].is_dirty());
+        drop(storage);
+        
+        let reopened = StorageEngine::new(&config).unwrap();
+        assert_eq!(reopened.query_range(0, 4 * 3600, "flush-race|8867-4|/min").unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_reloaded_chunks_are_not_reflushed_until_modified() {
+        let (mut config, _dir) = temp_config("clean-reload");
+        config.storage.rollup_resolution = Some(Duration::from_secs(300));
+        config.storage.max_resident_chunks = Some(1);
+        let metric = "clean|8867-4|/min";
+        
+        {
+            let storage = StorageEngine::new(&config).unwrap();
+            for hour in 0..3 {
+                storage.insert(Record {
+                    timestamp: hour * 3600 + 60,
+                    metric_name: metric.to_string(),
+                    value: 60.0,
+                    string_value: None,
+                    context: HashMap::new(),
+                    resource_type: "Observation".to_string(),
+                    id: None,
+                }).unwrap();
+            }
+            // Setting a value it already has is logged but changes nothing
+            assert!(storage.update_record(metric, 60, 60.0).unwrap());
+            storage.flush_all().unwrap();
+        }
+        
+        // Reads reload evicted chunks and decompress them, but write nothing
+        let storage = StorageEngine::new(&config).unwrap();
+        assert_eq!(storage.query_range(0, 3 * 3600, metric).unwrap().len(), 3);
+        assert!(storage.update_record(metric, 60, 60.0).unwrap());
+        assert_eq!(storage.dirty_chunk_count(), 0);
+        assert_eq!(storage.flush_all().unwrap().chunks_flushed, 0);
+        
+        assert!(storage.update_record(metric, 3600 + 60, 61.0).unwrap());
+        assert_eq!(storage.flush_all().unwrap().chunks_flushed, 1);
+    }
+
+    #[test]
+    fn test_wide_chunks_are_split_when_chunk_duration_shrinks() {
+        let (mut config, _dir) = temp_config("split");
+        let metric = "split|8867-4|/min";
+        let record = |timestamp: i64| Record {
+            timestamp,
+            metric_name: metric.to_string(),
+            value: 70.0,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        
+        {
+            let storage = StorageEngine::new(&config).unwrap();
+            storage.insert(record(100)).unwrap();
+            storage.insert(record(2000)).unwrap();
+            storage.flush_all().unwrap();
+        }
+        
+        // Reopened with half-hour chunks, the hour-long chunk becomes two
+        config.chunk_duration = Duration::from_secs(1800);
+        {
+            let storage = StorageEngine::new(&config).unwrap();
+            let spans = |storage: &StorageEngine| {
+                let mut spans: Vec<(i64, i64, usize)> = storage.chunks.read().unwrap().values()
+                    .map(|chunk| (chunk.start_time, chunk.end_time, chunk.record_count()))
+                    .collect();
+                spans.sort_unstable();
+                spans
+            };
+            assert_eq!(spans(&storage), vec![(0, 1800, 1), (1800, 3600, 1)]);
+            assert_eq!(storage.dirty_chunk_count(), 0);
+            
+            storage.insert(record(3000)).unwrap();
+            storage.insert(record(4000)).unwrap();
+            assert_eq!(spans(&storage), vec![(0, 1800, 1), (1800, 3600, 2), (3600, 5400, 1)]);
+            assert_eq!(storage.query_range(0, 5400, metric).unwrap().len(), 4);
+            storage.flush_all().unwrap();
+        }
+        
+        // The split was written back, so nothing is split or lost the next time
+        let storage = StorageEngine::new(&config).unwrap();
+        assert_eq!(storage.resident_chunk_count(), 3);
+        assert_eq!(storage.query_range(0, 5400, metric).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_shutdown_flush_through_shared_handle() {
+        let (config, dir) = temp_config("shutdown-flush");
+        let storage = Arc::new(StorageEngine::new(&config).unwrap());
+        // Stands in for the query engine, which keeps its handle until the process exits
+        let query_engine_handle = Arc::clone(&storage);
+        
+        // The server writes from its own task, then stops and releases its handle
+        let server_handle = Arc::clone(&storage);
+        std::thread::spawn(move || {
+            for minute in 0..5 {
+                server_handle.insert(Record {
+                    timestamp: 7200 + minute * 60,
+                    metric_name: "bye|8867-4|/min".to_string(),
+                    value: 70.0 + minute as f64,
+                    string_value: None,
+                    context: HashMap::new(),
+                    resource_type: "Observation".to_string(),
+                    id: None,
+                }).unwrap();
+            }
+        }).join().unwrap();
+        
+        let report = storage.flush_all().unwrap();
+        assert_eq!(report.chunks_flushed, 1);
+        assert!(report.wal_truncated);
+        assert!(chunk_file(&dir, 7200).is_file());
+        drop((storage, query_engine_handle));
+        
+        let reopened = StorageEngine::new(&config).unwrap();
+        assert_eq!(reopened.query_range(7200, 10800, "bye|8867-4|/min").unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_batch_insert_reserves_up_front() {
+        let storage = StorageEngine::new_in_memory(Duration::from_secs(3600));
+        let batch = |start: i64, count: i64| -> Vec<Record> {
+            (0..count).map(|i| Record {
+                timestamp: start + i,
+                metric_name: "batch|8867-4|bpm".to_string(),
+                value: 70.0,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).collect()
+        };
+        let capacity = |chunk_id: i64| storage.chunks.read().unwrap()[&chunk_id].records["batch|8867-4|bpm"].capacity();
+        
+        // One reservation for the whole batch rather than doubling up to it
+        storage.insert_batch(0, batch(0, 3000)).unwrap();
+        assert_eq!(capacity(0), 3000);
+        
+        // The next chunk is sized from this one before anything is batched into it
+        storage.insert(batch(3600, 1).remove(0)).unwrap();
+        assert_eq!(capacity(3600), 3000);
+    }
+
+    #[test]
+    fn test_flush_chunk_persists_only_that_chunk() {
+        let (config, dir) = temp_config("flush-chunk");
+        let storage = StorageEngine::new(&config).unwrap();
+        for hour in 0..3 {
+            storage.insert(Record {
+                timestamp: hour * 3600 + 60,
+                metric_name: "one|8867-4|/min".to_string(),
+                value: 70.0,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).unwrap();
+        }
+        
+        assert!(storage.flush_chunk(3600).unwrap());
+        let dirty: Vec<i64> = {
+            let chunks = storage.chunks.read().unwrap();
+            let mut dirty: Vec<i64> = chunks.iter().filter(|(_, chunk)| chunk.is_dirty()).map(|(&id, _)| id).collect();
+            dirty.sort_unstable();
+            dirty
+        };
+        assert_eq!(dirty, vec![0, 7200]);
+        assert!(chunk_file(&dir, 3600).is_file());
+        assert!(!chunk_file(&dir, 0).exists() && !chunk_file(&dir, 7200).exists());
+        
+        // Nothing left to do for a clean or unknown chunk
+        assert!(!storage.flush_chunk(3600).unwrap());
+        assert!(!storage.flush_chunk(999_999).unwrap());
+    }
+
+    #[test]
+    fn test_backpressure_refuses_writes_until_flushed() {
+        let (mut config, _dir) = temp_config("backpressure");
+        config.storage.max_unflushed_bytes = Some(4096);
+        
+        let make_record = |timestamp: i64| Record {
+            timestamp,
+            metric_name: "flood|8867-4|bpm".to_string(),
+            value: 70.0,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        
+        let storage = StorageEngine::new(&config).unwrap();
+        let mut refused = None;
+        for i in 0..10_000 {
+            if let Err(e) = storage.insert(make_record(i)) {
+                refused = Some((i, e));
+                break;
+            }
+        }
+        let (accepted, error) = refused.expect("flooding never hit backpressure");
+        assert!(matches!(error, StorageError::Backpressure(_)), "{:?}", error);
+        
+        // Growth stops just past the mark, and the refused record isn't stored
+        let stats = storage.stats();
+        assert!(stats.backpressure);
+        assert_eq!(stats.max_unflushed_bytes, Some(4096));
+        assert!(stats.unflushed_bytes > 4096 && stats.unflushed_bytes < 8192, "{}", stats.unflushed_bytes);
+        assert_eq!(storage.count_range(0, 10_000, "flood|8867-4|bpm").unwrap() as i64, accepted);
+        assert!(matches!(
+            storage.append_records_to_wal(vec![make_record(accepted)]),
+            Err(StorageError::Backpressure(_))
+        ));
+        
+        // The refusal asks for a flush, which clears the pressure
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        runtime.block_on(async { tokio::time::timeout(Duration::from_secs(1), storage.flush_requested()).await })
+            .expect("backpressure never requested a flush");
+        storage.flush_all().unwrap();
+        assert!(!storage.stats().backpressure);
+        storage.insert(make_record(accepted)).unwrap();
+    }
+
+    #[test]
+    fn test_unflushed_bytes_follow_dirty_chunks() {
+        let (mut config, _dir) = temp_config("dirty-bytes");
+        config.storage.max_resident_chunks = Some(2);
+        let storage = StorageEngine::new(&config).unwrap();
+        let dirty_size = |storage: &StorageEngine| -> usize {
+            storage.chunks.read().unwrap().values()
+                .filter(|chunk| chunk.is_dirty())
+                .map(|chunk| chunk.size_bytes())
+                .sum()
+        };
+        
+        for i in 0..40 {
+            storage.insert(Record {
+                timestamp: i * 600,
+                metric_name: "dirty|8867-4|bpm".to_string(),
+                value: i as f64,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            }).unwrap();
+        }
+        assert!(storage.unflushed_bytes() > 0);
+        assert_eq!(storage.unflushed_bytes(), dirty_size(&storage));
+        
+        // Updates and renames change sizes in place
+        assert!(storage.update_record("dirty|8867-4|bpm", 600, 99.0).unwrap());
+        storage.rename_metric("dirty|8867-4|bpm", "dirty|8867-4|/min").unwrap();
+        assert_eq!(storage.unflushed_bytes(), dirty_size(&storage));
+        
+        // Flushing and evicting leave nothing unflushed, and a reload comes back clean
+        storage.flush_all().unwrap();
+        assert_eq!(storage.unflushed_bytes(), 0);
+        assert_eq!(storage.query_range(0, 40 * 600, "dirty|8867-4|/min").unwrap().len(), 40);
+        assert_eq!(storage.unflushed_bytes(), 0);
+        
+        // Writing to a reloaded chunk counts it again
+        assert!(storage.update_record("dirty|8867-4|/min", 0, 1.0).unwrap());
+        assert!(storage.unflushed_bytes() > 0);
+        assert_eq!(storage.unflushed_bytes(), dirty_size(&storage));
+    }
+}
@@ -1,24 +1,270 @@
+use std::borrow::Cow;
 use std::path::{Path, PathBuf};
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, Read, Write, Seek, SeekFrom};
 use std::collections::HashMap;
 use std::sync::Mutex;
+use std::time::Instant;
+use serde::{Serialize, Deserialize};
 use serde_json;
 
 use super::chunk::TimeChunk;
 use super::Record;
 use super::StorageError;
+use crate::config::{ChunkLayout, FsyncPolicy, WalFormat};
+
+/// An operation recorded in the WAL
+///
+/// Plain appends are still written as bare records, the original WAL format;
+/// every other operation carries an `op` tag.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op")]
+pub enum WalEntry {
+    Append(Record),
+    Upsert(Record), // Last-write-wins insert at (metric_name, timestamp)
+    Update { metric_name: String, timestamp: i64, value: f64 }, // In-place value correction
+    Rename { from: String, to: String }, // Every record of a metric moved to another name
+}
+
+impl WalEntry {
+    /// The metric and timestamp this operation touches, if it touches a single record
+    pub fn key(&self) -> Option<(&str, i64)> {
+        match self {
+            WalEntry::Append(record) | WalEntry::Upsert(record) => Some((&record.metric_name, record.timestamp)),
+            WalEntry::Update { metric_name, timestamp, .. } => Some((metric_name, *timestamp)),
+            WalEntry::Rename { .. } => None,
+        }
+    }
+}
+
+/// Start of a WAL file's header, followed by one byte naming its `WalFormat`
+///
+/// WALs from before the header begin straight with an entry's length, whose
+/// first byte is never this large, so they are read as headerless JSON.
+const WAL_MAGIC: &[u8; 4] = b"EWAL";
+
+/// `WalEntry` as the binary WAL encodes it
+///
+/// `Record`'s serde attributes only suit self-describing formats (skipped
+/// fields, the `op` tag), so bincode gets this plain mirror instead.
+#[derive(Serialize, Deserialize)]
+enum BinaryEntry<'a> {
+    Append(BinaryRecord<'a>),
+    Upsert(BinaryRecord<'a>),
+    Update { metric_name: Cow<'a, str>, timestamp: i64, value: f64 },
+    Rename { from: Cow<'a, str>, to: Cow<'a, str> },
+}
+
+#[derive(Serialize, Deserialize)]
+struct BinaryRecord<'a> {
+    timestamp: i64,
+    metric_name: Cow<'a, str>,
+    value: f64,
+    string_value: Option<Cow<'a, str>>,
+    context: Cow<'a, HashMap<String, String>>,
+    resource_type: Cow<'a, str>,
+    id: Option<Cow<'a, str>>,
+}
+
+impl<'a> From<&'a Record> for BinaryRecord<'a> {
+    fn from(record: &'a Record) -> Self {
+        BinaryRecord {
+            timestamp: record.timestamp,
+            metric_name: Cow::Borrowed(&record.metric_name),
+            value: record.value,
+            string_value: record.string_value.as_deref().map(Cow::Borrowed),
+            context: Cow::Borrowed(&record.context),
+            resource_type: Cow::Borrowed(&record.resource_type),
+            id: record.id.as_deref().map(Cow::Borrowed),
+        }
+    }
+}
+
+impl From<BinaryRecord<'_>> for Record {
+    fn from(record: BinaryRecord<'_>) -> Self {
+        Record {
+            timestamp: record.timestamp,
+            metric_name: record.metric_name.into_owned(),
+            value: record.value,
+            string_value: record.string_value.map(Cow::into_owned),
+            context: record.context.into_owned(),
+            resource_type: record.resource_type.into_owned(),
+            id: record.id.map(Cow::into_owned),
+        }
+    }
+}
+
+impl<'a> From<&'a WalEntry> for BinaryEntry<'a> {
+    fn from(entry: &'a WalEntry) -> Self {
+        match entry {
+            WalEntry::Append(record) => BinaryEntry::Append(record.into()),
+            WalEntry::Upsert(record) => BinaryEntry::Upsert(record.into()),
+            WalEntry::Update { metric_name, timestamp, value } => BinaryEntry::Update {
+                metric_name: Cow::Borrowed(metric_name), timestamp: *timestamp, value: *value,
+            },
+            WalEntry::Rename { from, to } => BinaryEntry::Rename { from: Cow::Borrowed(from), to: Cow::Borrowed(to) },
+        }
+    }
+}
+
+impl From<BinaryEntry<'_>> for WalEntry {
+    fn from(entry: BinaryEntry<'_>) -> Self {
+        match entry {
+            BinaryEntry::Append(record) => WalEntry::Append(record.into()),
+            BinaryEntry::Upsert(record) => WalEntry::Upsert(record.into()),
+            BinaryEntry::Update { metric_name, timestamp, value } => WalEntry::Update {
+                metric_name: metric_name.into_owned(), timestamp, value,
+            },
+            BinaryEntry::Rename { from, to } => WalEntry::Rename { from: from.into_owned(), to: to.into_owned() },
+        }
+    }
+}
+
+/// Encode an appended record; in JSON a bare record, as in the original WAL format
+fn encode_record(format: WalFormat, record: &Record) -> io::Result<Vec<u8>> {
+    match format {
+        WalFormat::Json => Ok(serde_json::to_vec(record)?),
+        WalFormat::Binary => bincode::serialize(&BinaryEntry::Append(record.into()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+fn encode_entry(format: WalFormat, entry: &WalEntry) -> io::Result<Vec<u8>> {
+    match format {
+        WalFormat::Json => Ok(serde_json::to_vec(entry)?),
+        WalFormat::Binary => bincode::serialize(&BinaryEntry::from(entry))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+fn decode_entry(format: WalFormat, data: &[u8]) -> io::Result<WalEntry> {
+    match format {
+        // Tagged operations first; anything else is a bare appended record.
+        // The order matters because a tagged entry also parses as a Record.
+        WalFormat::Json => match serde_json::from_slice::<WalEntry>(data) {
+            Ok(entry) => Ok(entry),
+            Err(_) => Ok(WalEntry::Append(serde_json::from_slice::<Record>(data)?)),
+        },
+        WalFormat::Binary => bincode::deserialize::<BinaryEntry>(data)
+            .map(WalEntry::from)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+fn format_byte(format: WalFormat) -> u8 {
+    match format {
+        WalFormat::Json => 0,
+        WalFormat::Binary => 1,
+    }
+}
+
+/// The format a WAL file was started in and the length of its header
+///
+/// An empty file has no format yet; a file without a header is a JSON WAL
+/// from before headers were written.
+fn read_wal_header(file: &mut File) -> io::Result<(Option<WalFormat>, u64)> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut header = [0u8; WAL_MAGIC.len() + 1];
+    let mut filled = 0;
+    while filled < header.len() {
+        match file.read(&mut header[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    
+    if filled == 0 {
+        return Ok((None, 0));
+    }
+    if filled < header.len() || &header[..WAL_MAGIC.len()] != WAL_MAGIC {
+        return Ok((Some(WalFormat::Json), 0));
+    }
+    let format = match header[WAL_MAGIC.len()] {
+        0 => WalFormat::Json,
+        1 => WalFormat::Binary,
+        other => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown WAL format byte {}", other))),
+    };
+    Ok((Some(format), header.len() as u64))
+}
+
+/// A chunk file written by `PersistenceManager::prepare_chunk`, not yet in place
+///
+/// Dropping it uncommitted removes the file.
+#[derive(Debug)]
+pub struct PreparedChunk {
+    temp_path: Option<PathBuf>, // None once committed
+    chunk_path: PathBuf,
+    stray_path: PathBuf,
+}
+
+impl PreparedChunk {
+    /// Replace the chunk's file with the prepared one
+    pub fn commit(mut self) -> Result<(), StorageError> {
+        let temp_path = self.temp_path.take().expect("committed once");
+        fs::rename(&temp_path, &self.chunk_path)
+            .map_err(|e| StorageError::PersistenceError(format!("Failed to rename file: {}", e)))?;
+        
+        // A copy left in the other layout would be stale from now on
+        remove_if_present(&self.stray_path)
+    }
+}
+
+impl Drop for PreparedChunk {
+    fn drop(&mut self) {
+        if let Some(temp_path) = &self.temp_path {
+            let _ = fs::remove_file(temp_path);
+        }
+    }
+}
+
+/// A snapshot being built next to its destination by `PersistenceManager::stage_snapshot`
+///
+/// Dropping it unfinished removes what was staged.
+#[derive(Debug)]
+pub struct SnapshotStaging {
+    path: Option<PathBuf>, // None once renamed into place
+    dest: PathBuf,
+}
+
+impl SnapshotStaging {
+    fn path(&self) -> &Path {
+        self.path.as_deref().expect("staging is unfinished")
+    }
+    
+    /// Move the staged snapshot to its destination
+    pub fn finish(mut self) -> Result<(), StorageError> {
+        // An empty destination was allowed, but rename won't replace a directory everywhere
+        if self.dest.exists() {
+            fs::remove_dir(&self.dest)
+                .map_err(|e| StorageError::PersistenceError(format!("Failed to replace {}: {}", self.dest.display(), e)))?;
+        }
+        fs::rename(self.path(), &self.dest)
+            .map_err(|e| StorageError::PersistenceError(format!("Failed to move snapshot into place: {}", e)))?;
+        self.path = None;
+        Ok(())
+    }
+}
+
+impl Drop for SnapshotStaging {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = fs::remove_dir_all(path);
+        }
+    }
+}
 
 /// Manages storage and retrieval of chunks from disk
 #[derive(Debug)]
 pub struct PersistenceManager {
     base_path: PathBuf,
+    layout: ChunkLayout,
     wal: WriteAheadLog,
     active_records: Mutex<HashMap<String, i64>>, // metric_name -> latest timestamp
+    durable_watermarks: Mutex<HashMap<i64, i64>>, // chunk_id -> latest timestamp persisted in that chunk
 }
 
 impl PersistenceManager {
-    pub fn new(base_path: impl AsRef<Path>) -> io::Result<Self> {
+    pub fn new(base_path: impl AsRef<Path>, fsync: FsyncPolicy) -> io::Result<Self> {
         let base_path = base_path.as_ref().to_path_buf();
         
         // Create the base directory if it doesn't exist
@@ -30,43 +276,141 @@ impl PersistenceManager {
         fs::create_dir_all(&chunks_dir)?;
         fs::create_dir_all(&wal_dir)?;
         
-        let wal = WriteAheadLog::new(wal_dir)?;
+        let wal = WriteAheadLog::new(wal_dir, fsync)?;
         
         Ok(PersistenceManager {
             base_path,
+            layout: ChunkLayout::default(),
             wal,
             active_records: Mutex::new(HashMap::new()),
+            durable_watermarks: Mutex::new(HashMap::new()),
         })
     }
     
+    /// Arrange chunk files in `layout`, moving any files in the other layout into it
+    pub fn with_layout(mut self, layout: ChunkLayout) -> Result<Self, StorageError> {
+        self.layout = layout;
+        for chunk_id in self.list_chunks()? {
+            let (path, stray) = (self.get_chunk_path(chunk_id), self.stray_chunk_path(chunk_id));
+            if stray.is_file() && !path.exists() {
+                create_parent_dir(&path)?;
+                fs::rename(&stray, &path)
+                    .map_err(|e| StorageError::PersistenceError(format!("Failed to move chunk {}: {}", chunk_id, e)))?;
+            }
+        }
+        Ok(self)
+    }
+    
+    /// Start new WAL files in `format`
+    ///
+    /// An existing WAL keeps the format it was started in until it is next truncated.
+    pub fn with_wal_format(mut self, format: WalFormat) -> Self {
+        self.wal.preferred_format = format;
+        self
+    }
+    
     /// Save a chunk to disk
     pub fn save_chunk(&self, chunk: &TimeChunk) -> Result<(), StorageError> {
         let chunk_path = self.get_chunk_path(chunk.start_time);
-        let serialized = serde_json::to_vec(chunk)
-            .map_err(|e| StorageError::PersistenceError(format!("Serialization failed: {}", e)))?;
-        
-        // Write to a temporary file first
-        let temp_path = chunk_path.with_extension("tmp");
-        let mut file = File::create(&temp_path)
-            .map_err(|e| StorageError::PersistenceError(format!("Failed to create file: {}", e)))?;
-        
-        file.write_all(&serialized)
-            .map_err(|e| StorageError::PersistenceError(format!("Failed to write data: {}", e)))?;
-        
-        // Ensure data is flushed to disk
-        file.sync_all()
-            .map_err(|e| StorageError::PersistenceError(format!("Failed to sync data: {}", e)))?;
+        create_parent_dir(&chunk_path)?;
+        write_chunk_file(&chunk_path, chunk)?;
         
-        // Rename temp file to final name (atomic operation on most filesystems)
-        fs::rename(&temp_path, &chunk_path)
-            .map_err(|e| StorageError::PersistenceError(format!("Failed to rename file: {}", e)))?;
+        // A copy left in the other layout would be stale from now on
+        remove_if_present(&self.stray_chunk_path(chunk.start_time))
+    }
+    
+    /// Write `chunk` beside its file without replacing it yet
+    ///
+    /// `PreparedChunk::commit` swaps it in, so the slow write can happen
+    /// without holding the lock that the swap needs.
+    pub fn prepare_chunk(&self, chunk: &TimeChunk) -> Result<PreparedChunk, StorageError> {
+        let chunk_path = self.get_chunk_path(chunk.start_time);
+        create_parent_dir(&chunk_path)?;
+        let prepared = PreparedChunk {
+            temp_path: Some(chunk_path.with_extension("prepared")),
+            stray_path: self.stray_chunk_path(chunk.start_time),
+            chunk_path,
+        };
+        write_synced_chunk(prepared.temp_path.as_deref().expect("not yet committed"), chunk)?;
+        Ok(prepared)
+    }
+    
+    /// Check `dest` can take a snapshot and create the directory it is staged in
+    ///
+    /// `dest` must not exist yet, or be an empty directory, and must lie outside
+    /// `base_path`. The snapshot is built next to it and only renamed into place
+    /// by `SnapshotStaging::finish`, so a failed one leaves nothing behind.
+    pub fn stage_snapshot(&self, dest: &Path) -> Result<SnapshotStaging, StorageError> {
+        let invalid = |msg: String| StorageError::InvalidSnapshotDestination(msg);
+        let Some(name) = dest.file_name() else {
+            return Err(invalid(format!("{} does not name a directory", dest.display())));
+        };
+        match fs::read_dir(dest).map(|mut entries| entries.next().is_none()) {
+            Ok(true) => {}
+            Ok(false) => return Err(invalid(format!("{} is not empty", dest.display()))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(invalid(format!("{} is not usable: {}", dest.display(), e))),
+        }
+        let base = absolute_path(&self.base_path)
+            .map_err(|e| StorageError::PersistenceError(format!("Failed to resolve storage path: {}", e)))?;
+        let resolved = absolute_path(dest)
+            .map_err(|e| invalid(format!("{} is not usable: {}", dest.display(), e)))?;
+        if resolved.starts_with(&base) || base.starts_with(&resolved) {
+            return Err(invalid(format!("{} overlaps the storage path", dest.display())));
+        }
         
+        let mut staged_name = std::ffi::OsString::from(".");
+        staged_name.push(name);
+        staged_name.push(".partial");
+        let staging = SnapshotStaging { path: Some(resolved.with_file_name(staged_name)), dest: resolved };
+        let path = staging.path();
+        // Left over from a snapshot that was interrupted
+        if path.exists() {
+            fs::remove_dir_all(path)
+                .map_err(|e| StorageError::PersistenceError(format!("Failed to clear snapshot staging directory: {}", e)))?;
+        }
+        for dir in [path.join("chunks"), path.join("wal")] {
+            fs::create_dir_all(&dir)
+                .map_err(|e| StorageError::PersistenceError(format!("Failed to create snapshot directory: {}", e)))?;
+        }
+        Ok(staging)
+    }
+    
+    /// Link the chunk files `wanted` picks into `staging`, copying any that can't be linked
+    ///
+    /// Chunk files are only ever replaced by rename, so a link keeps the contents
+    /// the file had when it was made.
+    pub fn link_chunk_files<F>(&self, staging: &SnapshotStaging, wanted: F) -> Result<(), StorageError>
+    where
+        F: Fn(i64) -> bool,
+    {
+        let chunks_dir = staging.path().join("chunks");
+        for chunk_id in self.list_chunks()?.into_iter().filter(|&chunk_id| wanted(chunk_id)) {
+            let source = self.existing_chunk_path(chunk_id);
+            let target = chunk_file_path(&chunks_dir, self.layout, chunk_id);
+            create_parent_dir(&target)?;
+            if fs::hard_link(&source, &target).is_err() {
+                fs::copy(&source, &target)
+                    .map_err(|e| StorageError::PersistenceError(format!("Failed to copy chunk {}: {}", chunk_id, e)))?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Write in-memory `chunks` into `staging`
+    pub fn stage_chunks(&self, staging: &SnapshotStaging, chunks: &[(i64, TimeChunk)]) -> Result<(), StorageError> {
+        let chunks_dir = staging.path().join("chunks");
+        for (chunk_id, chunk) in chunks {
+            let chunk_path = chunk_file_path(&chunks_dir, self.layout, *chunk_id);
+            create_parent_dir(&chunk_path)?;
+            write_chunk_file(&chunk_path, chunk)?;
+        }
         Ok(())
     }
     
     /// Load a chunk from disk
     pub fn load_chunk(&self, chunk_id: i64) -> Result<TimeChunk, StorageError> {
-        let chunk_path = self.get_chunk_path(chunk_id);
+        let chunk_path = self.existing_chunk_path(chunk_id);
         
         let mut file = File::open(&chunk_path)
             .map_err(|e| StorageError::PersistenceError(format!("Failed to open chunk file: {}", e)))?;
@@ -75,180 +419,301 @@ impl PersistenceManager {
         file.read_to_end(&mut buffer)
             .map_err(|e| StorageError::PersistenceError(format!("Failed to read chunk file: {}", e)))?;
         
-        let chunk: TimeChunk = serde_json::from_slice(&buffer)
+        let mut chunk: TimeChunk = serde_json::from_slice(&buffer)
             .map_err(|e| StorageError::PersistenceError(format!("Failed to deserialize chunk: {}", e)))?;
         
+        // Chunks flushed while compressed are stored that way
+        chunk.decompress()?;
+        // The index drives resource type queries, so it's rebuilt rather than trusted
+        chunk.rebuild_resource_metrics();
+        
         Ok(chunk)
     }
     
-    /// List all available chunk IDs on disk
+    /// List all available chunk IDs on disk, in either layout
     pub fn list_chunks(&self) -> Result<Vec<i64>, StorageError> {
-        let chunks_dir = self.base_path.join("chunks");
         let mut chunk_ids = Vec::new();
+        collect_chunk_ids(&self.base_path.join("chunks"), SHARD_DEPTH, &mut chunk_ids)?;
         
-        for entry in fs::read_dir(&chunks_dir)
-            .map_err(|e| StorageError::PersistenceError(format!("Failed to read chunks directory: {}", e)))? {
-                
-            let entry = entry
-                .map_err(|e| StorageError::PersistenceError(format!("Failed to read directory entry: {}", e)))?;
-            let path = entry.path();
-            
-            if path.extension().map_or(false, |ext| ext == "chunk") {
-                if let Some(stem) = path.file_stem() {
-                    if let Some(stem_str) = stem.to_str() {
-                        if let Ok(chunk_id) = stem_str.parse::<i64>() {
-                            chunk_ids.push(chunk_id);
-                        }
-                    }
-                }
-            }
-        }
-        
+        // A move between layouts interrupted by a crash can leave a chunk in both
         chunk_ids.sort();
+        chunk_ids.dedup();
         Ok(chunk_ids)
     }
     
-    /// Append a record to the WAL for durability
-    pub fn append_record(&self, record: &Record) -> Result<(), StorageError> {
+    /// Append a record to the WAL for durability, returning the bytes written
+    pub fn append_record(&self, record: &Record) -> Result<u64, StorageError> {
         // Append to WAL first
-        self.wal.append_record(record)
+        let written = self.wal.append_record(record)
             .map_err(|e| StorageError::PersistenceError(e.to_string()))?;
         
         // Update the active records map
         let mut active_records = self.active_records.lock().unwrap();
         active_records.insert(record.metric_name.clone(), record.timestamp);
         
-        Ok(())
+        Ok(written)
+    }
+    
+    /// Append a tagged operation to the WAL, returning the bytes written
+    pub fn append_entry(&self, entry: &WalEntry) -> Result<u64, StorageError> {
+        let written = self.wal.append_entry(entry)
+            .map_err(|e| StorageError::PersistenceError(e.to_string()))?;
+        
+        if let Some((metric_name, timestamp)) = entry.key() {
+            let mut active_records = self.active_records.lock().unwrap();
+            active_records.insert(metric_name.to_string(), timestamp);
+        }
+        
+        Ok(written)
     }
     
     /// Append multiple records to the WAL in a batch for better performance
-    pub fn append_records(&self, records: &[Record]) -> Result<(), StorageError> {
+    ///
+    /// Returns the bytes written.
+    pub fn append_records(&self, records: &[Record]) -> Result<u64, StorageError> {
         if records.is_empty() {
-            return Ok(());
+            return Ok(0);
         }
         
         // Special case: we can skip disk operations if running in memory mode
         if self.base_path.as_os_str().is_empty() {
-            return Ok(());
+            return Ok(0);
         }
         
-        // Fast path: If many records, use a more efficient batch approach
+        // Fast path: If many records, write them all in one operation
         if records.len() > 100 {
-            let mut all_data = Vec::with_capacity(records.len() * 100); // Rough estimate
-            
-            // Pre-serialize everything
-            for record in records {
-                let serialized = serde_json::to_vec(record)
-                    .map_err(|e| StorageError::PersistenceError(format!("Serialization failed: {}", e)))?;
-                
-                // Store the record size as a 4-byte header
-                let record_size = serialized.len() as u32;
-                all_data.extend_from_slice(&record_size.to_be_bytes());
-                all_data.extend_from_slice(&serialized);
-            }
-            
-            // Write everything in one operation
-            let wal_path = self.get_wal_path();
-            let mut file = OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(&wal_path)
-                .map_err(|e| StorageError::PersistenceError(format!("Failed to open WAL: {}", e)))?;
-                
-            file.write_all(&all_data)
-                .map_err(|e| StorageError::PersistenceError(format!("Failed to write to WAL: {}", e)))?;
-                
-            return Ok(());
+            return self.wal.append_records(records)
+                .map_err(|e| StorageError::PersistenceError(format!("Failed to write to WAL: {}", e)));
         }
         
         // Slower path for fewer records: use existing approach
+        let mut written = 0;
         for record in records {
-            self.append_record(record)?;
+            written += self.append_record(record)?;
         }
         
-        Ok(())
+        Ok(written)
+    }
+    
+    /// Force any WAL writes the fsync policy has held back onto disk
+    pub fn sync_wal(&self) -> Result<(), StorageError> {
+        self.wal.sync()
+            .map_err(|e| StorageError::PersistenceError(format!("Failed to sync WAL: {}", e)))
     }
     
-    /// Replay WAL to recover data after a crash
-    pub fn replay_wal(&self) -> Result<Vec<Record>, StorageError> {
-        self.wal.replay()
+    /// Replay WAL to recover data after a crash, handing `visit` one entry at a time
+    ///
+    /// Returns the number of entries visited.
+    pub fn replay_wal_with<F: FnMut(WalEntry)>(&self, visit: F) -> Result<usize, StorageError> {
+        self.wal.replay_with(visit)
             .map_err(|e| StorageError::PersistenceError(e.to_string()))
     }
     
-    /// Truncate WAL after chunks are safely persisted
-    pub fn truncate_wal(&self) -> Result<(), StorageError> {
+    /// Where the WAL currently ends, for a later `truncate_wal_before`
+    pub fn wal_position(&self) -> Result<u64, StorageError> {
+        self.wal.position()
+            .map_err(|e| StorageError::PersistenceError(format!("Failed to read WAL position: {}", e)))
+    }
+    
+    /// Truncate the WAL up to `position`, keeping whatever was written after it
+    pub fn truncate_wal_before(&self, position: u64) -> Result<(), StorageError> {
         println!("Truncating WAL...");
-        
-        // Don't lock the entire file, just create a new one and atomically replace it
-        let log_path = self.wal.wal_path.join("records.wal");
-        let temp_path = self.wal.wal_path.join("records.wal.new");
-        
-        println!("Creating new empty WAL file at {:?}", temp_path);
-        
-        // Create a new empty file
-        {
-            let file = File::create(&temp_path)
-                .map_err(|e| StorageError::PersistenceError(format!("Failed to create new WAL file: {}", e)))?;
-            
-            // Explicitly close the file here
-            drop(file);
-        }
-        
-        // Atomically replace the old file with the new one
-        println!("Replacing old WAL with new empty file");
-        fs::rename(&temp_path, &log_path)
-            .map_err(|e| StorageError::PersistenceError(format!("Failed to replace WAL file: {}", e)))?;
-        
-        // Now reopen the file in the mutex
-        println!("Reopening WAL file handle");
-        let new_file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .append(true)
-            .open(&log_path)
-            .map_err(|e| StorageError::PersistenceError(format!("Failed to open new WAL file: {}", e)))?;
-        
-        // Replace the file in our mutex
-        {
-            println!("Acquiring WAL file lock to update handle");
-            match self.wal.log_file.lock() {
-                Ok(mut log_file) => {
-                    println!("Lock acquired, replacing WAL file handle");
-                    *log_file = new_file;
-                    println!("WAL file handle replaced successfully");
-                },
-                Err(e) => {
-                    println!("Error acquiring WAL lock: {:?}", e);
-                    return Err(StorageError::PersistenceError(format!("Mutex error: {:?}", e)));
-                }
-            }
-        }
-        
+        self.wal.truncate_before(position)
+            .map_err(|e| StorageError::PersistenceError(format!("Failed to truncate WAL: {}", e)))?;
         println!("WAL truncation completed successfully");
         Ok(())
     }
     
     /// Mark chunk WAL records as durable, removing them from active records
-    pub fn mark_chunk_durable(&self, chunk_id: i64, chunk_duration_secs: i64) -> Result<(), StorageError> {
-        let chunk_end_time = chunk_id + chunk_duration_secs;
+    /// and advancing the chunk's durable watermark
+    pub fn mark_chunk_durable(&self, chunk: &TimeChunk) -> Result<(), StorageError> {
         let mut active_records = self.active_records.lock().unwrap();
         
         // Remove all records that are now safely in a persisted chunk
-        active_records.retain(|_, timestamp| *timestamp >= chunk_end_time);
+        active_records.retain(|_, timestamp| *timestamp >= chunk.end_time);
+        
+        if let Some(latest) = chunk.latest_timestamp() {
+            let mut watermarks = self.durable_watermarks.lock().unwrap();
+            let watermark = watermarks.entry(chunk.start_time).or_insert(latest);
+            *watermark = (*watermark).max(latest);
+        }
         
         Ok(())
     }
     
+    /// Latest timestamp known to be persisted for a chunk, if it has been persisted at all
+    pub fn durable_watermark(&self, chunk_id: i64) -> Option<i64> {
+        self.durable_watermarks.lock().unwrap().get(&chunk_id).copied()
+    }
+    
+    /// Remove a chunk file; a chunk that was never written is not an error
+    pub fn delete_chunk(&self, chunk_id: i64) -> Result<(), StorageError> {
+        remove_if_present(&self.get_chunk_path(chunk_id))?;
+        remove_if_present(&self.stray_chunk_path(chunk_id))?;
+        self.durable_watermarks.lock().unwrap().remove(&chunk_id);
+        Ok(())
+    }
+    
     // Helper method to get the path for a chunk file
     fn get_chunk_path(&self, chunk_id: i64) -> PathBuf {
-        self.base_path.join("chunks").join(format!("{}.chunk", chunk_id))
+        chunk_file_path(&self.base_path.join("chunks"), self.layout, chunk_id)
     }
-
+    
     // Helper method to get the path for the WAL file
+    #[cfg(test)]
     fn get_wal_path(&self) -> PathBuf {
         self.base_path.join("wal").join("records.wal")
     }
+    
+    /// Where a chunk file would be in the layout not in use
+    fn stray_chunk_path(&self, chunk_id: i64) -> PathBuf {
+        let other = match self.layout {
+            ChunkLayout::Flat => ChunkLayout::Sharded,
+            ChunkLayout::Sharded => ChunkLayout::Flat,
+        };
+        chunk_file_path(&self.base_path.join("chunks"), other, chunk_id)
+    }
+    
+    /// The chunk's file in the current layout, or in the other one if only it exists
+    fn existing_chunk_path(&self, chunk_id: i64) -> PathBuf {
+        let path = self.get_chunk_path(chunk_id);
+        let stray = self.stray_chunk_path(chunk_id);
+        if !path.exists() && stray.exists() {
+            stray
+        } else {
+            path
+        }
+    }
+}
+
+/// Subdirectory levels of the sharded layout
+const SHARD_DEPTH: usize = 2;
+
+/// Path of a chunk's file under `chunks_dir`
+///
+/// Sharded directories take bits 24-31 and 16-23 of the id, so one leaf
+/// directory spans about 18 hours of chunk start times and chunks close in
+/// time stay together.
+pub(crate) fn chunk_file_path(chunks_dir: &Path, layout: ChunkLayout, chunk_id: i64) -> PathBuf {
+    let file_name = format!("{}.chunk", chunk_id);
+    match layout {
+        ChunkLayout::Flat => chunks_dir.join(file_name),
+        ChunkLayout::Sharded => {
+            let bits = chunk_id as u64;
+            chunks_dir
+                .join(format!("{:02x}", (bits >> 24) & 0xff))
+                .join(format!("{:02x}", (bits >> 16) & 0xff))
+                .join(file_name)
+        }
+    }
+}
+
+/// Gather the ids of `.chunk` files in `dir` and up to `depth` levels of subdirectories
+fn collect_chunk_ids(dir: &Path, depth: usize, chunk_ids: &mut Vec<i64>) -> Result<(), StorageError> {
+    for entry in fs::read_dir(dir)
+        .map_err(|e| StorageError::PersistenceError(format!("Failed to read chunks directory: {}", e)))? {
+        
+        let entry = entry
+            .map_err(|e| StorageError::PersistenceError(format!("Failed to read directory entry: {}", e)))?;
+        let path = entry.path();
+        
+        if path.extension().is_some_and(|ext| ext == "chunk") {
+            if let Some(chunk_id) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<i64>().ok()) {
+                chunk_ids.push(chunk_id);
+            }
+        } else if depth > 0 && path.is_dir() {
+            collect_chunk_ids(&path, depth - 1, chunk_ids)?;
+        }
+    }
+    Ok(())
+}
+
+fn create_parent_dir(path: &Path) -> Result<(), StorageError> {
+    match path.parent() {
+        Some(parent) => fs::create_dir_all(parent)
+            .map_err(|e| StorageError::PersistenceError(format!("Failed to create chunk directory: {}", e))),
+        None => Ok(()),
+    }
+}
+
+/// Remove a file; one that doesn't exist is not an error
+fn remove_if_present(path: &Path) -> Result<(), StorageError> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(StorageError::PersistenceError(format!("Failed to delete chunk file: {}", e))),
+    }
+}
+
+/// `path` made absolute, resolving symlinks in the part of it that exists
+fn absolute_path(path: &Path) -> io::Result<PathBuf> {
+    let mut existing = if path.is_absolute() { path.to_path_buf() } else { std::env::current_dir()?.join(path) };
+    let mut missing = Vec::new();
+    while !existing.exists() {
+        match (existing.file_name(), existing.parent()) {
+            (Some(name), Some(parent)) => {
+                missing.push(name.to_os_string());
+                existing = parent.to_path_buf();
+            }
+            _ => break,
+        }
+    }
+    let mut resolved = fs::canonicalize(&existing)?;
+    resolved.extend(missing.iter().rev());
+    Ok(resolved)
+}
+
+/// Serialize a chunk to `chunk_path` via a temp file and rename, so readers never see a partial chunk
+fn write_chunk_file(chunk_path: &Path, chunk: &TimeChunk) -> Result<(), StorageError> {
+    // Write to a temporary file first
+    let temp_path = chunk_path.with_extension("tmp");
+    write_synced_chunk(&temp_path, chunk)?;
+    
+    // Rename temp file to final name (atomic operation on most filesystems)
+    fs::rename(&temp_path, chunk_path)
+        .map_err(|e| StorageError::PersistenceError(format!("Failed to rename file: {}", e)))?;
+    
+    Ok(())
+}
+
+/// Serialize a chunk to `path` and sync it to disk
+fn write_synced_chunk(path: &Path, chunk: &TimeChunk) -> Result<(), StorageError> {
+    let serialized = serde_json::to_vec(chunk)
+        .map_err(|e| StorageError::PersistenceError(format!("Serialization failed: {}", e)))?;
+    
+    let mut file = File::create(path)
+        .map_err(|e| StorageError::PersistenceError(format!("Failed to create file: {}", e)))?;
+    
+    file.write_all(&serialized)
+        .map_err(|e| StorageError::PersistenceError(format!("Failed to write data: {}", e)))?;
+    
+    // Ensure data is flushed to disk
+    file.sync_all()
+        .map_err(|e| StorageError::PersistenceError(format!("Failed to sync data: {}", e)))
+}
+
+/// Forces WAL writes onto stable storage
+///
+/// A seam so tests can observe how often the fsync policy syncs.
+pub trait WalSync: Send + Sync + std::fmt::Debug {
+    fn sync(&self, file: &File) -> io::Result<()>;
+}
+
+/// `File::sync_data`, used outside tests
+#[derive(Debug)]
+struct FileSync;
+
+impl WalSync for FileSync {
+    fn sync(&self, file: &File) -> io::Result<()> {
+        file.sync_data()
+    }
+}
+
+/// Entries written since the last sync
+#[derive(Debug)]
+struct SyncState {
+    unsynced: usize,
+    last_sync: Instant,
 }
 
 /// Write-ahead log for crash recovery
@@ -256,64 +721,244 @@ impl PersistenceManager {
 pub struct WriteAheadLog {
     wal_path: PathBuf,
     log_file: Mutex<File>,
+    fsync: FsyncPolicy,
+    syncer: Box<dyn WalSync>,
+    sync_state: Mutex<SyncState>, // Only locked while holding `log_file`
+    preferred_format: WalFormat, // Format new WAL files are started in
+    format: Mutex<Option<WalFormat>>, // Format of the current file, `None` until its header is written; only locked while holding `log_file`
+    dropped: Mutex<u64>, // Entry bytes truncated from the front since opening; only locked while holding `log_file`
 }
 
 impl WriteAheadLog {
-    pub fn new(wal_dir: impl AsRef<Path>) -> io::Result<Self> {
+    pub fn new(wal_dir: impl AsRef<Path>, fsync: FsyncPolicy) -> io::Result<Self> {
+        Self::with_syncer(wal_dir, fsync, Box::new(FileSync))
+    }
+    
+    fn with_syncer(wal_dir: impl AsRef<Path>, fsync: FsyncPolicy, syncer: Box<dyn WalSync>) -> io::Result<Self> {
         let wal_dir = wal_dir.as_ref().to_path_buf();
         fs::create_dir_all(&wal_dir)?;
         
         let log_path = wal_dir.join("records.wal");
-        let log_file = OpenOptions::new()
+        let mut log_file = OpenOptions::new()
             .create(true)
             .read(true)
-            .write(true)
             .append(true)
             .open(&log_path)?;
+        let (format, _) = read_wal_header(&mut log_file)?;
         
         Ok(WriteAheadLog {
             wal_path: wal_dir,
             log_file: Mutex::new(log_file),
+            fsync,
+            syncer,
+            sync_state: Mutex::new(SyncState { unsynced: 0, last_sync: Instant::now() }),
+            preferred_format: WalFormat::default(),
+            format: Mutex::new(format),
+            dropped: Mutex::new(0),
         })
     }
     
     /// Append a record to the WAL
-    pub fn append_record(&self, record: &Record) -> io::Result<()> {
-        let serialized = serde_json::to_vec(record)?;
-        let record_size = serialized.len() as u32;
+    pub fn append_record(&self, record: &Record) -> io::Result<u64> {
+        self.append_with(|format| encode_record(format, record))
+    }
+    
+    /// Append a tagged operation to the WAL
+    pub fn append_entry(&self, entry: &WalEntry) -> io::Result<u64> {
+        self.append_with(|format| encode_entry(format, entry))
+    }
+    
+    /// Append many records in a single write, returning the bytes written
+    pub fn append_records(&self, records: &[Record]) -> io::Result<u64> {
+        let mut log_file = self.log_file.lock().unwrap();
+        let (format, mut written) = self.ensure_header(&mut log_file)?;
+        
+        let mut framed = Vec::with_capacity(records.len() * 100); // Rough estimate
+        for record in records {
+            let serialized = encode_record(format, record)?;
+            framed.extend_from_slice(&(serialized.len() as u32).to_be_bytes());
+            framed.extend_from_slice(&serialized);
+        }
         
+        // Synced per the fsync policy like single appends
+        self.write_framed(&mut log_file, &framed, records.len())?;
+        written += framed.len() as u64;
+        Ok(written)
+    }
+    
+    /// Write one already encoded entry, whatever the file's format
+    #[cfg(test)]
+    fn append_bytes(&self, serialized: &[u8]) -> io::Result<u64> {
+        self.append_with(|_| Ok(serialized.to_vec()))
+    }
+    
+    /// Write `entries` already length-prefixed and encoded entries in one go
+    #[cfg(test)]
+    fn append_framed(&self, framed: &[u8], entries: usize) -> io::Result<()> {
+        let mut log_file = self.log_file.lock().unwrap();
+        self.ensure_header(&mut log_file)?;
+        self.write_framed(&mut log_file, framed, entries)
+    }
+    
+    fn write_framed(&self, log_file: &mut File, framed: &[u8], entries: usize) -> io::Result<()> {
+        log_file.write_all(framed)?;
+        self.written(log_file, entries)
+    }
+    
+    /// Write one length-prefixed entry in the file's format, returning the bytes written including headers
+    fn append_with<F>(&self, encode: F) -> io::Result<u64>
+    where
+        F: FnOnce(WalFormat) -> io::Result<Vec<u8>>,
+    {
         let mut log_file = self.log_file.lock().unwrap();
+        let (format, header) = self.ensure_header(&mut log_file)?;
+        let serialized = encode(format)?;
         
         // Write 4-byte size header followed by record data
-        log_file.write_all(&record_size.to_be_bytes())?;
+        log_file.write_all(&(serialized.len() as u32).to_be_bytes())?;
         log_file.write_all(&serialized)?;
-        log_file.sync_data()?; // Ensure data is flushed to disk
+        self.written(&log_file, 1)?;
+        
+        Ok(header + 4 + serialized.len() as u64)
+    }
+    
+    /// The current file's format, first starting an empty file in the preferred one
+    ///
+    /// Returns the format and the bytes of header written.
+    fn ensure_header(&self, log_file: &mut File) -> io::Result<(WalFormat, u64)> {
+        let mut format = self.format.lock().unwrap();
+        if let Some(format) = *format {
+            return Ok((format, 0));
+        }
+        
+        let mut header = WAL_MAGIC.to_vec();
+        header.push(format_byte(self.preferred_format));
+        log_file.write_all(&header)?;
+        *format = Some(self.preferred_format);
+        Ok((self.preferred_format, header.len() as u64))
+    }
+    
+    /// Account for entries just written and sync if the policy says it's time
+    fn written(&self, log_file: &File, entries: usize) -> io::Result<()> {
+        let mut state = self.sync_state.lock().unwrap();
+        state.unsynced += entries;
         
+        let due = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EveryN(n) => state.unsynced >= n,
+            FsyncPolicy::Interval(interval) => state.last_sync.elapsed() >= interval,
+        };
+        if due {
+            self.syncer.sync(log_file)?;
+            state.unsynced = 0;
+            state.last_sync = Instant::now();
+        }
         Ok(())
     }
     
-    /// Replay the WAL to recover records
-    pub fn replay(&self) -> io::Result<Vec<Record>> {
+    /// Sync any entries the policy has not synced yet
+    pub fn sync(&self) -> io::Result<()> {
+        let log_file = self.log_file.lock().unwrap();
+        let mut state = self.sync_state.lock().unwrap();
+        if state.unsynced > 0 {
+            self.syncer.sync(&log_file)?;
+            state.unsynced = 0;
+            state.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+    
+    /// Where the log currently ends, as entry bytes written since it was opened
+    ///
+    /// Bytes truncated since still count, so a position stays valid for
+    /// `truncate_before` however often the front of the file is cut off.
+    pub fn position(&self) -> io::Result<u64> {
+        let mut log_file = self.log_file.lock().unwrap();
+        let (_, header) = read_wal_header(&mut log_file)?;
+        let len = log_file.metadata()?.len();
+        Ok(*self.dropped.lock().unwrap() + len.saturating_sub(header))
+    }
+    
+    /// Drop the entries before `position`, keeping the ones appended after it
+    ///
+    /// The kept tail is written to a fresh file that atomically replaces the
+    /// log. Appends wait for the log lock, so none can land in the old file
+    /// after the tail was copied.
+    pub fn truncate_before(&self, position: u64) -> io::Result<()> {
         let mut log_file = self.log_file.lock().unwrap();
-        log_file.seek(SeekFrom::Start(0))?;
+        let mut dropped = self.dropped.lock().unwrap();
+        let (format, header) = read_wal_header(&mut log_file)?;
+        let entry_bytes = log_file.metadata()?.len().saturating_sub(header);
+        let cut = position.saturating_sub(*dropped).min(entry_bytes);
+        if cut == 0 {
+            return Ok(());
+        }
+        
+        log_file.seek(SeekFrom::Start(header + cut))?;
+        let mut tail = Vec::new();
+        log_file.read_to_end(&mut tail)?;
+        
+        let log_path = self.wal_path.join("records.wal");
+        let temp_path = self.wal_path.join("records.wal.new");
+        {
+            let mut file = File::create(&temp_path)?;
+            if !tail.is_empty() {
+                file.write_all(WAL_MAGIC)?;
+                file.write_all(&[format_byte(format.unwrap_or_default())])?;
+                file.write_all(&tail)?;
+                file.sync_all()?;
+            }
+        }
+        fs::rename(&temp_path, &log_path)?;
         
+        *log_file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&log_path)?;
+        *dropped += cut;
+        self.sync_state.lock().unwrap().unsynced = 0;
+        // An emptied log starts over with a header in the preferred format on the next append
+        *self.format.lock().unwrap() = if tail.is_empty() { None } else { format };
+        Ok(())
+    }
+    
+    /// Replay the WAL to recover records
+    /// Read the whole WAL into memory; recovery streams it with `replay_with` instead
+    #[cfg(test)]
+    pub fn replay(&self) -> io::Result<Vec<WalEntry>> {
         let mut records = Vec::new();
+        self.replay_with(|entry| records.push(entry))?;
+        Ok(records)
+    }
+    
+    /// Read the WAL from the start, handing each entry to `visit` as it is read
+    ///
+    /// Only one entry is held at a time, however long the log. Returns the number of entries.
+    pub fn replay_with<F: FnMut(WalEntry)>(&self, mut visit: F) -> io::Result<usize> {
+        let mut log_file = self.log_file.lock().unwrap();
+        let (format, header) = read_wal_header(&mut log_file)?;
+        let format = format.unwrap_or_default();
+        log_file.seek(SeekFrom::Start(header))?;
+        let mut reader = io::BufReader::new(&*log_file);
+        
+        let mut count = 0;
+        let mut record_data = Vec::new();
         
         // Read each record
         loop {
             // Read record size (4 bytes)
             let mut size_buf = [0u8; 4];
-            match log_file.read_exact(&mut size_buf) {
+            match reader.read_exact(&mut size_buf) {
                 Ok(_) => {
                     let record_size = u32::from_be_bytes(size_buf) as usize;
                     
                     // Read the record data
-                    let mut record_data = vec![0u8; record_size];
-                    log_file.read_exact(&mut record_data)?;
+                    record_data.resize(record_size, 0);
+                    reader.read_exact(&mut record_data)?;
                     
-                    // Deserialize
-                    let record: Record = serde_json::from_slice(&record_data)?;
-                    records.push(record);
+                    visit(decode_entry(format, &record_data)?);
+                    count += 1;
                 }
                 Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
                     // Reached the end of the file
@@ -323,6 +968,299 @@ impl WriteAheadLog {
             }
         }
         
-        Ok(records)
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[derive(Debug, Default)]
+    struct CountingSync(Arc<AtomicUsize>);
+
+    impl WalSync for CountingSync {
+        fn sync(&self, _file: &File) -> io::Result<()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn counting_wal(dir: &Path, fsync: FsyncPolicy) -> (WriteAheadLog, Arc<AtomicUsize>) {
+        let syncs = Arc::new(AtomicUsize::new(0));
+        let wal = WriteAheadLog::with_syncer(dir, fsync, Box::new(CountingSync(Arc::clone(&syncs))))
+            .expect("Failed to create WAL");
+        (wal, syncs)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("emberdb_wal_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_fsync_policy_controls_sync_frequency() {
+        let dir = temp_dir("fsync_policy");
+
+        let (wal, syncs) = counting_wal(&dir.join("always"), FsyncPolicy::Always);
+        for _ in 0..5 {
+            wal.append_bytes(b"{}").unwrap();
+        }
+        assert_eq!(syncs.load(Ordering::SeqCst), 5);
+
+        let (wal, syncs) = counting_wal(&dir.join("every_n"), FsyncPolicy::EveryN(10));
+        for _ in 0..25 {
+            wal.append_bytes(b"{}").unwrap();
+        }
+        assert_eq!(syncs.load(Ordering::SeqCst), 2);
+        wal.sync().unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), 3);
+        wal.sync().unwrap(); // Nothing pending
+        assert_eq!(syncs.load(Ordering::SeqCst), 3);
+
+        let (wal, syncs) = counting_wal(&dir.join("interval"), FsyncPolicy::Interval(Duration::from_secs(3600)));
+        for _ in 0..25 {
+            wal.append_bytes(b"{}").unwrap();
+        }
+        assert_eq!(syncs.load(Ordering::SeqCst), 0);
+        wal.sync().unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), 1);
+
+        let (wal, syncs) = counting_wal(&dir.join("zero_interval"), FsyncPolicy::Interval(Duration::ZERO));
+        wal.append_bytes(b"{}").unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_batch_append_counts_every_record() {
+        let dir = temp_dir("fsync_batch");
+
+        // A framed batch of 150 entries crosses the EveryN(100) threshold once
+        let (wal, syncs) = counting_wal(&dir, FsyncPolicy::EveryN(100));
+        wal.append_framed(&[0, 0, 0, 2, b'{', b'}'], 150).unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), 1);
+        wal.append_framed(&[0, 0, 0, 2, b'{', b'}'], 99).unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), 1);
+        wal.append_bytes(b"{}").unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), 2);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_chunks_are_sharded_and_enumerable() {
+        let dir = temp_dir("sharded");
+        let chunks_dir = dir.join("chunks");
+        
+        // A chunk left over from the flat layout
+        let flat = PersistenceManager::new(&dir, FsyncPolicy::Always).unwrap()
+            .with_layout(ChunkLayout::Flat).unwrap();
+        flat.save_chunk(&TimeChunk::new(-3600, 0)).unwrap();
+        assert!(chunks_dir.join("-3600.chunk").is_file());
+        
+        let manager = PersistenceManager::new(&dir, FsyncPolicy::Always).unwrap()
+            .with_layout(ChunkLayout::Sharded).unwrap();
+        let mut expected = vec![-3600];
+        for week in 0..300 {
+            let chunk_id = 1_672_531_200 + week * 7 * 86_400;
+            manager.save_chunk(&TimeChunk::new(chunk_id, chunk_id + 3600)).unwrap();
+            expected.push(chunk_id);
+        }
+        
+        // Nothing stays at the top level, and the chunks spread over many directories
+        let top_level_files = fs::read_dir(&chunks_dir).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().is_file())
+            .count();
+        assert_eq!(top_level_files, 0);
+        let mut leaf_dirs: Vec<PathBuf> = expected.iter()
+            .map(|&chunk_id| {
+                let path = chunk_file_path(&chunks_dir, ChunkLayout::Sharded, chunk_id);
+                assert!(path.is_file(), "chunk {} not at {:?}", chunk_id, path);
+                assert_eq!(path.strip_prefix(&chunks_dir).unwrap().components().count(), 3);
+                path.parent().unwrap().to_path_buf()
+            })
+            .collect();
+        leaf_dirs.sort();
+        leaf_dirs.dedup();
+        assert!(leaf_dirs.len() > 100);
+        
+        assert_eq!(manager.list_chunks().unwrap(), expected);
+        assert_eq!(manager.load_chunk(-3600).unwrap().end_time, 0);
+        
+        // Switching back moves everything to the top level again
+        let flat = PersistenceManager::new(&dir, FsyncPolicy::Always).unwrap()
+            .with_layout(ChunkLayout::Flat).unwrap();
+        assert_eq!(flat.list_chunks().unwrap(), expected);
+        assert!(chunks_dir.join("1672531200.chunk").is_file());
+        assert!(!chunk_file_path(&chunks_dir, ChunkLayout::Sharded, 1_672_531_200).exists());
+        
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_streaming_replay_matches_buffered_replay() {
+        let dir = temp_dir("streaming_replay");
+        let (wal, _) = counting_wal(&dir, FsyncPolicy::Always);
+        
+        let record = |timestamp: i64| Record {
+            timestamp,
+            metric_name: "stream|8867-4|bpm".to_string(),
+            value: timestamp as f64,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        for timestamp in 0..50 {
+            wal.append_record(&record(timestamp)).unwrap();
+        }
+        wal.append_entry(&WalEntry::Upsert(record(7))).unwrap();
+        wal.append_entry(&WalEntry::Update { metric_name: "stream|8867-4|bpm".to_string(), timestamp: 3, value: 1.5 }).unwrap();
+        wal.append_entry(&WalEntry::Rename { from: "stream|8867-4|bpm".to_string(), to: "stream|8867-4|/min".to_string() }).unwrap();
+        wal.append_record(&record(50)).unwrap();
+        
+        let buffered: Vec<String> = wal.replay().unwrap().iter().map(|entry| format!("{:?}", entry)).collect();
+        let mut streamed = Vec::new();
+        let count = wal.replay_with(|entry| streamed.push(format!("{:?}", entry))).unwrap();
+        
+        assert_eq!(count, 54);
+        assert_eq!(streamed, buffered);
+        
+        // Appends after a replay still land at the end of the log
+        wal.append_record(&record(51)).unwrap();
+        assert_eq!(wal.replay_with(|_| {}).unwrap(), 55);
+        
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn wal_record(timestamp: i64) -> Record {
+        let mut context = HashMap::new();
+        context.insert("device_id".to_string(), "monitor-1".to_string());
+        Record {
+            timestamp,
+            metric_name: "bin|8867-4|bpm".to_string(),
+            value: 60.0 + timestamp as f64 / 4.0,
+            string_value: (timestamp % 5 == 0).then(|| "irregular".to_string()),
+            context,
+            resource_type: "Observation".to_string(),
+            id: Some(format!("obs-{}", timestamp)),
+        }
+    }
+
+    /// Debug renderings of every entry `manager` replays
+    fn replayed(manager: &PersistenceManager) -> Vec<String> {
+        let mut entries = Vec::new();
+        manager.replay_wal_with(|entry| entries.push(format!("{:?}", entry))).unwrap();
+        entries
+    }
+
+    #[test]
+    fn test_binary_wal_round_trip() {
+        let dir = temp_dir("binary_round_trip");
+        let batch: Vec<Record> = (10..160).map(wal_record).collect();
+        let entries = vec![
+            WalEntry::Append(wal_record(0)),
+            WalEntry::Upsert(wal_record(5)),
+            WalEntry::Update { metric_name: "bin|8867-4|bpm".to_string(), timestamp: 5, value: 1.5 },
+            WalEntry::Rename { from: "bin|8867-4|bpm".to_string(), to: "bin|8867-4|/min".to_string() },
+        ];
+        
+        let mut wal_sizes = Vec::new();
+        for format in [WalFormat::Json, WalFormat::Binary] {
+            let manager = PersistenceManager::new(dir.join(format!("{:?}", format)), FsyncPolicy::Always).unwrap()
+                .with_wal_format(format);
+            manager.append_record(&wal_record(1)).unwrap();
+            for entry in &entries {
+                manager.append_entry(entry).unwrap();
+            }
+            // More than 100 records take the batch path
+            manager.append_records(&batch).unwrap();
+            
+            let expected: Vec<String> = std::iter::once(format!("{:?}", WalEntry::Append(wal_record(1))))
+                .chain(entries.iter().map(|entry| format!("{:?}", entry)))
+                .chain(batch.iter().map(|record| format!("{:?}", WalEntry::Append(record.clone()))))
+                .collect();
+            assert_eq!(replayed(&manager), expected, "{:?}", format);
+            
+            let wal = fs::read(manager.get_wal_path()).unwrap();
+            assert_eq!(&wal[..4], WAL_MAGIC);
+            assert_eq!(wal[4], format_byte(format));
+            wal_sizes.push(wal.len());
+        }
+        assert!(wal_sizes[1] < wal_sizes[0], "binary {} vs json {} bytes", wal_sizes[1], wal_sizes[0]);
+        
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_switching_wal_format_keeps_existing_entries_readable() {
+        let dir = temp_dir("format_migration");
+        
+        // A WAL from before headers: bare length-prefixed JSON records
+        fs::create_dir_all(dir.join("wal")).unwrap();
+        let legacy = serde_json::to_vec(&wal_record(0)).unwrap();
+        let mut bytes = (legacy.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&legacy);
+        fs::write(dir.join("wal").join("records.wal"), bytes).unwrap();
+        
+        // Switching to binary keeps appending JSON to the existing file
+        let manager = PersistenceManager::new(&dir, FsyncPolicy::Always).unwrap()
+            .with_wal_format(WalFormat::Binary);
+        manager.append_record(&wal_record(1)).unwrap();
+        manager.append_records(&(2..200).map(wal_record).collect::<Vec<_>>()).unwrap();
+        assert_eq!(replayed(&manager).len(), 200);
+        assert!(replayed(&manager)[1].contains("obs-1"));
+        
+        // Once truncated, the WAL starts over in binary
+        manager.truncate_wal_before(manager.wal_position().unwrap()).unwrap();
+        manager.append_record(&wal_record(300)).unwrap();
+        let wal = fs::read(manager.get_wal_path()).unwrap();
+        assert_eq!((&wal[..4], wal[4]), (&WAL_MAGIC[..], format_byte(WalFormat::Binary)));
+        drop(manager);
+        
+        // Switching back to JSON still reads and extends the binary file
+        let manager = PersistenceManager::new(&dir, FsyncPolicy::Always).unwrap();
+        manager.append_entry(&WalEntry::Upsert(wal_record(301))).unwrap();
+        assert_eq!(replayed(&manager), vec![
+            format!("{:?}", WalEntry::Append(wal_record(300))),
+            format!("{:?}", WalEntry::Upsert(wal_record(301))),
+        ]);
+        
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_truncating_wal_keeps_entries_after_position() {
+        let dir = temp_dir("truncate_before");
+        let manager = PersistenceManager::new(&dir, FsyncPolicy::Always).unwrap()
+            .with_wal_format(WalFormat::Binary);
+        
+        manager.append_records(&(0..10).map(wal_record).collect::<Vec<_>>()).unwrap();
+        let position = manager.wal_position().unwrap();
+        manager.append_record(&wal_record(10)).unwrap();
+        
+        // Only the entries logged before the position are dropped
+        manager.truncate_wal_before(position).unwrap();
+        assert_eq!(replayed(&manager), vec![format!("{:?}", WalEntry::Append(wal_record(10)))]);
+        
+        // Positions keep counting across truncations, so an old one is a no-op
+        manager.append_record(&wal_record(11)).unwrap();
+        manager.truncate_wal_before(position).unwrap();
+        assert_eq!(replayed(&manager).len(), 2);
+        
+        // The kept tail survives a reopen
+        drop(manager);
+        let manager = PersistenceManager::new(&dir, FsyncPolicy::Always).unwrap();
+        assert_eq!(replayed(&manager).len(), 2);
+        manager.truncate_wal_before(manager.wal_position().unwrap()).unwrap();
+        assert!(replayed(&manager).is_empty());
+        
+        let _ = fs::remove_dir_all(&dir);
     }
-} 
\ No newline at end of file
+}
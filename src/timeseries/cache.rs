@@ -0,0 +1,196 @@
+//! Memoized analytics results
+//!
+//! Dashboards poll the same (metric, start, end) window every few seconds, so
+//! `QueryEngine` keeps the last few stats/trend results in a small LRU cache.
+//! Entries are dropped when a record lands inside their range.
+
+use std::collections::HashMap;
+use serde::Serialize;
+use crate::timeseries::functions::{TimeSeriesStats, TrendAnalysis};
+
+/// Entries kept when the config doesn't say otherwise
+pub const DEFAULT_QUERY_CACHE_SIZE: usize = 256;
+
+/// Which analytics function a cached result came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CachedFunction {
+    Stats,
+    Trend,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub metric: String,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub function: CachedFunction,
+}
+
+#[derive(Debug, Clone)]
+pub enum CachedResult {
+    Stats(TimeSeriesStats),
+    Trend(TrendAnalysis),
+}
+
+impl From<TimeSeriesStats> for CachedResult {
+    fn from(stats: TimeSeriesStats) -> Self {
+        CachedResult::Stats(stats)
+    }
+}
+
+impl From<TrendAnalysis> for CachedResult {
+    fn from(trend: TrendAnalysis) -> Self {
+        CachedResult::Trend(trend)
+    }
+}
+
+impl TryFrom<CachedResult> for TimeSeriesStats {
+    type Error = CachedResult;
+
+    fn try_from(result: CachedResult) -> Result<Self, Self::Error> {
+        match result {
+            CachedResult::Stats(stats) => Ok(stats),
+            other => Err(other),
+        }
+    }
+}
+
+impl TryFrom<CachedResult> for TrendAnalysis {
+    type Error = CachedResult;
+
+    fn try_from(result: CachedResult) -> Result<Self, Self::Error> {
+        match result {
+            CachedResult::Trend(trend) => Ok(trend),
+            other => Err(other),
+        }
+    }
+}
+
+/// Cache effectiveness counters
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+/// LRU cache of analytics results with range-based invalidation
+///
+/// A capacity of 0 disables caching.
+#[derive(Debug)]
+pub struct QueryCache {
+    capacity: usize,
+    entries: HashMap<CacheKey, (CachedResult, u64)>, // Result and the tick it was last used
+    tick: u64,
+    generation: u64, // Bumped by every invalidation
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize) -> Self {
+        QueryCache {
+            capacity,
+            entries: HashMap::new(),
+            tick: 0,
+            generation: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Current invalidation generation; pass it back to `put` after computing a miss
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn get(&mut self, key: &CacheKey) -> Option<CachedResult> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.entries.get_mut(key).map(|(result, last_used)| {
+            *last_used = tick;
+            result.clone()
+        })
+    }
+
+    /// Store a result computed while the cache was at `generation`
+    ///
+    /// If anything was invalidated since, the result may already be stale and is dropped.
+    pub fn put(&mut self, key: CacheKey, result: CachedResult, generation: u64) {
+        if self.capacity == 0 || generation != self.generation {
+            return;
+        }
+
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            let oldest = self.entries.iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.tick += 1;
+        self.entries.insert(key, (result, self.tick));
+    }
+
+    /// Drop every entry for `metric` whose range contains `timestamp`
+    pub fn invalidate(&mut self, metric: &str, timestamp: i64) {
+        self.generation += 1;
+        self.entries.retain(|key, _| {
+            key.metric != metric || timestamp < key.start_time || timestamp >= key.end_time
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timeseries::functions::TimeSeriesFunctions;
+
+    fn key(metric: &str, start_time: i64, end_time: i64) -> CacheKey {
+        CacheKey { metric: metric.to_string(), start_time, end_time, function: CachedFunction::Stats }
+    }
+
+    fn stats() -> CachedResult {
+        CachedResult::Stats(TimeSeriesFunctions::calculate_stats(&[]))
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = QueryCache::new(2);
+        cache.put(key("a", 0, 10), stats(), 0);
+        cache.put(key("b", 0, 10), stats(), 0);
+        assert!(cache.get(&key("a", 0, 10)).is_some());
+
+        cache.put(key("c", 0, 10), stats(), 0);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&key("a", 0, 10)).is_some());
+        assert!(cache.get(&key("b", 0, 10)).is_none());
+    }
+
+    #[test]
+    fn test_invalidation_is_range_scoped() {
+        let mut cache = QueryCache::new(8);
+        cache.put(key("a", 0, 10), stats(), 0);
+        cache.put(key("a", 10, 20), stats(), 0);
+        cache.put(key("b", 0, 10), stats(), 0);
+
+        cache.invalidate("a", 10);
+        assert!(cache.get(&key("a", 0, 10)).is_some());
+        assert!(cache.get(&key("a", 10, 20)).is_none());
+        assert!(cache.get(&key("b", 0, 10)).is_some());
+
+        // Results computed before an invalidation are not stored
+        cache.put(key("a", 10, 20), stats(), 0);
+        assert!(cache.get(&key("a", 10, 20)).is_none());
+
+        let mut disabled = QueryCache::new(0);
+        disabled.put(key("a", 0, 10), stats(), 0);
+        assert!(disabled.is_empty());
+    }
+}
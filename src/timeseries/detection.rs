@@ -1,9 +1,11 @@
+// The matrix and windowing code below reads more clearly with explicit indices
+#![allow(clippy::needless_range_loop)]
+
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 use std::path::Path;
 use std::fs;
 use crate::storage::Record;
-use crate::timeseries::functions::{TrendAnalysis, OutlierDetection, TimeSeriesFunctions};
 
 /// Configuration for pattern detection algorithms
 #[derive(Debug, Serialize, Deserialize)]
@@ -15,6 +17,58 @@ pub struct DetectionConfig {
     pub moving_window: Option<MovingWindowConfig>,
 }
 
+impl DetectionConfig {
+    /// Check that every enabled detector has usable parameters
+    pub fn validate(&self) -> Result<(), String> {
+        fn positive(name: &str, value: f64) -> Result<(), String> {
+            if value.is_finite() && value > 0.0 {
+                Ok(())
+            } else {
+                Err(format!("{} must be a positive number, got {}", name, value))
+            }
+        }
+        
+        if self.global.default_lookback_window <= 0 {
+            return Err("global.default_lookback_window must be positive".to_string());
+        }
+        
+        if let Some(cfg) = self.seasonal.as_ref().filter(|c| c.enabled) {
+            if cfg.period <= 0 {
+                return Err("seasonal.period must be positive".to_string());
+            }
+            if cfg.min_data_points == 0 {
+                return Err("seasonal.min_data_points must be at least 1".to_string());
+            }
+        }
+        
+        if let Some(cfg) = self.multivariate.as_ref().filter(|c| c.enabled) {
+            if !(0.0..=1.0).contains(&cfg.correlation_threshold) {
+                return Err(format!(
+                    "multivariate.correlation_threshold must be between 0 and 1, got {}",
+                    cfg.correlation_threshold
+                ));
+            }
+            positive("multivariate.threshold", cfg.threshold)?;
+        }
+        
+        if let Some(cfg) = self.changepoint.as_ref().filter(|c| c.enabled) {
+            positive("changepoint.threshold", cfg.threshold)?;
+            if !cfg.penalty.is_finite() || cfg.penalty < 0.0 {
+                return Err(format!("changepoint.penalty must be non-negative, got {}", cfg.penalty));
+            }
+        }
+        
+        if let Some(cfg) = self.moving_window.as_ref().filter(|c| c.enabled) {
+            if cfg.window_size <= 0 || cfg.step_size <= 0 {
+                return Err("moving_window.window_size and step_size must be positive".to_string());
+            }
+            positive("moving_window.threshold", cfg.threshold)?;
+        }
+        
+        Ok(())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GlobalConfig {
     pub enable_all: bool,
@@ -128,7 +182,7 @@ pub struct ChangepointResult {
     pub method: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WindowAnalysisPoint {
     pub window_start: i64,
     pub window_end: i64,
@@ -147,6 +201,12 @@ pub struct PatternDetector {
     config: DetectionConfig,
 }
 
+impl Default for PatternDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PatternDetector {
     /// Create a new pattern detector with default configuration
     pub fn new() -> Self {
@@ -186,6 +246,16 @@ impl PatternDetector {
         PatternDetector { config }
     }
     
+    /// Create a pattern detector from an already-built configuration
+    pub fn with_config(config: DetectionConfig) -> Self {
+        PatternDetector { config }
+    }
+    
+    /// The configuration this detector runs with
+    pub fn config(&self) -> &DetectionConfig {
+        &self.config
+    }
+    
     /// Load configuration from a TOML file
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
         let content = fs::read_to_string(path)?;
@@ -233,7 +303,7 @@ impl PatternDetector {
         
         // Apply seasonal pattern to each timestamp
         for i in 0..timestamps.len() {
-            let seasonal_idx = (i % period_samples) as usize;
+            let seasonal_idx = i % period_samples;
             if seasonal_idx < seasonal_pattern.len() {
                 seasonal.push((timestamps[i], seasonal_pattern[seasonal_idx]));
             }
@@ -495,7 +565,7 @@ impl PatternDetector {
                 .map(|(_, v)| *v)
                 .unwrap_or(values[i]);
                 
-            let position = (i % period_samples) as usize;
+            let position = i % period_samples;
             
             match method {
                 SeasonalMethod::Additive => {
@@ -552,7 +622,7 @@ impl PatternDetector {
             if let Some(records) = metric_records.get(metric) {
                 for record in records {
                     aligned_data.entry(record.timestamp)
-                        .or_insert_with(Vec::new)
+                        .or_default()
                         .push((metric.clone(), record.value));
                 }
             }
@@ -619,7 +689,7 @@ impl PatternDetector {
                 let correlation = if i == j {
                     1.0 // Perfect correlation with self
                 } else {
-                    self.calculate_correlation(
+                    Self::calculate_correlation(
                         metric_records.get(metric1).unwrap(),
                         metric_records.get(metric2).unwrap()
                     )
@@ -662,7 +732,10 @@ impl PatternDetector {
         groups
     }
     
-    fn calculate_correlation(&self, records1: &[Record], records2: &[Record]) -> f64 {
+    /// Pearson correlation of two series, aligned on their shared timestamps
+    ///
+    /// Returns 0.0 when fewer than three timestamps overlap or either side is constant.
+    pub fn calculate_correlation(records1: &[Record], records2: &[Record]) -> f64 {
         // Create a map of timestamp to value for each metric
         let mut values1: HashMap<i64, f64> = HashMap::new();
         let mut values2: HashMap<i64, f64> = HashMap::new();
@@ -695,8 +768,8 @@ impl PatternDetector {
         }
         
         // Calculate Pearson correlation
-        let mean_x = self.calculate_mean(&x);
-        let mean_y = self.calculate_mean(&y);
+        let mean_x = x.iter().sum::<f64>() / x.len() as f64;
+        let mean_y = y.iter().sum::<f64>() / y.len() as f64;
         
         let mut numerator = 0.0;
         let mut denom_x = 0.0;
@@ -854,7 +927,7 @@ impl PatternDetector {
             
             // Use max absolute Z-score as anomaly score (simplified approach)
             let max_zscore = z_scores.iter()
-                .fold(0.0, |max, &z| max.max(z.abs()));
+                .fold(0.0_f64, |max, &z| max.max(z.abs()));
                 
             if max_zscore > 3.0 { // Threshold of 3 sigma
                 outliers.push(MultivariateOutlier {
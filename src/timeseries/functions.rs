@@ -15,7 +15,7 @@ pub struct AnalysisResult {
 }
 
 /// Trend analysis for a time series
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrendAnalysis {
     pub metric_name: String,
     pub slope: f64,              // Rate of change per second
@@ -27,10 +27,26 @@ pub struct TrendAnalysis {
     pub stddev: f64,             // Standard deviation
     pub data_points: usize,      // Number of points analyzed
     pub samples: Vec<(i64, f64)>,// Sample points (for visualization)
+    pub direction: TrendDirection,
+    pub significant: bool,       // Large enough and consistent enough to act on
+}
+
+/// Fitted change over the whole range, in standard deviations, below which a series is `Stable`
+pub const TREND_STABLE_STDDEVS: f64 = 1.0;
+
+/// Fit quality a trend needs before it counts as significant
+pub const TREND_MIN_R_SQUARED: f64 = 0.5;
+
+/// Which way a series is heading, judged from the fitted change against its spread
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TrendDirection {
+    Increasing,
+    Decreasing,
+    Stable,
 }
 
 /// Statistics for a time period
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TimeSeriesStats {
     pub metric_name: String,
     pub min: f64,
@@ -51,6 +67,37 @@ pub struct OutlierDetection {
     pub method: String,
 }
 
+/// How `detect_outliers_with` scores points
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutlierMethod {
+    /// Distance from the mean in standard deviations
+    #[default]
+    ZScore,
+    /// Modified z-score: distance from the median in scaled median absolute deviations
+    Mad,
+}
+
+impl OutlierMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutlierMethod::ZScore => "zscore",
+            OutlierMethod::Mad => "mad",
+        }
+    }
+}
+
+impl std::str::FromStr for OutlierMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zscore" => Ok(OutlierMethod::ZScore),
+            "mad" => Ok(OutlierMethod::Mad),
+            _ => Err(format!("Unknown outlier method '{}', expected 'zscore' or 'mad'", s)),
+        }
+    }
+}
+
 /// A single outlier point
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OutlierPoint {
@@ -60,6 +107,35 @@ pub struct OutlierPoint {
     pub score: f64,      // 0-1 outlier score
 }
 
+/// Autocorrelation of a series and the period it suggests
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AutocorrelationResult {
+    pub metric_name: String,
+    pub acf: Vec<(usize, f64)>,       // (lag in samples, correlation)
+    pub period: Option<usize>,        // Lag of the first significant peak
+    pub period_seconds: Option<i64>,  // Period scaled by the median sampling interval
+    pub data_points: usize,
+}
+
+/// A point whose rate of change exceeded an alerting threshold
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RateBreach {
+    pub timestamp: i64,  // Timestamp of `after`
+    pub rate: f64,       // Signed change per period
+    pub before: Record,
+    pub after: Record,
+}
+
+/// One bucket of a rollup computed when its chunk was flushed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RollupPoint {
+    pub timestamp: i64,  // Start of the bucket
+    pub mean: f64,
+    pub min: f64,
+    pub max: f64,
+    pub count: usize,
+}
+
 /// Collection of time series functions
 pub struct TimeSeriesFunctions;
 
@@ -78,6 +154,8 @@ impl TimeSeriesFunctions {
                 stddev: 0.0,
                 data_points: 0,
                 samples: vec![],
+                direction: TrendDirection::Stable,
+                significant: false,
             };
         }
         
@@ -133,6 +211,18 @@ impl TimeSeriesFunctions {
         let var_sum: f64 = values.iter().map(|y| (y - mean_y).powi(2)).sum();
         let stddev = (var_sum / n).sqrt();
         
+        // Verdict: the change the fit predicts across the range, measured against the spread
+        let span = points.last().unwrap().0 - points.first().unwrap().0;
+        let fitted_change = slope * span;
+        let direction = if stddev == 0.0 || fitted_change.abs() < TREND_STABLE_STDDEVS * stddev {
+            TrendDirection::Stable
+        } else if fitted_change > 0.0 {
+            TrendDirection::Increasing
+        } else {
+            TrendDirection::Decreasing
+        };
+        let significant = direction != TrendDirection::Stable && r_squared >= TREND_MIN_R_SQUARED;
+        
         // Create sample points for visualization (take up to 20 evenly spaced points)
         let mut samples = Vec::new();
         let step = (points.len() / 20).max(1);
@@ -166,6 +256,8 @@ impl TimeSeriesFunctions {
             stddev,
             data_points: points.len(),
             samples,
+            direction,
+            significant,
         }
     }
     
@@ -194,7 +286,7 @@ impl TimeSeriesFunctions {
         let mean = values.iter().sum::<f64>() / count as f64;
         
         // Calculate median
-        let median = if count % 2 == 0 {
+        let median = if count.is_multiple_of(2) {
             (values[count / 2 - 1] + values[count / 2]) / 2.0
         } else {
             values[count / 2]
@@ -227,38 +319,62 @@ impl TimeSeriesFunctions {
         }
     }
     
-    /// Detect outliers in a time series
+    /// Detect outliers in a time series by z-score
     pub fn detect_outliers(records: &[Record], z_threshold: f64) -> OutlierDetection {
+        Self::detect_outliers_with(records, z_threshold, OutlierMethod::ZScore)
+    }
+    
+    /// Detect outliers whose score under `method` exceeds `threshold`
+    ///
+    /// A single extreme spike inflates the standard deviation enough to hide
+    /// itself from a z-score, but barely moves the median and MAD, so `Mad`
+    /// suits spiky vital-sign data better.
+    pub fn detect_outliers_with(records: &[Record], threshold: f64, method: OutlierMethod) -> OutlierDetection {
         if records.is_empty() {
             return OutlierDetection {
                 metric_name: "".to_string(),
                 outliers: vec![],
-                threshold: z_threshold,
-                method: "zscore".to_string(),
+                threshold,
+                method: method.as_str().to_string(),
             };
         }
         
         let metric_name = records[0].metric_name.clone();
         let values: Vec<f64> = records.iter().map(|r| r.value).collect();
         
-        // Calculate mean and standard deviation
-        let mean = values.iter().sum::<f64>() / values.len() as f64;
-        let var_sum: f64 = values.iter().map(|v| (v - mean).powi(2)).sum();
-        let stddev = (var_sum / values.len() as f64).sqrt();
+        // Center and spread the scores are measured against
+        let (center, spread) = match method {
+            OutlierMethod::ZScore => {
+                let mean = values.iter().sum::<f64>() / values.len() as f64;
+                let var_sum: f64 = values.iter().map(|v| (v - mean).powi(2)).sum();
+                (mean, (var_sum / values.len() as f64).sqrt())
+            }
+            OutlierMethod::Mad => {
+                let middle = median(values.clone());
+                let deviations: Vec<f64> = values.iter().map(|v| (v - middle).abs()).collect();
+                let mean_deviation = deviations.iter().sum::<f64>() / deviations.len() as f64;
+                // 1.4826 makes MAD estimate the standard deviation of normal data; when
+                // more than half the values are identical MAD is 0, so fall back to
+                // the mean absolute deviation, scaled the same way
+                let mad = median(deviations);
+                let spread = if mad > 0.0 { 1.4826 * mad } else { 1.253314 * mean_deviation };
+                (middle, spread)
+            }
+        };
         
-        // Find outliers based on Z-score
+        // Find outliers based on the score
         let mut outliers = Vec::new();
         
-        for (_i, record) in records.iter().enumerate() {
-            let z_score = if stddev > 0.0 { (record.value - mean) / stddev } else { 0.0 };
-            let abs_z_score = z_score.abs();
+        for record in records.iter() {
+            let score = if spread > 0.0 { (record.value - center) / spread } else { 0.0 };
+            let abs_score = score.abs();
             
-            if abs_z_score > z_threshold {
+            if abs_score > threshold {
                 outliers.push(OutlierPoint {
                     timestamp: record.timestamp,
                     value: record.value,
-                    deviation: record.value - mean,
-                    score: abs_z_score / (abs_z_score + 1.0), // Normalize to 0-1
+                    deviation: record.value - center,
+                    score: abs_score / (abs_score + 1.0), // Normalize to 0-1
                 });
             }
         }
@@ -269,14 +385,17 @@ impl TimeSeriesFunctions {
         OutlierDetection {
             metric_name,
             outliers,
-            threshold: z_threshold,
-            method: "zscore".to_string(),
+            threshold,
+            method: method.as_str().to_string(),
         }
     }
     
     /// Calculate rate of change (velocity) for a time series
+    ///
+    /// Rates are signed, so a falling series yields negative values. Pairs that
+    /// share a timestamp are skipped, and a non-positive period yields no rates.
     pub fn calculate_rate_of_change(records: &[Record], period_seconds: i64) -> Vec<Record> {
-        if records.len() < 2 {
+        if records.len() < 2 || period_seconds <= 0 {
             return Vec::new();
         }
         
@@ -287,23 +406,7 @@ impl TimeSeriesFunctions {
         let mut result = Vec::new();
         let metric_name = format!("{}_rate", sorted_records[0].metric_name);
         
-        for window in sorted_records.windows(2) {
-            if window.len() < 2 {
-                continue;
-            }
-            
-            let r1 = &window[0];
-            let r2 = &window[1];
-            
-            let time_diff = r2.timestamp - r1.timestamp;
-            if time_diff <= 0 {
-                continue; // Skip invalid time differences
-            }
-            
-            // Calculate rate as change per specified period
-            let value_diff = r2.value - r1.value;
-            let rate = value_diff / (time_diff as f64) * (period_seconds as f64);
-            
+        for (_, r2, rate) in Self::adjacent_rates(&sorted_records, period_seconds) {
             // Create a new record at the end timestamp
             let mut context = r2.context.clone();
             context.insert("rate_period_seconds".to_string(), period_seconds.to_string());
@@ -313,11 +416,530 @@ impl TimeSeriesFunctions {
                 timestamp: r2.timestamp,
                 metric_name: metric_name.clone(),
                 value: rate,
+                string_value: None,
                 context,
                 resource_type: r2.resource_type.clone(),
+                id: None,
+            });
+        }
+        
+        result
+    }
+
+    /// Pairs of adjacent points whose absolute rate of change exceeds `max_rate`
+    ///
+    /// Rates are computed as in `calculate_rate_of_change`; each breach keeps
+    /// the two records the rate came from.
+    pub fn rate_threshold_breaches(records: &[Record], period_seconds: i64, max_rate: f64) -> Vec<RateBreach> {
+        if records.len() < 2 || period_seconds <= 0 {
+            return Vec::new();
+        }
+        
+        let mut sorted_records = records.to_vec();
+        sorted_records.sort_by_key(|r| r.timestamp);
+        
+        Self::adjacent_rates(&sorted_records, period_seconds)
+            .filter(|(_, _, rate)| rate.abs() > max_rate)
+            .map(|(before, after, rate)| RateBreach {
+                timestamp: after.timestamp,
+                rate,
+                before: before.clone(),
+                after: after.clone(),
+            })
+            .collect()
+    }
+    
+    /// Change per period between each pair of adjacent records, skipping shared timestamps
+    fn adjacent_rates(sorted_records: &[Record], period_seconds: i64) 
+        -> impl Iterator<Item = (&Record, &Record, f64)> 
+    {
+        sorted_records.windows(2).filter_map(move |window| {
+            let (r1, r2) = (&window[0], &window[1]);
+            let time_diff = seconds_between(r1.timestamp, r2.timestamp);
+            if time_diff <= 0.0 {
+                return None;
+            }
+            let rate = (r2.value - r1.value) / time_diff * (period_seconds as f64);
+            Some((r1, r2, rate))
+        })
+    }
+
+    /// Rate of change per fixed grid bucket rather than per adjacent pair
+    ///
+    /// Each bucket of `bucket_seconds` (aligned to the epoch) with at least two
+    /// distinct timestamps yields one rate, from its first to its last point,
+    /// stamped at the bucket start.
+    pub fn calculate_rate_per_bucket(records: &[Record], bucket_seconds: i64, period_seconds: i64) -> Vec<Record> {
+        if records.len() < 2 || bucket_seconds <= 0 || period_seconds <= 0 {
+            return Vec::new();
+        }
+        
+        let mut sorted_records = records.to_vec();
+        sorted_records.sort_by_key(|r| r.timestamp);
+        let metric_name = format!("{}_rate", sorted_records[0].metric_name);
+        
+        let mut result = Vec::new();
+        for bucket in sorted_records.chunk_by(|a, b| {
+            a.timestamp.div_euclid(bucket_seconds) == b.timestamp.div_euclid(bucket_seconds)
+        }) {
+            let (first, last) = (&bucket[0], &bucket[bucket.len() - 1]);
+            let time_diff = seconds_between(first.timestamp, last.timestamp);
+            if time_diff <= 0.0 {
+                continue; // Not enough spread in this bucket
+            }
+            // The first bucket may start before the earliest representable timestamp
+            let Some(bucket_start) = first.timestamp.div_euclid(bucket_seconds).checked_mul(bucket_seconds) else {
+                continue;
+            };
+            
+            let rate = (last.value - first.value) / time_diff * (period_seconds as f64);
+            
+            let mut context = HashMap::new();
+            context.insert("rate_period_seconds".to_string(), period_seconds.to_string());
+            context.insert("bucket_seconds".to_string(), bucket_seconds.to_string());
+            context.insert("bucket_points".to_string(), bucket.len().to_string());
+            context.insert("original_metric".to_string(), first.metric_name.clone());
+            
+            result.push(Record {
+                timestamp: bucket_start,
+                metric_name: metric_name.clone(),
+                value: rate,
+                string_value: None,
+                context,
+                resource_type: first.resource_type.clone(),
+                id: None,
             });
         }
         
         result
     }
-} 
\ No newline at end of file
+    
+    /// Sample autocorrelation for lags 0..=max_lag, ordering records by timestamp
+    pub fn autocorrelation(records: &[Record], max_lag: usize) -> Vec<(usize, f64)> {
+        if records.len() < 2 {
+            return Vec::new();
+        }
+        
+        let mut sorted_records = records.to_vec();
+        sorted_records.sort_by_key(|r| r.timestamp);
+        let values: Vec<f64> = sorted_records.iter().map(|r| r.value).collect();
+        
+        let n = values.len();
+        let mean = values.iter().sum::<f64>() / n as f64;
+        let denominator: f64 = values.iter().map(|v| (v - mean).powi(2)).sum();
+        
+        (0..=max_lag.min(n - 1))
+            .map(|lag| {
+                // A constant series has no defined correlation
+                if denominator == 0.0 {
+                    return (lag, 0.0);
+                }
+                let numerator: f64 = (0..n - lag)
+                    .map(|t| (values[t] - mean) * (values[t + lag] - mean))
+                    .sum();
+                (lag, numerator / denominator)
+            })
+            .collect()
+    }
+    
+    /// Lag of the first local maximum in the ACF that clears the ~95% significance band
+    pub fn first_acf_peak(acf: &[(usize, f64)], data_points: usize) -> Option<usize> {
+        if data_points == 0 {
+            return None;
+        }
+        let significance = 1.96 / (data_points as f64).sqrt();
+        
+        acf.windows(3)
+            .find(|w| w[1].1 > w[0].1 && w[1].1 >= w[2].1 && w[1].1 > significance)
+            .map(|w| w[1].0)
+    }
+    
+    /// Bucket values into `bins` equal-width buckets spanning min..max
+    ///
+    /// Returns (bucket_low, bucket_high, count). The last bucket includes the max.
+    /// When every value is equal there is a single bucket holding all of them.
+    pub fn histogram(records: &[Record], bins: usize) -> Vec<(f64, f64, usize)> {
+        let values: Vec<f64> = records.iter()
+            .map(|r| r.value)
+            .filter(|v| v.is_finite())
+            .collect();
+        if values.is_empty() || bins == 0 {
+            return Vec::new();
+        }
+        
+        let min = values.iter().fold(f64::INFINITY, |a, &b| a.min(b));
+        let max = values.iter().fold(f64::NEG_INFINITY, |a, &b| a.max(b));
+        if min == max {
+            return vec![(min, max, values.len())];
+        }
+        
+        let width = (max - min) / bins as f64;
+        let mut counts = vec![0usize; bins];
+        for value in &values {
+            let index = (((value - min) / width) as usize).min(bins - 1);
+            counts[index] += 1;
+        }
+        
+        counts.into_iter()
+            .enumerate()
+            .map(|(i, count)| {
+                let low = min + width * i as f64;
+                let high = if i == bins - 1 { max } else { min + width * (i + 1) as f64 };
+                (low, high, count)
+            })
+            .collect()
+    }
+
+    /// Savitzky-Golay smoothing: a least-squares polynomial fitted over a sliding window
+    ///
+    /// Each point is replaced by the value at its own timestamp of a degree
+    /// `poly_order` polynomial fitted to the `window` points around it. Near the
+    /// ends the window is shifted rather than shrunk. Timestamps are used as the
+    /// x axis, so uneven spacing is handled. The window must be odd and larger
+    /// than `poly_order`; otherwise no points are returned.
+    pub fn savitzky_golay(records: &[Record], window: usize, poly_order: usize) -> Vec<Record> {
+        if records.is_empty() || window.is_multiple_of(2) || window <= poly_order {
+            return Vec::new();
+        }
+
+        let mut sorted_records = records.to_vec();
+        sorted_records.sort_by_key(|r| r.timestamp);
+
+        let metric_name = format!("{}_smooth", sorted_records[0].metric_name);
+        let count = sorted_records.len();
+        let span = window.min(count);
+        let half = window / 2;
+
+        sorted_records.iter()
+            .enumerate()
+            .map(|(i, record)| {
+                let first = i.saturating_sub(half).min(count - span);
+                let neighbours = &sorted_records[first..first + span];
+                let value = local_polynomial_value(neighbours, record.timestamp, poly_order);
+
+                let mut context = record.context.clone();
+                context.insert("original_metric".to_string(), record.metric_name.clone());
+                Record {
+                    timestamp: record.timestamp,
+                    metric_name: metric_name.clone(),
+                    value,
+                    string_value: None,
+                    context,
+                    resource_type: record.resource_type.clone(),
+                    id: None,
+                }
+            })
+            .collect()
+    }
+
+    /// Runs where a sensor kept reporting the same value for at least `min_duration_secs`
+    ///
+    /// Returns (start, end, value) per run, where start and end are the first and
+    /// last timestamps of the run and value is its first reading. Values within
+    /// `FLATLINE_EPSILON` (relative to their magnitude) of the run's first value
+    /// count as unchanged; non-finite values end a run.
+    pub fn detect_flatline(records: &[Record], min_duration_secs: i64) -> Vec<(i64, i64, f64)> {
+        let mut sorted_records: Vec<&Record> = records.iter().collect();
+        sorted_records.sort_by_key(|r| r.timestamp);
+
+        let mut runs = Vec::new();
+        let mut current: Option<(i64, i64, f64)> = None;
+        for record in sorted_records {
+            match current {
+                Some((_, ref mut end, value)) if nearly_equal(record.value, value) => {
+                    *end = record.timestamp;
+                    continue;
+                }
+                Some(run) if seconds_between(run.0, run.1) >= min_duration_secs as f64 => runs.push(run),
+                _ => {}
+            }
+            current = record.value.is_finite().then_some((record.timestamp, record.timestamp, record.value));
+        }
+        if let Some(run) = current {
+            if seconds_between(run.0, run.1) >= min_duration_secs as f64 {
+                runs.push(run);
+            }
+        }
+        runs
+    }
+}
+
+/// Relative tolerance under which `detect_flatline` treats two readings as the same
+pub const FLATLINE_EPSILON: f64 = 1e-9;
+
+fn nearly_equal(a: f64, b: f64) -> bool {
+    (a - b).abs() <= FLATLINE_EPSILON * a.abs().max(b.abs()).max(1.0)
+}
+
+/// Seconds from `from` to `to`, exact even when the difference overflows an `i64`
+fn seconds_between(from: i64, to: i64) -> f64 {
+    (i128::from(to) - i128::from(from)) as f64
+}
+
+/// Median of a non-empty set of values
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let count = values.len();
+    if count.is_multiple_of(2) {
+        (values[count / 2 - 1] + values[count / 2]) / 2.0
+    } else {
+        values[count / 2]
+    }
+}
+
+/// Value at `at` of the least-squares polynomial of degree `order` through `points`
+///
+/// The degree is lowered when there are too few points to fit it, and the mean
+/// is used when the fit is singular (e.g. every point shares a timestamp).
+fn local_polynomial_value(points: &[Record], at: i64, order: usize) -> f64 {
+    let mean = points.iter().map(|r| r.value).sum::<f64>() / points.len() as f64;
+    let terms = (order + 1).min(points.len());
+    let scale = points.iter()
+        .map(|r| (r.timestamp - at).abs())
+        .max()
+        .unwrap_or(0)
+        .max(1) as f64;
+
+    // Normal equations for coefficients of x^0..x^order, x centred on `at`
+    let mut matrix = vec![vec![0.0; terms + 1]; terms];
+    for point in points {
+        let x = (point.timestamp - at) as f64 / scale;
+        let powers: Vec<f64> = (0..2 * terms).map(|p| x.powi(p as i32)).collect();
+        for (row, cells) in matrix.iter_mut().enumerate() {
+            for (col, cell) in cells.iter_mut().take(terms).enumerate() {
+                *cell += powers[row + col];
+            }
+            cells[terms] += powers[row] * point.value;
+        }
+    }
+
+    // Gaussian elimination with partial pivoting
+    for col in 0..terms {
+        let pivot = (col..terms)
+            .max_by(|&a, &b| matrix[a][col].abs().total_cmp(&matrix[b][col].abs()))
+            .unwrap_or(col);
+        if matrix[pivot][col].abs() < 1e-12 {
+            return mean;
+        }
+        matrix.swap(col, pivot);
+        for row in col + 1..terms {
+            let factor = matrix[row][col] / matrix[col][col];
+            let pivot_row = matrix[col].clone();
+            for (cell, pivot_cell) in matrix[row].iter_mut().zip(pivot_row).skip(col) {
+                *cell -= factor * pivot_cell;
+            }
+        }
+    }
+
+    let mut coefficients = vec![0.0; terms];
+    for row in (0..terms).rev() {
+        let known: f64 = (row + 1..terms).map(|k| matrix[row][k] * coefficients[k]).sum();
+        coefficients[row] = (matrix[row][terms] - known) / matrix[row][row];
+    }
+
+    // x is zero at `at`, so only the constant term remains
+    if coefficients[0].is_finite() { coefficients[0] } else { mean }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(values: impl Iterator<Item = f64>) -> Vec<Record> {
+        values.enumerate()
+            .map(|(i, value)| Record {
+                timestamp: i as i64 * 60,
+                metric_name: "test|metric|unit".to_string(),
+                value,
+                string_value: None,
+                context: HashMap::new(),
+                resource_type: "Observation".to_string(),
+                id: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_autocorrelation_finds_sine_period() {
+        let period = 24;
+        let records = series((0..240).map(|i| {
+            (2.0 * std::f64::consts::PI * i as f64 / period as f64).sin() * 10.0 + 70.0
+        }));
+        
+        let acf = TimeSeriesFunctions::autocorrelation(&records, 60);
+        assert_eq!(acf.len(), 61);
+        assert!((acf[0].1 - 1.0).abs() < 1e-9);
+        
+        let detected = TimeSeriesFunctions::first_acf_peak(&acf, records.len()).unwrap();
+        assert!(detected.abs_diff(period) <= 1, "detected period {}", detected);
+    }
+
+    #[test]
+    fn test_autocorrelation_constant_series() {
+        let records = series(std::iter::repeat_n(5.0, 10));
+        let acf = TimeSeriesFunctions::autocorrelation(&records, 5);
+        assert!(acf.iter().all(|&(_, r)| r == 0.0));
+        assert_eq!(TimeSeriesFunctions::first_acf_peak(&acf, records.len()), None);
+    }
+
+    #[test]
+    fn test_histogram_counts_sum_to_records() {
+        let records = series((0..97).map(|i| (i * 7 % 50) as f64));
+        let buckets = TimeSeriesFunctions::histogram(&records, 10);
+        
+        assert_eq!(buckets.len(), 10);
+        assert_eq!(buckets.iter().map(|b| b.2).sum::<usize>(), records.len());
+        assert_eq!(buckets.first().unwrap().0, 0.0);
+        assert_eq!(buckets.last().unwrap().1, 49.0);
+    }
+
+    #[test]
+    fn test_histogram_all_equal_values() {
+        let records = series(std::iter::repeat_n(98.6, 5));
+        assert_eq!(TimeSeriesFunctions::histogram(&records, 10), vec![(98.6, 98.6, 5)]);
+    }
+
+    #[test]
+    fn test_trend_direction_and_significance() {
+        let rising = TimeSeriesFunctions::calculate_trend(&series((0..30).map(|i| 70.0 + i as f64)));
+        assert_eq!(rising.direction, TrendDirection::Increasing);
+        assert!(rising.significant);
+        
+        let falling = TimeSeriesFunctions::calculate_trend(&series((0..30).map(|i| 70.0 - 0.5 * i as f64)));
+        assert_eq!(falling.direction, TrendDirection::Decreasing);
+        assert!(falling.significant);
+        
+        let flat = TimeSeriesFunctions::calculate_trend(&series(std::iter::repeat_n(70.0, 30)));
+        assert_eq!(flat.direction, TrendDirection::Stable);
+        assert!(!flat.significant);
+        
+        // Swings of +-10 around a slow climb: the fit leans up but explains little of it
+        let noisy = TimeSeriesFunctions::calculate_trend(&series((0..30).map(|i| {
+            70.0 + 0.3 * i as f64 + if i % 2 == 0 { 10.0 } else { -10.0 }
+        })));
+        assert!(noisy.r_squared < TREND_MIN_R_SQUARED, "r_squared {}", noisy.r_squared);
+        assert!(!noisy.significant);
+    }
+
+    #[test]
+    fn test_rate_of_change_sign_on_decreasing_series() {
+        // Falls by 1 unit every minute
+        let records = series((0..5).map(|i| 100.0 - i as f64));
+        
+        let rates = TimeSeriesFunctions::calculate_rate_of_change(&records, 3600);
+        assert_eq!(rates.len(), 4);
+        assert!(rates.iter().all(|r| (r.value + 60.0).abs() < 1e-9));
+        
+        // Two 3-minute buckets: [0, 60, 120] and [180, 240]
+        let rates = TimeSeriesFunctions::calculate_rate_per_bucket(&records, 180, 3600);
+        assert_eq!(rates.iter().map(|r| r.timestamp).collect::<Vec<_>>(), vec![0, 180]);
+        assert!(rates.iter().all(|r| (r.value + 60.0).abs() < 1e-9));
+    }
+
+    #[test]
+    fn test_rate_of_change_rejects_non_positive_period() {
+        let records = series((0..5).map(|i| i as f64));
+        assert!(TimeSeriesFunctions::calculate_rate_of_change(&records, 0).is_empty());
+        assert!(TimeSeriesFunctions::calculate_rate_per_bucket(&records, 60, -1).is_empty());
+    }
+
+    #[test]
+    fn test_rate_of_change_at_extreme_timestamps() {
+        let mut records = series([0.0, 1.0, 10.0, 11.0].into_iter());
+        for (record, timestamp) in records.iter_mut().zip([i64::MIN, i64::MIN + 1, i64::MAX - 2, i64::MAX - 1]) {
+            record.timestamp = timestamp;
+        }
+        
+        let rates = TimeSeriesFunctions::calculate_rate_of_change(&records, 3600);
+        assert_eq!(rates.len(), 3);
+        assert!(rates.iter().all(|r| r.value > 0.0 && r.value.is_finite()));
+        
+        // The bucket holding i64::MIN starts 2s before it, so only the last bucket is stamped
+        let rates = TimeSeriesFunctions::calculate_rate_per_bucket(&records, 10, 3600);
+        assert_eq!(rates.iter().map(|r| r.timestamp).collect::<Vec<_>>(), vec![i64::MAX - 7]);
+        assert!((rates[0].value - 3600.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_rate_threshold_breaches_flag_only_the_spike() {
+        // Heart rate steady at 70, jumping 30 bpm within one minute at t=300
+        let values = [70.0, 71.0, 70.0, 72.0, 71.0, 101.0, 100.0, 99.0];
+        let records = series(values.iter().copied());
+        
+        let breaches = TimeSeriesFunctions::rate_threshold_breaches(&records, 60, 20.0);
+        assert_eq!(breaches.len(), 1);
+        assert_eq!(breaches[0].timestamp, 300);
+        assert!((breaches[0].rate - 30.0).abs() < 1e-9);
+        assert_eq!(breaches[0].before.value, 71.0);
+        assert_eq!(breaches[0].after.value, 101.0);
+        
+        // Falls count too, since the absolute rate is compared
+        let falling = series(values.iter().rev().copied());
+        let breaches = TimeSeriesFunctions::rate_threshold_breaches(&falling, 60, 20.0);
+        assert_eq!(breaches.len(), 1);
+        assert!((breaches[0].rate + 30.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_mad_flags_spike_that_masks_its_own_zscore() {
+        // With 10 points, no single value can be more than 9/sqrt(10) ~ 2.85 deviations out
+        let records = series([70.0, 71.0, 72.0, 70.0, 71.0, 72.0, 70.0, 71.0, 72.0, 200.0].into_iter());
+        
+        let zscore = TimeSeriesFunctions::detect_outliers_with(&records, 3.0, OutlierMethod::ZScore);
+        assert!(zscore.outliers.is_empty());
+        assert_eq!(zscore.method, "zscore");
+        
+        let mad = TimeSeriesFunctions::detect_outliers_with(&records, 3.0, OutlierMethod::Mad);
+        assert_eq!(mad.method, "mad");
+        assert_eq!(mad.outliers.len(), 1);
+        assert_eq!(mad.outliers[0].value, 200.0);
+        assert_eq!(mad.outliers[0].deviation, 129.0);
+        
+        assert_eq!("mad".parse(), Ok(OutlierMethod::Mad));
+        assert!("iqr".parse::<OutlierMethod>().is_err());
+    }
+
+    #[test]
+    fn test_savitzky_golay_smooths_noisy_sine() {
+        let signal = |i: usize| (2.0 * std::f64::consts::PI * i as f64 / 60.0).sin() * 10.0 + 70.0;
+        // Deterministic pseudo-random noise in [-2, 2)
+        let mut state: u64 = 42;
+        let mut noise = move || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((state >> 33) as f64 / (1u64 << 31) as f64) * 4.0 - 2.0
+        };
+        let records = series((0..240).map(|i| signal(i) + noise()));
+
+        let smoothed = TimeSeriesFunctions::savitzky_golay(&records, 11, 2);
+        assert_eq!(smoothed.len(), records.len());
+        assert!(smoothed.iter().zip(&records).all(|(s, r)| s.timestamp == r.timestamp));
+        assert_eq!(smoothed[0].metric_name, "test|metric|unit_smooth");
+
+        let error_variance = |values: Vec<f64>| {
+            values.iter().enumerate().map(|(i, v)| (v - signal(i)).powi(2)).sum::<f64>() / values.len() as f64
+        };
+        let noisy = error_variance(records.iter().map(|r| r.value).collect());
+        let smooth = error_variance(smoothed.iter().map(|r| r.value).collect());
+        assert!(smooth < noisy / 2.0, "smoothed error {} vs noisy {}", smooth, noisy);
+        assert!(smoothed.iter().enumerate().all(|(i, r)| (r.value - signal(i)).abs() < 2.0));
+
+        // Even windows and windows no wider than the order are rejected
+        assert!(TimeSeriesFunctions::savitzky_golay(&records, 10, 2).is_empty());
+        assert!(TimeSeriesFunctions::savitzky_golay(&records, 3, 3).is_empty());
+    }
+
+    #[test]
+    fn test_detect_flatline_reports_stuck_run() {
+        // Varying, then stuck at 72.0 (with float noise) from t=600 to t=1800, then varying again
+        let mut values: Vec<f64> = (0..10).map(|i| 70.0 + (i % 3) as f64).collect();
+        values.extend((0..21).map(|i| 72.0 + if i % 2 == 0 { 0.0 } else { 1e-12 }));
+        values.extend((0..5).map(|i| 80.0 + i as f64));
+        let records = series(values.into_iter());
+
+        let runs = TimeSeriesFunctions::detect_flatline(&records, 600);
+        assert_eq!(runs, vec![(600, 1800, 72.0)]);
+
+        // A higher bar filters the run out
+        assert!(TimeSeriesFunctions::detect_flatline(&records, 1800).is_empty());
+    }
+}
@@ -7,10 +7,13 @@
 
 pub mod query;
 pub mod functions;
+pub mod detection;
+pub mod cache;
 
 #[cfg(test)]
 mod tests {
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn it_works() {
         assert!(true);
     }
@@ -1,12 +1,17 @@
 use std::sync::Arc;
 use crate::storage::{self, StorageEngine, Record, StorageError};
-use std::time::Duration;
-use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use std::collections::{BTreeMap, HashMap};
 use serde::{Serialize, Deserialize};
 use crate::timeseries::functions::{
-    TimeSeriesFunctions, TrendAnalysis, TimeSeriesStats, OutlierDetection
+    TimeSeriesFunctions, TrendAnalysis, TimeSeriesStats, OutlierDetection, AutocorrelationResult, RateBreach, RollupPoint, OutlierMethod
 };
 use std::fmt;
+use crate::timeseries::detection::{PatternDetector, DetectionConfig, ChangepointResult};
+use crate::timeseries::cache::{QueryCache, CacheKey, CachedFunction, CachedResult, CacheStats, DEFAULT_QUERY_CACHE_SIZE};
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::fhir::units::UnitNormalizer;
 
 #[derive(Debug, Clone)]
 pub struct TimeSeriesQuery {
@@ -15,9 +20,14 @@ pub struct TimeSeriesQuery {
     pub metrics: Vec<String>,
     pub aggregation: Option<Aggregation>,
     pub interval: Option<Duration>,
+    pub timeout: Option<Duration>, // Abort the scan with QueryError::Timeout once exceeded
 }
 
-#[derive(Debug, Clone)]
+/// One metric's outcome within a batch query
+pub type MetricQueryResult = (String, Result<Vec<Record>, QueryError>);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Aggregation {
     Mean,
     Max,
@@ -31,6 +41,10 @@ pub enum QueryError {
     StorageError(String),
     InvalidTimeRange(String),
     MetricNotFound(String),
+    InvalidFilter(String),
+    InvalidParameter(String),
+    Timeout(String),
+    Backpressure(String),
 }
 
 impl fmt::Display for QueryError {
@@ -39,13 +53,125 @@ impl fmt::Display for QueryError {
             QueryError::StorageError(msg) => write!(f, "Storage error: {}", msg),
             QueryError::InvalidTimeRange(msg) => write!(f, "Invalid time range: {}", msg),
             QueryError::MetricNotFound(msg) => write!(f, "Metric not found: {}", msg),
+            QueryError::InvalidFilter(msg) => write!(f, "Invalid filter: {}", msg),
+            QueryError::InvalidParameter(msg) => write!(f, "Invalid parameter: {}", msg),
+            QueryError::Timeout(msg) => write!(f, "Query timed out: {}", msg),
+            QueryError::Backpressure(msg) => write!(f, "Write refused: {}", msg),
         }
     }
 }
 
 impl From<StorageError> for QueryError {
     fn from(error: StorageError) -> Self {
-        QueryError::StorageError(format!("{:?}", error))
+        match error {
+            StorageError::Timeout(msg) => QueryError::Timeout(msg),
+            StorageError::Backpressure(msg) => QueryError::Backpressure(msg),
+            error => QueryError::StorageError(format!("{:?}", error)),
+        }
+    }
+}
+
+/// A failed write as a `QueryError`, keeping backpressure distinct so callers can retry
+fn write_error(error: StorageError) -> QueryError {
+    match error {
+        StorageError::Backpressure(msg) => QueryError::Backpressure(msg),
+        error => QueryError::StorageError(error.to_string()),
+    }
+}
+
+/// Comparison prefixes for FHIR number/quantity search parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValuePrefix {
+    Eq,
+    Gt,
+    Lt,
+    Ge,
+    Le,
+}
+
+/// Value comparisons that must all hold, e.g. `value-quantity=ge100&value-quantity=le120`
+#[derive(Debug, Clone, Default)]
+pub struct ValueFilter {
+    // (prefix, target, half-width of the target's implicit precision)
+    comparisons: Vec<(ValuePrefix, f64, f64)>,
+}
+
+impl ValueFilter {
+    /// Parse `value-quantity` parameters such as `gt100`, `le5.5` or `100|http://unitsofmeasure.org|mg`
+    pub fn parse(params: &[&str]) -> Result<Self, QueryError> {
+        let mut comparisons = Vec::new();
+        
+        for param in params {
+            // Drop the optional |system|code unit suffix
+            let number_part = param.split('|').next().unwrap_or("").trim();
+            
+            let (prefix, number) = match number_part.get(..2) {
+                Some("eq") => (ValuePrefix::Eq, &number_part[2..]),
+                Some("gt") => (ValuePrefix::Gt, &number_part[2..]),
+                Some("lt") => (ValuePrefix::Lt, &number_part[2..]),
+                Some("ge") => (ValuePrefix::Ge, &number_part[2..]),
+                Some("le") => (ValuePrefix::Le, &number_part[2..]),
+                _ => (ValuePrefix::Eq, number_part),
+            };
+            
+            let target = number.parse::<f64>()
+                .ok()
+                .filter(|v| v.is_finite())
+                .ok_or_else(|| QueryError::InvalidFilter(format!("Invalid value-quantity: {}", param)))?;
+            
+            // FHIR equality is implicit-range equality based on the number of decimals given
+            let mantissa = number.split(['e', 'E']).next().unwrap_or(number);
+            let decimals = mantissa.split('.').nth(1).map(|d| d.len()).unwrap_or(0);
+            let half_width = 0.5 * 10f64.powi(-(decimals as i32));
+            
+            comparisons.push((prefix, target, half_width));
+        }
+        
+        Ok(ValueFilter { comparisons })
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.comparisons.is_empty()
+    }
+    
+    /// NaN never matches a non-empty filter
+    pub fn matches(&self, value: f64) -> bool {
+        if value.is_nan() {
+            return self.comparisons.is_empty();
+        }
+        
+        self.comparisons.iter().all(|&(prefix, target, half_width)| match prefix {
+            ValuePrefix::Eq => value >= target - half_width && value < target + half_width,
+            ValuePrefix::Gt => value > target,
+            ValuePrefix::Lt => value < target,
+            ValuePrefix::Ge => value >= target,
+            ValuePrefix::Le => value <= target,
+        })
+    }
+}
+
+/// Per-record search constraints: value comparisons, an observation category and context entries
+#[derive(Debug, Clone, Default)]
+pub struct RecordFilter {
+    pub value: ValueFilter,
+    pub category: Option<String>, // Matched against the record's `category` context
+    pub context_filter: HashMap<String, String>, // Every entry must appear in the record's context as is
+}
+
+impl RecordFilter {
+    pub fn is_empty(&self) -> bool {
+        self.value.is_empty() && self.category.is_none() && self.context_filter.is_empty()
+    }
+    
+    pub fn matches(&self, record: &Record) -> bool {
+        // Categorical observations only carry a shadow value, so they never match value comparisons
+        let value_matches = self.value.is_empty()
+            || (record.string_value.is_none() && self.value.matches(record.value));
+        let category_matches = self.category.as_ref()
+            .is_none_or(|category| record.context.get("category") == Some(category));
+        let context_matches = self.context_filter.iter()
+            .all(|(key, value)| record.context.get(key) == Some(value));
+        value_matches && category_matches && context_matches
     }
 }
 
@@ -65,47 +191,200 @@ pub struct TimeChunk {
     pub records: Vec<Record>,
 }
 
+/// Several metrics aggregated onto one time grid, column by column
+///
+/// `timestamps` holds the start of every grid cell; each column has one
+/// value per cell, `None` where the metric has no data.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct AlignedMatrix {
+    pub timestamps: Vec<i64>,
+    pub columns: Vec<MatrixColumn>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct MatrixColumn {
+    pub metric: String,
+    pub values: Vec<Option<f64>>,
+}
+
+/// Most grid cells an aligned matrix may have per column
+pub const MAX_MATRIX_ROWS: i64 = 100_000;
+
 pub struct QueryEngine {
     storage: Arc<StorageEngine>,
+    detector: RwLock<PatternDetector>,
+    cache: Mutex<QueryCache>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    value_decimals: Option<u8>, // Stored values are rounded to this many decimal places when set
+    units: UnitNormalizer,      // Source units rewritten to their canonical unit before storing
 }
 
 impl QueryEngine {
     pub fn new(storage: Arc<StorageEngine>) -> Self {
-        QueryEngine { storage }
+        QueryEngine {
+            storage,
+            detector: RwLock::new(PatternDetector::new()),
+            cache: Mutex::new(QueryCache::new(DEFAULT_QUERY_CACHE_SIZE)),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            value_decimals: None,
+            units: UnitNormalizer::default(),
+        }
+    }
+    
+    /// Keep up to `capacity` stats/trend results; 0 disables the cache
+    pub fn with_cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache = Mutex::new(QueryCache::new(capacity));
+        self
+    }
+
+    /// Round stored values to `decimals` places; `None`, the default, stores them as given
+    pub fn with_value_decimals(mut self, decimals: Option<u8>) -> Self {
+        self.value_decimals = decimals;
+        self
+    }
+    
+    /// Store values in `units`' canonical units; by default they keep the unit they came in
+    pub fn with_unit_normalizer(mut self, units: UnitNormalizer) -> Self {
+        self.units = units;
+        self
+    }
+    
+    /// Apply the configured unit conversion, then rounding, to a record about to be stored
+    fn canonical_record(&self, record: Record) -> Record {
+        let mut record = self.units.normalize(record);
+        if let Some(decimals) = self.value_decimals {
+            record.value = round_to_decimals(record.value, decimals);
+        }
+        record
     }
 
     pub fn store_record(&self, record: Record) -> Result<(), QueryError> {
-        self.storage.insert(record)
-            .map_err(|e| QueryError::StorageError(e.to_string()))
+        let record = self.canonical_record(record);
+        let (metric, timestamp) = (record.metric_name.clone(), record.timestamp);
+        let result = self.storage.insert(record)
+            .map_err(write_error);
+        self.invalidate_cached(std::iter::once((metric.as_str(), timestamp)));
+        result
+    }
+    
+    /// Store a record and return only once it is synced to the WAL
+    pub fn store_record_durable(&self, record: Record) -> Result<(), QueryError> {
+        let record = self.canonical_record(record);
+        let (metric, timestamp) = (record.metric_name.clone(), record.timestamp);
+        let result = self.storage.insert_durable(record)
+            .map_err(write_error);
+        self.invalidate_cached(std::iter::once((metric.as_str(), timestamp)));
+        result
     }
     
     pub fn store_records(&self, records: Vec<Record>) -> Result<(), QueryError> {
+        let result = self.store_records_partial(records)?;
+        match result.failed.first() {
+            Some((position, error)) => Err(QueryError::StorageError(
+                format!("Failed to store record {}: {}", position, error)
+            )),
+            None => Ok(()),
+        }
+    }
+    
+    /// Store a batch, reporting the records that couldn't be stored instead of stopping at the first
+    ///
+    /// Failure positions refer to `records`. Errors that stop the whole batch,
+    /// like a failed WAL write, are still returned as `Err`.
+    pub fn store_records_partial(&self, records: Vec<Record>) -> Result<storage::BatchResult, QueryError> {
+        let mut result = storage::BatchResult::default();
         if records.is_empty() {
-            return Ok(());
+            return Ok(result);
         }
         
-        // Group records by chunk to reduce lock contention
-        let mut records_by_chunk = std::collections::HashMap::new();
+        // Group records by chunk to reduce lock contention, remembering where each came from
+        let mut records_by_chunk: HashMap<i64, (Vec<usize>, Vec<Record>)> = HashMap::new();
         
         // Pre-process to group records by chunk ID
-        for record in records {
-            let chunk_id = storage::chunk_id_for_timestamp(record.timestamp, self.storage.chunk_duration());
-            records_by_chunk.entry(chunk_id).or_insert_with(Vec::new).push(record);
+        for (position, record) in records.into_iter().enumerate() {
+            let record = self.canonical_record(record);
+            let chunk_id = self.storage.chunk_id_for(record.timestamp);
+            let (positions, chunk_records) = records_by_chunk.entry(chunk_id).or_default();
+            positions.push(position);
+            chunk_records.push(record);
         }
         
-        // First, write everything to WAL in a single operation if possible
-        if let Err(e) = self.storage.append_records_to_wal(records_by_chunk.values().flatten().cloned().collect()) {
-            return Err(QueryError::StorageError(e.to_string()));
-        }
+        // First, write everything to WAL in a single operation if possible; no
+        // flush may truncate it before the records are in their chunks
+        let logged = self.storage.write_guard();
+        let all_records = records_by_chunk.values().flat_map(|(_, records)| records).cloned().collect();
+        self.storage.append_records_to_wal(all_records).map_err(write_error)?;
+        
+        // Cached results are invalidated after the insert, so one computed in between can't be kept
+        let touched: Vec<(String, i64)> = records_by_chunk.values()
+            .flat_map(|(_, records)| records)
+            .map(|record| (record.metric_name.clone(), record.timestamp))
+            .collect();
         
         // Then store records in each chunk
-        for (chunk_id, chunk_records) in records_by_chunk {
-            if let Err(e) = self.storage.insert_batch(chunk_id, chunk_records) {
-                return Err(QueryError::StorageError(e.to_string()));
+        let mut outcome = Ok(());
+        for (chunk_id, (positions, chunk_records)) in records_by_chunk {
+            match self.storage.insert_batch_partial(chunk_id, chunk_records) {
+                Ok(chunk_result) => {
+                    result.inserted += chunk_result.inserted;
+                    result.failed.extend(chunk_result.failed.into_iter()
+                        .map(|(index, error)| (positions[index], error)));
+                }
+                Err(e) => {
+                    outcome = Err(QueryError::StorageError(e.to_string()));
+                    break;
+                }
             }
         }
+        drop(logged);
+        result.failed.sort_by_key(|(position, _)| *position);
         
-        Ok(())
+        self.invalidate_cached(touched.iter().map(|(metric, timestamp)| (metric.as_str(), *timestamp)));
+        outcome.map(|()| result)
+    }
+    
+    /// Drop cached results whose range covers any of the written (metric, timestamp) pairs
+    fn invalidate_cached<'a>(&self, written: impl Iterator<Item = (&'a str, i64)>) {
+        // A poisoned cache only ever holds complete entries, so keep using it
+        let mut cache = self.cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (metric, timestamp) in written {
+            cache.invalidate(metric, timestamp);
+        }
+    }
+    
+    /// Return the cached result for `key`, or compute and remember it
+    fn cached<T, F>(&self, key: CacheKey, compute: F) -> Result<T, QueryError>
+    where
+        T: Clone + Into<CachedResult> + TryFrom<CachedResult>,
+        F: FnOnce() -> Result<T, QueryError>,
+    {
+        let generation = {
+            let mut cache = self.cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Some(Ok(hit)) = cache.get(&key).map(T::try_from) {
+                self.cache_hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(hit);
+            }
+            cache.generation()
+        };
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        
+        // Computed without holding the lock; `put` drops it if an insert raced with us
+        let result = compute()?;
+        self.cache.lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .put(key, result.clone().into(), generation);
+        Ok(result)
+    }
+    
+    /// Hit/miss counters and size of the stats/trend cache
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.cache_hits.load(Ordering::Relaxed),
+            misses: self.cache_misses.load(Ordering::Relaxed),
+            entries: self.cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).len(),
+        }
     }
 
     pub fn query_range(&self, query: TimeSeriesQuery) -> Result<Vec<Record>, QueryError> {
@@ -115,12 +394,12 @@ impl QueryEngine {
             ));
         }
 
+        // One deadline covers every metric in the query
+        let deadline = query.timeout.map(|timeout| Instant::now() + timeout);
         let mut results = Vec::new();
         
         for metric in &query.metrics {
-            let records = self.storage.as_ref()
-                .query_range(query.start_time, query.end_time, metric)
-                .map_err(|e| QueryError::StorageError(e.to_string()))?;
+            let records = self.collect_range(metric, query.start_time, query.end_time, deadline)?;
 
             if let Some(aggregation) = &query.aggregation {
                 results.extend(self.aggregate_records(records, aggregation, query.interval));
@@ -132,12 +411,124 @@ impl QueryEngine {
         Ok(results)
     }
 
+    /// Run a range query for every metric in `query`, reporting each metric separately
+    ///
+    /// All metrics are read under one storage lock. Unknown metrics come back as
+    /// `MetricNotFound` instead of failing the whole batch; only an invalid time
+    /// range does that. Results keep the order of `query.metrics`.
+    pub fn query_batch(&self, query: &TimeSeriesQuery) 
+        -> Result<Vec<MetricQueryResult>, QueryError> 
+    {
+        if query.start_time >= query.end_time {
+            return Err(QueryError::InvalidTimeRange(
+                "Start time must be before end time".to_string()
+            ));
+        }
+        
+        let mut found = self.storage.as_ref()
+            .query_range_many(query.start_time, query.end_time, &query.metrics)?;
+        
+        let results = query.metrics.iter()
+            .map(|metric| {
+                let result = match found.remove(metric).flatten() {
+                    Some(records) => Ok(match &query.aggregation {
+                        Some(aggregation) => self.aggregate_records(records, aggregation, query.interval),
+                        None => records,
+                    }),
+                    None => Err(QueryError::MetricNotFound(metric.clone())),
+                };
+                (metric.clone(), result)
+            })
+            .collect();
+        
+        Ok(results)
+    }
+
     pub fn query_latest(&self, metric: &str) -> Result<Option<Record>, QueryError> {
         self.storage.as_ref()
             .get_latest(metric)
             .map_err(|e| QueryError::StorageError(e.to_string()))
     }
 
+    /// The latest record of every metric starting with `prefix`, ordered by metric name
+    ///
+    /// With a `{patient}|` prefix this is the newest value of each code recorded
+    /// for the patient, e.g. for a summary view. Rollups are left out.
+    pub fn latest_by_prefix(&self, prefix: &str) -> Result<Vec<Record>, QueryError> {
+        let mut latest = Vec::new();
+        for metric in self.get_matching_metrics(prefix)? {
+            if let Some(record) = self.query_latest(&metric)? {
+                latest.push(record);
+            }
+        }
+        Ok(latest)
+    }
+
+    /// The `n` most recent records for a metric, newest first
+    pub fn query_latest_n(&self, metric: &str, n: usize) -> Result<Vec<Record>, QueryError> {
+        self.storage.as_ref()
+            .get_latest_n(metric, n)
+            .map_err(|e| QueryError::StorageError(e.to_string()))
+    }
+
+    /// Records of a metric in the range that pass a search filter
+    pub fn query_range_matching(&self, metric: &str, start_time: i64, end_time: i64, filter: &RecordFilter)
+        -> Result<Vec<Record>, QueryError>
+    {
+        self.query_range_filtered(metric, start_time, end_time, |record| filter.matches(record))
+    }
+    
+    /// Query a metric over a time range, keeping only records that satisfy the predicate
+    pub fn query_range_filtered<F>(&self, metric: &str, start_time: i64, end_time: i64, predicate: F) 
+        -> Result<Vec<Record>, QueryError> 
+    where
+        F: Fn(&Record) -> bool,
+    {
+        if start_time >= end_time {
+            return Err(QueryError::InvalidTimeRange(
+                "Start time must be before end time".to_string()
+            ));
+        }
+        
+        let mut records = self.storage.as_ref()
+            .query_range(start_time, end_time, metric)
+            .map_err(|e| QueryError::StorageError(e.to_string()))?;
+        records.retain(|r| predicate(r));
+        
+        Ok(records)
+    }
+    
+    /// Gather a metric's records through the streaming path so the deadline is checked between chunks
+    fn collect_range(&self, metric: &str, start_time: i64, end_time: i64, deadline: Option<Instant>) 
+        -> Result<Vec<Record>, QueryError> 
+    {
+        let mut records = Vec::new();
+        self.storage.as_ref()
+            .query_range_streaming_until(start_time, end_time, metric, deadline, |record| {
+                records.push(record.clone());
+                true
+            })?;
+        Ok(records)
+    }
+    
+    /// Stream a metric's records to a visitor without materializing the range
+    pub fn query_range_streaming<F>(&self, metric: &str, start_time: i64, end_time: i64, visit: F) 
+        -> Result<usize, QueryError> 
+    where
+        F: FnMut(&Record) -> bool,
+    {
+        self.storage.as_ref()
+            .query_range_streaming(start_time, end_time, metric, visit)
+            .map_err(|e| QueryError::StorageError(e.to_string()))
+    }
+    
+    /// List every metric name starting with the given prefix
+    pub fn get_matching_metrics(&self, prefix: &str) -> Result<Vec<String>, QueryError> {
+        self.storage.as_ref()
+            .get_matching_metrics(prefix)
+            .map_err(|e| QueryError::StorageError(e.to_string()))
+    }
+
     pub fn get_metrics_by_prefix(&self, prefix: &str) -> Result<Option<Record>, QueryError> {
         println!("Searching for metrics with prefix: {}", prefix);
         
@@ -171,6 +562,162 @@ impl QueryEngine {
             .map_err(|e| QueryError::StorageError(e.to_string()))
     }
     
+    /// Records of the FHIR resource with the given id
+    pub fn query_by_id(&self, id: &str) -> Result<Vec<Record>, QueryError> {
+        self.storage.as_ref()
+            .query_by_id(id)
+            .map_err(|e| QueryError::StorageError(e.to_string()))
+    }
+    
+    /// Number of records `query_by_resource_type` would return
+    pub fn count_by_resource_type(&self, resource_type: &str, start_time: i64, end_time: i64) 
+        -> Result<usize, QueryError> 
+    {
+        if start_time >= end_time {
+            return Err(QueryError::InvalidTimeRange(
+                "Start time must be before end time".to_string()
+            ));
+        }
+        
+        self.storage.as_ref()
+            .count_by_resource_type(resource_type, start_time, end_time)
+            .map_err(|e| QueryError::StorageError(e.to_string()))
+    }
+    
+    /// Number of records for a metric in range
+    pub fn count_range(&self, metric: &str, start_time: i64, end_time: i64) -> Result<usize, QueryError> {
+        if start_time >= end_time {
+            return Err(QueryError::InvalidTimeRange(
+                "Start time must be before end time".to_string()
+            ));
+        }
+        
+        self.storage.as_ref()
+            .count_range(start_time, end_time, metric)
+            .map_err(|e| QueryError::StorageError(e.to_string()))
+    }
+    
+    /// Rollup buckets of a metric at `resolution` starting in range
+    ///
+    /// Rollups only exist for chunks flushed while `storage.rollup_resolution`
+    /// matched `resolution`; data still only in memory has none yet.
+    pub fn query_rollup(&self, metric: &str, resolution: Duration, start_time: i64, end_time: i64) 
+        -> Result<Vec<RollupPoint>, QueryError> 
+    {
+        if resolution.as_secs() == 0 {
+            return Err(QueryError::InvalidParameter(
+                "Rollup resolution must be at least one second".to_string()
+            ));
+        }
+        
+        if start_time >= end_time {
+            return Err(QueryError::InvalidTimeRange(
+                "Start time must be before end time".to_string()
+            ));
+        }
+        
+        let rollup_metric = storage::rollup_metric_name(metric, resolution);
+        let records = self.storage.as_ref()
+            .query_range(start_time, end_time, &rollup_metric)
+            .map_err(|e| QueryError::StorageError(e.to_string()))?;
+        records.iter().map(|record| {
+            let stat = |name: &str| record.context.get(name).ok_or_else(|| QueryError::StorageError(
+                format!("Rollup record for {} at {} is missing {}", metric, record.timestamp, name)
+            ));
+            let parse_error = |name: &str| QueryError::StorageError(
+                format!("Rollup record for {} at {} has an invalid {}", metric, record.timestamp, name)
+            );
+            Ok(RollupPoint {
+                timestamp: record.timestamp,
+                mean: record.value,
+                min: stat("min")?.parse().map_err(|_| parse_error("min"))?,
+                max: stat("max")?.parse().map_err(|_| parse_error("max"))?,
+                count: stat("count")?.parse().map_err(|_| parse_error("count"))?,
+            })
+        }).collect()
+    }
+    
+    /// Query every record belonging to a patient, across all resource types
+    pub fn query_by_patient(&self, patient_id: &str, start_time: i64, end_time: i64) 
+        -> Result<Vec<Record>, QueryError> 
+    {
+        if start_time >= end_time {
+            return Err(QueryError::InvalidTimeRange(
+                "Start time must be before end time".to_string()
+            ));
+        }
+        
+        // Patient-scoped metric names all start with "{patient_id}|"
+        let prefix = format!("{}|", patient_id);
+        let metrics = self.storage.as_ref()
+            .get_matching_metrics(&prefix)
+            .map_err(|e| QueryError::StorageError(e.to_string()))?;
+        
+        let mut results = Vec::new();
+        for metric in metrics {
+            let records = self.storage.as_ref()
+                .query_range(start_time, end_time, &metric)
+                .map_err(|e| QueryError::StorageError(e.to_string()))?;
+            results.extend(records);
+        }
+        
+        Ok(results)
+    }
+    
+    /// Query every observation a device reported in range, oldest first
+    ///
+    /// Device metrics are named `{device_id}|{code}|{unit}`, sharing the leading
+    /// segment with patient metrics, so only `DeviceObservation` records are kept.
+    pub fn query_by_device(&self, device_id: &str, start_time: i64, end_time: i64) 
+        -> Result<Vec<Record>, QueryError> 
+    {
+        if start_time >= end_time {
+            return Err(QueryError::InvalidTimeRange(
+                "Start time must be before end time".to_string()
+            ));
+        }
+        
+        let prefix = format!("{}|", device_id);
+        let mut results = Vec::new();
+        for metric in self.get_matching_metrics(&prefix)? {
+            results.extend(self.query_range_filtered(&metric, start_time, end_time, |record| {
+                record.resource_type == "DeviceObservation"
+            })?);
+        }
+        
+        results.sort_by_key(|record| record.timestamp);
+        Ok(results)
+    }
+    
+    /// Query a patient's medication administrations in range, oldest first
+    ///
+    /// Medication metrics are named `{patient_id}|{medication_code}|{dose_unit}`,
+    /// so `medication` narrows the prefix to one code.
+    pub fn query_medication_administrations(&self, patient_id: &str, medication: Option<&str>,
+                                            start_time: i64, end_time: i64) 
+        -> Result<Vec<Record>, QueryError> 
+    {
+        if start_time >= end_time {
+            return Err(QueryError::InvalidTimeRange(
+                "Start time must be before end time".to_string()
+            ));
+        }
+        
+        let prefix = match medication {
+            Some(code) => format!("{}|{}|", patient_id, code),
+            None => format!("{}|", patient_id),
+        };
+        let mut results = Vec::new();
+        for metric in self.get_matching_metrics(&prefix)? {
+            results.extend(self.query_range_filtered(&metric, start_time, end_time, |record| {
+                record.resource_type == "MedicationAdministration"
+            })?);
+        }
+        
+        results.sort_by_key(|record| record.timestamp);
+        Ok(results)
+    }
+    
     /// Get metrics for a specific resource type
     pub fn get_metrics_by_resource_type(&self, resource_type: &str) -> Result<Vec<String>, QueryError> {
         println!("Getting metrics for resource type: {}", resource_type);
@@ -202,18 +749,8 @@ impl QueryEngine {
         aggregation: &Aggregation,
         interval: Duration
     ) -> Vec<Record> {
-        let mut grouped: HashMap<i64, Vec<Record>> = HashMap::new();
-        let interval_secs = interval.as_secs() as i64;
-
-        for record in records {
-            let interval_start = record.timestamp - (record.timestamp % interval_secs);
-            grouped.entry(interval_start)
-                .or_insert_with(Vec::new)
-                .push(record);
-        }
-
-        grouped.into_iter()
-            .map(|(_timestamp, group)| self.aggregate_all(group, aggregation))
+        group_by_interval(records, interval).into_values()
+            .map(|group| self.aggregate_all(group, aggregation))
             .collect()
     }
 
@@ -233,12 +770,43 @@ impl QueryEngine {
             timestamp: first_record.timestamp,
             metric_name: first_record.metric_name.clone(),
             value,
+            string_value: None,
             context: first_record.context.clone(),
             resource_type: first_record.resource_type.clone(),
+            id: None,
         }
     }
 
     /// Get debug info about metrics and resources
+    /// Receive every record inserted from now on
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<Record> {
+        self.storage.subscribe()
+    }
+
+    /// Ingest counters from the storage engine
+    pub fn ingest_stats(&self) -> storage::IngestStats {
+        self.storage.stats()
+    }
+
+    /// Merge neighbouring under-full chunks, see `StorageEngine::compact`
+    pub fn compact(&self) -> Result<storage::CompactionReport, QueryError> {
+        Ok(self.storage.compact()?)
+    }
+
+    /// Resident chunks with their span, size and state, oldest first
+    pub fn chunk_infos(&self) -> Vec<storage::ChunkInfo> {
+        self.storage.chunk_infos()
+    }
+
+    /// Summary of one metric within a resident chunk
+    pub fn summarize_chunk(&self, chunk_id: i64, metric: &str) -> Result<storage::ChunkSummary, QueryError> {
+        self.storage.summarize_chunk(chunk_id, metric).map_err(|e| match e {
+            StorageError::ChunkNotFound(msg) => QueryError::MetricNotFound(msg),
+            StorageError::ChunkError(storage::ChunkError::IndexError(msg)) => QueryError::MetricNotFound(msg),
+            e => QueryError::from(e),
+        })
+    }
+
     pub fn debug_metrics(&self) -> Result<DebugMetricsInfo, QueryError> {
         // Get the raw debug info from storage
         self.storage.as_ref()
@@ -246,9 +814,14 @@ impl QueryEngine {
             .map_err(|e| QueryError::StorageError(e.to_string()))
     }
 
-    /// Query data in specific time chunks
-    pub fn query_time_chunked(&self, resource_type: &str, start_time: i64, end_time: i64, chunk_size_secs: u64) 
-        -> Result<Vec<TimeChunk>, QueryError> 
+    /// Record count and time bounds of every resident metric, largest first
+    pub fn metric_counts(&self) -> Result<Vec<storage::MetricCount>, QueryError> {
+        self.storage.metric_counts().map_err(QueryError::from)
+    }
+
+    /// Query data in specific time chunks, failing with `QueryError::Timeout` once `timeout` elapses
+    pub fn query_time_chunked(&self, resource_type: &str, start_time: i64, end_time: i64, chunk_size_secs: u64,
+                              timeout: Option<Duration>) -> Result<Vec<TimeChunk>, QueryError> 
     {
         if start_time >= end_time {
             return Err(QueryError::InvalidTimeRange(
@@ -260,7 +833,8 @@ impl QueryEngine {
             resource_type, start_time, end_time, chunk_size_secs);
         
         // First get all matching records
-        let records = self.query_by_resource_type(resource_type, start_time, end_time)?;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let records = self.storage.query_by_resource_type_until(resource_type, start_time, end_time, deadline)?;
         
         // Group them by time chunks
         let chunk_size = chunk_size_secs as i64;
@@ -271,7 +845,7 @@ impl QueryEngine {
             let chunk_start = record.timestamp - (record.timestamp % chunk_size);
             
             chunked_data.entry(chunk_start)
-                .or_insert_with(Vec::new)
+                .or_default()
                 .push(record);
         }
         
@@ -293,15 +867,60 @@ impl QueryEngine {
         Ok(result)
     }
 
+    /// Like `query_time_chunked`, but hands each chunk to `visit` as soon as it's read
+    ///
+    /// Windows are queried one at a time, so only one chunk's records are held at
+    /// once. Empty windows are skipped; returning false from `visit` stops the scan.
+    /// Returns the number of chunks visited.
+    pub fn query_time_chunked_streaming<F>(&self, resource_type: &str, start_time: i64, end_time: i64,
+        chunk_size_secs: u64, mut visit: F) -> Result<usize, QueryError>
+    where
+        F: FnMut(TimeChunk) -> bool,
+    {
+        if start_time >= end_time {
+            return Err(QueryError::InvalidTimeRange(
+                "Start time must be before end time".to_string()
+            ));
+        }
+        let chunk_size = i64::try_from(chunk_size_secs).ok()
+            .filter(|&size| size > 0)
+            .ok_or_else(|| QueryError::InvalidParameter("chunk_size must be a positive number of seconds".to_string()))?;
+
+        let mut visited = 0;
+        let mut chunk_start = start_time - start_time.rem_euclid(chunk_size);
+        while chunk_start < end_time {
+            let chunk_end = chunk_start.saturating_add(chunk_size);
+            let records = self.storage.as_ref()
+                .query_by_resource_type(resource_type, chunk_start.max(start_time), chunk_end.min(end_time))
+                .map_err(|e| QueryError::StorageError(e.to_string()))?;
+            if !records.is_empty() {
+                visited += 1;
+                if !visit(TimeChunk { start_time: chunk_start, end_time: chunk_end, records }) {
+                    break;
+                }
+            }
+            chunk_start = chunk_end;
+        }
+        Ok(visited)
+    }
+
     /// Calculate trend analysis for a specific metric
     pub fn calculate_trend(&self, metric: &str, start_time: i64, end_time: i64) 
         -> Result<TrendAnalysis, QueryError> 
     {
-        let records = self.storage.as_ref()
-            .query_range(start_time, end_time, metric)
-            .map_err(|e| QueryError::StorageError(e.to_string()))?;
-            
-        Ok(TimeSeriesFunctions::calculate_trend(&records))
+        let key = CacheKey {
+            metric: metric.to_string(),
+            start_time,
+            end_time,
+            function: CachedFunction::Trend,
+        };
+        self.cached(key, || {
+            let records = self.storage.as_ref()
+                .query_range(start_time, end_time, metric)
+                .map_err(|e| QueryError::StorageError(e.to_string()))?;
+                
+            Ok(TimeSeriesFunctions::calculate_trend(&records))
+        })
     }
     
     /// Calculate trend analysis for records by resource type
@@ -342,36 +961,334 @@ impl QueryEngine {
     }
     
     /// Calculate statistics for a metric
-    pub fn calculate_stats(&self, metric: &str, start_time: i64, end_time: i64) 
+    pub fn calculate_stats(&self, metric: &str, start_time: i64, end_time: i64, timeout: Option<Duration>) 
         -> Result<TimeSeriesStats, QueryError> 
     {
+        let key = CacheKey {
+            metric: metric.to_string(),
+            start_time,
+            end_time,
+            function: CachedFunction::Stats,
+        };
+        self.cached(key, || {
+            let deadline = timeout.map(|timeout| Instant::now() + timeout);
+            let records = self.collect_range(metric, start_time, end_time, deadline)?;
+                
+            Ok(TimeSeriesFunctions::calculate_stats(&records))
+        })
+    }
+    
+    /// Statistics for each `interval`-wide bucket of the range, oldest first
+    ///
+    /// Buckets line up with the ones used by interval aggregation; empty
+    /// buckets are left out.
+    pub fn calculate_stats_bucketed(&self, metric: &str, start_time: i64, end_time: i64, interval: Duration) 
+        -> Result<Vec<(i64, TimeSeriesStats)>, QueryError> 
+    {
+        if interval.as_secs() == 0 {
+            return Err(QueryError::InvalidParameter(
+                "Interval must be at least one second".to_string()
+            ));
+        }
+        let records = self.collect_range(metric, start_time, end_time, None)?;
+        
+        Ok(group_by_interval(records, interval).into_iter()
+            .map(|(bucket_start, group)| (bucket_start, TimeSeriesFunctions::calculate_stats(&group)))
+            .collect())
+    }
+    
+    /// Aggregate each metric into `interval`-wide cells on a grid shared by all of them
+    ///
+    /// Cells line up with the buckets of interval aggregation, starting with the
+    /// one holding `start`. Metrics without data in a cell, or at all, get `None`.
+    pub fn aligned_matrix(&self, metrics: &[String], start_time: i64, end_time: i64, interval: Duration,
+                          aggregation: &Aggregation) 
+        -> Result<AlignedMatrix, QueryError> 
+    {
+        if metrics.is_empty() {
+            return Err(QueryError::InvalidParameter("At least one metric is required".to_string()));
+        }
+        if interval.as_secs() == 0 {
+            return Err(QueryError::InvalidParameter(
+                "Interval must be at least one second".to_string()
+            ));
+        }
+        let interval_secs = i64::try_from(interval.as_secs()).map_err(|_| QueryError::InvalidParameter(
+            format!("Interval must be at most {} seconds", i64::MAX)
+        ))?;
+        if start_time >= end_time {
+            return Err(QueryError::InvalidTimeRange(
+                "Start time must be before end time".to_string()
+            ));
+        }
+        
+        let grid_start = start_time.checked_sub(start_time.rem_euclid(interval_secs))
+            .ok_or_else(|| QueryError::InvalidTimeRange("Start time is too far in the past".to_string()))?;
+        // Widened, since the span from the grid start can exceed i64
+        let rows = (end_time as i128 - grid_start as i128 + interval_secs as i128 - 1) / interval_secs as i128;
+        if rows <= 0 || rows > MAX_MATRIX_ROWS as i128 {
+            return Err(QueryError::InvalidParameter(format!(
+                "Matrix would have {} rows, outside 1 to {}; use a wider interval", rows, MAX_MATRIX_ROWS
+            )));
+        }
+        let rows = rows as i64;
+        
+        let mut found = self.storage.as_ref()
+            .query_range_many(start_time, end_time, metrics)?;
+        let columns = metrics.iter()
+            .map(|metric| {
+                let mut values = vec![None; rows as usize];
+                let records = found.remove(metric).flatten().unwrap_or_default();
+                for (bucket_start, group) in group_by_interval(records, interval) {
+                    let row = (bucket_start as i128 - grid_start as i128) / interval_secs as i128;
+                    if let Some(value) = values.get_mut(row as usize) {
+                        *value = Some(self.aggregate_all(group, aggregation).value);
+                    }
+                }
+                MatrixColumn { metric: metric.clone(), values }
+            })
+            .collect();
+        
+        Ok(AlignedMatrix {
+            timestamps: (0..rows).map(|row| grid_start + row * interval_secs).collect(),
+            columns,
+        })
+    }
+    
+    /// Detect outliers for a metric
+    pub fn detect_outliers(&self, metric: &str, start_time: i64, end_time: i64, threshold: f64, 
+                           method: OutlierMethod, timeout: Option<Duration>) 
+        -> Result<OutlierDetection, QueryError> 
+    {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let records = self.collect_range(metric, start_time, end_time, deadline)?;
+            
+        Ok(TimeSeriesFunctions::detect_outliers_with(&records, threshold, method))
+    }
+    
+    /// Calculate rate of change for a metric
+    ///
+    /// With `bucket_seconds` the rate is computed once per fixed grid bucket
+    /// instead of between each adjacent pair of points.
+    pub fn calculate_rate_of_change(&self, metric: &str, start_time: i64, end_time: i64, 
+                                    period_seconds: i64, bucket_seconds: Option<i64>) 
+        -> Result<Vec<Record>, QueryError> 
+    {
+        if period_seconds <= 0 {
+            return Err(QueryError::InvalidParameter(
+                format!("Rate period must be positive, got {}", period_seconds)
+            ));
+        }
+        if let Some(bucket) = bucket_seconds.filter(|&b| b <= 0) {
+            return Err(QueryError::InvalidParameter(
+                format!("Rate bucket must be positive, got {}", bucket)
+            ));
+        }
+        
         let records = self.storage.as_ref()
             .query_range(start_time, end_time, metric)
             .map_err(|e| QueryError::StorageError(e.to_string()))?;
-            
-        Ok(TimeSeriesFunctions::calculate_stats(&records))
+        
+        Ok(match bucket_seconds {
+            Some(bucket) => TimeSeriesFunctions::calculate_rate_per_bucket(&records, bucket, period_seconds),
+            None => TimeSeriesFunctions::calculate_rate_of_change(&records, period_seconds),
+        })
+    }
+
+    /// Savitzky-Golay smoothed copy of a metric, as a `{metric}_smooth` series
+    pub fn smooth(&self, metric: &str, start_time: i64, end_time: i64,
+                  window: usize, poly_order: usize) -> Result<Vec<Record>, QueryError> {
+        if window.is_multiple_of(2) {
+            return Err(QueryError::InvalidParameter(
+                format!("Smoothing window must be odd, got {}", window)
+            ));
+        }
+        if window <= poly_order {
+            return Err(QueryError::InvalidParameter(
+                format!("Smoothing window ({}) must be larger than the polynomial order ({})", window, poly_order)
+            ));
+        }
+
+        let records = self.storage.as_ref()
+            .query_range(start_time, end_time, metric)
+            .map_err(|e| QueryError::StorageError(e.to_string()))?;
+
+        Ok(TimeSeriesFunctions::savitzky_golay(&records, window, poly_order))
+    }
+
+    /// Points where a metric changed faster than `max_rate` per `period_seconds`, either way
+    pub fn rate_threshold_breaches(&self, metric: &str, start_time: i64, end_time: i64, 
+                                   period_seconds: i64, max_rate: f64) 
+        -> Result<Vec<RateBreach>, QueryError> 
+    {
+        if period_seconds <= 0 {
+            return Err(QueryError::InvalidParameter(
+                format!("Rate period must be positive, got {}", period_seconds)
+            ));
+        }
+        if !max_rate.is_finite() || max_rate < 0.0 {
+            return Err(QueryError::InvalidParameter(
+                format!("max_rate must be a non-negative number, got {}", max_rate)
+            ));
+        }
+        
+        let records = self.storage.as_ref()
+            .query_range(start_time, end_time, metric)
+            .map_err(|e| QueryError::StorageError(e.to_string()))?;
+        
+        Ok(TimeSeriesFunctions::rate_threshold_breaches(&records, period_seconds, max_rate))
+    }
+
+    /// Autocorrelation for a metric, with the dominant period if one stands out
+    pub fn autocorrelation(&self, metric: &str, start_time: i64, end_time: i64, max_lag: Option<usize>) 
+        -> Result<AutocorrelationResult, QueryError> 
+    {
+        let mut records = self.storage.as_ref()
+            .query_range(start_time, end_time, metric)
+            .map_err(|e| QueryError::StorageError(e.to_string()))?;
+        records.sort_by_key(|r| r.timestamp);
+        
+        // Lags beyond half the series are too noisy to be useful
+        let max_lag = max_lag.unwrap_or(records.len() / 2);
+        let acf = TimeSeriesFunctions::autocorrelation(&records, max_lag);
+        let period = TimeSeriesFunctions::first_acf_peak(&acf, records.len());
+        
+        let mut intervals: Vec<i64> = records.windows(2)
+            .map(|w| w[1].timestamp - w[0].timestamp)
+            .collect();
+        intervals.sort_unstable();
+        let period_seconds = period
+            .zip(intervals.get(intervals.len() / 2))
+            .map(|(lag, interval)| lag as i64 * interval);
+        
+        Ok(AutocorrelationResult {
+            metric_name: metric.to_string(),
+            acf,
+            period,
+            period_seconds,
+            data_points: records.len(),
+        })
     }
     
-    /// Detect outliers for a metric
-    pub fn detect_outliers(&self, metric: &str, start_time: i64, end_time: i64, threshold: f64) 
-        -> Result<OutlierDetection, QueryError> 
+    /// Lag (in samples) of the first significant autocorrelation peak
+    pub fn find_period(&self, metric: &str, start_time: i64, end_time: i64) 
+        -> Result<Option<usize>, QueryError> 
+    {
+        Ok(self.autocorrelation(metric, start_time, end_time, None)?.period)
+    }
+
+    /// Value distribution of a metric as (bucket_low, bucket_high, count)
+    pub fn histogram(&self, metric: &str, start_time: i64, end_time: i64, bins: usize) 
+        -> Result<Vec<(f64, f64, usize)>, QueryError> 
     {
         let records = self.storage.as_ref()
             .query_range(start_time, end_time, metric)
             .map_err(|e| QueryError::StorageError(e.to_string()))?;
             
-        Ok(TimeSeriesFunctions::detect_outliers(&records, threshold))
+        Ok(TimeSeriesFunctions::histogram(&records, bins))
     }
-    
-    /// Calculate rate of change for a metric
-    pub fn calculate_rate_of_change(&self, metric: &str, start_time: i64, end_time: i64, period_seconds: i64) 
-        -> Result<Vec<Record>, QueryError> 
+
+    /// Runs of a metric stuck on one value for at least `min_duration_secs`, as (start, end, value)
+    pub fn detect_flatline(&self, metric: &str, start_time: i64, end_time: i64, min_duration_secs: i64) 
+        -> Result<Vec<(i64, i64, f64)>, QueryError> 
     {
         let records = self.storage.as_ref()
             .query_range(start_time, end_time, metric)
             .map_err(|e| QueryError::StorageError(e.to_string()))?;
             
-        Ok(TimeSeriesFunctions::calculate_rate_of_change(&records, period_seconds))
+        Ok(TimeSeriesFunctions::detect_flatline(&records, min_duration_secs))
+    }
+
+    /// Pairwise Pearson correlation between metrics, aligned by timestamp
+    ///
+    /// `matrix[i][j]` is the correlation of `metrics[i]` with `metrics[j]`. The
+    /// diagonal is always 1.0; pairs without enough overlapping points are 0.0.
+    pub fn correlation_matrix(&self, metrics: &[String], start_time: i64, end_time: i64) 
+        -> Result<Vec<Vec<f64>>, QueryError> 
+    {
+        if start_time >= end_time {
+            return Err(QueryError::InvalidTimeRange(
+                "Start time must be before end time".to_string()
+            ));
+        }
+        
+        let mut series = Vec::with_capacity(metrics.len());
+        for metric in metrics {
+            series.push(self.storage.as_ref()
+                .query_range(start_time, end_time, metric)
+                .map_err(|e| QueryError::StorageError(e.to_string()))?);
+        }
+        
+        let n = metrics.len();
+        let mut matrix = vec![vec![1.0; n]; n];
+        for i in 0..n {
+            for j in (i + 1)..n {
+                let correlation = PatternDetector::calculate_correlation(&series[i], &series[j]);
+                matrix[i][j] = correlation;
+                matrix[j][i] = correlation;
+            }
+        }
+        
+        Ok(matrix)
+    }
+
+    /// Replace the active pattern detector with one built from `config`
+    ///
+    /// The config is validated first; on error the current detector stays in place.
+    pub fn set_detection_config(&self, config: DetectionConfig) -> Result<(), QueryError> {
+        config.validate().map_err(QueryError::InvalidParameter)?;
+        
+        let mut detector = self.detector.write()
+            .map_err(|_| QueryError::StorageError("Detector lock poisoned".to_string()))?;
+        *detector = PatternDetector::with_config(config);
+        Ok(())
+    }
+    
+    /// Run changepoint detection over a metric with the active detector
+    pub fn detect_changepoints(&self, metric: &str, start_time: i64, end_time: i64) 
+        -> Result<ChangepointResult, QueryError> 
+    {
+        let records = self.storage.as_ref()
+            .query_range(start_time, end_time, metric)
+            .map_err(|e| QueryError::StorageError(e.to_string()))?;
+        if records.is_empty() {
+            return Err(QueryError::MetricNotFound(metric.to_string()));
+        }
+        
+        let detector = self.detector.read()
+            .map_err(|_| QueryError::StorageError("Detector lock poisoned".to_string()))?;
+        detector.detect_changepoints(&records)
+            .map_err(QueryError::InvalidParameter)
+    }
+
+    /// Write a consistent point-in-time copy of the store into `dest`
+    pub fn snapshot(&self, dest: &std::path::Path) -> Result<(), QueryError> {
+        self.storage.snapshot(dest).map_err(|e| match e {
+            StorageError::InvalidSnapshotDestination(msg) => QueryError::InvalidParameter(msg),
+            e => QueryError::StorageError(e.to_string()),
+        })
+    }
+
+    /// Persist every dirty chunk and truncate the WAL
+    pub fn flush(&self) -> Result<storage::FlushReport, QueryError> {
+        self.storage.flush_all()
+            .map_err(|e| QueryError::StorageError(e.to_string()))
+    }
+
+    /// Whether writes currently reach disk
+    pub fn persistence_active(&self) -> bool {
+        self.storage.persistence_active()
+    }
+
+    /// Resident chunks with writes not yet flushed
+    pub fn dirty_chunk_count(&self) -> usize {
+        self.storage.dirty_chunk_count()
+    }
+
+    /// Check every persisted chunk and report the ones that fail validation
+    pub fn verify_integrity(&self) -> Result<storage::IntegrityReport, QueryError> {
+        self.storage.verify_integrity()
+            .map_err(|e| QueryError::StorageError(e.to_string()))
     }
 
     /// Set debug settings for performance optimization
@@ -384,10 +1301,237 @@ impl QueryEngine {
         self.storage.set_debug_settings(memory_mode, disable_wal, batch_size)
             .map_err(|e| QueryError::StorageError(e.to_string()))
     }
+    
+    /// Preferred number of records per batched write
+    pub fn batch_size(&self) -> usize {
+        self.storage.batch_size()
+    }
+}
+
+/// `value` rounded half away from zero to `decimals` places
+///
+/// Values too large to scale without overflowing are already coarser than that and kept as is.
+fn round_to_decimals(value: f64, decimals: u8) -> f64 {
+    let factor = 10f64.powi(decimals as i32);
+    let scaled = value * factor;
+    if !scaled.is_finite() {
+        return value;
+    }
+    scaled.round() / factor
+}
+
+/// Group records into `interval`-wide buckets keyed by bucket start
+fn group_by_interval(records: Vec<Record>, interval: Duration) -> BTreeMap<i64, Vec<Record>> {
+    let mut grouped: BTreeMap<i64, Vec<Record>> = BTreeMap::new();
+    let interval_secs = i64::try_from(interval.as_secs()).unwrap_or(i64::MAX);
+
+    for record in records {
+        // Buckets start at or before the timestamp, negative ones included
+        let interval_start = record.timestamp.saturating_sub(record.timestamp.rem_euclid(interval_secs));
+        grouped.entry(interval_start)
+            .or_default()
+            .push(record);
+    }
+    grouped
 }
 
 impl TimeSeriesQuery {
     pub fn execute(&self, _engine: &StorageEngine) -> Result<Vec<crate::storage::Record>, QueryError> {
         todo!("Implement execute")
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heart_rate(timestamp: i64, value: f64) -> Record {
+        Record {
+            timestamp,
+            metric_name: "cache|8867-4|bpm".to_string(),
+            value,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        }
+    }
+
+    #[test]
+    fn test_repeated_stats_hit_cache_until_insert_in_range() {
+        let storage = Arc::new(StorageEngine::new_in_memory(Duration::from_secs(3600)));
+        let engine = QueryEngine::new(storage);
+        engine.store_records(vec![heart_rate(100, 70.0), heart_rate(200, 80.0)]).unwrap();
+        
+        let first = engine.calculate_stats("cache|8867-4|bpm", 0, 1000, None).unwrap();
+        let second = engine.calculate_stats("cache|8867-4|bpm", 0, 1000, None).unwrap();
+        assert_eq!(first.mean, second.mean);
+        let stats = engine.cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+        
+        // Stats and trend over the same window are cached separately
+        engine.calculate_trend("cache|8867-4|bpm", 0, 1000).unwrap();
+        engine.calculate_trend("cache|8867-4|bpm", 0, 1000).unwrap();
+        assert_eq!(engine.cache_stats().hits, 2);
+        
+        // Inserting outside the window leaves the cached results alone
+        engine.store_record(heart_rate(5000, 200.0)).unwrap();
+        engine.calculate_stats("cache|8867-4|bpm", 0, 1000, None).unwrap();
+        assert_eq!(engine.cache_stats().hits, 3);
+        
+        // Inserting inside it forces a recompute that sees the new record
+        engine.store_record(heart_rate(300, 90.0)).unwrap();
+        let refreshed = engine.calculate_stats("cache|8867-4|bpm", 0, 1000, None).unwrap();
+        assert_eq!(refreshed.count, 3);
+        assert_eq!(engine.cache_stats().hits, 3);
+        assert_eq!(engine.cache_stats().misses, 3);
+    }
+
+    #[test]
+    fn test_zero_capacity_disables_cache() {
+        let storage = Arc::new(StorageEngine::new_in_memory(Duration::from_secs(3600)));
+        let engine = QueryEngine::new(storage).with_cache_capacity(0);
+        engine.store_record(heart_rate(100, 70.0)).unwrap();
+        
+        engine.calculate_stats("cache|8867-4|bpm", 0, 1000, None).unwrap();
+        engine.calculate_stats("cache|8867-4|bpm", 0, 1000, None).unwrap();
+        let stats = engine.cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (0, 2, 0));
+    }
+
+    #[test]
+    fn test_flush_writes_one_minute_rollups() {
+        let dir = std::env::temp_dir().join(format!("emberdb-rollups-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let yaml = format!(
+            "storage:\n  path: \"{}\"\n  max_chunk_size: 1048576\n  rollup_resolution: \"1m\"\n\
+             api:\n  host: \"127.0.0.1\"\n  port: 5432\nchunk_duration: \"1h\"\n",
+            dir.display()
+        );
+        let config: crate::config::Config = serde_yaml::from_str(&yaml).unwrap();
+        config.validate().unwrap();
+        
+        let storage = Arc::new(StorageEngine::new(&config).unwrap());
+        let engine = QueryEngine::new(Arc::clone(&storage));
+        engine.store_records((0..120).map(|second| heart_rate(second, second as f64)).collect()).unwrap();
+        storage.flush_all().unwrap();
+        
+        let rollups = engine.query_rollup("cache|8867-4|bpm", Duration::from_secs(60), 0, 3600).unwrap();
+        assert_eq!(rollups.len(), 2);
+        assert_eq!((rollups[0].timestamp, rollups[0].mean, rollups[0].min, rollups[0].max, rollups[0].count),
+                   (0, 29.5, 0.0, 59.0, 60));
+        assert_eq!((rollups[1].timestamp, rollups[1].mean, rollups[1].min, rollups[1].max, rollups[1].count),
+                   (60, 89.5, 60.0, 119.0, 60));
+        
+        // Rollups stay out of prefix listings and are refreshed by the next flush
+        assert_eq!(engine.get_matching_metrics("cache|").unwrap(), vec!["cache|8867-4|bpm".to_string()]);
+        engine.store_record(heart_rate(30, 1000.0)).unwrap();
+        storage.flush_all().unwrap();
+        let rollups = engine.query_rollup("cache|8867-4|bpm", Duration::from_secs(60), 0, 3600).unwrap();
+        assert_eq!((rollups[0].max, rollups[0].count), (1000.0, 61));
+        
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_stats_bucketed_by_hour() {
+        let storage = Arc::new(StorageEngine::new_in_memory(Duration::from_secs(3600)));
+        let engine = QueryEngine::new(storage);
+        // 60-80 bpm in the first hour, 100-120 in the second
+        let records = (0..120)
+            .map(|minute| {
+                let base = if minute < 60 { 60.0 } else { 100.0 };
+                heart_rate(minute * 60, base + (minute % 3) as f64 * 10.0)
+            })
+            .collect();
+        engine.store_records(records).unwrap();
+        
+        let buckets = engine.calculate_stats_bucketed("cache|8867-4|bpm", 0, 7200, Duration::from_secs(3600)).unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].0, 0);
+        assert_eq!((buckets[0].1.mean, buckets[0].1.min, buckets[0].1.max, buckets[0].1.count), (70.0, 60.0, 80.0, 60));
+        assert_eq!(buckets[1].0, 3600);
+        assert_eq!((buckets[1].1.mean, buckets[1].1.min, buckets[1].1.max, buckets[1].1.count), (110.0, 100.0, 120.0, 60));
+        
+        assert!(engine.calculate_stats_bucketed("cache|8867-4|bpm", 0, 7200, Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn test_aligned_matrix_puts_offset_metrics_on_one_grid() {
+        let storage = Arc::new(StorageEngine::new_in_memory(Duration::from_secs(3600)));
+        let engine = QueryEngine::new(storage);
+        let spo2 = |timestamp: i64, value: f64| Record {
+            metric_name: "cache|59408-5|%".to_string(),
+            ..heart_rate(timestamp, value)
+        };
+        // Heart rate on the minute, SpO2 20s later and skipping the second minute
+        engine.store_records(vec![
+            heart_rate(0, 70.0), heart_rate(30, 72.0), heart_rate(60, 75.0), heart_rate(120, 80.0),
+            spo2(20, 97.0), spo2(140, 95.0),
+        ]).unwrap();
+        
+        let metrics = vec!["cache|8867-4|bpm".to_string(), "cache|59408-5|%".to_string(), "cache|none|x".to_string()];
+        let matrix = engine.aligned_matrix(&metrics, 10, 180, Duration::from_secs(60), &Aggregation::Mean).unwrap();
+        assert_eq!(matrix.timestamps, vec![0, 60, 120]);
+        assert_eq!(matrix.columns[0].metric, "cache|8867-4|bpm");
+        // The first cell only sees readings from `start` on
+        assert_eq!(matrix.columns[0].values, vec![Some(72.0), Some(75.0), Some(80.0)]);
+        assert_eq!(matrix.columns[1].values, vec![Some(97.0), None, Some(95.0)]);
+        assert_eq!(matrix.columns[2].values, vec![None, None, None]);
+        
+        assert!(engine.aligned_matrix(&metrics, 0, 180, Duration::ZERO, &Aggregation::Mean).is_err());
+        assert!(engine.aligned_matrix(&metrics, 0, i64::MAX / 2, Duration::from_secs(1), &Aggregation::Mean).is_err());
+        
+        // Huge intervals and extreme ranges are refused rather than overflowing
+        assert!(engine.aligned_matrix(&metrics, 0, 180, Duration::from_secs(u64::MAX), &Aggregation::Mean).is_err());
+        assert!(engine.aligned_matrix(&metrics, i64::MIN, i64::MAX, Duration::from_secs(60), &Aggregation::Mean).is_err());
+        let whole = engine.aligned_matrix(&metrics, i64::MIN + 1, i64::MAX, Duration::from_secs(i64::MAX as u64), &Aggregation::Mean).unwrap();
+        assert_eq!(whole.columns[0].values.iter().flatten().count(), 1);
+    }
+
+    #[test]
+    fn test_aligned_matrix_keeps_rows_before_zero() {
+        let storage = Arc::new(StorageEngine::new_in_memory(Duration::from_secs(3600)));
+        let engine = QueryEngine::new(storage);
+        engine.store_records(vec![heart_rate(-90, 60.0), heart_rate(-30, 62.0), heart_rate(10, 64.0)]).unwrap();
+        
+        let metrics = vec!["cache|8867-4|bpm".to_string()];
+        let matrix = engine.aligned_matrix(&metrics, -90, 60, Duration::from_secs(60), &Aggregation::Mean).unwrap();
+        assert_eq!(matrix.timestamps, vec![-120, -60, 0]);
+        assert_eq!(matrix.columns[0].values, vec![Some(60.0), Some(62.0), Some(64.0)]);
+    }
+
+    #[test]
+    fn test_latest_by_prefix_returns_each_metric_once() {
+        let storage = Arc::new(StorageEngine::new_in_memory(Duration::from_secs(3600)));
+        let engine = QueryEngine::new(storage);
+        let record = |timestamp: i64, metric: &str, value: f64| Record {
+            timestamp,
+            metric_name: metric.to_string(),
+            value,
+            string_value: None,
+            context: HashMap::new(),
+            resource_type: "Observation".to_string(),
+            id: None,
+        };
+        engine.store_records(vec![
+            record(100, "p7|8867-4|bpm", 70.0),
+            record(7300, "p7|8867-4|bpm", 75.0),
+            record(200, "p7|29463-7|kg", 80.5),
+            record(4000, "p7|8310-5|Cel", 37.2),
+            record(3000, "p7|8310-5|Cel", 36.8),
+            record(5000, "p70|8867-4|bpm", 99.0),
+        ]).unwrap();
+        
+        let latest = engine.latest_by_prefix("p7|").unwrap();
+        let summary: Vec<(&str, i64, f64)> = latest.iter()
+            .map(|r| (r.metric_name.as_str(), r.timestamp, r.value))
+            .collect();
+        assert_eq!(summary, vec![
+            ("p7|29463-7|kg", 200, 80.5),
+            ("p7|8310-5|Cel", 4000, 37.2),
+            ("p7|8867-4|bpm", 7300, 75.0),
+        ]);
+        assert!(engine.latest_by_prefix("nobody|").unwrap().is_empty());
+    }
+}
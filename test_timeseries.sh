@@ -394,8 +394,8 @@ echo -e "\n4-6. Running remaining queries in parallel:"
 PARALLEL_QUERY_START=$(date +%s.%N)
 
 # Systems BP, Diastolic BP, and ECG queries in parallel
-curl -s "$API_URL/timeseries/rate?metric=${PATIENT_ID}|85354-9|8480-6|mmHg&start=$START_TIME&period=14400" > /dev/null &
-curl -s "$API_URL/timeseries/rate?metric=${PATIENT_ID}|85354-9|8462-4|mmHg&start=$START_TIME&period=14400" > /dev/null &
+curl -s "$API_URL/timeseries/rate?metric=${PATIENT_ID}|85354-9|8480-6|mmHg&start=$START_TIME&interval=4h" > /dev/null &
+curl -s "$API_URL/timeseries/rate?metric=${PATIENT_ID}|85354-9|8462-4|mmHg&start=$START_TIME&interval=4h" > /dev/null &
 curl -s "$API_URL/timeseries/trend?metric=${PATIENT_ID}|11524-6|sampled&start=$START_TIME" > /dev/null &
 curl -s "$API_URL/timeseries/trend?resource_type=Observation&start=$START_TIME" > /dev/null &
 